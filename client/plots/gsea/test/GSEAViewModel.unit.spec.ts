@@ -125,6 +125,11 @@ tape('getOutputMap should parse blitz and cerno responses and reject invalid cer
 		cernoMap,
 		'Cerno output should accept map-shaped output.data payloads'
 	)
+	test.deepEqual(
+		viewModel.getOutputMap({ pathways: cernoMap, unmapped_genes: ['FOO1'] }, 'cerno'),
+		cernoMap,
+		'Cerno output should be read from output.pathways, without the fields next to it'
+	)
 	test.throws(
 		() => viewModel.getOutputMap(['bad'] as any, 'cerno'),
 		/Invalid cerno response/,
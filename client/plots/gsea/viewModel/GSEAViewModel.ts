@@ -166,6 +166,8 @@ export class GSEAViewModel {
 			return output.data
 		}
 
+		// rust/src/cerno.rs nests the gene sets under "pathways", next to unmapped_genes, clusters and plot
+		if (output?.pathways && typeof output.pathways == 'object') return output.pathways
 		if (output?.data && !Array.isArray(output.data) && !output.data.genes && !output.data.fold_change) {
 			return output.data
 		}
@@ -1,6 +1,9 @@
 // cd .. && cargo build --release && json='{"min_count":10,"min_total_count":15,"case":"SJMB030827,SJMB030838,SJMB032893,SJMB031131,SJMB031227","control":"SJMB030488,SJMB030825,SJMB031110","data_type":"do_DE","input_file":"/Users/rpaul1/pp_data/files/hg38/ALL-pharmacotyping/rnaseq/counts.h5"}' && time echo $json | target/release/DEanalysis
 // cd .. && cargo build --release && json='{"data_type":"get_samples","input_file":"/Users/rpaul1/pp_data/files/hg38/ALL-pharmacotyping/rnaseq/counts.h5"}' && time echo $json | target/release/DEanalysis
 // cd .. && cargo build --release && time cat ~/sjpp/test.txt | target/release/DEanalysis
+// CAMERA competitive gene set test (data_type camera), gene sets from db + geneset_group, gmt_file or gene_sets; inter_gene_cor is a number (default 0.01) or "estimate" (with input_file):
+// cd .. && cargo build --release && json='{"data_type":"camera","min_count":10,"min_total_count":15,"case":"SJMB030827,SJMB030838,SJMB032893","control":"SJMB030488,SJMB030825,SJMB031110","input_file":"/Users/rpaul1/pp_data/files/hg38/ALL-pharmacotyping/rnaseq/counts.h5","db":"/Users/rpaul1/pp_data/msigdb/db","geneset_group":"H: hallmark gene sets"}' && time echo $json | target/release/DEanalysis
+// cd .. && cargo build --release && json='{"data_type":"camera","genes":["TP53","KRAS","MYC"],"statistics":[3.2,-1.1,0.4],"inter_gene_cor":0.01,"gmt_file":"sets.gmt"}' && time echo $json | target/release/DEanalysis
 #![allow(non_snake_case)]
 use hdf5::File as HDF5File;
 use hdf5::types::VarLenAscii;
@@ -28,23 +31,26 @@ use std::thread;
 //use std::cmp::Ordering;
 //use std::env;
 use std::io;
+mod camera;
+mod geneset_functions;
 mod stats_functions; // Importing Wilcoxon function from stats_functions.rs
+#[cfg(test)]
+mod test_camera; // Tests of the CAMERA competitive gene set test
 const PAR_CUTOFF: usize = 100000; // Cutoff for triggering multithreading processing of data
 
 //const PAR_CUTOFF: usize = 1000000000000000;
 #[allow(non_upper_case_globals)]
 const max_threads: usize = 6; // Max number of threads in case the parallel processing of reads is invoked
 
-fn input_data_from_HDF5(
-    hdf5_filename: &String,
-    case_list: &Vec<&str>,
-    control_list: &Vec<&str>,
-) -> (
+/// Expression matrix (genes x samples), column indexes of the cases and controls and gene names
+type CountMatrix = (
     Matrix<f64, Dyn, Dyn, VecStorage<f64, Dyn, Dyn>>,
     Vec<usize>,
     Vec<usize>,
     Vec<String>,
-) {
+);
+
+fn input_data_from_HDF5(hdf5_filename: &String, case_list: &Vec<&str>, control_list: &Vec<&str>) -> CountMatrix {
     let file = HDF5File::open(&hdf5_filename).unwrap(); // open for reading
 
     //let ds_dim = file.dataset("dims").unwrap(); // open the dataset
@@ -207,6 +213,11 @@ fn main() {
             let input_json = json::parse(&input);
             match input_json {
                 Ok(json_string) => {
+                    // camera does not require input_file when precomputed statistics are given
+                    if json_string["data_type"].as_str() == Some("camera") {
+                        run_camera(&json_string);
+                        return;
+                    }
                     //let now = Instant::now();
                     let file_name = &json_string["input_file"]
                         .to_owned()
@@ -222,68 +233,8 @@ fn main() {
                             if x == "get_samples" {
                                 get_DE_samples(file_name)
                             } else if x == "do_DE" {
-                                let min_count_option = json_string["min_count"].as_f64().to_owned();
-                                let min_total_count_option = json_string["min_total_count"].as_f64().to_owned();
-                                let min_count;
-                                match min_count_option {
-                                    Some(x) => min_count = x,
-                                    None => {
-                                        panic!("min_count is missing a value")
-                                    }
-                                }
-                                let min_total_count;
-                                match min_total_count_option {
-                                    Some(x) => min_total_count = x,
-                                    None => {
-                                        panic!("min_total_count is missing a value")
-                                    }
-                                }
-                                let case_string = &json_string["case"].to_owned().as_str().unwrap().to_string();
-                                let control_string = &json_string["control"].to_owned().as_str().unwrap().to_string();
-                                let case_list: Vec<&str> = case_string.split(",").collect();
-                                let control_list: Vec<&str> = control_string.split(",").collect();
-                                let (input_matrix, case_indexes, control_indexes, gene_names) =
-                                    input_data_from_HDF5(file_name, &case_list, &control_list);
-                                //let filtering_time = Instant::now();
-                                let (filtered_matrix, lib_sizes, filtered_gene_names) = filter_by_expr(
-                                    min_count,
-                                    min_total_count,
-                                    &input_matrix,
-                                    case_indexes.len(),
-                                    control_indexes.len(),
-                                    gene_names,
-                                );
-                                //println!("filtering time:{:?}", filtering_time.elapsed());
-                                //println!("filtered_matrix_rows:{:?}", filtered_matrix.nrows());
-                                //println!("filtered_matrix_cols:{:?}", filtered_matrix.ncols());
-                                if filtered_matrix.nrows() == 0 {
-                                    // Its possible after filtering there might not be any genes left in the matrix, in such a case the rust code must exit gracefully with an error.
-                                    panic!("Number of genes after filtering = 0, cannot proceed any further")
-                                }
-                                if filtered_matrix.ncols() == 0 {
-                                    // Its possible after filtering there might not be any samples left in the matrix, in such a case the rust code must exit gracefully with an error.
-                                    panic!("Number of samples after filtering = 0, cannot proceed any further")
-                                }
-                                //let cpm_normalization_time = Instant::now();
-                                let mut normalized_matrix = cpm(&filtered_matrix);
-                                //println!(
-                                //    "cpm normalization time:{:?}",
-                                //    cpm_normalization_time.elapsed()
-                                //);
-                                //let tmm_normalization_time = Instant::now();
-                                let norm_factors = tmm_normalization(filtered_matrix, &lib_sizes);
-                                //println!(
-                                //    "tmm normalization time:{:?}",
-                                //    tmm_normalization_time.elapsed()
-                                //);
-                                //println!("norm_factors:{:?}", norm_factors);
-
-                                for col in 0..normalized_matrix.ncols() {
-                                    let norm_factor = norm_factors[col];
-                                    for row in 0..normalized_matrix.nrows() {
-                                        normalized_matrix[(row, col)] = normalized_matrix[(row, col)] / norm_factor;
-                                    }
-                                }
+                                let (normalized_matrix, case_indexes, control_indexes, filtered_gene_names) =
+                                    normalized_input(&json_string, file_name);
                                 //println!("normalized_matrix:{:?}", normalized_matrix);
                                 //println!("Number of cases:{}", case_list.len());
                                 //println!("Number of controls:{}", control_list.len());
@@ -444,6 +395,163 @@ fn main() {
     }
 }
 
+// Competitive gene set test (CAMERA) on precomputed statistics (genes + statistics) or on the HDF5 counts of the case and control samples
+fn run_camera(json_string: &json::JsonValue) {
+    let custom_gene_sets = match geneset_functions::load_custom_gene_sets(json_string) {
+        Ok(custom_gene_sets) => custom_gene_sets,
+        Err(error) => panic!("{}", error),
+    };
+    let genesets = match custom_gene_sets {
+        Some(genesets) => genesets,
+        None => {
+            let msigdb = match json_string["db"].as_str() {
+                Some(db_string) => db_string.to_string(),
+                None => panic!("msigdb file path is missing"),
+            };
+            let genesetgroup = match json_string["geneset_group"].as_str() {
+                Some(genesetgroup_string) => genesetgroup_string.to_string(),
+                None => panic!("genesetgroup is missing"),
+            };
+            let pool = match geneset_functions::open_pool(&msigdb) {
+                Ok(pool) => pool,
+                Err(error) => panic!("{}", error),
+            };
+            let msigdbconn = pool.get().expect("Cannot get a connection to the sqlite database");
+            match geneset_functions::load_gene_sets(&msigdbconn, &genesetgroup) {
+                Ok(genesets) => genesets,
+                Err(error) => panic!("{}", error),
+            }
+        }
+    };
+    let size_filter = match geneset_functions::SizeFilter::from_json(json_string) {
+        Ok(size_filter) => size_filter,
+        Err(error) => panic!("{}", error),
+    };
+
+    // inter_gene_cor is either a number (default 0.01, as limma camera) or "estimate" (only with the expression matrix)
+    let inter_gene_cor_input: &json::JsonValue = &json_string["inter_gene_cor"];
+    let gene_statistics;
+    let inter_gene_cor;
+    if json_string["statistics"].is_null() {
+        let file_name = match json_string["input_file"].as_str() {
+            Some(file_name) => file_name.to_string(),
+            None => panic!("input_file or statistics must be given"),
+        };
+        let (normalized_matrix, case_indexes, control_indexes, filtered_gene_names) =
+            normalized_input(json_string, &file_name);
+        // Moderated t statistics are computed on log2 normalized CPM (limma-trend like)
+        let log_expr = normalized_matrix.map(|x| (x + 0.5).log2());
+        gene_statistics =
+            match camera::two_group_statistics(filtered_gene_names, &log_expr, &case_indexes, &control_indexes) {
+                Ok(gene_statistics) => gene_statistics,
+                Err(error) => panic!("{}", error),
+            };
+        inter_gene_cor = match inter_gene_cor_input.as_f64() {
+            Some(correlation) => Some(correlation),
+            None if inter_gene_cor_input.is_null() => Some(camera::DEFAULT_INTER_GENE_COR),
+            None if inter_gene_cor_input.as_str() == Some("estimate") => None,
+            None => panic!("inter_gene_cor must be a number or \"estimate\""),
+        };
+    } else {
+        let genes_input: &json::JsonValue = &json_string["genes"];
+        let statistics_input: &json::JsonValue = &json_string["statistics"];
+        if genes_input.len() != statistics_input.len() {
+            panic!("Length of genes array and statistics array are not equal");
+        }
+        let mut genes = Vec::<String>::with_capacity(genes_input.len());
+        let mut statistics = Vec::<f64>::with_capacity(statistics_input.len());
+        for iter in 0..genes_input.len() {
+            genes.push(genes_input[iter].as_str().expect("genes must be strings").to_string());
+            statistics.push(statistics_input[iter].as_f64().expect("statistics must be numbers"));
+        }
+        gene_statistics = camera::GeneStatistics {
+            genes,
+            statistics,
+            residuals: None,
+        };
+        // Without the expression matrix the correlation cannot be estimated (cameraPR)
+        inter_gene_cor = match inter_gene_cor_input.as_f64() {
+            Some(correlation) => Some(correlation),
+            None if inter_gene_cor_input.is_null() => Some(camera::DEFAULT_INTER_GENE_COR),
+            None => panic!("inter_gene_cor must be a number when precomputed statistics are given"),
+        };
+    }
+
+    let accepts_size = |size: usize| size_filter.accepts(size);
+    match camera::camera(&gene_statistics, &genesets, inter_gene_cor, &accepts_size) {
+        Ok(results) => println!("{}", serde_json::to_string(&results).unwrap()),
+        Err(error) => panic!("{}", error),
+    }
+}
+
+// Reads the counts of the case and control samples from the HDF5 file, filters lowly expressed genes (edgeR filterByExpr)
+// and returns the TMM normalized CPM matrix with the column indexes of cases and controls and the names of the retained genes
+fn normalized_input(json_string: &json::JsonValue, file_name: &String) -> CountMatrix {
+    let min_count_option = json_string["min_count"].as_f64().to_owned();
+    let min_total_count_option = json_string["min_total_count"].as_f64().to_owned();
+    let min_count;
+    match min_count_option {
+        Some(x) => min_count = x,
+        None => {
+            panic!("min_count is missing a value")
+        }
+    }
+    let min_total_count;
+    match min_total_count_option {
+        Some(x) => min_total_count = x,
+        None => {
+            panic!("min_total_count is missing a value")
+        }
+    }
+    let case_string = &json_string["case"].to_owned().as_str().unwrap().to_string();
+    let control_string = &json_string["control"].to_owned().as_str().unwrap().to_string();
+    let case_list: Vec<&str> = case_string.split(",").collect();
+    let control_list: Vec<&str> = control_string.split(",").collect();
+    let (input_matrix, case_indexes, control_indexes, gene_names) =
+        input_data_from_HDF5(file_name, &case_list, &control_list);
+    //let filtering_time = Instant::now();
+    let (filtered_matrix, lib_sizes, filtered_gene_names) = filter_by_expr(
+        min_count,
+        min_total_count,
+        &input_matrix,
+        case_indexes.len(),
+        control_indexes.len(),
+        gene_names,
+    );
+    //println!("filtering time:{:?}", filtering_time.elapsed());
+    //println!("filtered_matrix_rows:{:?}", filtered_matrix.nrows());
+    //println!("filtered_matrix_cols:{:?}", filtered_matrix.ncols());
+    if filtered_matrix.nrows() == 0 {
+        // Its possible after filtering there might not be any genes left in the matrix, in such a case the rust code must exit gracefully with an error.
+        panic!("Number of genes after filtering = 0, cannot proceed any further")
+    }
+    if filtered_matrix.ncols() == 0 {
+        // Its possible after filtering there might not be any samples left in the matrix, in such a case the rust code must exit gracefully with an error.
+        panic!("Number of samples after filtering = 0, cannot proceed any further")
+    }
+    //let cpm_normalization_time = Instant::now();
+    let mut normalized_matrix = cpm(&filtered_matrix);
+    //println!(
+    //    "cpm normalization time:{:?}",
+    //    cpm_normalization_time.elapsed()
+    //);
+    //let tmm_normalization_time = Instant::now();
+    let norm_factors = tmm_normalization(filtered_matrix, &lib_sizes);
+    //println!(
+    //    "tmm normalization time:{:?}",
+    //    tmm_normalization_time.elapsed()
+    //);
+    //println!("norm_factors:{:?}", norm_factors);
+
+    for col in 0..normalized_matrix.ncols() {
+        let norm_factor = norm_factors[col];
+        for row in 0..normalized_matrix.nrows() {
+            normalized_matrix[(row, col)] /= norm_factor;
+        }
+    }
+    (normalized_matrix, case_indexes, control_indexes, filtered_gene_names)
+}
+
 fn adjust_p_values(mut original_p_values: Vec<PValueIndexes>) -> String {
     // Sorting p-values in ascending order
     original_p_values
@@ -0,0 +1,333 @@
+// Biclustering methods used by cluster.rs, finding blocks of rows (e.g. genes) that behave alike in a subset of the columns (e.g. samples)
+//
+// 1) SpectralCoclustering: Dhillon 2001, as in scikit-learn's SpectralCoclustering. The non-negative matrix A is normalized to
+//    D_r^-1/2 A D_c^-1/2, the singular vectors 2..=l+1 (l = ceil(log2 k)) of the rows and of the columns are stacked and clustered
+//    together with k-means. Row cluster i and column cluster i form bicluster i, so every row and every column belongs to exactly one bicluster.
+// 2) ChengChurch: Cheng & Church 2000. Biclusters with a mean squared residue (MSR) below delta are found one at a time with multiple
+//    node deletion (rows/columns with a residue above alpha * MSR, while more than 100 remain), single node deletion and node addition.
+//    The elements of each bicluster found are then masked with uniform random values so that the next search finds a different bicluster.
+//    Rows and columns may belong to several biclusters or to none.
+//
+// Row and column indexes in the output are 0-based indexes of the input matrix.
+use crate::partition_clustering;
+use crate::stats_functions::SeededRng;
+use nalgebra::DMatrix;
+use serde::Serialize;
+
+const MULTIPLE_DELETION_CUTOFF: usize = 100; // Multiple node deletion is only used while more rows/columns than this remain
+// Node addition accepts residues up to MSR + ADDITION_TOLERANCE * (value range)^2, so that rows/columns fitting an exact
+// (MSR 0) bicluster are not rejected because of rounding errors
+const ADDITION_TOLERANCE: f64 = 1e-12;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Bicluster {
+    pub rows: Vec<usize>,
+    pub cols: Vec<usize>,
+    pub msr: f64, // Mean squared residue of the submatrix
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BiclusterResult {
+    pub method: String,
+    pub biclusters: Vec<Bicluster>,
+    pub sorted_row_elements: Vec<usize>, // Rows grouped by (first) bicluster, rows outside all biclusters last
+    pub sorted_col_elements: Vec<usize>, // Columns grouped by (first) bicluster, columns outside all biclusters last
+    pub sorted_matrix: Vec<Vec<f64>>,    // Input matrix reordered by sorted_row_elements and sorted_col_elements
+}
+
+/// Mean squared residue of the submatrix together with the mean residue of each of its rows and columns
+struct Residues {
+    msr: f64,
+    row_residue: Vec<f64>,
+    col_residue: Vec<f64>,
+}
+
+fn residues(data: &DMatrix<f64>, rows: &[usize], cols: &[usize]) -> Residues {
+    let (num_rows, num_cols) = (rows.len() as f64, cols.len() as f64);
+    let row_mean: Vec<f64> = rows
+        .iter()
+        .map(|&r| cols.iter().map(|&c| data[(r, c)]).sum::<f64>() / num_cols)
+        .collect();
+    let col_mean: Vec<f64> = cols
+        .iter()
+        .map(|&c| rows.iter().map(|&r| data[(r, c)]).sum::<f64>() / num_rows)
+        .collect();
+    let mean = row_mean.iter().sum::<f64>() / num_rows;
+    let mut row_residue = vec![0.0; rows.len()];
+    let mut col_residue = vec![0.0; cols.len()];
+    for (i, &r) in rows.iter().enumerate() {
+        for (j, &c) in cols.iter().enumerate() {
+            let residue = (data[(r, c)] - row_mean[i] - col_mean[j] + mean).powi(2);
+            row_residue[i] += residue;
+            col_residue[j] += residue;
+        }
+    }
+    let msr = row_residue.iter().sum::<f64>() / (num_rows * num_cols);
+    for residue in row_residue.iter_mut() {
+        *residue /= num_cols;
+    }
+    for residue in col_residue.iter_mut() {
+        *residue /= num_rows;
+    }
+    Residues {
+        msr,
+        row_residue,
+        col_residue,
+    }
+}
+
+/// Mean residue of each candidate row (or column, with `by_row` false) that is not in the bicluster, measured against the bicluster means
+fn addition_residues(
+    data: &DMatrix<f64>,
+    rows: &[usize],
+    cols: &[usize],
+    candidates: &[usize],
+    by_row: bool,
+) -> Vec<f64> {
+    let (num_rows, num_cols) = (rows.len() as f64, cols.len() as f64);
+    let row_mean: Vec<f64> = rows
+        .iter()
+        .map(|&r| cols.iter().map(|&c| data[(r, c)]).sum::<f64>() / num_cols)
+        .collect();
+    let col_mean: Vec<f64> = cols
+        .iter()
+        .map(|&c| rows.iter().map(|&r| data[(r, c)]).sum::<f64>() / num_rows)
+        .collect();
+    let mean = row_mean.iter().sum::<f64>() / num_rows;
+    candidates
+        .iter()
+        .map(|&candidate| {
+            if by_row {
+                let candidate_mean = cols.iter().map(|&c| data[(candidate, c)]).sum::<f64>() / num_cols;
+                cols.iter()
+                    .enumerate()
+                    .map(|(j, &c)| (data[(candidate, c)] - candidate_mean - col_mean[j] + mean).powi(2))
+                    .sum::<f64>()
+                    / num_cols
+            } else {
+                let candidate_mean = rows.iter().map(|&r| data[(r, candidate)]).sum::<f64>() / num_rows;
+                rows.iter()
+                    .enumerate()
+                    .map(|(i, &r)| (data[(r, candidate)] - row_mean[i] - candidate_mean + mean).powi(2))
+                    .sum::<f64>()
+                    / num_rows
+            }
+        })
+        .collect()
+}
+
+/// Finds a single bicluster with MSR <= delta in `data`
+fn cheng_church_single(data: &DMatrix<f64>, delta: f64, alpha: f64) -> Bicluster {
+    let mut rows: Vec<usize> = (0..data.nrows()).collect();
+    let mut cols: Vec<usize> = (0..data.ncols()).collect();
+
+    // Multiple node deletion
+    loop {
+        let mut changed = false;
+        let r = residues(data, &rows, &cols);
+        if r.msr <= delta {
+            break;
+        }
+        if rows.len() > MULTIPLE_DELETION_CUTOFF {
+            let kept: Vec<usize> = rows
+                .iter()
+                .zip(r.row_residue.iter())
+                .filter(|&(_, &residue)| residue <= alpha * r.msr)
+                .map(|(&row, _)| row)
+                .collect();
+            if kept.len() < rows.len() && !kept.is_empty() {
+                rows = kept;
+                changed = true;
+            }
+        }
+        let r = residues(data, &rows, &cols);
+        if r.msr <= delta {
+            break;
+        }
+        if cols.len() > MULTIPLE_DELETION_CUTOFF {
+            let kept: Vec<usize> = cols
+                .iter()
+                .zip(r.col_residue.iter())
+                .filter(|&(_, &residue)| residue <= alpha * r.msr)
+                .map(|(&col, _)| col)
+                .collect();
+            if kept.len() < cols.len() && !kept.is_empty() {
+                cols = kept;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // Single node deletion: drop the row or column with the largest mean residue
+    let mut r = residues(data, &rows, &cols);
+    while r.msr > delta && (rows.len() > 1 || cols.len() > 1) {
+        let (worst_row, worst_row_residue) = r.row_residue.iter().enumerate().fold(
+            (0, f64::NEG_INFINITY),
+            |best, (i, &v)| if v > best.1 { (i, v) } else { best },
+        );
+        let (worst_col, worst_col_residue) = r.col_residue.iter().enumerate().fold(
+            (0, f64::NEG_INFINITY),
+            |best, (j, &v)| if v > best.1 { (j, v) } else { best },
+        );
+        if (worst_row_residue >= worst_col_residue && rows.len() > 1) || cols.len() == 1 {
+            rows.remove(worst_row);
+        } else {
+            cols.remove(worst_col);
+        }
+        r = residues(data, &rows, &cols);
+    }
+
+    // Node addition: columns and then rows whose mean residue does not exceed the current MSR, until nothing is added
+    let tolerance = ADDITION_TOLERANCE * (data.max() - data.min()).powi(2);
+    let mut in_rows = vec![false; data.nrows()];
+    let mut in_cols = vec![false; data.ncols()];
+    for &i in &rows {
+        in_rows[i] = true;
+    }
+    for &c in &cols {
+        in_cols[c] = true;
+    }
+    loop {
+        let mut added = false;
+        let msr = residues(data, &rows, &cols).msr;
+        let col_candidates: Vec<usize> = (0..data.ncols()).filter(|&c| !in_cols[c]).collect();
+        let col_residues = addition_residues(data, &rows, &cols, &col_candidates, false);
+        for (k, &c) in col_candidates.iter().enumerate() {
+            if col_residues[k] <= msr + tolerance {
+                cols.push(c);
+                in_cols[c] = true;
+                added = true;
+            }
+        }
+        let msr = residues(data, &rows, &cols).msr;
+        let row_candidates: Vec<usize> = (0..data.nrows()).filter(|&i| !in_rows[i]).collect();
+        let row_residues = addition_residues(data, &rows, &cols, &row_candidates, true);
+        for (k, &i) in row_candidates.iter().enumerate() {
+            if row_residues[k] <= msr + tolerance {
+                rows.push(i);
+                in_rows[i] = true;
+                added = true;
+            }
+        }
+        if !added {
+            break;
+        }
+    }
+    rows.sort_unstable();
+    cols.sort_unstable();
+    let msr = residues(data, &rows, &cols).msr;
+    Bicluster { rows, cols, msr }
+}
+
+/// Cheng & Church biclustering. delta defaults (when None) to 10% of the MSR of the whole matrix.
+pub fn cheng_church(
+    data: &DMatrix<f64>,
+    num_biclusters: usize,
+    delta: Option<f64>,
+    alpha: f64,
+    rng: &mut SeededRng,
+) -> Vec<Bicluster> {
+    let all_rows: Vec<usize> = (0..data.nrows()).collect();
+    let all_cols: Vec<usize> = (0..data.ncols()).collect();
+    let delta = delta.unwrap_or_else(|| 0.1 * residues(data, &all_rows, &all_cols).msr);
+    let (min, max) = (data.min(), data.max());
+    let mut masked = data.clone();
+    let mut biclusters = Vec::with_capacity(num_biclusters);
+    for _ in 0..num_biclusters {
+        let found = cheng_church_single(&masked, delta, alpha);
+        if found.rows.is_empty() || found.cols.is_empty() {
+            break;
+        }
+        for &r in &found.rows {
+            for &c in &found.cols {
+                masked[(r, c)] = min + (max - min) * rng.next_f64();
+            }
+        }
+        // The MSR is reported on the original values
+        let msr = residues(data, &found.rows, &found.cols).msr;
+        biclusters.push(Bicluster { msr, ..found });
+    }
+    biclusters
+}
+
+/// Spectral co-clustering of a non-negative matrix into k biclusters
+pub fn spectral_coclustering(
+    data: &DMatrix<f64>,
+    k: usize,
+    n_start: usize,
+    max_iter: usize,
+    rng: &mut SeededRng,
+) -> Result<Vec<Bicluster>, String> {
+    if data.iter().any(|v| *v < 0.0 || !v.is_finite()) {
+        return Err("Spectral co-clustering requires a matrix of finite non-negative values".to_string());
+    }
+    let (num_rows, num_cols) = (data.nrows(), data.ncols());
+    if k < 2 || k > num_rows.min(num_cols) {
+        return Err(format!("k must be between 2 and {}", num_rows.min(num_cols)));
+    }
+    let inverse_sqrt = |sum: f64| if sum > 0.0 { 1.0 / sum.sqrt() } else { 0.0 };
+    let row_scale: Vec<f64> = (0..num_rows).map(|r| inverse_sqrt(data.row(r).sum())).collect();
+    let col_scale: Vec<f64> = (0..num_cols).map(|c| inverse_sqrt(data.column(c).sum())).collect();
+    let normalized = DMatrix::<f64>::from_fn(num_rows, num_cols, |r, c| data[(r, c)] * row_scale[r] * col_scale[c]);
+    let svd = normalized.svd(true, true); // Singular values are sorted in descending order
+    let u = svd.u.unwrap();
+    let v_t = svd.v_t.unwrap();
+    let num_vectors = ((k as f64).log2().ceil() as usize).max(1).min(u.ncols() - 1);
+    // Rows and columns are embedded in the same space and clustered together
+    let embedding = DMatrix::<f64>::from_fn(num_rows + num_cols, num_vectors, |item, vector| {
+        if item < num_rows {
+            row_scale[item] * u[(item, vector + 1)]
+        } else {
+            col_scale[item - num_rows] * v_t[(vector + 1, item - num_rows)]
+        }
+    });
+    let assignments = partition_clustering::kmeans(&embedding, k, n_start, max_iter, rng).assignments;
+    let mut biclusters = Vec::with_capacity(k);
+    for cluster in 0..k {
+        let rows: Vec<usize> = (0..num_rows).filter(|&r| assignments[r] == cluster).collect();
+        let cols: Vec<usize> = (0..num_cols)
+            .filter(|&c| assignments[num_rows + c] == cluster)
+            .collect();
+        let msr = if rows.is_empty() || cols.is_empty() {
+            0.0
+        } else {
+            residues(data, &rows, &cols).msr
+        };
+        biclusters.push(Bicluster { rows, cols, msr });
+    }
+    Ok(biclusters)
+}
+
+/// Orders rows and columns by the first bicluster they belong to and builds the reordered matrix
+pub fn bicluster_result(method: &str, data: &DMatrix<f64>, biclusters: Vec<Bicluster>) -> BiclusterResult {
+    let order = |count: usize, members: &dyn Fn(&Bicluster) -> &Vec<usize>| -> Vec<usize> {
+        let mut placed = vec![false; count];
+        let mut sorted = Vec::with_capacity(count);
+        for bicluster in &biclusters {
+            for &item in members(bicluster) {
+                if !placed[item] {
+                    placed[item] = true;
+                    sorted.push(item);
+                }
+            }
+        }
+        sorted.extend((0..count).filter(|&item| !placed[item]));
+        sorted
+    };
+    let sorted_row_elements = order(data.nrows(), &|b: &Bicluster| &b.rows);
+    let sorted_col_elements = order(data.ncols(), &|b: &Bicluster| &b.cols);
+    let sorted_matrix = sorted_row_elements
+        .iter()
+        .map(|&r| sorted_col_elements.iter().map(|&c| data[(r, c)]).collect())
+        .collect();
+    BiclusterResult {
+        method: method.to_string(),
+        biclusters,
+        sorted_row_elements,
+        sorted_col_elements,
+        sorted_matrix,
+    }
+}
@@ -0,0 +1,220 @@
+// Competitive gene set test accounting for inter-gene correlation (CAMERA, Wu & Smyth 2012, limma camera/cameraPR)
+//
+// The gene-wise statistics are either precomputed (e.g. moderated t statistics from an external limma run) or
+// computed here from a log-expression matrix with a two-group linear model and empirical Bayes moderated t statistics
+// (converted to z-scores as in limma zscoreT). In the latter case, the standardized residuals of the model can be used
+// to estimate the variance inflation factor of each gene set from the correlation of its genes instead of assuming the
+// default inter-gene correlation.
+use crate::geneset_functions::GeneSet;
+use crate::stats_functions::fit_f_dist;
+use nalgebra::DMatrix;
+use serde::Serialize;
+use statrs::distribution::{ContinuousCDF, Normal, StudentsT};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Default inter-gene correlation, with both precomputed statistics and the expression matrix (same as limma camera
+/// and cameraPR). It is estimated from the residuals only when asked for ("estimate", inter.gene.cor = NA in limma).
+#[allow(dead_code)]
+pub const DEFAULT_INTER_GENE_COR: f64 = 0.01;
+
+#[allow(dead_code)]
+#[derive(Debug, Serialize)]
+pub struct CameraResult {
+    pub name: String,
+    pub num_genes: usize,
+    pub correlation: f64,
+    pub direction: String, // "Up" or "Down"
+    pub p_value: f64,
+    pub fdr: f64,
+}
+
+/// Gene-wise statistics tested by camera
+#[allow(dead_code)]
+pub struct GeneStatistics {
+    pub genes: Vec<String>,
+    pub statistics: Vec<f64>,
+    /// Standardized residual effects (genes × residual df), only available when the statistics are computed from the expression matrix
+    pub residuals: Option<DMatrix<f64>>,
+}
+
+/// Fits a two-group linear model (intercept + group) to each row of log_expr and returns the moderated t statistics
+/// of case vs control converted to z-scores, together with the standardized residual effects of each gene
+#[allow(dead_code)]
+pub fn two_group_statistics(
+    genes: Vec<String>,
+    log_expr: &DMatrix<f64>,
+    case_indexes: &[usize],
+    control_indexes: &[usize],
+) -> Result<GeneStatistics, String> {
+    let n1 = case_indexes.len();
+    let n2 = control_indexes.len();
+    if n1 + n2 < 3 || n1 == 0 || n2 == 0 {
+        return Err("camera requires at least one case, one control and three samples in total".to_string());
+    }
+    // Orthonormal basis of the residual space of the design: Helmert contrasts within each group
+    let mut basis: Vec<Vec<(usize, f64)>> = Vec::with_capacity(n1 + n2 - 2);
+    for group in [case_indexes, control_indexes] {
+        for j in 1..group.len() {
+            let norm = ((j * (j + 1)) as f64).sqrt();
+            let mut vector: Vec<(usize, f64)> = group[..j].iter().map(|&col| (col, 1.0 / norm)).collect();
+            vector.push((group[j], -(j as f64) / norm));
+            basis.push(vector);
+        }
+    }
+    let df_residual = basis.len() as f64;
+    let stdev_unscaled = (1.0 / n1 as f64 + 1.0 / n2 as f64).sqrt();
+
+    let num_genes = log_expr.nrows();
+    let mut log_fc = Vec::with_capacity(num_genes);
+    let mut residual_var = Vec::with_capacity(num_genes);
+    let mut residuals = DMatrix::<f64>::zeros(num_genes, basis.len());
+    for i in 0..num_genes {
+        let case_mean = case_indexes.iter().map(|&col| log_expr[(i, col)]).sum::<f64>() / n1 as f64;
+        let control_mean = control_indexes.iter().map(|&col| log_expr[(i, col)]).sum::<f64>() / n2 as f64;
+        log_fc.push(case_mean - control_mean);
+        let mut sum_squares = 0.0;
+        for (k, vector) in basis.iter().enumerate() {
+            let effect: f64 = vector.iter().map(|&(col, weight)| weight * log_expr[(i, col)]).sum();
+            residuals[(i, k)] = effect;
+            sum_squares += effect * effect;
+        }
+        let sigma2 = sum_squares / df_residual;
+        residual_var.push(sigma2);
+        // Residual effects are standardized to unit variance per gene (limma camera)
+        let scale = sigma2.max(1e-8).sqrt();
+        for k in 0..basis.len() {
+            residuals[(i, k)] /= scale;
+        }
+    }
+
+    // Empirical Bayes moderation of the gene-wise variances (limma squeezeVar)
+    let dfs = vec![df_residual; num_genes];
+    let (s20, df0) = fit_f_dist(&residual_var, &dfs);
+    let df_total = (df_residual + df0).min(df_residual * num_genes as f64);
+    let tdist = StudentsT::new(0.0, 1.0, df_total).map_err(|error| format!("{}", error))?;
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    let statistics = (0..num_genes)
+        .map(|i| {
+            let s2_post = if df0.is_finite() {
+                (df0 * s20 + df_residual * residual_var[i]) / (df0 + df_residual)
+            } else {
+                s20
+            };
+            let t = log_fc[i] / (s2_post.sqrt() * stdev_unscaled);
+            if t.is_nan() {
+                return 0.0;
+            }
+            // z-score with the same tail probability as t (limma zscoreT), computed from the smaller tail
+            let tail = tdist.sf(t.abs()).max(f64::MIN_POSITIVE);
+            -normal.inverse_cdf(tail) * t.signum()
+        })
+        .collect();
+    Ok(GeneStatistics {
+        genes,
+        statistics,
+        residuals: Some(residuals),
+    })
+}
+
+/// Runs camera on each gene set. With inter_gene_cor = None the correlation is estimated from the residuals
+/// (which must then be present in gene_statistics), otherwise the given correlation is used for every set.
+#[allow(dead_code)]
+pub fn camera(
+    gene_statistics: &GeneStatistics,
+    genesets: &[GeneSet],
+    inter_gene_cor: Option<f64>,
+    accepts_size: &dyn Fn(usize) -> bool,
+) -> Result<Vec<CameraResult>, String> {
+    let statistics = &gene_statistics.statistics;
+    let num_genes = statistics.len();
+    if num_genes < 3 {
+        return Err("camera requires at least three genes with statistics".to_string());
+    }
+    let residuals = match (inter_gene_cor, &gene_statistics.residuals) {
+        (Some(_), _) => None,
+        (None, Some(residuals)) => Some(residuals),
+        (None, None) => {
+            return Err("The inter-gene correlation can only be estimated from an expression matrix".to_string());
+        }
+    };
+    let gene_index: HashMap<&str, usize> = gene_statistics
+        .genes
+        .iter()
+        .enumerate()
+        .map(|(i, gene)| (gene.as_str(), i))
+        .collect();
+
+    let g = num_genes as f64;
+    let mean_stat = statistics.iter().sum::<f64>() / g;
+    let var_stat = statistics.iter().map(|s| (s - mean_stat).powi(2)).sum::<f64>() / (g - 1.0);
+    let df_camera = match residuals {
+        Some(residuals) => (residuals.ncols() as f64).min(g - 2.0),
+        None => g - 2.0,
+    };
+    let tdist = StudentsT::new(0.0, 1.0, df_camera).map_err(|error| format!("{}", error))?;
+
+    let mut results = Vec::<CameraResult>::new();
+    for geneset in genesets {
+        let mut index: Vec<usize> = geneset
+            .genes
+            .iter()
+            .filter_map(|gene| gene_index.get(gene.as_str()).copied())
+            .collect();
+        index.sort();
+        let m = index.len();
+        if m == 0 || m == num_genes || !accepts_size(m) {
+            continue;
+        }
+        let (vif, correlation) = match residuals {
+            Some(residuals) if m > 1 => {
+                // vif = m * mean over residual df of the squared mean residual of the set
+                let mut sum_squares = 0.0;
+                for k in 0..residuals.ncols() {
+                    let column_mean = index.iter().map(|&i| residuals[(i, k)]).sum::<f64>() / m as f64;
+                    sum_squares += column_mean * column_mean;
+                }
+                let vif = m as f64 * sum_squares / residuals.ncols() as f64;
+                (vif, (vif - 1.0) / (m as f64 - 1.0))
+            }
+            Some(_) => (1.0, 0.0),
+            None => {
+                let correlation = inter_gene_cor.unwrap();
+                (1.0 + (m as f64 - 1.0) * correlation, correlation)
+            }
+        };
+        let m1 = m as f64;
+        let m2 = g - m1;
+        let mean_in_set = index.iter().map(|&i| statistics[i]).sum::<f64>() / m1;
+        let delta = g / m2 * (mean_in_set - mean_stat);
+        let var_pooled = ((g - 1.0) * var_stat - delta * delta * m1 * m2 / g) / (g - 2.0);
+        let two_sample_t = delta / (var_pooled * (vif / m1 + 1.0 / m2)).sqrt();
+        if two_sample_t.is_nan() {
+            continue;
+        }
+        let down = tdist.cdf(two_sample_t);
+        let up = tdist.sf(two_sample_t);
+        results.push(CameraResult {
+            name: geneset.id.clone(),
+            num_genes: m,
+            correlation,
+            direction: if up < down {
+                "Up".to_string()
+            } else {
+                "Down".to_string()
+            },
+            p_value: (2.0 * up.min(down)).min(1.0),
+            fdr: 1.0,
+        });
+    }
+
+    // Benjamini-Hochberg adjustment across the tested gene sets
+    results.sort_by(|a, b| a.p_value.partial_cmp(&b.p_value).unwrap_or(Ordering::Equal));
+    let num_tests = results.len() as f64;
+    let mut cummin: f64 = 1.0;
+    for (rank, result) in results.iter_mut().enumerate().rev() {
+        cummin = cummin.min(result.p_value * num_tests / (rank + 1) as f64);
+        result.fdr = cummin;
+    }
+    Ok(results)
+}
@@ -1,27 +1,45 @@
 // Syntax: cd .. && cargo build --release && time cat ~/sjpp/test.txt | target/release/cerno
+// Input keys: db, geneset_group, genes, fold_change, genedb, filter_non_coding_genes
+//   gene_sets (optional): Inline {name: [genes]} gene sets used instead of db/geneset_group
+//   gmt_file (optional): Path of a GMT file used instead of db/geneset_group
+//     Gene symbols of custom gene sets are normalized against the genedb codingGenes table. Symbols that cannot be
+//     mapped are listed in "unmapped_genes" (JSON array), a field of the output object.
+//   method (optional): "cerno" (default) or "gsea" for preranked GSEA with the weighted Kolmogorov-Smirnov statistic
+//   num_permutations (optional, gsea): Number of random gene sets per gene set size (default 1000)
+//   seed (optional, gsea): Seed for the permutations (default 1)
+//   gsea_weight (optional, gsea): Exponent applied to the ranking statistic (default 1)
+//   min_gs_size, max_gs_size (optional): Gene sets with fewer/more genes in the ranked list are not tested (applied before
+//     the FDR correction)
+//   redundancy (optional): {"metric": "jaccard" | "kappa", "threshold", "fdr_cutoff"} clusters the significant gene sets
+//     by gene set similarity and adds "clusters": [{"representative", "members"}] to the output object.
+//   plot_geneset (optional): Gene set whose enrichment curve is rendered, with a tick at the rank of each of its genes: the
+//     fraction of the gene set found along the ranked list (cerno) or the running sum (gsea). The plot is added as
+//     "plot": {"png", "svg", "labels", ...} (see enrichment_plots.rs) to the output object.
+//     image_width, image_height (CSS pixels) and devicePixelRatio set the size of the image.
+// Output: {"pathways": {gene set: result}} with the optional fields above next to "pathways". The cerno result has pval,
+// fdr, es, auc, leading_edge and geneset_size. The gsea result has pval, fdr, es, nes, leading_edge, geneset_size and
+// running_sum (vertices of the running-sum curve, the curve is linear between vertices)
 #![allow(non_snake_case)]
 use json::JsonValue;
-use r2d2;
-use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{Connection, Result};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::sync::{Arc, Mutex}; // Multithreading library
 use std::thread;
 
+mod enrichment_plots;
+mod geneset_functions;
+mod gsea;
 mod stats_functions;
 #[cfg(test)]
 mod test_cerno; // Contains test examples to test cerno
-
-#[allow(non_camel_case_types)]
-#[allow(non_snake_case)]
-#[derive(Debug, Clone)]
-struct GO_pathway {
-    GO_id: String,
-}
+#[cfg(test)]
+mod test_enrichment_plots; // Tests of the enrichment curve, dot plot and scene rendering
+#[cfg(test)]
+mod test_gsea; // Tests of the preranked GSEA running sum, enrichment scores and p-values
 
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
@@ -49,6 +67,18 @@ struct output_struct {
     es: f32,
     geneset_size: usize,
 }
+#[allow(non_camel_case_types)]
+#[derive(Debug, Serialize, Deserialize)]
+struct gsea_output_struct {
+    pval: f64,
+    fdr: f64,
+    leading_edge: String,
+    es: f64,
+    nes: f64,
+    geneset_size: usize,
+    running_sum: Vec<(usize, f64)>, // Vertices of the running-sum curve as (gene rank, running sum)
+}
+
 const PAR_CUTOFF: usize = 1000; // Cutoff for triggering multithreading processing of data
 #[allow(non_upper_case_globals)]
 const max_threads: usize = 3; // Max number of threads in case the parallel processing of reads is invoked
@@ -61,18 +91,29 @@ fn main() -> Result<()> {
             let input_json = json::parse(&input);
             match input_json {
                 Ok(json_string) => {
+                    // Gene sets are read from an inline gene_sets object or a gmt_file if given, otherwise from the msigdb database
+                    let custom_gene_sets = match geneset_functions::load_custom_gene_sets(&json_string) {
+                        Ok(custom_gene_sets) => custom_gene_sets,
+                        Err(error) => panic!("{}", error),
+                    };
                     let msigdb_input: &JsonValue = &json_string["db"];
                     let msigdb;
                     match msigdb_input.as_str() {
                         Some(db_string) => msigdb = db_string.to_string(),
+                        None if custom_gene_sets.is_some() => msigdb = String::new(),
                         None => panic!("msigdb file path is missing"),
                     }
                     let genesetgroup;
                     let genesetgroup_input: &JsonValue = &json_string["geneset_group"];
                     match genesetgroup_input.as_str() {
                         Some(genesetgroup_string) => genesetgroup = genesetgroup_string.to_string(),
+                        None if custom_gene_sets.is_some() => genesetgroup = String::new(),
                         None => panic!("genesetgroup is missing"),
                     }
+                    let method = json_string["method"].as_str().unwrap_or("cerno");
+                    if method != "cerno" && method != "gsea" {
+                        panic!("Unknown method {}, must be cerno or gsea", method);
+                    }
                     let sample_genes_input: &JsonValue = &json_string["genes"];
                     let mut sample_genes = Vec::<&str>::new();
                     for iter in 0..sample_genes_input.len() {
@@ -118,25 +159,14 @@ fn main() -> Result<()> {
                     let filter_non_coding_genes: bool = filter_non_coding_genes_input.as_bool().unwrap();
 
                     let genedbconn = Connection::open(genedb)?;
-                    let genedb_result = genedbconn.prepare(&("select * from codingGenes"));
-                    let mut sample_coding_genes: Vec<stats_functions::gene_order> = Vec::with_capacity(24000);
-                    match genedb_result {
-                        Ok(mut x) => {
-                            let mut genes = x.query([])?;
-                            while let Some(coding_gene) = genes.next()? {
-                                //println!("coding_gene:{:?}", coding_gene);
-                                for sample_gene in &genes_vector {
-                                    let code_gene: String = coding_gene.get(0).unwrap();
-                                    if filter_non_coding_genes == true && code_gene == *sample_gene.gene_name {
-                                        sample_coding_genes.push(sample_gene.clone());
-                                    } else if filter_non_coding_genes == false {
-                                        sample_coding_genes.push(sample_gene.clone());
-                                    }
-                                }
-                            }
-                        }
-                        Err(_) => {}
-                    }
+                    // Coding genes are loaded once and looked up in a HashSet
+                    let coding_genes_list = match geneset_functions::load_coding_genes(&genedbconn) {
+                        Ok(coding_genes) => coding_genes,
+                        Err(error) => panic!("{}", error),
+                    };
+                    let coding_genes: HashSet<String> = coding_genes_list.iter().cloned().collect();
+                    let mut sample_coding_genes: Vec<stats_functions::gene_order> =
+                        geneset_functions::filter_query_genes(genes_vector, &coding_genes, filter_non_coding_genes);
 
                     if sample_coding_genes.len() == 0 {
                         panic!("All query genes are non-coding");
@@ -167,165 +197,160 @@ fn main() -> Result<()> {
                     //println!("sample_genes:{:?}", sample_genes);
                     //println!("background_genes:{:?}", background_genes);
 
-                    let msigdbconn = Connection::open(&msigdb)?;
-                    let stmt_result = msigdbconn
-                        .prepare(&("select id from terms where parent_id='".to_owned() + &genesetgroup + "'"));
-                    match stmt_result {
-                        Ok(mut stmt) => {
-                            #[allow(non_snake_case)]
-                            let GO_iter = stmt.query_map([], |row| Ok(GO_pathway { GO_id: row.get(0)? }))?;
-                            #[allow(non_snake_case)]
-                            let mut genesets = Vec::<String>::new();
-                            for GO_term in GO_iter {
-                                match GO_term {
-                                    Ok(n) => {
-                                        genesets.push(n.GO_id);
-                                    }
-                                    Err(_) => {
-                                        println!("GO term not found!")
-                                    }
-                                }
+                    // Fields added to the output object next to "pathways"
+                    let mut extra_fields = Vec::<String>::new();
+                    let mut genesets = match custom_gene_sets {
+                        Some(mut genesets) => {
+                            // Symbols of the custom gene sets not found in genedb
+                            let unmapped_genes =
+                                geneset_functions::normalize_gene_sets(&mut genesets, &coding_genes_list);
+                            extra_fields.push(
+                                "\"unmapped_genes\":".to_string() + &serde_json::to_string(&unmapped_genes).unwrap(),
+                            );
+                            genesets
+                        }
+                        None => {
+                            let pool = match geneset_functions::open_pool(&msigdb) {
+                                Ok(pool) => pool,
+                                Err(error) => panic!("{}", error),
+                            };
+                            // All the gene sets of the group are loaded with a single query
+                            let msigdbconn = pool.get().expect("Cannot get a connection to the sqlite database");
+                            match geneset_functions::load_gene_sets(&msigdbconn, &genesetgroup) {
+                                Ok(genesets) => genesets,
+                                Err(error) => panic!("{}", error),
                             }
+                        }
+                    };
+
+                    let size_filter = match geneset_functions::SizeFilter::from_json(&json_string) {
+                        Ok(size_filter) => size_filter,
+                        Err(error) => panic!("{}", error),
+                    };
+                    let redundancy = match geneset_functions::RedundancyOptions::from_json(&json_string) {
+                        Ok(redundancy) => redundancy,
+                        Err(error) => panic!("{}", error),
+                    };
+                    // Gene sets outside the size limits are not tested, so they do not count in the FDR correction
+                    filter_gene_set_sizes(&mut genesets, &genes_descending, &size_filter);
+
+                    if let Some(plot_geneset) = json_string["plot_geneset"].as_str() {
+                        extra_fields.push(enrichment_plot_field(
+                            plot_geneset,
+                            method,
+                            &genes_descending,
+                            &genesets,
+                            &json_string,
+                        ));
+                    }
 
-                            if genesets.len() < PAR_CUTOFF {
-                                for gs in genesets {
-                                    let sql_statement =
-                                        "select genes from term2genes where id='".to_owned() + &gs + &"'";
-                                    //println!("sql_statement:{}", sql_statement);
-                                    let mut gene_stmt = msigdbconn.prepare(&(sql_statement))?;
-                                    //println!("gene_stmt:{:?}", gene_stmt);
-
-                                    let mut rows = gene_stmt.query([])?;
-                                    let mut names = HashSet::<String>::new();
-                                    while let Some(row) = rows.next()? {
-                                        let a: String = row.get(0)?;
-                                        let input_gene_json = json::parse(&a);
-                                        match input_gene_json {
-                                            Ok(json_genes) => {
-                                                for json_iter in 0..json_genes.len() {
-                                                    names.insert(json_genes[json_iter]["symbol"].to_string());
-                                                }
-                                            }
-                                            Err(_) => {
-                                                panic!("Symbol, ensg, enstCanonical structure is missing!")
-                                            }
+                    if method == "gsea" {
+                        let output_string = run_gsea(
+                            &genes_descending,
+                            &genesets,
+                            &json_string,
+                            redundancy,
+                            &mut extra_fields,
+                        );
+                        println!("{}", output_object(output_string, &extra_fields));
+                        return Ok(());
+                    }
+                    // The genes of the gene sets are needed after testing to cluster redundant terms
+                    let redundancy_genesets = redundancy.map(|_| genesets.clone());
+                    let num_ranked_genes = genes_descending.len();
+
+                    if genesets.len() < PAR_CUTOFF {
+                        for gs in genesets {
+                            let gene_set_size = gs.genes.len();
+                            let (p_value, auc, es, matches, gene_set_hits, _cerno_output) =
+                                stats_functions::cerno(&genes_descending, &genes_ascending, gs.genes);
+
+                            if matches >= 1.0
+                                && p_value.is_nan() == false
+                                && es.is_nan() == false
+                                && es != f32::INFINITY
+                                && auc != f32::INFINITY
+                                && auc.is_nan() == false
+                            {
+                                pathway_p_values.push(pathway_p_value {
+                                    pathway_name: gs.id,
+                                    p_value_original: p_value,
+                                    p_value_adjusted: None,
+                                    auc: auc,
+                                    es: es,
+                                    gene_set_hits: gene_set_hits,
+                                    gene_set_size: gene_set_size,
+                                })
+                            }
+                        }
+                    } else {
+                        // Multithreaded implementation
+                        let genesets = Arc::new(genesets);
+                        let genes_descending = Arc::new(genes_descending);
+                        let genes_ascending = Arc::new(genes_ascending);
+                        let pathway_p_values_temp =
+                            Arc::new(Mutex::new(Vec::<pathway_p_value>::with_capacity(genesets.len())));
+                        let mut handles = vec![]; // Vector to store handle which is used to prevent one thread going ahead of another
+                        for thread_num in 0..max_threads {
+                            let genesets = Arc::clone(&genesets);
+                            let genes_descending = Arc::clone(&genes_descending);
+                            let genes_ascending = Arc::clone(&genes_ascending);
+                            let pathway_p_values_temp = Arc::clone(&pathway_p_values_temp);
+                            let handle = thread::spawn(move || {
+                                let mut pathway_p_values_thread: Vec<pathway_p_value> = Vec::with_capacity(10000);
+                                for iter in 0..genesets.len() {
+                                    let remainder: usize = iter % max_threads;
+                                    if remainder == thread_num {
+                                        let names = genesets[iter].genes.clone();
+                                        let gene_set_size = names.len();
+                                        let (p_value, auc, es, matches, gene_set_hits, _cerno_output) =
+                                            stats_functions::cerno(&genes_descending, &genes_ascending, names);
+
+                                        if matches >= 1.0
+                                            && p_value.is_nan() == false
+                                            && es.is_nan() == false
+                                            && es != f32::INFINITY
+                                            && auc != f32::INFINITY
+                                            && auc.is_nan() == false
+                                        {
+                                            pathway_p_values_thread.push(pathway_p_value {
+                                                pathway_name: genesets[iter].id.clone(),
+                                                p_value_original: p_value,
+                                                p_value_adjusted: None,
+                                                auc: auc,
+                                                es: es,
+                                                gene_set_hits: gene_set_hits,
+                                                gene_set_size: gene_set_size,
+                                            })
                                         }
                                     }
-                                    let gene_set_size = names.len();
-                                    let (p_value, auc, es, matches, gene_set_hits, _cerno_output) =
-                                        stats_functions::cerno(&genes_descending, &genes_ascending, names);
-
-                                    if matches >= 1.0
-                                        && p_value.is_nan() == false
-                                        && es.is_nan() == false
-                                        && es != f32::INFINITY
-                                        && auc != f32::INFINITY
-                                        && auc.is_nan() == false
-                                    {
-                                        pathway_p_values.push(pathway_p_value {
-                                            pathway_name: gs,
-                                            p_value_original: p_value,
-                                            p_value_adjusted: None,
-                                            auc: auc,
-                                            es: es,
-                                            gene_set_hits: gene_set_hits,
-                                            gene_set_size: gene_set_size,
-                                        })
-                                    }
                                 }
-                            } else {
-                                // Multithreaded implementation
-                                let manager = SqliteConnectionManager::file(&msigdb); // This enables sqlite query from multiple threads simultaneously
-                                let pool = r2d2::Pool::new(manager).unwrap(); // This enables sqlite query from multiple threads simultaneously
-                                let genesets = Arc::new(genesets);
-                                let pool_arc = Arc::new(pool);
-                                let genes_descending = Arc::new(genes_descending);
-                                let genes_ascending = Arc::new(genes_ascending);
-                                let pathway_p_values_temp =
-                                    Arc::new(Mutex::new(Vec::<pathway_p_value>::with_capacity(genesets.len())));
-                                let mut handles = vec![]; // Vector to store handle which is used to prevent one thread going ahead of another
-                                for thread_num in 0..max_threads {
-                                    let genesets = Arc::clone(&genesets);
-                                    let pool_arc = Arc::clone(&pool_arc);
-                                    let genes_descending = Arc::clone(&genes_descending);
-                                    let genes_ascending = Arc::clone(&genes_ascending);
-                                    let pathway_p_values_temp = Arc::clone(&pathway_p_values_temp);
-                                    let handle = thread::spawn(move || {
-                                        let mut pathway_p_values_thread: Vec<pathway_p_value> =
-                                            Vec::with_capacity(10000);
-                                        for iter in 0..genesets.len() {
-                                            let remainder: usize = iter % max_threads;
-                                            if remainder == thread_num {
-                                                let sql_statement = "select genes from term2genes where id='"
-                                                    .to_owned()
-                                                    + &genesets[iter]
-                                                    + &"'";
-                                                //println!("sql_statement:{}", sql_statement);
-                                                let conn = pool_arc.get().unwrap();
-                                                let mut gene_stmt = conn.prepare(&sql_statement).unwrap();
-                                                //println!("gene_stmt:{:?}", gene_stmt);
-
-                                                let mut rows = gene_stmt.query([]).unwrap();
-                                                let mut names = HashSet::<String>::new();
-                                                while let Some(row) = rows.next().unwrap() {
-                                                    let a: String = row.get(0).unwrap();
-                                                    let input_gene_json = json::parse(&a);
-                                                    match input_gene_json {
-                                                        Ok(json_genes) => {
-                                                            for json_iter in 0..json_genes.len() {
-                                                                names.insert(
-                                                                    json_genes[json_iter]["symbol"].to_string(),
-                                                                );
-                                                            }
-                                                        }
-                                                        Err(_) => {
-                                                            panic!("Symbol, ensg, enstCanonical structure is missing!")
-                                                        }
-                                                    }
-                                                }
-                                                let gene_set_size = names.len();
-                                                let (p_value, auc, es, matches, gene_set_hits, _cerno_output) =
-                                                    stats_functions::cerno(&genes_descending, &genes_ascending, names);
-
-                                                if matches >= 1.0
-                                                    && p_value.is_nan() == false
-                                                    && es.is_nan() == false
-                                                    && es != f32::INFINITY
-                                                    && auc != f32::INFINITY
-                                                    && auc.is_nan() == false
-                                                {
-                                                    pathway_p_values_thread.push(pathway_p_value {
-                                                        pathway_name: genesets[iter].clone(),
-                                                        p_value_original: p_value,
-                                                        p_value_adjusted: None,
-                                                        auc: auc,
-                                                        es: es,
-                                                        gene_set_hits: gene_set_hits,
-                                                        gene_set_size: gene_set_size,
-                                                    })
-                                                }
-                                            }
-                                        }
-                                        pathway_p_values_temp
-                                            .lock()
-                                            .unwrap()
-                                            .append(&mut pathway_p_values_thread);
-                                        drop(pathway_p_values_temp);
-                                    });
-                                    handles.push(handle); // The handle (which contains the thread) is stored in the handles vector
-                                }
-                                for handle in handles {
-                                    // Wait for all threads to finish before proceeding further
-                                    handle.join().unwrap();
-                                }
-                                // Combining data from all different threads
-                                pathway_p_values.append(&mut *pathway_p_values_temp.lock().unwrap());
-                            }
+                                pathway_p_values_temp
+                                    .lock()
+                                    .unwrap()
+                                    .append(&mut pathway_p_values_thread);
+                                drop(pathway_p_values_temp);
+                            });
+                            handles.push(handle); // The handle (which contains the thread) is stored in the handles vector
                         }
-                        Err(_) => panic!("sqlite database file not found"),
+                        for handle in handles {
+                            // Wait for all threads to finish before proceeding further
+                            handle.join().unwrap();
+                        }
+                        // Combining data from all different threads
+                        pathway_p_values.append(&mut *pathway_p_values_temp.lock().unwrap());
+                    }
+                    let adjusted_p_values = adjust_p_values(pathway_p_values);
+                    if let (Some(redundancy), Some(genesets)) = (redundancy, redundancy_genesets) {
+                        let significant: Vec<&str> = adjusted_p_values
+                            .iter()
+                            .filter(|p| p.p_value_adjusted.unwrap() as f64 <= redundancy.fdr_cutoff)
+                            .map(|p| p.pathway_name.as_str())
+                            .collect();
+                        extra_fields.push(clusters_field(&significant, &genesets, &redundancy, num_ranked_genes));
                     }
-                    let output_string = adjust_p_values(pathway_p_values);
-                    println!("{}", output_string);
+                    let output_string = pathways_to_string(&adjusted_p_values);
+                    println!("{}", output_object(output_string, &extra_fields));
                 }
                 Err(error) => println!("Incorrect json:{}", error),
             }
@@ -335,7 +360,170 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn adjust_p_values(mut original_p_values: Vec<pathway_p_value>) -> String {
+fn run_gsea(
+    genes_descending: &[stats_functions::gene_order],
+    genesets: &[geneset_functions::GeneSet],
+    json_string: &JsonValue,
+    redundancy: Option<geneset_functions::RedundancyOptions>,
+    extra_fields: &mut Vec<String>,
+) -> String {
+    let num_permutations = json_string["num_permutations"].as_usize().unwrap_or(1000);
+    let seed = json_string["seed"].as_u64().unwrap_or(1);
+    let weight = json_string["gsea_weight"].as_f64().unwrap_or(1.0);
+    let ranked = gsea::RankedList::new(
+        genes_descending.iter().map(|g| g.gene_name.clone()).collect(),
+        &genes_descending
+            .iter()
+            .map(|g| g.fold_change as f64)
+            .collect::<Vec<f64>>(),
+        weight,
+    );
+    let gene_sets: Vec<&HashSet<String>> = genesets.iter().map(|gs| &gs.genes).collect();
+    let results = gsea::gsea(&ranked, &gene_sets, num_permutations, seed, max_threads);
+    let mut tested: Vec<(&String, gsea::GseaResult)> = genesets
+        .iter()
+        .zip(results)
+        .filter_map(|(gs, result)| result.map(|r| (&gs.id, r)))
+        .filter(|(_, r)| !r.nes.is_nan())
+        .collect();
+    tested.sort_by(|a, b| a.1.p_value.partial_cmp(&b.1.p_value).unwrap_or(Ordering::Equal));
+
+    // Benjamini-Hochberg adjustment, the results are sorted by increasing p-value
+    let mut fdr = vec![1.0; tested.len()];
+    let mut old_p_value: f64 = 1.0;
+    for i in (0..tested.len()).rev() {
+        let adjusted_p_val = (tested[i].1.p_value * tested.len() as f64 / (i + 1) as f64).min(old_p_value);
+        fdr[i] = adjusted_p_val;
+        old_p_value = adjusted_p_val;
+    }
+
+    if let Some(redundancy) = redundancy {
+        let significant: Vec<&str> = tested
+            .iter()
+            .zip(&fdr)
+            .filter(|(_, fdr)| **fdr <= redundancy.fdr_cutoff)
+            .map(|((name, _), _)| name.as_str())
+            .collect();
+        extra_fields.push(clusters_field(
+            &significant,
+            genesets,
+            &redundancy,
+            genes_descending.len(),
+        ));
+    }
+
+    let mut output_string = "{".to_string();
+    for (i, (name, result)) in tested.iter().enumerate() {
+        let item = gsea_output_struct {
+            pval: result.p_value,
+            fdr: fdr[i],
+            leading_edge: result.leading_edge.join(","),
+            es: result.es,
+            nes: result.nes,
+            geneset_size: result.gene_set_size,
+            running_sum: result.running_sum.clone(),
+        };
+        output_string += &format!(
+            "{}:{}",
+            serde_json::to_string(name).unwrap(),
+            serde_json::to_string(&item).unwrap()
+        );
+        if i < tested.len() - 1 {
+            output_string += ",";
+        }
+    }
+    output_string += "}";
+    output_string
+}
+
+/// "plot" output field: the enrichment curve of the gene set plot_geneset
+fn enrichment_plot_field(
+    plot_geneset: &str,
+    method: &str,
+    genes_descending: &[stats_functions::gene_order],
+    genesets: &[geneset_functions::GeneSet],
+    json_string: &JsonValue,
+) -> String {
+    let geneset = match genesets.iter().find(|gs| gs.id == plot_geneset) {
+        Some(geneset) => geneset,
+        None => panic!("Gene set {} to plot is not in the tested gene sets", plot_geneset),
+    };
+    let ranked = gsea::RankedList::new(
+        genes_descending.iter().map(|g| g.gene_name.clone()).collect(),
+        &genes_descending
+            .iter()
+            .map(|g| g.fold_change as f64)
+            .collect::<Vec<f64>>(),
+        json_string["gsea_weight"].as_f64().unwrap_or(1.0),
+    );
+    let hits = ranked.hit_positions(&geneset.genes);
+    if hits.is_empty() || hits.len() == ranked.len() {
+        panic!("Gene set {} cannot be plotted against the ranked genes", plot_geneset);
+    }
+    let (curve, y_label, reference) = if method == "gsea" {
+        (
+            ranked.running_sum(&geneset.genes),
+            "Running enrichment score",
+            enrichment_plots::Reference::Zero,
+        )
+    } else {
+        (
+            enrichment_plots::evidence_curve(&hits, ranked.len()),
+            "Fraction of gene set",
+            enrichment_plots::Reference::Diagonal,
+        )
+    };
+    let plot = enrichment_plots::EnrichmentCurve {
+        title: plot_geneset,
+        y_label,
+        curve: &curve,
+        hits: &hits,
+        num_genes: ranked.len(),
+        reference,
+    };
+    let options = enrichment_plots::PlotOptions::from_json(json_string, 500, 350);
+    match enrichment_plots::render_enrichment_curve(&plot, &options) {
+        Ok(plot) => "\"plot\":".to_string() + &plot.to_string(),
+        Err(error) => panic!("Enrichment plot rendering failed:{}", error),
+    }
+}
+
+/// "clusters" output field: the clusters of redundant significant terms (given by increasing p-value)
+fn clusters_field(
+    significant: &[&str],
+    genesets: &[geneset_functions::GeneSet],
+    redundancy: &geneset_functions::RedundancyOptions,
+    universe_size: usize,
+) -> String {
+    let genes_of: HashMap<&str, &HashSet<String>> = genesets.iter().map(|gs| (gs.id.as_str(), &gs.genes)).collect();
+    let terms: Vec<(&str, &HashSet<String>)> = significant.iter().map(|name| (*name, genes_of[name])).collect();
+    let clusters = geneset_functions::cluster_redundant_terms(&terms, redundancy, universe_size);
+    "\"clusters\":".to_string() + &serde_json::to_string(&clusters).unwrap()
+}
+
+/// Keeps the gene sets whose number of genes in the ranked list passes the size filter, as minGSSize/maxGSSize in
+/// clusterProfiler::GSEA. The genes outside the ranked list do not take part in the test.
+fn filter_gene_set_sizes(
+    genesets: &mut Vec<geneset_functions::GeneSet>,
+    genes_descending: &[stats_functions::gene_order],
+    size_filter: &geneset_functions::SizeFilter,
+) {
+    let ranked_genes: HashSet<&str> = genes_descending.iter().map(|g| g.gene_name.as_str()).collect();
+    genesets.retain(|gs| {
+        let size = gs.genes.iter().filter(|g| ranked_genes.contains(g.as_str())).count();
+        size_filter.accepts(size)
+    });
+}
+
+/// Output object with the gene set results under "pathways" and the "key":value `fields` next to them, so that the
+/// field names cannot collide with gene set names
+fn output_object(output_string: String, fields: &[String]) -> String {
+    let mut all_fields = vec![format!("\"pathways\":{}", output_string)];
+    all_fields.extend(fields.iter().cloned());
+    format!("{{{}}}", all_fields.join(","))
+}
+
+fn adjust_p_values(mut original_p_values: Vec<pathway_p_value>) -> Vec<pathway_p_value> {
     // Sorting p-values in ascending order
     original_p_values.as_mut_slice().sort_by(|a, b| {
         (a.p_value_original)
@@ -381,7 +569,10 @@ fn adjust_p_values(mut original_p_values: Vec<pathway_p_value>) -> String {
             .partial_cmp(&b.p_value_adjusted.unwrap())
             .unwrap_or(Ordering::Equal)
     });
+    adjusted_p_values
+}
 
+fn pathways_to_string(adjusted_p_values: &[pathway_p_value]) -> String {
     let mut output_string = "{".to_string();
     for i in 0..adjusted_p_values.len() {
         let item = output_struct {
@@ -393,8 +584,8 @@ fn adjust_p_values(mut original_p_values: Vec<pathway_p_value>) -> String {
             auc: adjusted_p_values[i].auc,
         };
         output_string += &format!(
-            "\"{}\":{}",
-            adjusted_p_values[i].pathway_name.clone(),
+            "{}:{}",
+            serde_json::to_string(&adjusted_p_values[i].pathway_name).unwrap(),
             serde_json::to_string(&item).unwrap()
         );
         if i < adjusted_p_values.len() - 1 {
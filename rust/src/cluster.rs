@@ -13,10 +13,33 @@ JSON input fields:
 
            is represented as [[3,4,5,20];[3,5,6,1];[3,5,6,10]]
 
-  plot_image (optional): Plot the 2D sorted matrix.
+  plot_image (optional): Render the clustered matrix as a heatmap PNG (hierarchical methods only, see heatmap_render.rs).
+  image_width (optional): Width of the heatmap body in CSS pixels (default 12 pixels per column, at most 1200).
+  image_height (optional): Height of the heatmap body in CSS pixels (default 12 pixels per row, at most 1200).
+  devicePixelRatio (optional): The image is rendered at devicePixelRatio times the CSS size (default 1).
+  dendrogram_size (optional): Height of the column dendrogram and width of the row dendrogram in CSS pixels (default 80).
+  annotation_size (optional): Thickness of each annotation color bar in CSS pixels (default 10).
+  color_scale (optional): Diverging color scale as {"colors":["blue","white","red"],"domain":[min,mid,max]}. The domain defaults to [-m,0,m] (m = maximum absolute value) when the matrix has both negative and positive values and to [min,(min+max)/2,max] otherwise.
+  row_annotations (optional): List of row annotations [{"name":"Pathway","values":[one value per row],"colors":{"value":"#hex"}}]. colors is optional.
+  col_annotations (optional): List of column annotations, same format as row_annotations with one value per column.
   row_names (optional): Optionally input the names of the rows. This is used in the plot (if chosen).
   col_names (optional): Optionally input the names of the cols. This is used in the plot (if chosen).
-  cluster_method (optional): Choose the clustering method. Options are Complete (default), Average, Weighted, Ward, Centroid and Median.
+  cluster_method (optional): Choose the clustering method. Hierarchical options are Complete (default), Single, Average, Weighted, Ward, Centroid and Median. Non-hierarchical options are KMeans, PAM and Consensus (see partition_clustering.rs). Biclustering options are SpectralCoclustering and ChengChurch (see biclustering.rs).
+  cluster_dimension (optional): For KMeans, PAM and Consensus, cluster the columns ("col", default) or the rows ("row") of the matrix. In low memory mode "both" (default), "col" or "row".
+  low_memory (optional): Cluster without building the dissimilarity matrix (see MEMORY USE below). Only Single, Ward, Centroid and Median linkage are supported. With plot_image, which is not available in this mode, only the line "heatmap:{"error":...}" is printed.
+  input_file (optional): Read the matrix from an HDF5 file in the DEanalysis.rs layout (item: row names, samples: column names, matrix: rows x columns) instead of the matrix field. Implies low_memory.
+  samples (optional): Comma separated list of the columns (samples) of input_file to cluster (default all).
+  k (optional): Number of clusters for KMeans and PAM, of co-clusters for SpectralCoclustering and maximum number of biclusters for ChengChurch (default 2).
+  seed (optional): Seed for the random number generator used by KMeans and Consensus (default 1).
+  n_start (optional): Number of k-means++ random starts for KMeans, the best one is kept (default 10).
+  max_iter (optional): Maximum number of iterations for KMeans and of SWAP steps for PAM (default 100).
+  max_k (optional): Consensus clustering is carried out for k = 2..max_k (default 6).
+  reps (optional): Number of resamplings for Consensus (default 100).
+  p_item (optional): Fraction of items sampled in each Consensus resampling (default 0.8).
+  p_feature (optional): Fraction of features sampled in each Consensus resampling (default 1.0).
+  inner_method (optional): Clustering method run on each Consensus resampling. Options are KMeans (default), PAM and Hierarchical (average linkage).
+  delta (optional): Maximum mean squared residue of a ChengChurch bicluster (default 10% of the mean squared residue of the whole matrix).
+  alpha (optional): Threshold of the ChengChurch multiple node deletion step (default 1.2).
 
 OUTPUT PARAMETERS
 
@@ -35,15 +58,45 @@ OUTPUT PARAMETERS
        child_node_coordinates: (X,Y) coordinates of each of the two child nodes (if they exist).
  6) all_original_nodes: This contains list of all descendent original nodes under the current node. This will be empty for the original nodes but will be populated with original node ID in derived nodes. This list will be shown in the UI on clicking a derived node.
 
+ With input_file, the row and column names are printed first in lines prefixed by "rowNames:" and "colNames:" (JSON arrays) since the
+ indexes in colSteps/rowSteps refer to them.
+
+ When plot_image is true, a line prefixed by "heatmap:" contains a JSON string with png (base64 data URL), width, height, devicePixelRatio,
+ heatmap (x, y, width and height of the heatmap body), row_order and col_order (input indexes in display order), row_annotations and
+ col_annotations (position and value colors of each bar) and legend (position, colors and domain). All positions are in CSS pixels so the client can draw the labels.
+ When the heatmap cannot be rendered (e.g. invalid color_scale or annotations of the wrong length) the line is "heatmap:{"error":"..."}" instead.
+
+ For KMeans, PAM and Consensus only a single line is printed, prefixed by "colClusters:" or "rowClusters:" depending upon cluster_dimension, containing a JSON string:
+   KMeans: assignments (0-based cluster index of each item), centroids, cluster_sizes, within_ss, total_within_ss, iterations.
+   PAM: assignments, medoids (item index of each medoid), medoid_coordinates, cluster_sizes, total_cost.
+   Consensus: inner_method, reps, p_item, p_feature and results, a list with one entry per k containing assignments, consensus_matrix, cdf_x, cdf_y, area (area under the consensus CDF) and delta_area.
+
+ For SpectralCoclustering and ChengChurch a single line prefixed by "biclusters:" is printed, containing a JSON string with method,
+ biclusters (rows, cols and msr, the mean squared residue, of each bicluster), sorted_row_elements and sorted_col_elements (rows/columns
+ grouped by bicluster, with the rows/columns outside all biclusters last) and sorted_matrix (the input matrix in that order).
+
 EXAMPLES
  1) Syntax: cd .. && cargo build --release && json='{"matrix":[[9.5032,12.2685,8.2919,2.9634,9.2435],[10.5632,9.1719,22.7488,10.2698,31.7872],[0.1035,0.0525,0.0378,0.573,2.0522]],"row_names":["GeneA","GeneB","GeneC"],"col_names":["SampleA","SampleB","SampleC","SampleD","SampleE"],"plot_image":true,"cluster_method":"Average"}' && time echo "$json" | target/release/cluster
 
-    Takes 2D matrix, row names and col names using cluster method "Average". In addition to stdout also plots the sorted 2D matrix to 1.png file
+    Takes 2D matrix, row names and col names using cluster method "Average". In addition to the dendrograms also prints the heatmap (base64 PNG and layout) in a line prefixed by "heatmap:"
 
  2) Syntax: cd .. && cargo build --release && json='{"matrix":[[9.5032,12.2685,8.2919,2.9634,9.2435],[10.5632,9.1719,22.7488,10.2698,31.7872],[0.1035,0.0525,0.0378,0.573,2.0522]],"row_names":["GeneA","GeneB","GeneC"],"col_names":["SampleA","SampleB","SampleC","SampleD","SampleE"]}' && time echo "$json" | target/release/cluster
 
     Only prints sorted 2D matrix to stdout.
 
+ 3) Syntax: cd .. && cargo build --release && json='{"matrix":[[9.5032,12.2685,8.2919,2.9634,9.2435],[10.5632,9.1719,22.7488,10.2698,31.7872],[0.1035,0.0525,0.0378,0.573,2.0522]],"cluster_method":"Consensus","max_k":3,"reps":50,"seed":7}' && time echo "$json" | target/release/cluster
+
+    Consensus clustering of the columns (samples) for k = 2 and 3.
+
+MEMORY USE
+
+ The default mode stores the n(n-1)/2 dissimilarities of each dimension as f64, i.e. 1.6 GB for the rows of a 20000 genes x 2000 samples
+ matrix, in addition to the JSON input. In low memory mode (see low_memory_linkage.rs) distances are computed on the fly and cluster
+ centroids overwrite the items they replace, so for a matrix of r rows and c columns read from input_file the peak memory use is at most
+   4*r*c bytes (single precision matrix) + 4*r*c bytes (transposed copy, only while the columns are clustered) + about 100*(r+c) bytes
+ plus the HDF5 read buffer of 1000 rows. For 20000 x 2000 this is about 330 MB. Single linkage needs O((r^2)*c) time, Ward O((r^2)*c)
+ in practice and Centroid/Median up to O((r^3)*c) in the worst case.
+
 TO DO:
 
 Implement parallelization of calculation of dissimilarity matrix using eculidean distance by using in-built parallelization in nalgebra crate using rayon. See this link below:
@@ -51,7 +104,6 @@ Implement parallelization of calculation of dissimilarity matrix using eculidean
 https://docs.rs/nalgebra/latest/nalgebra/base/par_iter/struct.ParColumnIterMut.html
 
 */
-use colorgrad;
 use json;
 use json::JsonValue;
 use kodama::{linkage, Method};
@@ -65,11 +117,24 @@ use std::env;
 //use ndarray::Array1;
 use ndarray::ArrayBase;
 use ndarray::OwnedRepr;
-use plotters::prelude::*;
 use rayon::prelude::*;
 use std::any::type_name;
 use std::io;
 use std::time::Instant;
+mod biclustering; // Spectral co-clustering and Cheng-Church biclustering
+mod hdf5_expression; // Reading the matrix from an HDF5 file in low memory mode
+mod heatmap_render; // Heatmap PNG with dendrograms, annotations and legend
+mod low_memory_linkage; // Hierarchical clustering with on-the-fly distances
+mod partition_clustering; // KMeans, PAM and consensus clustering
+mod stats_functions; // Seeded random number generator
+#[cfg(test)]
+mod test_cluster; // Tests of the partition, low memory and biclustering methods and of the heatmap
+
+const HIERARCHICAL_METHODS: [&str; 7] = [
+    "Single", "Complete", "Average", "Weighted", "Ward", "Centroid", "Median",
+];
+const PARTITION_METHODS: [&str; 3] = ["KMeans", "PAM", "Consensus"];
+const BICLUSTER_METHODS: [&str; 2] = ["SpectralCoclustering", "ChengChurch"];
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 struct Steps {
@@ -336,13 +401,9 @@ fn main() {
                     let cluster_method_search: &JsonValue = &json_string["cluster_method"];
                     match cluster_method_search.as_str() {
                         Some(cluster_method_se) => {
-                            if cluster_method_se != "Single".to_string()
-                                && cluster_method_se != "Complete".to_string()
-                                && cluster_method_se != "Average".to_string()
-                                && cluster_method_se != "Weighted".to_string()
-                                && cluster_method_se != "Ward".to_string()
-                                && cluster_method_se != "Centroid".to_string()
-                                && cluster_method_se != "Median".to_string()
+                            if !HIERARCHICAL_METHODS.contains(&cluster_method_se)
+                                && !PARTITION_METHODS.contains(&cluster_method_se)
+                                && !BICLUSTER_METHODS.contains(&cluster_method_se)
                             {
                                 panic!("Unknown clustering method:{}", cluster_method_se);
                             } else {
@@ -353,14 +414,14 @@ fn main() {
                     }
                     //println!("cluster_method:{}", cluster_method);
 
-                    let _plot_image;
-                    let plot_image_option = &json_string["plot_image"].as_bool();
-                    match plot_image_option {
-                        Some(plot_image_op) => _plot_image = plot_image_op.to_owned(),
-                        None => _plot_image = false,
-                    }
+                    let plot_image = json_string["plot_image"].as_bool().unwrap_or(false);
                     //println!("plot_image:{}", plot_image);
 
+                    if json_string["input_file"].is_string() || json_string["low_memory"].as_bool() == Some(true) {
+                        run_low_memory_clustering(&json_string, &cluster_method);
+                        return;
+                    }
+
                     let now = Instant::now();
                     // Generating dissimilarity matrix
                     let mut coordinates: Vec<Vec<f64>> = vec![];
@@ -404,6 +465,14 @@ fn main() {
                     );
                     //println!("coordinates:{:?}", coordinates);
 
+                    if PARTITION_METHODS.contains(&cluster_method.as_str()) {
+                        run_partition_clustering(&json_string, &input_matrix, &cluster_method);
+                        return;
+                    } else if BICLUSTER_METHODS.contains(&cluster_method.as_str()) {
+                        run_biclustering(&json_string, &input_matrix.transpose(), &cluster_method);
+                        return;
+                    }
+
                     // Build our condensed matrix by computinghe dissimilarity between all
                     // possible coordinate pairs.
                     let col_steps = sort_elements(&input_matrix, &cluster_method);
@@ -426,68 +495,9 @@ fn main() {
                     }
                     row_output_string += &"]".to_string();
                     println!("rowSteps:{:?}", row_output_string);
-                    //let mut sorted_row_names = Vec::<String>::new();
-                    //let mut sorted_col_names = Vec::<String>::new();
-                    //
-                    //match row_string_search.as_str() {
-                    //    Some(_row_string_se) => {
-                    //        sorted_row_names = sorted_row_elements
-                    //            .clone()
-                    //            .into_iter()
-                    //            .map(|x| row_names[x].clone())
-                    //            .collect::<Vec<String>>();
-                    //    }
-                    //    None => {}
-                    //}
-                    //
-                    //match col_string_search.as_str() {
-                    //    Some(_col_string_se) => {
-                    //        sorted_col_names = sorted_col_elements
-                    //            .clone()
-                    //            .into_iter()
-                    //            .map(|x| col_names[x].clone())
-                    //            .collect::<Vec<String>>();
-                    //    }
-                    //    None => {}
-                    //}
-                    ////println!("sorted_row_names:{:?}", sorted_row_names);
-                    ////println!("sorted_col_names:{:?}", sorted_col_names);
-                    //
-                    //let mut sorted_col_coordinates_string = "[".to_string();
-                    //for i in 0..sorted_col_coordinates.len() {
-                    //    sorted_col_coordinates_string +=
-                    //        &serde_json::to_string(&sorted_col_coordinates[i]).unwrap();
-                    //    if i != sorted_col_coordinates.len() - 1 {
-                    //        sorted_col_coordinates_string += &",".to_string();
-                    //    }
-                    //}
-                    //sorted_col_coordinates_string += &"]".to_string();
-                    ////println!("sorted_col_coordinates:{:?}", sorted_col_coordinates_string);
-                    //
-                    //let mut sorted_row_coordinates_string = "[".to_string();
-                    //for i in 0..sorted_row_coordinates.len() {
-                    //    sorted_row_coordinates_string +=
-                    //        &serde_json::to_string(&sorted_row_coordinates[i]).unwrap();
-                    //    if i != sorted_row_coordinates.len() - 1 {
-                    //        sorted_row_coordinates_string += &",".to_string();
-                    //    }
-                    //}
-                    //sorted_row_coordinates_string += &"]".to_string();
-                    ////println!("sorted_row_coordinates:{:?}", sorted_row_coordinates_string);
-                    //if plot_image == true {
-                    //    let sorted_matrix =
-                    //        sort_matrix(sorted_col_elements, sorted_row_elements, &input_matrix);
-                    //    let sorted_matrix_transpose = sorted_matrix.transpose();
-                    //    //println!(
-                    //    //    "sorted_matrix:{:?}",
-                    //    //    &serde_json::to_string(&sorted_matrix_transpose).unwrap()
-                    //    //);
-                    //    let _plot_result = plot_matrix(
-                    //        &sorted_matrix_transpose,
-                    //        sorted_row_names,
-                    //        sorted_col_names,
-                    //    );
-                    //}
+                    if plot_image {
+                        plot_heatmap(&json_string, &input_matrix.transpose(), &row_steps, &col_steps);
+                    }
                 }
                 Err(error) => println!("Incorrect json: {}", error),
             }
@@ -497,179 +507,275 @@ fn main() {
     }
 }
 
-#[allow(dead_code)]
-fn sort_matrix(
-    // Rearranging matrix so as to plot it
-    sorted_elements: Vec<usize>,
-    sorted_elements2: Vec<usize>,
-    original_array: &Matrix<f64, Dyn, Dyn, VecStorage<f64, Dyn, Dyn>>,
-) -> Matrix<f64, Dyn, Dyn, VecStorage<f64, Dyn, Dyn>> {
+fn run_partition_clustering(
+    json_string: &JsonValue,
+    input_matrix: &Matrix<f64, Dyn, Dyn, VecStorage<f64, Dyn, Dyn>>,
+    cluster_method: &String,
+) {
+    let cluster_dimension = json_string["cluster_dimension"].as_str().unwrap_or("col");
+    // Rows of input_matrix correspond to the columns of the input JSON matrix
+    let items = match cluster_dimension {
+        "col" => input_matrix.clone(),
+        "row" => input_matrix.transpose(),
+        _ => panic!("Unknown cluster_dimension:{}", cluster_dimension),
+    };
+    let k = json_string["k"].as_usize().unwrap_or(2);
+    let seed = json_string["seed"].as_u64().unwrap_or(1);
+    let max_iter = json_string["max_iter"].as_usize().unwrap_or(100);
+    let mut rng = stats_functions::SeededRng::new(seed);
+
+    let now = Instant::now();
+    let output_string = if cluster_method == "KMeans" {
+        let n_start = json_string["n_start"].as_usize().unwrap_or(10);
+        let result = partition_clustering::kmeans(&items, k, n_start, max_iter, &mut rng);
+        serde_json::to_string(&result).unwrap()
+    } else if cluster_method == "PAM" {
+        let result = partition_clustering::pam(&items, k, max_iter);
+        serde_json::to_string(&result).unwrap()
+    } else if cluster_method == "Consensus" {
+        let max_k = json_string["max_k"].as_usize().unwrap_or(6).min(items.nrows());
+        let reps = json_string["reps"].as_usize().unwrap_or(100);
+        let p_item = json_string["p_item"].as_f64().unwrap_or(0.8);
+        let p_feature = json_string["p_feature"].as_f64().unwrap_or(1.0);
+        let inner_method = json_string["inner_method"].as_str().unwrap_or("KMeans");
+        let result =
+            partition_clustering::consensus_clustering(&items, max_k, reps, p_item, p_feature, inner_method, &mut rng);
+        serde_json::to_string(&result).unwrap()
+    } else {
+        panic!("Unknown clustering method:{}", cluster_method);
+    };
     let new_now = Instant::now();
-    let mut sorted_array = DMatrix::<f64>::zeros(original_array.nrows(), original_array.ncols());
-    for col in 0..sorted_elements2.len() {
-        let col_iter = sorted_elements2[col];
-        //let mut row_iter;
-        for row in 0..sorted_elements.len() {
-            let row_iter = sorted_elements[row];
-            //println!("row:{},col:{}", row_iter, col_iter);
-            sorted_array[(row, col)] = original_array[(row_iter, col_iter)];
-        }
-    }
-    let new_now2 = Instant::now();
     println!(
-        "Time taken to sort matrix:{:?}",
-        new_now2.duration_since(new_now)
+        "Time taken to cluster {}s:{:?}",
+        cluster_dimension,
+        new_now.duration_since(now)
     );
-    //println!("sorted_array:{:?}", sorted_array);
-    sorted_array
+    println!("{}Clusters:{}", cluster_dimension, output_string);
 }
 
-#[allow(dead_code)]
-fn plot_matrix(
-    original_array: &Matrix<f64, Dyn, Dyn, VecStorage<f64, Dyn, Dyn>>,
-    sorted_row_names: Vec<String>,
-    sorted_col_names: Vec<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let new_now = Instant::now();
-    let cell_width: u32 = 200;
-    let cell_height: u32 = 200;
-    let row_text_font_size = 15; // Font size of row legend text
-    let col_text_font_size = 15; // Font size of column legend text
-    let multiplication_factor = 6; // This factor needs to be increased as row/column font size is increased.
-    let offset_size = 2;
-
-    let mut max_col_length = 0;
-    let mut max_col_string: String = "".to_string();
-    for i in 0..sorted_col_names.len() {
-        if max_col_length < sorted_col_names[i].len() {
-            max_col_length = sorted_col_names[i].len();
-            max_col_string = sorted_col_names[i].clone();
+fn run_biclustering(
+    json_string: &JsonValue,
+    matrix: &Matrix<f64, Dyn, Dyn, VecStorage<f64, Dyn, Dyn>>, // In the input orientation (rows x cols)
+    cluster_method: &String,
+) {
+    let k = json_string["k"].as_usize().unwrap_or(2);
+    let seed = json_string["seed"].as_u64().unwrap_or(1);
+    let mut rng = stats_functions::SeededRng::new(seed);
+    let now = Instant::now();
+    let biclusters = if cluster_method == "SpectralCoclustering" {
+        let n_start = json_string["n_start"].as_usize().unwrap_or(10);
+        let max_iter = json_string["max_iter"].as_usize().unwrap_or(100);
+        match biclustering::spectral_coclustering(matrix, k, n_start, max_iter, &mut rng) {
+            Ok(biclusters) => biclusters,
+            Err(error) => panic!("{}", error),
         }
-    }
+    } else {
+        let delta = json_string["delta"].as_f64();
+        let alpha = json_string["alpha"].as_f64().unwrap_or(1.2);
+        biclustering::cheng_church(matrix, k, delta, alpha, &mut rng)
+    };
+    let new_now = Instant::now();
+    println!(
+        "Time taken to bicluster:{:?}",
+        new_now.duration_since(now)
+    );
+    let result = biclustering::bicluster_result(cluster_method, matrix, biclusters);
+    println!("biclusters:{}", serde_json::to_string(&result).unwrap());
+}
 
-    let mut max_row_length = 0;
-    let mut max_row_string: String = "".to_string();
-    for i in 0..sorted_row_names.len() {
-        if max_row_length < sorted_row_names[i].len() {
-            max_row_length = sorted_row_names[i].len();
-            max_row_string = sorted_row_names[i].clone();
-        }
+fn run_low_memory_clustering(json_string: &JsonValue, cluster_method: &String) {
+    if json_string["plot_image"].as_bool() == Some(true) {
+        // The heatmap needs the whole matrix in double precision, which this mode avoids
+        println!(
+            "heatmap:{}",
+            serde_json::json!({ "error": "plot_image is not available in low memory mode" })
+        );
+        return;
     }
-
-    let image_width = cell_width * (original_array.ncols() as u32)
-        + ((max_row_string.len() * multiplication_factor + offset_size) as u32);
-    let image_height = cell_height * (original_array.nrows() as u32)
-        + ((max_col_string.len() * multiplication_factor + offset_size) as u32);
-    let backend = BitMapBackend::new("1.png", (image_width, image_height)).into_drawing_area(); // Replace BitMapBackend with CanvasBackend in wasm
-    println!("image_width:{},image_height:{}", image_width, image_height);
-
-    let row_style =
-        TextStyle::from(("arial", row_text_font_size as u32, "Normal").into_font()).color(&BLACK);
-    let col_style = TextStyle::from(("arial", col_text_font_size as u32, "Normal").into_font())
-        .color(&BLACK)
-        .transform(FontTransform::Rotate270);
-    let row_text_dim =
-        DrawingArea::estimate_text_size(&backend, &max_row_string, &row_style).unwrap();
-    let col_text_dim =
-        DrawingArea::estimate_text_size(&backend, &max_col_string, &col_style).unwrap();
-    println!("row text dimensions:{:?}", row_text_dim);
-    println!("col text dimensions:{:?}", col_text_dim);
-
-    backend.fill(&RGBColor(255, 255, 255))?;
-
-    let max_value = original_array.max();
-    let min_value = original_array.min();
-
-    // Rendering row legends
-    if sorted_row_names.len() > 0 {
-        for row in 0..original_array.nrows() {
-            backend.draw_text(
-                &sorted_row_names[row].to_string(),
-                &row_style,
-                (
-                    0,
-                    (row as i32) * (cell_width as i32)
-                        + ((col_text_dim.0 + offset_size as u32) as i32)
-                        + (cell_height as i32) / 2,
-                ),
-            )?;
+    if !low_memory_linkage::SUPPORTED_METHODS.contains(&cluster_method.as_str()) {
+        panic!(
+            "Low memory clustering supports only {} linkage, not {}",
+            low_memory_linkage::SUPPORTED_METHODS.join(", "),
+            cluster_method
+        );
+    }
+    let now = Instant::now();
+    // Row major (rows x cols) single precision matrix
+    let values: Vec<f32>;
+    let num_cols: usize;
+    match json_string["input_file"].as_str() {
+        Some(input_file) => {
+            let samples: Option<Vec<&str>> = json_string["samples"]
+                .as_str()
+                .map(|x| x.split(",").filter(|s| !s.is_empty()).collect());
+            let expression = match hdf5_expression::read_expression_values(input_file, samples.as_deref()) {
+                Ok(expression) => expression,
+                Err(error) => panic!("{}", error),
+            };
+            println!("rowNames:{}", serde_json::to_string(&expression.gene_names).unwrap());
+            println!("colNames:{}", serde_json::to_string(&expression.sample_names).unwrap());
+            num_cols = expression.sample_names.len();
+            values = expression.values;
         }
+        None => {
+            let matrix = &json_string["matrix"];
+            num_cols = matrix[0].len();
+            let mut matrix_values = Vec::<f32>::with_capacity(matrix.len() * num_cols);
+            for row in matrix.members() {
+                if row.len() != num_cols {
+                    panic!("All rows of the matrix must have the same length");
+                }
+                for value in row.members() {
+                    matrix_values.push(value.as_f32().unwrap());
+                }
+            }
+            values = matrix_values;
+        }
+    }
+    let num_rows = values.len().checked_div(num_cols).unwrap_or(0);
+    if num_rows <= 1 || num_cols <= 1 {
+        panic!("Please input a 2D matrix. The number of rows or cols <= 1");
     }
+    let new_now = Instant::now();
+    println!("Time taken to read matrix:{:?}", new_now.duration_since(now));
 
-    // Rendering col legends
-    if sorted_col_names.len() > 0 {
-        for col in 0..original_array.ncols() {
-            backend.draw_text(
-                &sorted_col_names[col].to_string(),
-                &col_style,
-                (
-                    (col as i32) * (cell_width as i32)
-                        + ((col_text_dim.0 + offset_size as u32) as i32)
-                        + (cell_width as i32) / 2,
-                    (col_text_dim.0 as i32),
-                ),
-            )?;
-        }
+    let cluster_dimension = json_string["cluster_dimension"].as_str().unwrap_or("both");
+    if !["both", "col", "row"].contains(&cluster_dimension) {
+        panic!("Unknown cluster_dimension:{}", cluster_dimension);
+    }
+    let to_output_string = |steps: Vec<kodama::Step<f64>>| -> String {
+        let steps_vec: Vec<Steps> = steps
+            .into_iter()
+            .map(|step| Steps {
+                cluster1: step.cluster1,
+                cluster2: step.cluster2,
+                dissimilarity: step.dissimilarity,
+                size: step.size,
+            })
+            .collect();
+        serde_json::to_string(&steps_vec).unwrap()
+    };
+    if cluster_dimension != "row" {
+        // The transposed copy is released before the rows are clustered
+        let mut items = low_memory_linkage::ItemMatrix::from_columns(&values, num_cols);
+        let col_steps = low_memory_linkage::linkage(&mut items, cluster_method).unwrap();
+        drop(items);
+        let new_now2 = Instant::now();
+        println!(
+            "Time taken to cluster columns:{:?}",
+            new_now2.duration_since(new_now)
+        );
+        println!("Number of nodes:{}", num_cols);
+        println!("colSteps:{:?}", to_output_string(col_steps));
     }
+    if cluster_dimension != "col" {
+        let new_now2 = Instant::now();
+        let mut items = low_memory_linkage::ItemMatrix::from_rows(values, num_cols);
+        let row_steps = low_memory_linkage::linkage(&mut items, cluster_method).unwrap();
+        let new_now3 = Instant::now();
+        println!(
+            "Time taken to cluster rows:{:?}",
+            new_now3.duration_since(new_now2)
+        );
+        println!("Number of nodes:{}", num_rows);
+        println!("rowSteps:{:?}", to_output_string(row_steps));
+    }
+}
 
-    //println!("nrows:{}", original_array.nrows());
-    //println!("ncols:{}", original_array.ncols());
-    for col in 0..original_array.ncols() {
-        for row in 0..original_array.nrows() {
-            //println!("cell_value:{}", original_array[(row, col)]);
-            let grad =
-                create_color_gradient(original_array[(row, col)] as f64, max_value, min_value);
-            //println!("grad:{:?}", grad);
-            //println!(
-            //    "total_cell_width:{}",
-            //    cell_width * (original_array.nrows() as u32)
-            //);
-            //println!(
-            //    "total_cell_height:{}",
-            //    cell_height * (original_array.ncols() as u32)
-            //);
-            //println!(
-            //    "top-left x,y:{},{}",
-            //    (row as i32) * (cell_width as i32),
-            //    (cell_height as i32) * (col as i32)
-            //);
-            //println!(
-            //    "bottom-right x,y:{},{}",
-            //    (row as i32 + 1) * (cell_width as i32),
-            //    (cell_height as i32) * (col as i32 + 1)
-            //);
-
-            let start = (
-                (cell_height as i32) * (col as i32) + (col_text_dim.0 as i32 + offset_size as i32),
-                (row as i32) * (cell_width as i32) + (row_text_dim.0 as i32 + offset_size as i32),
-            );
-            let stop = (
-                (cell_height as i32) * (col as i32 + 1)
-                    + (col_text_dim.0 as i32 + offset_size as i32),
-                (row as i32 + 1) * (cell_width as i32)
-                    + (row_text_dim.0 as i32 + offset_size as i32),
-            );
-            backend.draw(&Rectangle::new(
-                [start, stop],
-                Into::<ShapeStyle>::into(&RGBColor(grad[0], grad[1], grad[2])).filled(),
-            ))?;
-            //println!("row:{},col:{}", row, col);
-            //println!("start:{:?},stop:{:?}", start, stop);
+fn parse_annotations(annotations_json: &JsonValue) -> Vec<heatmap_render::Annotation> {
+    let mut annotations = Vec::<heatmap_render::Annotation>::new();
+    for annotation in annotations_json.members() {
+        let mut colors = std::collections::HashMap::<String, String>::new();
+        for (value, color) in annotation["colors"].entries() {
+            colors.insert(value.to_string(), color.to_string());
         }
+        annotations.push(heatmap_render::Annotation {
+            name: annotation["name"].to_string(),
+            values: annotation["values"].members().map(|x| x.to_string()).collect(),
+            colors,
+        });
     }
-    let new_now2 = Instant::now();
+    annotations
+}
+
+fn plot_heatmap(
+    json_string: &JsonValue,
+    matrix: &Matrix<f64, Dyn, Dyn, VecStorage<f64, Dyn, Dyn>>, // In the input orientation (rows x cols)
+    row_steps: &Vec<Steps>,
+    col_steps: &Vec<Steps>,
+) {
+    let now = Instant::now();
+    let heatmap = heatmap_output(json_string, matrix, row_steps, col_steps);
+    let new_now = Instant::now();
     println!(
-        "Time taken to plot matrix:{:?}",
-        new_now2.duration_since(new_now)
+        "Time taken to render heatmap:{:?}",
+        new_now.duration_since(now)
     );
-    Ok(())
+    println!("heatmap:{}", heatmap);
 }
 
-fn create_color_gradient(cell_mag: f64, max_value: f64, min_value: f64) -> [u8; 4] {
-    let grad = colorgrad::CustomGradient::new()
-        .html_colors(&["red", "blue"])
-        .domain(&[min_value, max_value])
-        .mode(colorgrad::BlendMode::Rgb)
-        .build();
-    grad.unwrap().at(cell_mag).to_rgba8()
+/// Contents of the "heatmap:" line: the rendered heatmap and its layout, or {"error": message} when it cannot be rendered
+/// (e.g. an invalid color scale or annotations of the wrong length)
+fn heatmap_output(
+    json_string: &JsonValue,
+    matrix: &Matrix<f64, Dyn, Dyn, VecStorage<f64, Dyn, Dyn>>,
+    row_steps: &Vec<Steps>,
+    col_steps: &Vec<Steps>,
+) -> serde_json::Value {
+    let mut colors: Vec<String> = json_string["color_scale"]["colors"]
+        .members()
+        .map(|x| x.to_string())
+        .collect();
+    if colors.is_empty() {
+        colors = vec!["blue".to_string(), "white".to_string(), "red".to_string()];
+    }
+    let mut domain: Vec<f64> = json_string["color_scale"]["domain"]
+        .members()
+        .filter_map(|x| x.as_f64())
+        .collect();
+    if domain.is_empty() {
+        let default_domain = heatmap_render::default_domain(matrix);
+        // Spread the default [min, mid, max] domain over the requested colors
+        domain = (0..colors.len())
+            .map(|i| {
+                let t = i as f64 / (colors.len() - 1).max(1) as f64 * 2.0;
+                if t <= 1.0 {
+                    default_domain[0] + (default_domain[1] - default_domain[0]) * t
+                } else {
+                    default_domain[1] + (default_domain[2] - default_domain[1]) * (t - 1.0)
+                }
+            })
+            .collect();
+    }
+    let options = heatmap_render::HeatmapOptions {
+        width: json_string["image_width"]
+            .as_u32()
+            .unwrap_or((matrix.ncols() as u32 * 12).min(1200)),
+        height: json_string["image_height"]
+            .as_u32()
+            .unwrap_or((matrix.nrows() as u32 * 12).min(1200)),
+        dendrogram_size: json_string["dendrogram_size"].as_u32().unwrap_or(80),
+        annotation_size: json_string["annotation_size"].as_u32().unwrap_or(10),
+        device_pixel_ratio: json_string["devicePixelRatio"].as_f32().unwrap_or(1.0),
+        colors,
+        domain,
+    };
+    let to_merges = |steps: &Vec<Steps>| -> Vec<(usize, usize, f64)> {
+        steps
+            .iter()
+            .map(|step| (step.cluster1, step.cluster2, step.dissimilarity))
+            .collect()
+    };
+    let result = heatmap_render::render_heatmap(
+        matrix,
+        &to_merges(row_steps),
+        &to_merges(col_steps),
+        &parse_annotations(&json_string["row_annotations"]),
+        &parse_annotations(&json_string["col_annotations"]),
+        &options,
+    );
+    match result {
+        Ok(heatmap) => heatmap,
+        Err(error) => serde_json::json!({ "error": format!("Heatmap rendering failed: {}", error) }),
+    }
 }
@@ -0,0 +1,983 @@
+// dimensionReduction.rs — Low dimensional sample embeddings (PCA, t-SNE, UMAP) for scatter plots
+// PCA: thin SVD of the centered (samples x features) matrix using nalgebra
+// t-SNE: van der Maaten & Hinton 2008 (exact gradient), van der Maaten 2014 (Barnes-Hut gradient)
+// Barnes-Hut t-SNE and UMAP only need the nearest neighbors of every sample, which are found with a vantage-point tree
+// (as in bhtsne), so their memory use is linear in the number of samples. Exact t-SNE (theta = 0) is O(n^2).
+// UMAP: McInnes, Healy & Melville 2018, following the optimization in the python umap-learn package
+//
+// INPUT (JSON string on stdin), either
+//   input_file: HDF5 expression file in the DEanalysis.rs layout (item, samples, matrix), with
+//   samples (optional): Comma separated list of samples to embed (default all samples in the file)
+// or
+//   matrix: 2D matrix in the cluster.rs layout, rows are features (e.g. genes) and columns are samples
+//   sample_names (optional): Array of sample (column) names
+//   feature_names (optional): Array of feature (row) names
+//
+// Other fields (all optional):
+//   method: pca (default), tsne or umap
+//   n_components: Number of output dimensions, 2 (default) or 3. PCA accepts any number up to min(samples, features)
+//   log_transform: Apply log2(x + 1) before anything else (default false)
+//   top_variable_features: Only use the N features with the largest variance
+//   scale: Scale every feature to unit variance after centering (default false)
+//   seed: Seed for the random number generator used by t-SNE and UMAP (default 1)
+//   t-SNE: perplexity (30), theta (0.5, use 0 for the exact O(n^2) gradient), max_iter (1000), learning_rate (200), initial_dims (50, number of principal components used as t-SNE input, 0 to use all features)
+//   UMAP: n_neighbors (15), min_dist (0.1), spread (1.0), n_epochs (500 for <= 10000 samples, else 200), negative_sample_rate (5)
+//
+// OUTPUT
+//   {"method":..,"samples":[..],"coordinates":[[x,y(,z)],..]} with one coordinate per sample.
+//   PCA additionally returns sdev, variance_explained (fraction of total variance of each component), features and loadings (features x components).
+//   On error, {"error": message} is printed.
+//
+// Usage: echo '{"matrix":[[1,2,3,4],[4,5,6,9],[7,8,10,1]],"method":"pca"}' | target/release/dimensionReduction
+//        echo '{"input_file":"counts.h5","samples":"s1,s2,s3","log_transform":true,"top_variable_features":2000,"method":"umap","n_neighbors":10}' | target/release/dimensionReduction
+
+use nalgebra::DMatrix;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Instant;
+
+mod hdf5_expression; // Reading expression matrices in the DEanalysis HDF5 layout
+mod stats_functions; // Seeded random number generator
+use stats_functions::SeededRng;
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test_dimensionReduction; // Tests of PCA, the nearest neighbor search and the seeded t-SNE and UMAP embeddings
+
+const TSNE_MOMENTUM_SWITCH_ITER: usize = 250; // Iteration at which momentum switches from 0.5 to 0.8
+const TSNE_STOP_LYING_ITER: usize = 250; // Iteration at which early exaggeration stops
+const TSNE_EARLY_EXAGGERATION: f64 = 12.0;
+const SPTREE_MAX_DEPTH: usize = 50; // Beyond this depth points are kept together in a leaf (near-duplicate points)
+
+/// Log transformation, removal of constant/non-finite features and selection of the most variable features.
+/// Takes a (features x samples) matrix and returns the centered (samples x features) matrix with the kept feature names.
+fn preprocess(
+    matrix: &DMatrix<f64>,
+    feature_names: &[String],
+    log_transform: bool,
+    top_variable_features: Option<usize>,
+    scale: bool,
+) -> (DMatrix<f64>, Vec<String>) {
+    let num_samples = matrix.ncols();
+    let mut kept: Vec<(usize, f64)> = Vec::with_capacity(matrix.nrows());
+    for row in 0..matrix.nrows() {
+        let values: Vec<f64> = (0..num_samples)
+            .map(|col| {
+                let v = matrix[(row, col)];
+                if log_transform { (v + 1.0).log2() } else { v }
+            })
+            .collect();
+        if values.iter().any(|v| !v.is_finite()) {
+            continue;
+        }
+        let mean = values.iter().sum::<f64>() / num_samples as f64;
+        let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (num_samples as f64 - 1.0).max(1.0);
+        if var > 0.0 {
+            kept.push((row, var));
+        }
+    }
+    if let Some(top) = top_variable_features {
+        if top < kept.len() {
+            kept.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+            kept.truncate(top);
+            kept.sort_by_key(|&(row, _)| row);
+        }
+    }
+    let mut x = DMatrix::<f64>::zeros(num_samples, kept.len());
+    for (out_col, &(row, var)) in kept.iter().enumerate() {
+        let values: Vec<f64> = (0..num_samples)
+            .map(|col| {
+                let v = matrix[(row, col)];
+                if log_transform { (v + 1.0).log2() } else { v }
+            })
+            .collect();
+        let mean = values.iter().sum::<f64>() / num_samples as f64;
+        let sd = if scale { var.sqrt() } else { 1.0 };
+        for sample in 0..num_samples {
+            x[(sample, out_col)] = (values[sample] - mean) / sd;
+        }
+    }
+    let names = kept
+        .iter()
+        .map(|&(row, _)| feature_names.get(row).cloned().unwrap_or_else(|| row.to_string()))
+        .collect();
+    (x, names)
+}
+
+struct PcaResult {
+    scores: DMatrix<f64>,   // samples x components
+    loadings: DMatrix<f64>, // features x components
+    sdev: Vec<f64>,
+    variance_explained: Vec<f64>,
+}
+
+/// PCA of a centered (samples x features) matrix through its thin SVD X = U S V^T.
+/// Scores are U S and loadings V. Signs are fixed so that the largest absolute loading of each component is positive.
+fn pca(x: &DMatrix<f64>, num_components: usize) -> PcaResult {
+    let n = x.nrows();
+    let svd = x.clone().svd(true, true); // Singular values are sorted in descending order
+    let u = svd.u.unwrap();
+    let v_t = svd.v_t.unwrap();
+    let singular_values = svd.singular_values;
+    let total_ss: f64 = singular_values.iter().map(|s| s * s).sum();
+    let num_components = num_components.min(singular_values.len());
+    let mut scores = DMatrix::<f64>::zeros(n, num_components);
+    let mut loadings = DMatrix::<f64>::zeros(x.ncols(), num_components);
+    let mut sdev = Vec::with_capacity(num_components);
+    let mut variance_explained = Vec::with_capacity(num_components);
+    for c in 0..num_components {
+        let mut max_abs = 0.0;
+        let mut sign = 1.0;
+        for f in 0..x.ncols() {
+            if v_t[(c, f)].abs() > max_abs {
+                max_abs = v_t[(c, f)].abs();
+                sign = v_t[(c, f)].signum();
+            }
+        }
+        for f in 0..x.ncols() {
+            loadings[(f, c)] = sign * v_t[(c, f)];
+        }
+        for s in 0..n {
+            scores[(s, c)] = sign * u[(s, c)] * singular_values[c];
+        }
+        let s2 = singular_values[c] * singular_values[c];
+        sdev.push((s2 / (n as f64 - 1.0).max(1.0)).sqrt());
+        variance_explained.push(if total_ss > 0.0 { s2 / total_ss } else { 0.0 });
+    }
+    PcaResult {
+        scores,
+        loadings,
+        sdev,
+        variance_explained,
+    }
+}
+
+fn squared_distance(x: &DMatrix<f64>, i: usize, j: usize) -> f64 {
+    (0..x.ncols()).map(|c| (x[(i, c)] - x[(j, c)]).powi(2)).sum()
+}
+
+/// Vantage-point tree over the rows of x (Yianilos 1993), for the nearest neighbor searches of t-SNE and UMAP.
+/// Every node splits the rows below it at the median euclidean distance to its vantage point.
+struct VpTree<'a> {
+    x: &'a DMatrix<f64>,
+    nodes: Vec<VpNode>,
+}
+
+struct VpNode {
+    row: usize,          // Vantage point
+    threshold: f64,      // Median distance of the rows below this node to the vantage point
+    left: Option<usize>, // Rows closer than threshold
+    right: Option<usize>,
+}
+
+impl<'a> VpTree<'a> {
+    fn new(x: &'a DMatrix<f64>) -> VpTree<'a> {
+        let mut tree = VpTree {
+            x,
+            nodes: Vec::with_capacity(x.nrows()),
+        };
+        let mut rows: Vec<usize> = (0..x.nrows()).collect();
+        tree.build(&mut rows);
+        tree
+    }
+
+    fn distance(&self, i: usize, j: usize) -> f64 {
+        squared_distance(self.x, i, j).sqrt()
+    }
+
+    /// Builds the subtree of rows, with rows[0] as vantage point, and returns the index of its root
+    fn build(&mut self, rows: &mut [usize]) -> Option<usize> {
+        if rows.is_empty() {
+            return None;
+        }
+        let node = self.nodes.len();
+        self.nodes.push(VpNode {
+            row: rows[0],
+            threshold: 0.0,
+            left: None,
+            right: None,
+        });
+        if rows.len() > 1 {
+            let vantage = rows[0];
+            let others = &mut rows[1..];
+            let median = others.len() / 2;
+            others.select_nth_unstable_by(median, |&a, &b| {
+                self.distance(vantage, a).total_cmp(&self.distance(vantage, b))
+            });
+            self.nodes[node].threshold = self.distance(vantage, others[median]);
+            let (closer, farther) = others.split_at_mut(median);
+            self.nodes[node].left = self.build(closer);
+            self.nodes[node].right = self.build(farther);
+        }
+        Some(node)
+    }
+
+    /// The k rows nearest to row i (other than i itself) as (row, euclidean distance), nearest first
+    fn nearest(&self, i: usize, k: usize) -> Vec<(usize, f64)> {
+        let mut found: Vec<(usize, f64)> = Vec::with_capacity(k + 1);
+        if k > 0 && !self.nodes.is_empty() {
+            self.search(0, i, k, &mut found);
+        }
+        found
+    }
+
+    fn search(&self, node: usize, i: usize, k: usize, found: &mut Vec<(usize, f64)>) {
+        let n = &self.nodes[node];
+        let d = self.distance(n.row, i);
+        let tau = |found: &Vec<(usize, f64)>| if found.len() < k { f64::INFINITY } else { found[k - 1].1 };
+        if n.row != i && d < tau(found) {
+            let position = found.partition_point(|&(_, f)| f <= d);
+            found.insert(position, (n.row, d));
+            found.truncate(k);
+        }
+        // Visit the side of the threshold holding i first, then the other side if it can still hold a nearer row
+        let (near, far) = if d < n.threshold {
+            (n.left, n.right)
+        } else {
+            (n.right, n.left)
+        };
+        if let Some(near) = near {
+            self.search(near, i, k, found);
+        }
+        if let Some(far) = far {
+            if (d - n.threshold).abs() <= tau(found) {
+                self.search(far, i, k, found);
+            }
+        }
+    }
+}
+
+/// Binary search for the gaussian precision giving the requested perplexity over the supplied squared distances.
+/// Returns the conditional probabilities p(j|i) (same order as `distances`).
+fn conditional_probabilities(distances: &[f64], perplexity: f64) -> Vec<f64> {
+    let target_entropy = perplexity.ln();
+    let (mut beta, mut beta_min, mut beta_max) = (1.0, f64::NEG_INFINITY, f64::INFINITY);
+    let mut p = vec![0.0; distances.len()];
+    for _ in 0..200 {
+        let mut sum_p = 0.0;
+        for (j, &d) in distances.iter().enumerate() {
+            p[j] = (-beta * d).exp();
+            sum_p += p[j];
+        }
+        if sum_p == 0.0 {
+            sum_p = f64::MIN_POSITIVE;
+        }
+        let mut entropy = 0.0;
+        for (j, &d) in distances.iter().enumerate() {
+            entropy += beta * d * p[j];
+        }
+        entropy = entropy / sum_p + sum_p.ln();
+        for pj in p.iter_mut() {
+            *pj /= sum_p;
+        }
+        let diff = entropy - target_entropy;
+        if diff.abs() < 1e-5 {
+            break;
+        }
+        if diff > 0.0 {
+            beta_min = beta;
+            beta = if beta_max.is_infinite() {
+                beta * 2.0
+            } else {
+                (beta + beta_max) / 2.0
+            };
+        } else {
+            beta_max = beta;
+            beta = if beta_min.is_infinite() {
+                beta / 2.0
+            } else {
+                (beta + beta_min) / 2.0
+            };
+        }
+    }
+    p
+}
+
+/// Space partitioning tree (quadtree in 2D, octree in 3D) used for the Barnes-Hut t-SNE gradient
+struct SpNode {
+    center: [f64; 3],     // Center of the cell
+    half_width: [f64; 3], // Half width of the cell in each dimension
+    center_of_mass: [f64; 3],
+    count: usize,
+    points: Vec<usize>,         // Points held by a leaf
+    first_child: Option<usize>, // Index of the first of the 2^dims children in SpTree::nodes
+}
+
+struct SpTree<'a> {
+    dims: usize,
+    y: &'a [f64], // Embedding stored row-major (n x dims)
+    nodes: Vec<SpNode>,
+}
+
+impl<'a> SpTree<'a> {
+    fn new(y: &'a [f64], dims: usize) -> SpTree<'a> {
+        let n = y.len() / dims;
+        let mut min = [f64::INFINITY; 3];
+        let mut max = [f64::NEG_INFINITY; 3];
+        for i in 0..n {
+            for d in 0..dims {
+                min[d] = min[d].min(y[i * dims + d]);
+                max[d] = max[d].max(y[i * dims + d]);
+            }
+        }
+        let mut center = [0.0; 3];
+        let mut half_width = [0.0; 3];
+        for d in 0..dims {
+            center[d] = (min[d] + max[d]) / 2.0;
+            half_width[d] = ((max[d] - min[d]) / 2.0).max(1e-5) + 1e-5;
+        }
+        let mut tree = SpTree {
+            dims,
+            y,
+            nodes: vec![SpNode {
+                center,
+                half_width,
+                center_of_mass: [0.0; 3],
+                count: 0,
+                points: vec![],
+                first_child: None,
+            }],
+        };
+        for i in 0..n {
+            tree.insert(0, i, 0);
+        }
+        tree
+    }
+
+    fn point(&self, i: usize) -> &[f64] {
+        &self.y[i * self.dims..(i + 1) * self.dims]
+    }
+
+    fn child_for(&self, node: usize, i: usize) -> usize {
+        let mut offset = 0;
+        for d in 0..self.dims {
+            if self.point(i)[d] > self.nodes[node].center[d] {
+                offset |= 1 << d;
+            }
+        }
+        self.nodes[node].first_child.unwrap() + offset
+    }
+
+    fn insert(&mut self, node: usize, i: usize, depth: usize) {
+        let count = self.nodes[node].count as f64;
+        for d in 0..self.dims {
+            let com = self.nodes[node].center_of_mass[d];
+            self.nodes[node].center_of_mass[d] = (com * count + self.y[i * self.dims + d]) / (count + 1.0);
+        }
+        self.nodes[node].count += 1;
+        if self.nodes[node].first_child.is_none() {
+            let duplicate = self.nodes[node]
+                .points
+                .iter()
+                .all(|&p| (0..self.dims).all(|d| self.point(p)[d] == self.point(i)[d]));
+            if self.nodes[node].points.is_empty() || duplicate || depth >= SPTREE_MAX_DEPTH {
+                self.nodes[node].points.push(i);
+                return;
+            }
+            // Split the leaf and push its points down to the children
+            let first_child = self.nodes.len();
+            for c in 0..(1usize << self.dims) {
+                let mut center = [0.0; 3];
+                let mut half_width = [0.0; 3];
+                for d in 0..self.dims {
+                    half_width[d] = self.nodes[node].half_width[d] / 2.0;
+                    center[d] = if c & (1 << d) != 0 {
+                        self.nodes[node].center[d] + half_width[d]
+                    } else {
+                        self.nodes[node].center[d] - half_width[d]
+                    };
+                }
+                self.nodes.push(SpNode {
+                    center,
+                    half_width,
+                    center_of_mass: [0.0; 3],
+                    count: 0,
+                    points: vec![],
+                    first_child: None,
+                });
+            }
+            self.nodes[node].first_child = Some(first_child);
+            let existing = std::mem::take(&mut self.nodes[node].points);
+            for p in existing {
+                let child = self.child_for(node, p);
+                self.insert(child, p, depth + 1);
+            }
+        }
+        let child = self.child_for(node, i);
+        self.insert(child, i, depth + 1);
+    }
+
+    /// Accumulates the repulsive forces on point i into neg_force and the normalization term into sum_q
+    fn non_edge_forces(&self, node: usize, i: usize, theta: f64, neg_force: &mut [f64], sum_q: &mut f64) {
+        let n = &self.nodes[node];
+        if n.count == 0 {
+            return;
+        }
+        let yi = self.point(i);
+        if n.first_child.is_none() {
+            for &p in &n.points {
+                if p == i {
+                    continue;
+                }
+                let yp = self.point(p);
+                let dist: f64 = (0..self.dims).map(|d| (yi[d] - yp[d]).powi(2)).sum();
+                let q = 1.0 / (1.0 + dist);
+                *sum_q += q;
+                for d in 0..self.dims {
+                    neg_force[d] += q * q * (yi[d] - yp[d]);
+                }
+            }
+            return;
+        }
+        let dist: f64 = (0..self.dims).map(|d| (yi[d] - n.center_of_mass[d]).powi(2)).sum();
+        let max_width = (0..self.dims).map(|d| 2.0 * n.half_width[d]).fold(0.0, f64::max);
+        if dist > 0.0 && max_width / dist.sqrt() < theta {
+            // Summarize the whole cell by its center of mass
+            let q = 1.0 / (1.0 + dist);
+            let mult = n.count as f64 * q;
+            *sum_q += mult;
+            for d in 0..self.dims {
+                neg_force[d] += mult * q * (yi[d] - n.center_of_mass[d]);
+            }
+        } else {
+            let first_child = n.first_child.unwrap();
+            for c in 0..(1usize << self.dims) {
+                self.non_edge_forces(first_child + c, i, theta, neg_force, sum_q);
+            }
+        }
+    }
+}
+
+/// t-SNE embedding of the rows of x. theta = 0 uses the exact gradient, otherwise the Barnes-Hut approximation
+/// with input similarities restricted to the 3 * perplexity nearest neighbors.
+fn tsne(
+    x: &DMatrix<f64>,
+    dims: usize,
+    perplexity: f64,
+    theta: f64,
+    max_iter: usize,
+    learning_rate: f64,
+    rng: &mut SeededRng,
+) -> Vec<f64> {
+    let n = x.nrows();
+    let exact = theta <= 0.0;
+
+    // Symmetrized input similarities as (row -> [(col, p_ij)]) adjacency lists
+    let mut p_rows: Vec<Vec<(usize, f64)>> = vec![vec![]; n];
+    if exact {
+        for (i, row) in p_rows.iter_mut().enumerate() {
+            let others: Vec<usize> = (0..n).filter(|&j| j != i).collect();
+            let d: Vec<f64> = others.iter().map(|&j| squared_distance(x, i, j)).collect();
+            let p = conditional_probabilities(&d, perplexity);
+            row.extend(others.into_iter().zip(p));
+        }
+    } else {
+        let num_neighbors = ((3.0 * perplexity) as usize).min(n - 1);
+        let tree = VpTree::new(x);
+        for (i, row) in p_rows.iter_mut().enumerate() {
+            let neighbors = tree.nearest(i, num_neighbors);
+            let d: Vec<f64> = neighbors.iter().map(|&(_, d)| d * d).collect();
+            let p = conditional_probabilities(&d, perplexity);
+            for (&(j, _), p_ij) in neighbors.iter().zip(p) {
+                row.push((j, p_ij));
+            }
+        }
+    }
+    // BTreeMap rows keep the sums in the order of j, so a seeded run gives the same embedding every time
+    let mut symmetric: Vec<BTreeMap<usize, f64>> = vec![BTreeMap::new(); n];
+    for i in 0..n {
+        for &(j, p) in &p_rows[i] {
+            *symmetric[i].entry(j).or_insert(0.0) += p;
+            *symmetric[j].entry(i).or_insert(0.0) += p;
+        }
+    }
+    let mut p_sym: Vec<Vec<(usize, f64)>> = symmetric.into_iter().map(|row| row.into_iter().collect()).collect();
+    let total: f64 = p_sym.iter().map(|row| row.iter().map(|&(_, p)| p).sum::<f64>()).sum();
+    for row in p_sym.iter_mut() {
+        for (_, p) in row.iter_mut() {
+            *p /= total;
+        }
+    }
+
+    let mut y: Vec<f64> = (0..n * dims).map(|_| rng.next_gaussian() * 1e-4).collect();
+    let mut update = vec![0.0; n * dims];
+    let mut gains = vec![1.0; n * dims];
+    let mut grad = vec![0.0; n * dims];
+    // Exact gradient: Student-t kernel of every pair and dense row of the input similarities, reused every iteration
+    let mut num = if exact { vec![0.0; n * n] } else { Vec::new() };
+    let mut p_dense = if exact { vec![0.0; n] } else { Vec::new() };
+    for iter in 0..max_iter {
+        let exaggeration = if iter < TSNE_STOP_LYING_ITER {
+            TSNE_EARLY_EXAGGERATION
+        } else {
+            1.0
+        };
+        let momentum = if iter < TSNE_MOMENTUM_SWITCH_ITER { 0.5 } else { 0.8 };
+        for g in grad.iter_mut() {
+            *g = 0.0;
+        }
+        if exact {
+            let mut sum_q = 0.0;
+            for i in 0..n {
+                for j in i + 1..n {
+                    let dist: f64 = (0..dims).map(|d| (y[i * dims + d] - y[j * dims + d]).powi(2)).sum();
+                    let q = 1.0 / (1.0 + dist);
+                    num[i * n + j] = q;
+                    num[j * n + i] = q;
+                    sum_q += 2.0 * q;
+                }
+            }
+            for i in 0..n {
+                for p in p_dense.iter_mut() {
+                    *p = 0.0;
+                }
+                for &(j, p) in &p_sym[i] {
+                    p_dense[j] = p;
+                }
+                for j in 0..n {
+                    if i == j {
+                        continue;
+                    }
+                    let mult = (exaggeration * p_dense[j] - num[i * n + j] / sum_q) * num[i * n + j];
+                    for d in 0..dims {
+                        grad[i * dims + d] += 4.0 * mult * (y[i * dims + d] - y[j * dims + d]);
+                    }
+                }
+            }
+        } else {
+            let tree = SpTree::new(&y, dims);
+            let mut neg = vec![0.0; n * dims];
+            let mut sum_q = 0.0;
+            for i in 0..n {
+                tree.non_edge_forces(0, i, theta, &mut neg[i * dims..(i + 1) * dims], &mut sum_q);
+            }
+            for i in 0..n {
+                for &(j, p) in &p_sym[i] {
+                    let dist: f64 = (0..dims).map(|d| (y[i * dims + d] - y[j * dims + d]).powi(2)).sum();
+                    let q = 1.0 / (1.0 + dist);
+                    for d in 0..dims {
+                        grad[i * dims + d] += 4.0 * exaggeration * p * q * (y[i * dims + d] - y[j * dims + d]);
+                    }
+                }
+                for d in 0..dims {
+                    grad[i * dims + d] -= 4.0 * neg[i * dims + d] / sum_q;
+                }
+            }
+        }
+        for k in 0..n * dims {
+            gains[k] = if (grad[k] > 0.0) != (update[k] > 0.0) {
+                gains[k] + 0.2
+            } else {
+                gains[k] * 0.8
+            };
+            if gains[k] < 0.01 {
+                gains[k] = 0.01;
+            }
+            update[k] = momentum * update[k] - learning_rate * gains[k] * grad[k];
+            y[k] += update[k];
+        }
+        // Keep the embedding centered
+        for d in 0..dims {
+            let mean = (0..n).map(|i| y[i * dims + d]).sum::<f64>() / n as f64;
+            for i in 0..n {
+                y[i * dims + d] -= mean;
+            }
+        }
+    }
+    y
+}
+
+/// Fits a and b in 1 / (1 + a * x^(2b)) to the UMAP target membership curve defined by min_dist and spread (Levenberg-Marquardt)
+fn find_ab_params(spread: f64, min_dist: f64) -> (f64, f64) {
+    let xs: Vec<f64> = (0..300).map(|i| 3.0 * spread * i as f64 / 299.0).collect();
+    let ys: Vec<f64> = xs
+        .iter()
+        .map(|&x| {
+            if x < min_dist {
+                1.0
+            } else {
+                (-(x - min_dist) / spread).exp()
+            }
+        })
+        .collect();
+    let residual_ss = |a: f64, b: f64| -> f64 {
+        xs.iter()
+            .zip(ys.iter())
+            .map(|(&x, &y)| (1.0 / (1.0 + a * x.powf(2.0 * b)) - y).powi(2))
+            .sum()
+    };
+    let (mut a, mut b, mut lambda) = (1.0, 1.0, 1e-3);
+    let mut current = residual_ss(a, b);
+    for _ in 0..500 {
+        // Gauss-Newton normal equations with the analytic jacobian
+        let (mut jaa, mut jab, mut jbb, mut ga, mut gb) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for (&x, &y) in xs.iter().zip(ys.iter()) {
+            if x <= 0.0 {
+                continue;
+            }
+            let x2b = x.powf(2.0 * b);
+            let f = 1.0 / (1.0 + a * x2b);
+            let r = f - y;
+            let da = -x2b * f * f;
+            let db = -a * x2b * 2.0 * x.ln() * f * f;
+            jaa += da * da;
+            jab += da * db;
+            jbb += db * db;
+            ga += da * r;
+            gb += db * r;
+        }
+        let (maa, mbb) = (jaa * (1.0 + lambda), jbb * (1.0 + lambda));
+        let det = maa * mbb - jab * jab;
+        if det.abs() < 1e-300 {
+            break;
+        }
+        let step_a = -(mbb * ga - jab * gb) / det;
+        let step_b = -(maa * gb - jab * ga) / det;
+        let (new_a, new_b) = (a + step_a, b + step_b);
+        let candidate = if new_a > 0.0 && new_b > 0.0 {
+            residual_ss(new_a, new_b)
+        } else {
+            f64::INFINITY
+        };
+        if candidate < current {
+            let converged = (current - candidate) < 1e-14;
+            a = new_a;
+            b = new_b;
+            current = candidate;
+            lambda /= 10.0;
+            if converged {
+                break;
+            }
+        } else {
+            lambda *= 10.0;
+            if lambda > 1e10 {
+                break;
+            }
+        }
+    }
+    (a, b)
+}
+
+/// UMAP embedding of the rows of x, initialized from the leading principal components
+#[allow(clippy::too_many_arguments)]
+fn umap(
+    x: &DMatrix<f64>,
+    dims: usize,
+    n_neighbors: usize,
+    min_dist: f64,
+    spread: f64,
+    n_epochs: usize,
+    negative_sample_rate: usize,
+    rng: &mut SeededRng,
+) -> Vec<f64> {
+    let n = x.nrows();
+    let k = n_neighbors.min(n);
+
+    // Fuzzy simplicial set: smooth kNN distances (k nearest neighbors including the point itself)
+    let target = (k as f64).log2();
+    let mut graph: Vec<std::collections::HashMap<usize, f64>> = vec![std::collections::HashMap::new(); n];
+    let tree = VpTree::new(x);
+    let knn: Vec<Vec<(usize, f64)>> = (0..n).map(|i| tree.nearest(i, k - 1)).collect();
+    // Mean kNN distance over all points (as umap-learn), the scale of the smallest sigma of points whose neighbors all
+    // coincide with them
+    let num_distances: usize = knn.iter().map(|neighbors| neighbors.len()).sum();
+    let mean_distance = knn.iter().flatten().map(|&(_, d)| d).sum::<f64>() / num_distances.max(1) as f64;
+    for (i, neighbors) in knn.iter().enumerate() {
+        let rho = neighbors.iter().map(|&(_, d)| d).find(|&d| d > 0.0).unwrap_or(0.0);
+        let (mut lo, mut hi, mut sigma) = (0.0, f64::INFINITY, 1.0);
+        for _ in 0..64 {
+            let psum: f64 = neighbors
+                .iter()
+                .map(|&(_, d)| if d - rho > 0.0 { (-(d - rho) / sigma).exp() } else { 1.0 })
+                .sum();
+            if (psum - target).abs() < 1e-5 {
+                break;
+            }
+            if psum > target {
+                hi = sigma;
+                sigma = (lo + hi) / 2.0;
+            } else {
+                lo = sigma;
+                sigma = if hi.is_infinite() { sigma * 2.0 } else { (lo + hi) / 2.0 };
+            }
+        }
+        let mean_neighbor_distance = neighbors.iter().map(|&(_, d)| d).sum::<f64>() / neighbors.len().max(1) as f64;
+        let min_sigma = if rho > 0.0 {
+            1e-3 * mean_neighbor_distance
+        } else {
+            1e-3 * mean_distance
+        };
+        sigma = sigma.max(min_sigma);
+        for &(j, d) in neighbors {
+            let w = if d - rho > 0.0 { (-(d - rho) / sigma).exp() } else { 1.0 };
+            graph[i].insert(j, w);
+        }
+    }
+    drop(knn);
+    // Fuzzy union: w_ij + w_ji - w_ij * w_ji
+    let mut edges: Vec<(usize, usize, f64)> = Vec::new();
+    for i in 0..n {
+        let mut row: Vec<(usize, f64)> = Vec::new();
+        for (&j, &w_ij) in &graph[i] {
+            let w_ji = graph[j].get(&i).copied().unwrap_or(0.0);
+            row.push((j, w_ij + w_ji - w_ij * w_ji));
+        }
+        for j in 0..n {
+            if !graph[i].contains_key(&j) {
+                if let Some(&w_ji) = graph[j].get(&i) {
+                    row.push((j, w_ji));
+                }
+            }
+        }
+        row.sort_by_key(|&(j, _)| j);
+        for (j, w) in row {
+            edges.push((i, j, w));
+        }
+    }
+    let max_weight = edges.iter().map(|e| e.2).fold(0.0, f64::max);
+    edges.retain(|e| e.2 >= max_weight / n_epochs as f64);
+
+    // PCA initialization expanded to a [-10, 10] box with a little noise, as umap-learn does for its spectral initialization
+    let init = pca(x, dims).scores;
+    let max_abs = init.iter().fold(0.0f64, |m, v| m.max(v.abs())).max(1e-12);
+    let mut y = vec![0.0; n * dims];
+    for i in 0..n {
+        for d in 0..dims {
+            let value = if d < init.ncols() {
+                init[(i, d)] * 10.0 / max_abs
+            } else {
+                0.0
+            };
+            y[i * dims + d] = value + rng.next_gaussian() * 1e-4;
+        }
+    }
+
+    let (a, b) = find_ab_params(spread, min_dist);
+    let epochs_per_sample: Vec<f64> = edges.iter().map(|e| max_weight / e.2).collect();
+    let epochs_per_negative_sample: Vec<f64> = epochs_per_sample
+        .iter()
+        .map(|e| e / negative_sample_rate as f64)
+        .collect();
+    let mut epoch_of_next_sample = epochs_per_sample.clone();
+    let mut epoch_of_next_negative_sample = epochs_per_negative_sample.clone();
+    let clip = |v: f64| v.clamp(-4.0, 4.0);
+    let initial_alpha = 1.0;
+    let gamma = 1.0;
+    for epoch in 0..n_epochs {
+        let alpha = initial_alpha * (1.0 - epoch as f64 / n_epochs as f64);
+        for (e, &(j, k, _)) in edges.iter().enumerate() {
+            if epoch_of_next_sample[e] > epoch as f64 {
+                continue;
+            }
+            // Attractive force between the two ends of the edge
+            let dist: f64 = (0..dims).map(|d| (y[j * dims + d] - y[k * dims + d]).powi(2)).sum();
+            let grad_coeff = if dist > 0.0 {
+                -2.0 * a * b * dist.powf(b - 1.0) / (a * dist.powf(b) + 1.0)
+            } else {
+                0.0
+            };
+            for d in 0..dims {
+                let grad = clip(grad_coeff * (y[j * dims + d] - y[k * dims + d]));
+                y[j * dims + d] += grad * alpha;
+                y[k * dims + d] -= grad * alpha;
+            }
+            epoch_of_next_sample[e] += epochs_per_sample[e];
+
+            // Repulsive forces against randomly sampled points
+            let num_negative =
+                ((epoch as f64 - epoch_of_next_negative_sample[e]) / epochs_per_negative_sample[e]).max(0.0) as usize;
+            for _ in 0..num_negative {
+                let other = rng.gen_range(n);
+                if other == j {
+                    continue;
+                }
+                let dist: f64 = (0..dims).map(|d| (y[j * dims + d] - y[other * dims + d]).powi(2)).sum();
+                let grad_coeff = if dist > 0.0 {
+                    2.0 * gamma * b / ((0.001 + dist) * (a * dist.powf(b) + 1.0))
+                } else {
+                    0.0
+                };
+                for d in 0..dims {
+                    let grad = if grad_coeff > 0.0 {
+                        clip(grad_coeff * (y[j * dims + d] - y[other * dims + d]))
+                    } else {
+                        4.0
+                    };
+                    y[j * dims + d] += grad * alpha;
+                }
+            }
+            epoch_of_next_negative_sample[e] += num_negative as f64 * epochs_per_negative_sample[e];
+        }
+    }
+    y
+}
+
+fn to_rows(values: &[f64], dims: usize) -> Vec<Vec<f64>> {
+    values
+        .chunks(dims)
+        .map(|c| c.iter().map(|v| (v * 1e6).round() / 1e6).collect())
+        .collect()
+}
+
+/// Embedding of the samples described by the input JSON, or the message printed as {"error": message}
+fn run(p: &Value) -> Result<Value, String> {
+    // Input matrix as (features x samples)
+    let (matrix, feature_names, sample_names) = if let Some(h5_path) = p["input_file"].as_str() {
+        let requested: Option<Vec<&str>> = p["samples"]
+            .as_str()
+            .map(|s| s.split(',').filter(|x| !x.is_empty()).collect());
+        let e = hdf5_expression::read_expression_matrix(h5_path, requested.as_deref())?;
+        (e.matrix, e.gene_names, e.sample_names)
+    } else if let Some(rows) = p["matrix"].as_array() {
+        let num_cols = rows.first().and_then(|r| r.as_array()).map_or(0, |r| r.len());
+        let mut m = DMatrix::<f64>::zeros(rows.len(), num_cols);
+        for (i, row) in rows.iter().enumerate() {
+            let values = match row.as_array() {
+                Some(v) if v.len() == num_cols => v,
+                _ => return Err(format!("Row {} of matrix does not have {} columns", i, num_cols)),
+            };
+            for (j, v) in values.iter().enumerate() {
+                m[(i, j)] = v.as_f64().unwrap_or(f64::NAN);
+            }
+        }
+        let names = |key: &str, len: usize| -> Vec<String> {
+            match p[key].as_array() {
+                Some(a) => a.iter().map(|v| v.as_str().unwrap_or("").to_string()).collect(),
+                None => (0..len).map(|i| i.to_string()).collect(),
+            }
+        };
+        (m, names("feature_names", rows.len()), names("sample_names", num_cols))
+    } else {
+        return Err("Either input_file or matrix must be provided".to_string());
+    };
+    if sample_names.len() != matrix.ncols() || feature_names.len() != matrix.nrows() {
+        return Err("Number of sample/feature names does not match the matrix dimensions".to_string());
+    }
+    if matrix.ncols() < 3 {
+        return Err(format!("At least 3 samples are required, found {}", matrix.ncols()));
+    }
+
+    let method = p["method"].as_str().unwrap_or("pca");
+    let n_components = p["n_components"].as_u64().unwrap_or(2) as usize;
+    if n_components == 0 || (method != "pca" && n_components > 3) {
+        return Err(format!("n_components must be 2 or 3 for {}", method));
+    }
+    let seed = p["seed"].as_u64().unwrap_or(1);
+    let log_transform = p["log_transform"].as_bool().unwrap_or(false);
+    let top_variable_features = p["top_variable_features"].as_u64().map(|v| v as usize);
+    let scale = p["scale"].as_bool().unwrap_or(false);
+    let (x, kept_features) = preprocess(&matrix, &feature_names, log_transform, top_variable_features, scale);
+    drop(matrix);
+    if x.ncols() == 0 {
+        return Err("No features with non-zero variance".to_string());
+    }
+    let n = x.nrows();
+    let num_features = x.ncols();
+    let mut rng = SeededRng::new(seed);
+
+    let mut output = match method {
+        "pca" => {
+            let result = pca(&x, n_components);
+            let loadings: Vec<Vec<f64>> = (0..result.loadings.nrows())
+                .map(|f| result.loadings.row(f).iter().cloned().collect())
+                .collect();
+            let scores: Vec<f64> = (0..n)
+                .flat_map(|s| (0..result.scores.ncols()).map(move |c| (s, c)))
+                .map(|(s, c)| result.scores[(s, c)])
+                .collect();
+            json!({
+                "method": "pca",
+                "samples": sample_names,
+                "coordinates": to_rows(&scores, result.scores.ncols()),
+                "sdev": result.sdev,
+                "variance_explained": result.variance_explained,
+                "features": kept_features,
+                "loadings": loadings,
+            })
+        }
+        "tsne" => {
+            let perplexity = p["perplexity"].as_f64().unwrap_or(30.0);
+            if (n as f64 - 1.0) < 3.0 * perplexity {
+                return Err(format!("Perplexity {} is too large for {} samples", perplexity, n));
+            }
+            let theta = p["theta"].as_f64().unwrap_or(0.5);
+            let max_iter = p["max_iter"].as_u64().unwrap_or(1000) as usize;
+            let learning_rate = p["learning_rate"].as_f64().unwrap_or(200.0);
+            let initial_dims = p["initial_dims"].as_u64().unwrap_or(50) as usize;
+            let input = if initial_dims > 0 && initial_dims < x.ncols() {
+                pca(&x, initial_dims).scores
+            } else {
+                x
+            };
+            let y = tsne(
+                &input,
+                n_components,
+                perplexity,
+                theta,
+                max_iter,
+                learning_rate,
+                &mut rng,
+            );
+            json!({
+                "method": "tsne",
+                "samples": sample_names,
+                "coordinates": to_rows(&y, n_components),
+                "perplexity": perplexity,
+                "theta": theta,
+            })
+        }
+        "umap" => {
+            let n_neighbors = p["n_neighbors"].as_u64().unwrap_or(15) as usize;
+            if n_neighbors < 2 {
+                return Err("n_neighbors must be at least 2".to_string());
+            }
+            let min_dist = p["min_dist"].as_f64().unwrap_or(0.1);
+            let spread = p["spread"].as_f64().unwrap_or(1.0);
+            if min_dist > spread {
+                return Err("min_dist must be less than or equal to spread".to_string());
+            }
+            let default_epochs = if n <= 10000 { 500 } else { 200 };
+            let n_epochs = p["n_epochs"].as_u64().unwrap_or(default_epochs) as usize;
+            let negative_sample_rate = p["negative_sample_rate"].as_u64().unwrap_or(5) as usize;
+            let y = umap(
+                &x,
+                n_components,
+                n_neighbors,
+                min_dist,
+                spread,
+                n_epochs,
+                negative_sample_rate,
+                &mut rng,
+            );
+            json!({
+                "method": "umap",
+                "samples": sample_names,
+                "coordinates": to_rows(&y, n_components),
+                "n_neighbors": n_neighbors,
+                "min_dist": min_dist,
+            })
+        }
+        _ => return Err(format!("Unknown method: {}", method)),
+    };
+    output["num_features"] = json!(num_features);
+    Ok(output)
+}
+
+fn main() {
+    let t0 = Instant::now();
+    let mut input = String::new();
+    let output = match io::stdin().read_line(&mut input) {
+        Err(e) => Err(format!("Failed to read stdin: {}", e)),
+        Ok(_) => match serde_json::from_str::<Value>(&input) {
+            Err(e) => Err(format!("Invalid JSON: {}", e)),
+            Ok(p) => run(&p),
+        },
+    };
+    match output {
+        Ok(mut output) => {
+            output["elapsed_ms"] = json!(t0.elapsed().as_millis());
+            println!("{}", output);
+        }
+        Err(e) => println!("{}", json!({ "error": e })),
+    }
+}
@@ -0,0 +1,405 @@
+// dmr_annotation.rs — Gene and CpG island annotation of DMRs
+//
+// Genes come from the genedb sqlite (default isoform of each gene in the genes table) or from a local BED/GTF file
+// (optionally gzipped); CpG islands come from a BED file. Each chromosome is indexed with static interval trees of
+// gene bodies, promoters and CpG islands, plus a position-sorted list of TSSs for the nearest gene search.
+//
+// Per DMR this gives the overlapping genes, the nearest gene(s) with their signed distance to the TSS (negative =
+// upstream of the TSS on the gene strand, 0 = TSS inside the DMR), the genes whose promoter (TSS − promoter_upstream to
+// TSS + promoter_downstream, strand aware) overlaps the DMR, and the CpG island context: island, shore (within 2 kb of
+// an island), shelf (2–4 kb) or open sea. All intervals are closed and 1-based like the probe positions of the DMRs:
+// the 0-based starts of BED files and of the genedb gene models are converted when loading, GTF is already 1-based.
+use flate2::read::MultiGzDecoder;
+use rusqlite::{Connection, OpenFlags};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+const SHORE_WIDTH: i64 = 2000;
+const SHELF_WIDTH: i64 = 2000; // Shelves extend another 2 kb beyond the shores
+
+/// Static interval tree: intervals sorted by start with a segment tree of the maximum end, so a query visits only
+/// the subtrees that can contain an overlapping interval (O(log n + k log n)).
+pub struct IntervalTree<T> {
+    starts: Vec<i64>,
+    ends: Vec<i64>,
+    data: Vec<T>,
+    max_end: Vec<i64>, // Segment tree over the sorted intervals, leaves at [size, 2 * size)
+    size: usize,
+}
+
+impl<T> IntervalTree<T> {
+    pub fn new(mut intervals: Vec<(i64, i64, T)>) -> Self {
+        intervals.sort_by_key(|iv| (iv.0, iv.1));
+        let size = intervals.len().next_power_of_two();
+        let mut max_end = vec![i64::MIN; 2 * size];
+        let (mut starts, mut ends, mut data) = (Vec::new(), Vec::new(), Vec::new());
+        for (i, (start, end, item)) in intervals.into_iter().enumerate() {
+            max_end[size + i] = end;
+            starts.push(start);
+            ends.push(end);
+            data.push(item);
+        }
+        for node in (1..size).rev() {
+            max_end[node] = max_end[2 * node].max(max_end[2 * node + 1]);
+        }
+        IntervalTree {
+            starts,
+            ends,
+            data,
+            max_end,
+            size,
+        }
+    }
+
+    /// Items whose interval overlaps [start, end], in order of interval start
+    pub fn query(&self, start: i64, end: i64) -> Vec<&T> {
+        let mut found = Vec::new();
+        // Only the intervals starting at or before the end of the query can overlap it
+        let limit = self.starts.partition_point(|&s| s <= end);
+        if limit > 0 {
+            self.collect(1, 0, self.size, limit, start, &mut found);
+        }
+        found
+    }
+
+    fn collect<'a>(&'a self, node: usize, lo: usize, hi: usize, limit: usize, start: i64, found: &mut Vec<&'a T>) {
+        if lo >= limit || self.max_end[node] < start {
+            return;
+        }
+        if hi - lo == 1 {
+            if self.ends[lo] >= start {
+                found.push(&self.data[lo]);
+            }
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        self.collect(2 * node, lo, mid, limit, start, found);
+        self.collect(2 * node + 1, mid, hi, limit, start, found);
+    }
+}
+
+/// Gene body with its strand
+#[derive(Debug, Clone)]
+pub struct Gene {
+    pub name: String,
+    pub chr: String,
+    pub start: i64,
+    pub stop: i64,
+    pub strand: char, // '+', '-' or '.' (unknown strand is treated as '+')
+}
+
+impl Gene {
+    fn tss(&self) -> i64 {
+        if self.strand == '-' { self.stop } else { self.start }
+    }
+
+    /// Signed distance from the TSS to the closest base of [start, stop], negative upstream of the TSS
+    fn distance_to_tss(&self, start: i64, stop: i64) -> i64 {
+        let tss = self.tss();
+        let d = if tss < start {
+            start - tss
+        } else if tss > stop {
+            stop - tss
+        } else {
+            0
+        };
+        if self.strand == '-' { -d } else { d }
+    }
+}
+
+struct ChromosomeIndex {
+    genes: IntervalTree<usize>,
+    promoters: IntervalTree<usize>,
+    tss: Vec<(i64, usize)>, // Sorted by position
+    islands: IntervalTree<()>,
+}
+
+/// Gene and CpG island annotation of genomic regions
+pub struct RegionAnnotation {
+    genes: Vec<Gene>,
+    chromosomes: HashMap<String, ChromosomeIndex>,
+    has_islands: bool,
+}
+
+impl RegionAnnotation {
+    pub fn new(genes: Vec<Gene>, islands: Option<Vec<(String, i64, i64)>>, upstream: i64, downstream: i64) -> Self {
+        let has_islands = islands.is_some();
+        let mut chr_genes: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, gene) in genes.iter().enumerate() {
+            chr_genes.entry(gene.chr.clone()).or_default().push(i);
+        }
+        let mut chr_islands: HashMap<String, Vec<(i64, i64, ())>> = HashMap::new();
+        for (chr, start, stop) in islands.unwrap_or_default() {
+            chr_genes.entry(chr.clone()).or_default();
+            chr_islands.entry(chr).or_default().push((start, stop, ()));
+        }
+        let chromosomes = chr_genes
+            .into_iter()
+            .map(|(chr, gene_idx)| {
+                let islands = chr_islands.remove(&chr).unwrap_or_default();
+                let bodies = gene_idx.iter().map(|&i| (genes[i].start, genes[i].stop, i)).collect();
+                let promoters = gene_idx
+                    .iter()
+                    .map(|&i| {
+                        let tss = genes[i].tss();
+                        if genes[i].strand == '-' {
+                            (tss - downstream, tss + upstream, i)
+                        } else {
+                            (tss - upstream, tss + downstream, i)
+                        }
+                    })
+                    .collect();
+                let mut tss: Vec<(i64, usize)> = gene_idx.iter().map(|&i| (genes[i].tss(), i)).collect();
+                tss.sort_unstable();
+                let index = ChromosomeIndex {
+                    genes: IntervalTree::new(bodies),
+                    promoters: IntervalTree::new(promoters),
+                    tss,
+                    islands: IntervalTree::new(islands),
+                };
+                (chr, index)
+            })
+            .collect();
+        RegionAnnotation {
+            genes,
+            chromosomes,
+            has_islands,
+        }
+    }
+
+    fn names(&self, idx: &[usize]) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for &i in idx {
+            if !names.contains(&self.genes[i].name) {
+                names.push(self.genes[i].name.clone());
+            }
+        }
+        names
+    }
+
+    /// Genes with the TSS closest to [start, stop] (several when equally close)
+    fn nearest_genes(&self, index: &ChromosomeIndex, start: i64, stop: i64) -> Vec<usize> {
+        let tss = &index.tss;
+        let first = tss.partition_point(|&(p, _)| p < start);
+        let last = tss.partition_point(|&(p, _)| p <= stop);
+        if first < last {
+            return tss[first..last].iter().map(|&(_, i)| i).collect(); // TSSs inside the region
+        }
+        let mut candidates: Vec<(i64, usize)> = Vec::new();
+        if first > 0 {
+            let p = tss[first - 1].0;
+            candidates.extend(
+                tss[..first]
+                    .iter()
+                    .rev()
+                    .take_while(|&&(q, _)| q == p)
+                    .map(|&(q, i)| (start - q, i)),
+            );
+        }
+        if last < tss.len() {
+            let p = tss[last].0;
+            candidates.extend(
+                tss[last..]
+                    .iter()
+                    .take_while(|&&(q, _)| q == p)
+                    .map(|&(q, i)| (q - stop, i)),
+            );
+        }
+        let best = candidates.iter().map(|c| c.0).min().unwrap_or(0);
+        candidates.into_iter().filter(|c| c.0 == best).map(|c| c.1).collect()
+    }
+
+    /// CpG island context of [start, stop]: island, shore, shelf or open_sea
+    fn cpg_context(&self, index: Option<&ChromosomeIndex>, start: i64, stop: i64) -> &'static str {
+        let islands = match index {
+            Some(index) => &index.islands,
+            None => return "open_sea",
+        };
+        if !islands.query(start, stop).is_empty() {
+            "island"
+        } else if !islands.query(start - SHORE_WIDTH, stop + SHORE_WIDTH).is_empty() {
+            "shore"
+        } else if !islands
+            .query(start - SHORE_WIDTH - SHELF_WIDTH, stop + SHORE_WIDTH + SHELF_WIDTH)
+            .is_empty()
+        {
+            "shelf"
+        } else {
+            "open_sea"
+        }
+    }
+
+    /// Adds the annotation fields to a DMR object with chr, start and stop
+    pub fn annotate(&self, dmr: &mut Value) {
+        let (chr, start, stop) = match (dmr["chr"].as_str(), dmr["start"].as_i64(), dmr["stop"].as_i64()) {
+            (Some(c), Some(s), Some(e)) => (c.to_string(), s, e),
+            _ => return,
+        };
+        let index = self.chromosomes.get(&chr);
+        let (overlapping, nearest, promoters) = match index {
+            Some(index) => {
+                let overlapping: Vec<usize> = index.genes.query(start, stop).into_iter().copied().collect();
+                let promoters: Vec<usize> = index.promoters.query(start, stop).into_iter().copied().collect();
+                (overlapping, self.nearest_genes(index, start, stop), promoters)
+            }
+            None => (vec![], vec![], vec![]),
+        };
+        let overlapping_names = self.names(&overlapping);
+        // Comma-separated like DMRCate's overlapping.genes
+        dmr["overlapping_genes"] = if overlapping_names.is_empty() {
+            Value::Null
+        } else {
+            json!(overlapping_names.join(", "))
+        };
+        dmr["nearest_genes"] = json!(
+            nearest
+                .iter()
+                .map(
+                    |&i| json!({"gene": self.genes[i].name, "strand": self.genes[i].strand.to_string(),
+                    "distance_to_tss": self.genes[i].distance_to_tss(start, stop)})
+                )
+                .collect::<Vec<_>>()
+        );
+        dmr["promoter_genes"] = json!(self.names(&promoters));
+        dmr["promoter_overlap"] = json!(!promoters.is_empty());
+        if self.has_islands {
+            dmr["cpg_context"] = json!(self.cpg_context(index, start, stop));
+        }
+    }
+}
+
+/// Default isoforms of the genes of the genedb sqlite, optionally restricted to one chromosome
+pub fn load_genedb_genes(genedb: &str, chr: Option<&str>) -> Result<Vec<Gene>, String> {
+    let conn = Connection::open_with_flags(genedb, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("Cannot open genedb {}: {}", genedb, e))?;
+    let sql = "SELECT name, json_extract(genemodel, '$.chr'), json_extract(genemodel, '$.start'), \
+               json_extract(genemodel, '$.stop'), json_extract(genemodel, '$.strand') \
+               FROM genes WHERE isdefault = 1 AND (?1 IS NULL OR json_extract(genemodel, '$.chr') = ?1)";
+    let mut stmt = conn.prepare(sql).map_err(|e| format!("genedb genes: {}", e))?;
+    let rows = stmt
+        .query_map([chr], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<i64>>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })
+        .map_err(|e| format!("genedb genes: {}", e))?;
+    let mut genes = Vec::new();
+    for row in rows {
+        if let (name, Some(chr), Some(start), Some(stop), strand) = row.map_err(|e| e.to_string())? {
+            genes.push(Gene {
+                name,
+                chr,
+                start: start + 1, // genemodel start is 0-based, stop is the last base
+                stop,
+                strand: parse_strand(strand.as_deref().unwrap_or(".")),
+            });
+        }
+    }
+    Ok(genes)
+}
+
+fn parse_strand(s: &str) -> char {
+    match s {
+        "+" | "-" => s.chars().next().unwrap(),
+        _ => '.',
+    }
+}
+
+pub fn open_text(path: &str) -> Result<Box<dyn BufRead>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let reader: Box<dyn Read> = if path.ends_with(".gz") {
+        Box::new(MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+fn is_comment(line: &str) -> bool {
+    line.is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser")
+}
+
+/// Genes of a BED (chr, start, end, name[, score, strand]) or GTF file (gene features, named by gene_name or
+/// gene_id), optionally restricted to one chromosome. BED starts are converted to 1-based like GTF.
+pub fn load_gene_file(path: &str, chr: Option<&str>) -> Result<Vec<Gene>, String> {
+    let gtf = path.trim_end_matches(".gz").ends_with(".gtf");
+    let mut genes = Vec::new();
+    for (n, line) in open_text(path)?.lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", path, e))?;
+        if is_comment(&line) {
+            continue;
+        }
+        let f: Vec<&str> = line.split('\t').collect();
+        if chr.is_some_and(|c| f[0] != c) {
+            continue;
+        }
+        let bad_line = || format!("{}: invalid line {}", path, n + 1);
+        let gene = if gtf {
+            if f.len() < 9 {
+                return Err(bad_line());
+            }
+            if f[2] != "gene" {
+                continue;
+            }
+            let attribute = |key: &str| {
+                f[8].split(';').map(|a| a.trim()).find_map(|a| {
+                    a.strip_prefix(key)
+                        .filter(|v| v.starts_with(' '))
+                        .map(|v| v.trim().trim_matches('"').to_string())
+                })
+            };
+            let name = attribute("gene_name")
+                .or_else(|| attribute("gene_id"))
+                .ok_or_else(bad_line)?;
+            Gene {
+                name,
+                chr: f[0].to_string(),
+                start: f[3].parse().map_err(|_| bad_line())?,
+                stop: f[4].parse().map_err(|_| bad_line())?,
+                strand: parse_strand(f[6]),
+            }
+        } else {
+            if f.len() < 4 {
+                return Err(bad_line());
+            }
+            Gene {
+                name: f[3].to_string(),
+                chr: f[0].to_string(),
+                start: f[1].parse::<i64>().map_err(|_| bad_line())? + 1,
+                stop: f[2].parse().map_err(|_| bad_line())?,
+                strand: parse_strand(f.get(5).copied().unwrap_or(".")),
+            }
+        };
+        genes.push(gene);
+    }
+    Ok(genes)
+}
+
+/// CpG islands of a BED file as 1-based closed intervals, optionally restricted to one chromosome
+pub fn load_islands(path: &str, chr: Option<&str>) -> Result<Vec<(String, i64, i64)>, String> {
+    let mut islands = Vec::new();
+    for (n, line) in open_text(path)?.lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", path, e))?;
+        if is_comment(&line) {
+            continue;
+        }
+        let f: Vec<&str> = line.split('\t').collect();
+        if chr.is_some_and(|c| f[0] != c) {
+            continue;
+        }
+        let bad_line = || format!("{}: invalid line {}", path, n + 1);
+        if f.len() < 3 {
+            return Err(bad_line());
+        }
+        let start = f[1].parse::<i64>().map_err(|_| bad_line())? + 1;
+        let stop = f[2].parse().map_err(|_| bad_line())?;
+        islands.push((f[0].to_string(), start, stop));
+    }
+    Ok(islands)
+}
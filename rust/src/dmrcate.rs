@@ -3,19 +3,56 @@
 //
 // Reads probe-level beta values from HDF5, runs chromosome-chunked OLS → genome-wide
 // eBayes → regional kernel smoothing → DMR segmentation with proximity fallback.
+// The genome-wide per-probe statistics are cached in {cachedir}/dmrcate (see dmrcate_cache.rs),
+// so later region queries of the same case/control comparison skip the genome-wide fit.
+// The per-probe model is case vs control by default; "covariates", "pairs" or an explicit "design" with a
+// "contrast" adjust it (see dmrcate_design.rs). With "test":"diffvar" probes are tested for differential
+// variability and the regions are variably methylated regions (direction hypervariable/hypovariable); their effect
+// size cutoff is "min_delta_sd" on the difference of the group SDs instead of "min_delta_beta".
+// "scope":"genome" calls and ranks the DMRs of every chromosome; "export_prefix" writes BED, bedGraph of the
+// smoothed FDR and TSV files (see dmrcate_export.rs).
+// "method" selects the DMR caller: "dmrcate" kernel smoothing (default), "bumphunter" (region scope only) or "combp",
+// see dmrcate_callers.rs.
+// Optional preprocessing removes listed cross-reactive/SNP probes and sex chromosomes, corrects the type I/II probe
+// bias with BMIQ ("normalization":"bmiq"), and reports per-sample QC, see dmrcate_preprocess.rs.
+// "scope":"regions" returns the per-sample mean/median beta of a list of regions as a region × sample matrix for the
+// cluster binary; it needs no comparison and reads the probes by position, see dmrcate_aggregate.rs.
+// "tracks" renders the region as separate genome browser tracks (PNG and SVG, each with a legend), see
+// dmrcate_tracks.rs.
+// DMRs are annotated with genes (genedb or gene_annotation_file: BED/GTF), promoters and CpG island
+// context (cpg_island_file: BED) when these are given, see dmr_annotation.rs.
 // Usage: echo '{"probe_h5_file":"beta.h5","chr":"chr14","start":100000,"stop":105000,
 //              "case":"s1,s2","control":"s3,s4"}' | target/release/dmrcate
 
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use dmr_annotation::RegionAnnotation;
+use dmrcate_cache::{CachedProbe, CachedRegion, GenomeFit};
+use dmrcate_callers::BumpParams;
+use dmrcate_design::{Design, build_design};
+use dmrcate_export::SmoothedFdr;
+use dmrcate_preprocess::{PreprocessOptions, PreprocessSummary, Preprocessing, SampleSummary, TypeNormalization};
 use hdf5::File;
 use hdf5::types::VarLenUnicode;
 use serde_json::{Value, json};
 use statrs::distribution::{ContinuousCDF, StudentsT};
-use statrs::function::gamma::{digamma, gamma_ur, ln_gamma};
+use statrs::function::gamma::{gamma_ur, ln_gamma};
+use stats_functions::fit_f_dist;
 use std::collections::HashMap;
 use std::io;
 use std::time::Instant;
 use tiny_skia::{FillRule, Paint, PathBuilder, Pixmap, Stroke, StrokeDash, Transform};
+mod dmr_annotation;
+mod dmrcate_aggregate;
+mod dmrcate_cache;
+mod dmrcate_callers;
+mod dmrcate_design;
+mod dmrcate_export;
+mod dmrcate_preprocess;
+mod dmrcate_tracks;
+mod enrichment_plots;
+mod stats_functions;
+#[cfg(test)]
+mod test_dmrcate;
 
 fn get_rss_mb() -> f64 {
     unsafe {
@@ -32,47 +69,6 @@ fn get_rss_mb() -> f64 {
     }
 }
 
-fn trigamma(mut x: f64) -> f64 {
-    if x <= 0.0 {
-        return f64::NAN;
-    }
-    let mut r = 0.0;
-    while x < 6.0 {
-        r += 1.0 / (x * x);
-        x += 1.0;
-    }
-    let x2 = x * x;
-    r + 1.0 / x + 1.0 / (2.0 * x2) + 1.0 / (6.0 * x2 * x) - 1.0 / (30.0 * x2 * x2 * x) + 1.0 / (42.0 * x2 * x2 * x2 * x)
-}
-
-fn trigamma_deriv(mut x: f64) -> f64 {
-    let mut r = 0.0;
-    while x < 6.0 {
-        r -= 2.0 / (x * x * x);
-        x += 1.0;
-    }
-    let x2 = x * x;
-    r - 1.0 / x2 - 1.0 / (x2 * x) - 1.0 / (2.0 * x2 * x2) + 1.0 / (6.0 * x2 * x2 * x2)
-}
-
-fn trigamma_inverse(x: f64) -> f64 {
-    if x.is_nan() || x <= 0.0 {
-        return f64::NAN;
-    }
-    let mut y = if x > 1e-6 { 1.0 / x.sqrt() } else { 1.0 / x };
-    for _ in 0..8 {
-        let delta = (trigamma(y) - x) / trigamma_deriv(y);
-        y -= delta;
-        if y <= 0.0 {
-            y = 0.5 * (y + delta);
-        }
-        if delta.abs() < 1e-12 * y.abs() {
-            break;
-        }
-    }
-    y
-}
-
 fn bh_adjust(pvalues: &[f64]) -> Vec<f64> {
     let n = pvalues.len();
     if n == 0 {
@@ -92,14 +88,17 @@ fn bh_adjust(pvalues: &[f64]) -> Vec<f64> {
 struct ProbeStats {
     chr: String,
     start: i64,
-    probe_id: String,
+    row: usize,
     log_fc: f64,
     residual_var: f64,
     df_residual: f64,
     stdev_unscaled: f64,
 }
 
-fn read_h5_metadata(file: &File) -> Result<(Vec<String>, Vec<usize>, Vec<String>, Vec<i64>, Vec<String>), String> {
+/// Chromosome names, number of probes of each chromosome, sample names and probe start positions
+type H5Metadata = (Vec<String>, Vec<usize>, Vec<String>, Vec<i64>);
+
+fn read_h5_metadata(file: &File) -> Result<H5Metadata, String> {
     let samples: Vec<String> = file
         .dataset("meta/samples/names")
         .map_err(|e| e.to_string())?
@@ -114,14 +113,6 @@ fn read_h5_metadata(file: &File) -> Result<(Vec<String>, Vec<usize>, Vec<String>
         .read_1d::<i64>()
         .map_err(|e| e.to_string())?
         .to_vec();
-    let probes: Vec<String> = file
-        .dataset("meta/probe/probeID")
-        .map_err(|e| e.to_string())?
-        .read_1d::<VarLenUnicode>()
-        .map_err(|e| e.to_string())?
-        .iter()
-        .map(|s| s.to_string())
-        .collect();
     let root = file.group("/").map_err(|e| e.to_string())?;
     let cl_json: String = root
         .attr("chrom_lengths")
@@ -137,9 +128,77 @@ fn read_h5_metadata(file: &File) -> Result<(Vec<String>, Vec<usize>, Vec<String>
         names.push(k.to_string());
         lens.push(v.as_u64().unwrap_or(0) as usize);
     }
-    Ok((names, lens, samples, starts, probes))
+    Ok((names, lens, samples, starts))
 }
 
+/// Probe IDs (meta/probe/probeID), needed by the probe filters
+fn read_probe_ids(file: &File) -> Result<Vec<String>, String> {
+    Ok(file
+        .dataset("meta/probe/probeID")
+        .map_err(|e| format!("meta/probe/probeID: {}", e))?
+        .read_1d::<VarLenUnicode>()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(|s| s.to_string())
+        .collect())
+}
+
+/// Infinium design type of each probe (meta/probe/type), stored as 1/2 or as "I"/"II"
+fn read_probe_types(file: &File) -> Result<Vec<u8>, String> {
+    let ds = file
+        .dataset("meta/probe/type")
+        .map_err(|e| format!("meta/probe/type: {}", e))?;
+    if let Ok(types) = ds.read_1d::<u8>() {
+        return Ok(types.to_vec());
+    }
+    let names = ds.read_1d::<VarLenUnicode>().map_err(|e| e.to_string())?;
+    Ok(names
+        .iter()
+        .map(|t| match t.as_str().trim() {
+            "I" | "1" => 1,
+            "II" | "2" => 2,
+            _ => 0,
+        })
+        .collect())
+}
+
+/// Mean and sample standard deviation of the finite values of row at idx (NaN when there are too few values)
+fn group_mean_sd(row: &[f32], idx: &[usize]) -> (f64, f64) {
+    let values: Vec<f64> = idx
+        .iter()
+        .filter_map(|&si| row.get(si).map(|&v| v as f64))
+        .filter(|v| v.is_finite())
+        .collect();
+    let n = values.len() as f64;
+    if values.is_empty() {
+        return (f64::NAN, f64::NAN);
+    }
+    let mean = values.iter().sum::<f64>() / n;
+    let sd = if values.len() > 1 {
+        (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+    } else {
+        f64::NAN
+    };
+    (mean, sd)
+}
+
+/// M-values of the beta values of a probe, in the order of the design rows (NaN when missing)
+fn m_values(row: &[f32], design: &Design) -> Vec<f64> {
+    let to_m = |b: f64| {
+        let c = b.clamp(0.001, 0.999);
+        (c / (1.0 - c)).log2()
+    };
+    design
+        .samples
+        .iter()
+        .map(|&si| match row.get(si) {
+            Some(&v) if v.is_finite() => to_m(v as f64),
+            _ => f64::NAN,
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
 fn process_chromosome(
     file: &File,
     row_start: usize,
@@ -148,137 +207,518 @@ fn process_chromosome(
     ctrl_idx: &[usize],
     chr: &str,
     starts: &[i64],
-    probe_ids: &[String],
+    design: &Design,
     min_spg: usize,
+    prep: &Preprocessing,
+    mut summary: Option<&mut SampleSummary>,
 ) -> Result<Vec<ProbeStats>, String> {
     let n_probes = row_end - row_start;
     if n_probes == 0 {
         return Ok(vec![]);
     }
     let ds = file.dataset("beta/values").map_err(|e| format!("beta/values: {}", e))?;
+    let detection = match summary {
+        Some(_) => file.dataset("detection/pvalues").ok(),
+        None => None,
+    };
     let mut results = Vec::with_capacity(n_probes);
     const CHUNK: usize = 1000;
     for chunk_i in 0..((n_probes + CHUNK - 1) / CHUNK) {
         let cs = chunk_i * CHUNK;
         let ce = std::cmp::min(cs + CHUNK, n_probes);
-        let sel = hdf5::Selection::from((row_start + cs..row_start + ce, ..));
-        let data = ds
-            .read_slice_2d::<f32, _>(sel)
-            .map_err(|e| format!("HDF5 read: {}", e))?;
+        let read = |d: &hdf5::Dataset| {
+            d.read_slice_2d::<f32, _>(hdf5::Selection::from((row_start + cs..row_start + ce, ..)))
+                .map_err(|e| format!("HDF5 read: {}", e))
+        };
+        let data = read(&ds)?;
+        let det = detection.as_ref().map(read).transpose()?;
         for lp in 0..(ce - cs) {
-            let row = data.row(lp);
-            let (mut cv, mut kv) = (Vec::with_capacity(case_idx.len()), Vec::with_capacity(ctrl_idx.len()));
-            for &si in case_idx {
-                if si < row.len() {
-                    let v = row[si] as f64;
-                    if v.is_finite() {
-                        cv.push(v);
-                    }
-                }
-            }
-            for &si in ctrl_idx {
-                if si < row.len() {
-                    let v = row[si] as f64;
-                    if v.is_finite() {
-                        kv.push(v);
-                    }
-                }
-            }
-            if cv.len() < min_spg || kv.len() < min_spg {
+            let idx = row_start + cs + lp;
+            if prep.is_excluded(idx) {
                 continue;
             }
-            let to_m = |b: f64| {
-                let c = b.clamp(0.001, 0.999);
-                (c / (1.0 - c)).log2()
-            };
-            let cm: Vec<f64> = cv.iter().map(|&b| to_m(b)).collect();
-            let km: Vec<f64> = kv.iter().map(|&b| to_m(b)).collect();
-            let all: Vec<f64> = cm.iter().chain(km.iter()).copied().collect();
-            let mean_all = all.iter().sum::<f64>() / all.len() as f64;
-            let var = all.iter().map(|&x| (x - mean_all).powi(2)).sum::<f64>() / (all.len() as f64 - 1.0);
-            if var <= 0.0 || !var.is_finite() {
-                continue;
+            let mut row = data.row(lp).to_vec();
+            if let Some(summary) = summary.as_deref_mut() {
+                let det_row = det.as_ref().map(|d| d.row(lp).to_vec());
+                summary.add_row(&row, det_row.as_deref(), None);
             }
-            let (n1, n2) = (cm.len() as f64, km.len() as f64);
-            let (mc, mk) = (cm.iter().sum::<f64>() / n1, km.iter().sum::<f64>() / n2);
-            let ss: f64 =
-                cm.iter().map(|&x| (x - mc).powi(2)).sum::<f64>() + km.iter().map(|&x| (x - mk).powi(2)).sum::<f64>();
-            let df = n1 + n2 - 2.0;
-            let rv = ss / df;
-            if !rv.is_finite() || rv <= 0.0 {
+            prep.correct(idx, &mut row);
+            let observed = |idx: &[usize]| {
+                idx.iter()
+                    .filter(|&&si| row.get(si).is_some_and(|v| v.is_finite()))
+                    .count()
+            };
+            if observed(case_idx) < min_spg || observed(ctrl_idx) < min_spg {
                 continue;
             }
-            let su = (1.0 / n1 + 1.0 / n2).sqrt();
-            let idx = row_start + cs + lp;
+            let y = m_values(&row, design);
+            let fit = match design.fit(&y) {
+                Some(f) => f,
+                None => continue,
+            };
             results.push(ProbeStats {
                 chr: chr.to_string(),
                 start: starts[idx],
-                probe_id: probe_ids[idx].clone(),
-                log_fc: mc - mk,
-                residual_var: rv,
-                df_residual: df,
-                stdev_unscaled: su,
+                row: idx,
+                log_fc: fit.coefficient,
+                residual_var: fit.residual_var,
+                df_residual: fit.df_residual,
+                stdev_unscaled: fit.stdev_unscaled,
             });
         }
     }
     Ok(results)
 }
 
-fn fit_f_dist(vars: &[f64], dfs: &[f64]) -> (f64, f64) {
-    if vars.len() < 3 {
-        return (1.0, 0.0);
+/// Per-group mean and standard deviation of the beta values of probes (group 1 = control, group 2 = case)
+struct GroupStats {
+    mean1: Vec<f64>,
+    mean2: Vec<f64>,
+    sd1: Vec<f64>,
+    sd2: Vec<f64>,
+}
+
+const GENOME_BLOCK: u64 = 1000; // Rows per read when summarizing every probe of the genome
+const REGION_BLOCK: u64 = 20000; // Rows per read in region queries: the rows of a region are usually read at once
+
+/// Calls f with the index and the (corrected) beta values of every row of beta/values in rows, reading the rows in
+/// contiguous blocks of up to block rows. Stops at the first failed read.
+fn visit_rows(
+    ds: &hdf5::Dataset,
+    rows: &[u64],
+    prep: &Preprocessing,
+    block: u64,
+    mut f: impl FnMut(usize, &[f32]),
+) -> Result<(), String> {
+    let n = rows.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by_key(|&i| rows[i]);
+    let mut k = 0;
+    while k < n {
+        // Block of up to block rows starting at the next row not read yet
+        let first = rows[order[k]];
+        let last = (first + block).min(rows[order[n - 1]] + 1);
+        let sel = hdf5::Selection::from((first as usize..last as usize, ..));
+        let data = ds
+            .read_slice_2d::<f32, _>(sel)
+            .map_err(|e| format!("HDF5 read: {}", e))?;
+        while k < n && rows[order[k]] < last {
+            let i = order[k];
+            let mut row = data.row((rows[i] - first) as usize).to_vec();
+            prep.correct(rows[i] as usize, &mut row);
+            f(i, &row);
+            k += 1;
+        }
+    }
+    Ok(())
+}
+
+fn probe_rows(probes: &[CachedProbe]) -> Vec<u64> {
+    probes.iter().map(|p| p.row).collect()
+}
+
+impl GroupStats {
+    fn missing(n: usize) -> Self {
+        GroupStats {
+            mean1: vec![f64::NAN; n],
+            mean2: vec![f64::NAN; n],
+            sd1: vec![f64::NAN; n],
+            sd2: vec![f64::NAN; n],
+        }
+    }
+
+    fn set(&mut self, i: usize, row: &[f32], case_idx: &[usize], ctrl_idx: &[usize]) {
+        (self.mean1[i], self.sd1[i]) = group_mean_sd(row, ctrl_idx);
+        (self.mean2[i], self.sd2[i]) = group_mean_sd(row, case_idx);
+    }
+}
+
+/// Reads the beta values of the probes and summarizes them per group
+fn read_group_stats(
+    ds: Option<&hdf5::Dataset>,
+    probes: &[CachedProbe],
+    case_idx: &[usize],
+    ctrl_idx: &[usize],
+    prep: &Preprocessing,
+) -> GroupStats {
+    let mut stats = GroupStats::missing(probes.len());
+    if let Some(ds) = ds {
+        // Probes whose rows cannot be read keep missing summaries
+        let _ = visit_rows(ds, &probe_rows(probes), prep, GENOME_BLOCK, |i, row| {
+            stats.set(i, row, case_idx, ctrl_idx)
+        });
+    }
+    stats
+}
+
+/// Beta values of the probes of a region (None when they cannot be read). The probes of a region occupy a contiguous
+/// range of rows, which is read with a single hyperslab unless it exceeds REGION_BLOCK rows.
+fn read_region_rows(ds: Option<&hdf5::Dataset>, probes: &[CachedProbe], prep: &Preprocessing) -> Vec<Option<Vec<f32>>> {
+    let mut rows = vec![None; probes.len()];
+    if let Some(ds) = ds {
+        let _ = visit_rows(ds, &probe_rows(probes), prep, REGION_BLOCK, |i, row| {
+            rows[i] = Some(row.to_vec())
+        });
     }
-    // Match R's fitFDist pre-processing:
-    // 1. Filter to ok probes (finite df > 1e-15, finite var > -1e-15)
-    // 2. Clamp var to max(var, 0), then floor at 1e-5 * median(var)
-    let ok: Vec<usize> = (0..vars.len())
-        .filter(|&i| dfs[i].is_finite() && dfs[i] > 1e-15 && vars[i].is_finite() && vars[i] > -1e-15)
+    rows
+}
+
+/// Probe IDs of the probes, read from the range of rows they occupy
+fn read_region_probe_ids(file: &File, probes: &[CachedProbe]) -> Option<Vec<String>> {
+    let first = probes.iter().map(|p| p.row).min()? as usize;
+    let last = probes.iter().map(|p| p.row).max()? as usize;
+    let ids = file
+        .dataset("meta/probe/probeID")
+        .ok()?
+        .read_slice_1d::<VarLenUnicode, _>(first..last + 1)
+        .ok()?;
+    Some(probes.iter().map(|p| ids[p.row as usize - first].to_string()).collect())
+}
+
+/// Fitted responses of the probes (M-values, or their deviations for diffvar) in the order of the design rows
+fn responses(rows: &[Option<Vec<f32>>], design: &Design) -> Result<Vec<Vec<f64>>, String> {
+    rows.iter()
+        .map(|row| {
+            row.as_ref()
+                .map(|row| design.response(&m_values(row, design)))
+                .ok_or_else(|| "Cannot read the beta values of the region".to_string())
+        })
+        .collect()
+}
+
+/// Per-probe statistics of one chromosome (or region) used to call DMRs
+struct ChromosomeProbes {
+    chr: String,
+    pos: Vec<i64>,
+    t: Vec<f64>,
+    p: Vec<f64>,
+    fdr: Vec<f64>,
+    lfc: Vec<f64>,
+    g1: Vec<f64>, // Control and case summaries whose difference gives maxdiff/meandiff
+    g2: Vec<f64>,
+}
+
+impl ChromosomeProbes {
+    fn new(chr: &str, probes: &[CachedProbe], stats: GroupStats, test: &str) -> Self {
+        // Variably methylated regions are segmented on the difference of the group standard deviations
+        let (g1, g2) = if test == "diffvar" {
+            (stats.sd1, stats.sd2)
+        } else {
+            (stats.mean1, stats.mean2)
+        };
+        ChromosomeProbes {
+            chr: chr.to_string(),
+            pos: probes.iter().map(|p| p.start).collect(),
+            t: probes.iter().map(|p| p.t).collect(),
+            p: probes.iter().map(|p| p.raw_p).collect(),
+            fdr: probes.iter().map(|p| p.adj_p).collect(),
+            lfc: probes.iter().map(|p| p.log_fc).collect(),
+            g1,
+            g2,
+        }
+    }
+}
+
+struct CallParams {
+    lambda: f64,
+    c_param: f64,
+    fdr_cut: f64,
+    min_db: f64, // Cutoff on |g2 - g1|: the difference of the group means, or of their SDs for diffvar
+}
+
+/// Kernel smoothing of each chromosome, BH adjustment of the smoothed p-values over all the chromosomes, then DMR
+/// segmentation per chromosome. Returns the DMRs and the smoothed FDR of the probes of each chromosome.
+fn call_dmrs(chromosomes: &[ChromosomeProbes], params: &CallParams) -> (Vec<Value>, Vec<Vec<f64>>) {
+    // Kernel smoothing in log space to avoid underflow for extreme t-statistics
+    let log_smoothed: Vec<f64> = chromosomes
+        .iter()
+        .flat_map(|c| kernel_smooth_log(&c.pos, &c.t, params.lambda, params.c_param))
         .collect();
-    if ok.len() < 3 {
-        return (1.0, 0.0);
-    }
-    let mut xv: Vec<f64> = ok.iter().map(|&i| vars[i].max(0.0)).collect();
-    let xdf: Vec<f64> = ok.iter().map(|&i| dfs[i]).collect();
-    // Median of variances
-    let mut sorted_v = xv.clone();
-    sorted_v.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
-    let median_v = if sorted_v.len() % 2 == 0 {
-        (sorted_v[sorted_v.len() / 2 - 1] + sorted_v[sorted_v.len() / 2]) / 2.0
+    let log_sfdr = bh_adjust_log(&log_smoothed);
+    // Adaptive threshold matching R's dmrcate(): select the same NUMBER of CpGs
+    // as are per-CpG significant, but ranked by smoothed FDR instead.
+    // Work in log space so extreme p-values maintain proper ordering.
+    let nsig = chromosomes
+        .iter()
+        .flat_map(|c| c.fdr.iter())
+        .filter(|&&f| f < params.fdr_cut)
+        .count();
+    let adaptive_log_cut = if nsig > 0 && nsig <= log_sfdr.len() {
+        let mut sorted_log: Vec<f64> = log_sfdr.clone();
+        sorted_log.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        sorted_log[nsig - 1] // nsig-th smallest log FDR (most negative = most significant)
     } else {
-        sorted_v[sorted_v.len() / 2]
+        params.fdr_cut.ln()
     };
-    if median_v == 0.0 {
-        return (1.0, f64::INFINITY);
+
+    let mut dmrs = Vec::new();
+    let mut sfdr_per_chr = Vec::with_capacity(chromosomes.len());
+    let mut offset = 0;
+    for c in chromosomes {
+        let log_sfdr = &log_sfdr[offset..offset + c.pos.len()];
+        offset += c.pos.len();
+        // Convert log FDR to linear for diagnostic output and Sig. CpGs track
+        let sfdr: Vec<f64> = log_sfdr.iter().map(|&v| v.exp()).collect();
+        // Build sig_fdr: probes with log_sfdr <= adaptive_log_cut get 0 (significant), others get 1
+        let sig_fdr: Vec<f64> = log_sfdr
+            .iter()
+            .map(|&v| if v <= adaptive_log_cut { 0.0 } else { 1.0 })
+            .collect();
+        let mut chr_dmrs = build_dmrs(
+            &c.chr,
+            &c.pos,
+            &sig_fdr,
+            &c.lfc,
+            &c.g1,
+            &c.g2,
+            0.5,
+            params.lambda,
+            2,
+            None,
+            false,
+        );
+        for dmr in &mut chr_dmrs {
+            if let (Some(s), Some(e)) = (dmr["start"].as_i64(), dmr["stop"].as_i64()) {
+                let min_sfdr = c
+                    .pos
+                    .iter()
+                    .zip(sfdr.iter())
+                    .filter(|(&p, _)| p >= s && p <= e)
+                    .map(|(_, &f)| f)
+                    .fold(f64::INFINITY, f64::min);
+                dmr["min_smoothed_fdr"] = json!(min_sfdr);
+            }
+        }
+        dmrs.extend(chr_dmrs);
+        sfdr_per_chr.push(sfdr);
     }
-    // Floor small variances at 1e-5 * median (matches R's fitFDist)
-    let floor = 1e-5 * median_v;
-    for v in &mut xv {
-        if *v < floor {
-            *v = floor;
+    // Proximity fallback on the per-CpG FDR when kernel smoothing finds no DMR
+    if dmrs.is_empty() {
+        for c in chromosomes {
+            dmrs.extend(build_dmrs(
+                &c.chr,
+                &c.pos,
+                &c.fdr,
+                &c.lfc,
+                &c.g1,
+                &c.g2,
+                params.fdr_cut,
+                params.lambda,
+                2,
+                Some(params.min_db),
+                true,
+            ));
         }
     }
-    let n = xv.len() as f64;
-    // e = log(var) + logmdigamma(df/2) where logmdigamma(a) = log(a) - digamma(a)
-    let e: Vec<f64> = xv
-        .iter()
-        .zip(xdf.iter())
-        .map(|(&v, &d)| v.ln() + (d / 2.0).ln() - digamma(d / 2.0))
-        .collect();
-    let me = e.iter().sum::<f64>() / n;
-    let ve = e.iter().map(|&ei| (ei - me).powi(2)).sum::<f64>() / (n - 1.0);
-    let mean_tri: f64 = xdf.iter().map(|&d| trigamma(d / 2.0)).sum::<f64>() / n;
-    let target = ve - mean_tri;
-    let df0 = if target > 0.0 {
-        2.0 * trigamma_inverse(target)
+    (dmrs, sfdr_per_chr)
+}
+
+fn label_variable_regions(dmrs: &mut [Value]) {
+    for dmr in dmrs {
+        let direction = if dmr["direction"] == "hyper" {
+            "hypervariable"
+        } else {
+            "hypovariable"
+        };
+        dmr["direction"] = json!(direction);
+    }
+}
+
+/// Gene and CpG island annotation of the DMRs, when an annotation source is given. chr restricts the loaded
+/// annotation to one chromosome.
+fn annotate_dmrs(p: &Value, chr: Option<&str>, dmrs: &mut [Value]) -> Result<(), String> {
+    let genedb = p["genedb"].as_str();
+    let gene_file = p["gene_annotation_file"].as_str();
+    let island_file = p["cpg_island_file"].as_str();
+    if genedb.is_none() && gene_file.is_none() && island_file.is_none() {
+        return Ok(());
+    }
+    let genes = match (gene_file, genedb) {
+        (Some(f), _) => dmr_annotation::load_gene_file(f, chr)?,
+        (None, Some(db)) => dmr_annotation::load_genedb_genes(db, chr)?,
+        (None, None) => vec![],
+    };
+    let islands = island_file.map(|f| dmr_annotation::load_islands(f, chr)).transpose()?;
+    let upstream = p["promoter_upstream"].as_i64().unwrap_or(1500);
+    let downstream = p["promoter_downstream"].as_i64().unwrap_or(500);
+    let annotation = RegionAnnotation::new(genes, islands, upstream, downstream);
+    for dmr in dmrs {
+        annotation.annotate(dmr);
+    }
+    Ok(())
+}
+
+/// Preprocessing pass over the rows of beta/values that are not excluded, for the type I/II correction whose BMIQ
+/// fits are needed before the genome-wide fit: summaries of the samples of the given columns by probe type, and QC
+fn summarize_samples(
+    file: &File,
+    columns: &[usize],
+    excluded: &[bool],
+    types: &[u8],
+    detection_cutoff: f64,
+) -> Result<SampleSummary, String> {
+    const CHUNK: usize = 1000;
+    let ds = file.dataset("beta/values").map_err(|e| format!("beta/values: {}", e))?;
+    let detection = file.dataset("detection/pvalues").ok();
+    let mut summary = SampleSummary::new(columns, true, detection_cutoff);
+    let n = excluded.len();
+    for cs in (0..n).step_by(CHUNK) {
+        let ce = (cs + CHUNK).min(n);
+        let read = |d: &hdf5::Dataset| {
+            d.read_slice_2d::<f32, _>(hdf5::Selection::from((cs..ce, ..)))
+                .map_err(|e| format!("HDF5 read: {}", e))
+        };
+        let data = read(&ds)?;
+        let det = detection.as_ref().map(read).transpose()?;
+        for lp in 0..(ce - cs) {
+            if excluded[cs + lp] {
+                continue;
+            }
+            let det_row = det.as_ref().map(|d| d.row(lp).to_vec());
+            summary.add_row(&data.row(lp).to_vec(), det_row.as_deref(), types.get(cs + lp).copied());
+        }
+    }
+    Ok(summary)
+}
+
+/// "scope":"regions": region × sample matrix of the aggregated betas of the probes of each region, for the cluster
+/// binary (see dmrcate_aggregate.rs). The probes are found by position and read directly, with the probe filters and
+/// the type I/II correction when requested, but without the genome-wide fit that only a comparison needs.
+fn aggregate_regions(
+    p: &Value,
+    file: &File,
+    metadata: &H5Metadata,
+    regions: &[dmrcate_aggregate::Region],
+    aggregate: dmrcate_aggregate::Aggregate,
+) -> Result<Value, String> {
+    let (chr_names, chr_lens, sample_names, starts) = metadata;
+    let (columns, groups) = dmrcate_aggregate::select_samples(p, sample_names)?;
+    let options = PreprocessOptions::from_input(p)?;
+    let probe_ids = if options.needs_probe_ids() {
+        Some(read_probe_ids(file)?)
     } else {
-        f64::INFINITY
+        None
     };
-    let s20 = if df0.is_finite() {
-        (me - (df0 / 2.0).ln() + digamma(df0 / 2.0)).exp()
+    let (excluded, removed) = options.excluded_rows(chr_names, chr_lens, probe_ids.as_deref());
+    let normalization = if options.bmiq {
+        let types = read_probe_types(file).map_err(|e| format!("Type I/II correction needs the probe types: {}", e))?;
+        let (_, fits) = summarize_samples(file, &columns, &excluded, &types, options.detection_cutoff)?.finish();
+        fits.map(|fits| TypeNormalization { types, fits })
     } else {
-        xv.iter().sum::<f64>() / n
+        None
     };
-    (s20, df0)
+    let prep = Preprocessing {
+        excluded,
+        normalization,
+    };
+    let ds = file.dataset("beta/values").map_err(|e| format!("beta/values: {}", e))?;
+    let index = dmrcate_aggregate::ProbeIndex::new(chr_names, chr_lens, starts);
+    let mut values = Vec::with_capacity(regions.len());
+    let mut num_probes = Vec::with_capacity(regions.len());
+    for region in regions {
+        let rows: Vec<u64> = index
+            .rows(region)
+            .into_iter()
+            .filter(|&row| !prep.is_excluded(row as usize))
+            .collect();
+        let mut betas = vec![None; rows.len()];
+        visit_rows(&ds, &rows, &prep, REGION_BLOCK, |i, row| betas[i] = Some(row.to_vec()))?;
+        values.push(dmrcate_aggregate::aggregate_rows(&betas, &columns, aggregate));
+        num_probes.push(rows.len());
+    }
+    let samples: Vec<&str> = columns.iter().map(|&c| sample_names[c].as_str()).collect();
+    let mut output =
+        dmrcate_aggregate::region_matrix(regions, &values, &num_probes, &samples, groups.as_deref(), aggregate);
+    output["diagnostic"] = json!({
+        "scope": "regions",
+        "preprocessing": {
+            "normalization": if options.bmiq { "bmiq" } else { "none" },
+            "corrected_samples": prep.normalization.as_ref().map_or(0, |n| n.fits.len()),
+            "removed_probes": removed,
+        },
+    });
+    Ok(output)
+}
+
+/// Genome-wide OLS + eBayes: per-probe moderated t statistics, raw p-values and BH-adjusted p-values over all
+/// chromosomes. When summary is given, the rows that are not excluded are also added to it, before their correction.
+#[allow(clippy::too_many_arguments)]
+fn fit_genome(
+    file: &File,
+    chr_names: &[String],
+    chr_lens: &[usize],
+    starts: &[i64],
+    case_idx: &[usize],
+    ctrl_idx: &[usize],
+    design: &Design,
+    min_spg: usize,
+    prep: &Preprocessing,
+    mut summary: Option<&mut SampleSummary>,
+) -> Result<GenomeFit, String> {
+    let mut all: Vec<ProbeStats> = Vec::new();
+    let mut pfx = 0usize;
+    for (i, &cl) in chr_lens.iter().enumerate() {
+        if cl == 0 {
+            continue;
+        }
+        if let Ok(s) = process_chromosome(
+            file,
+            pfx,
+            pfx + cl,
+            case_idx,
+            ctrl_idx,
+            &chr_names[i],
+            starts,
+            design,
+            min_spg,
+            prep,
+            summary.as_deref_mut(),
+        ) {
+            all.extend(s);
+        }
+        pfx += cl;
+    }
+    if all.len() < 3 {
+        return Err(format!("Too few probes after filtering ({})", all.len()));
+    }
+
+    let all_vars: Vec<f64> = all.iter().map(|s| s.residual_var).collect();
+    let all_dfs: Vec<f64> = all.iter().map(|s| s.df_residual).collect();
+    let (s20, df0) = fit_f_dist(&all_vars, &all_dfs);
+    let mut mod_t = Vec::with_capacity(all.len());
+    let mut raw_p = Vec::with_capacity(all.len());
+    for s in &all {
+        let s2p = if df0.is_finite() {
+            (df0 * s20 + s.df_residual * s.residual_var) / (df0 + s.df_residual)
+        } else {
+            s.residual_var
+        };
+        let t = s.log_fc / (s2p.sqrt() * s.stdev_unscaled);
+        let df_tot = s.df_residual + df0;
+        let tdist = StudentsT::new(0.0, 1.0, df_tot).unwrap_or_else(|_| StudentsT::new(0.0, 1.0, 100.0).unwrap());
+        mod_t.push(t);
+        raw_p.push(2.0 * tdist.sf(t.abs()));
+    }
+    let adj_p = bh_adjust(&raw_p);
+
+    let mut chromosomes: Vec<(String, Vec<CachedProbe>)> = Vec::new();
+    for (i, s) in all.iter().enumerate() {
+        if chromosomes.last().is_none_or(|(chr, _)| *chr != s.chr) {
+            chromosomes.push((s.chr.clone(), Vec::new()));
+        }
+        chromosomes.last_mut().unwrap().1.push(CachedProbe {
+            row: s.row as u64,
+            start: s.start,
+            log_fc: s.log_fc,
+            t: mod_t[i],
+            raw_p: raw_p[i],
+            adj_p: adj_p[i],
+        });
+    }
+    for (_, probes) in &mut chromosomes {
+        probes.sort_by_key(|p| p.start);
+    }
+    Ok(GenomeFit { s20, df0, chromosomes })
 }
 
 /// Log of the upper regularized incomplete gamma function Q(a, x).
@@ -446,24 +886,27 @@ fn build_dmrs(
     groups
         .iter()
         .filter(|g| g.len() >= min_cpgs)
-        .map(|g| {
-            let deltas: Vec<f64> = g.iter().map(|&j| mg2[j] - mg1[j]).collect();
-            let fdrs: Vec<f64> = g.iter().map(|&j| fdr[j]).collect();
-            let md = deltas.iter().sum::<f64>() / deltas.len() as f64;
-            let mxd = if md >= 0.0 {
-                deltas.iter().cloned().fold(f64::NEG_INFINITY, f64::max)
-            } else {
-                deltas.iter().cloned().fold(f64::INFINITY, f64::min)
-            };
-            json!({ "chr": chr, "start": pos[*g.first().unwrap()], "stop": pos[*g.last().unwrap()],
-            "no_cpgs": g.len(), "min_smoothed_fdr": fdrs.iter().cloned().fold(f64::INFINITY, f64::min),
-            "HMFDR": fdrs.len() as f64 / fdrs.iter().map(|&f| 1.0/f.max(1e-300)).sum::<f64>(),
-            "maxdiff": mxd, "meandiff": md, "direction": if md >= 0.0 {"hyper"} else {"hypo"},
-            "overlapping_genes": null })
-        })
+        .map(|g| dmr_json(chr, pos, g, fdr, mg1, mg2))
         .collect()
 }
 
+/// DMR made of the probes g (sorted indexes into pos), in the JSON shape shared by all the DMR callers
+fn dmr_json(chr: &str, pos: &[i64], g: &[usize], fdr: &[f64], mg1: &[f64], mg2: &[f64]) -> Value {
+    let deltas: Vec<f64> = g.iter().map(|&j| mg2[j] - mg1[j]).collect();
+    let fdrs: Vec<f64> = g.iter().map(|&j| fdr[j]).collect();
+    let md = deltas.iter().sum::<f64>() / deltas.len() as f64;
+    let mxd = if md >= 0.0 {
+        deltas.iter().cloned().fold(f64::NEG_INFINITY, f64::max)
+    } else {
+        deltas.iter().cloned().fold(f64::INFINITY, f64::min)
+    };
+    json!({ "chr": chr, "start": pos[*g.first().unwrap()], "stop": pos[*g.last().unwrap()],
+    "no_cpgs": g.len(), "min_smoothed_fdr": fdrs.iter().cloned().fold(f64::INFINITY, f64::min),
+    "HMFDR": fdrs.len() as f64 / fdrs.iter().map(|&f| 1.0/f.max(1e-300)).sum::<f64>(),
+    "maxdiff": mxd, "meandiff": md, "direction": if md >= 0.0 {"hyper"} else {"hypo"},
+    "overlapping_genes": null })
+}
+
 macro_rules! bail { ($($t:tt)*) => { { println!("{}", json!({"error": format!($($t)*)})); return; } } }
 
 /// LOESS (locally weighted scatterplot smoothing) with tricube weights and local linear fit.
@@ -751,7 +1194,10 @@ fn main() {
     let block_width = p["blockWidth"].as_u64().unwrap_or(800) as u32;
     let device_pixel_ratio = p["devicePixelRatio"].as_f64().unwrap_or(1.0) as f32;
     let max_loess_region = p["maxLoessRegion"].as_f64().unwrap_or(50000.0);
-    let track_height = 150u32;
+    let track_height = p["trackHeight"].as_u64().unwrap_or(150) as u32;
+    if track_height == 0 {
+        bail!("trackHeight must be > 0");
+    }
     let mut render_colors: HashMap<String, String> = HashMap::new();
     if let Some(obj) = p["colors"].as_object() {
         for (k, v) in obj {
@@ -761,7 +1207,30 @@ fn main() {
         }
     }
 
-    if h5_path.is_empty() || qchr.is_empty() || cases.is_empty() || ctrls.is_empty() {
+    // "region" calls DMRs in chr:start-stop, "genome" on every chromosome, "regions" aggregates the betas of a list
+    // of regions
+    let scope = p["scope"].as_str().unwrap_or("region");
+    if scope != "region" && scope != "genome" && scope != "regions" {
+        bail!("Unknown scope: {} (expected region, genome or regions)", scope);
+    }
+    let (regions, aggregate) = if scope == "regions" {
+        let regions = match dmrcate_aggregate::read_regions(&p) {
+            Ok(r) => r,
+            Err(e) => bail!("{}", e),
+        };
+        match dmrcate_aggregate::Aggregate::from_input(p["aggregate"].as_str()) {
+            Ok(a) => (regions, a),
+            Err(e) => bail!("{}", e),
+        }
+    } else {
+        (Vec::new(), dmrcate_aggregate::Aggregate::Mean)
+    };
+    // Aggregating regions needs no comparison
+    let needs_groups = scope != "regions";
+    if h5_path.is_empty()
+        || (scope == "region" && qchr.is_empty())
+        || (needs_groups && (cases.is_empty() || ctrls.is_empty()))
+    {
         bail!("Missing required parameters");
     }
 
@@ -769,169 +1238,381 @@ fn main() {
         Ok(f) => f,
         Err(e) => bail!("HDF5 open: {}", e),
     };
-    let (chr_names, chr_lens, sample_names, starts, probe_ids) = match read_h5_metadata(&file) {
+    let metadata = match read_h5_metadata(&file) {
         Ok(m) => m,
         Err(e) => bail!("{}", e),
     };
+    if scope == "regions" {
+        let mut output = match aggregate_regions(&p, &file, &metadata, &regions, aggregate) {
+            Ok(o) => o,
+            Err(e) => bail!("{}", e),
+        };
+        let rss_peak = get_rss_mb();
+        output["diagnostic"]["peak_memory_mb"] = json!((rss_peak * 10.0).round() / 10.0);
+        output["diagnostic"]["start_memory_mb"] = json!((rss_start * 10.0).round() / 10.0);
+        output["diagnostic"]["elapsed_ms"] = json!(t0.elapsed().as_millis());
+        println!("{}", output);
+        return;
+    }
+    let (chr_names, chr_lens, sample_names, starts) = metadata;
     let smap: HashMap<&str, usize> = sample_names.iter().enumerate().map(|(i, s)| (s.as_str(), i)).collect();
     let ci: Vec<usize> = cases.iter().filter_map(|s| smap.get(s).copied()).collect();
     let ki: Vec<usize> = ctrls.iter().filter_map(|s| smap.get(s).copied()).collect();
     if ci.len() < min_spg || ki.len() < min_spg {
         bail!("Not enough samples: case={}, control={}", ci.len(), ki.len());
     }
-    let mut all: Vec<ProbeStats> = Vec::new();
-    let mut pfx = 0usize;
-    for (i, &cl) in chr_lens.iter().enumerate() {
-        if cl == 0 {
-            pfx += cl;
-            continue;
-        }
-        match process_chromosome(
-            &file,
-            pfx,
-            pfx + cl,
-            &ci,
-            &ki,
-            &chr_names[i],
-            &starts,
-            &probe_ids,
-            min_spg,
-        ) {
-            Ok(s) => all.extend(s),
-            Err(_e) => {}
-        }
-        pfx += cl;
+    let case_names: Vec<&str> = cases.iter().copied().filter(|s| smap.contains_key(s)).collect();
+    let ctrl_names: Vec<&str> = ctrls.iter().copied().filter(|s| smap.contains_key(s)).collect();
+    let design = match build_design(&p, &smap, &case_names, &ctrl_names) {
+        Ok(d) => d,
+        Err(e) => bail!("{}", e),
+    };
+    // "diffmean" tests methylation differences, "diffvar" tests variability differences. The effect size cutoff of
+    // diffvar is on the difference of the group SDs, which is not on the scale of a mean difference: it has its own
+    // parameter, with no cutoff by default.
+    let test = p["test"].as_str().unwrap_or("diffmean");
+    let min_db = if test == "diffvar" {
+        p["min_delta_sd"].as_f64().unwrap_or(0.0)
+    } else {
+        min_db
+    };
+    let design = match test {
+        "diffmean" => design,
+        "diffvar" => match design.with_deviation_groups(&ci, &ki) {
+            Ok(d) => d,
+            Err(e) => bail!("{}", e),
+        },
+        _ => bail!("Unknown test: {} (expected diffmean or diffvar)", test),
+    };
+    // DMR caller: DMRCate kernel smoothing, bumphunter or comb-p (see dmrcate_callers.rs)
+    let method = p["method"].as_str().unwrap_or("dmrcate");
+    match method {
+        "dmrcate" | "combp" => {}
+        // Permutations refit every probe, which is only feasible for the probes of a region
+        "bumphunter" if scope == "region" => {}
+        "bumphunter" => bail!("bumphunter is only available for region queries"),
+        _ => bail!("Unknown method: {} (expected dmrcate, bumphunter or combp)", method),
     }
-    if all.len() < 3 {
-        bail!("Too few probes after filtering ({})", all.len());
+    let bump_params = BumpParams {
+        lambda,
+        c_param,
+        cutoff: p["bump_cutoff"].as_f64(),
+        num_permutations: p["num_permutations"].as_u64().unwrap_or(100) as usize,
+        seed: p["seed"].as_u64().unwrap_or(1),
+    };
+    if method == "bumphunter" && bump_params.num_permutations == 0 {
+        bail!("bumphunter requires num_permutations > 0");
     }
-
-    let all_vars: Vec<f64> = all.iter().map(|s| s.residual_var).collect();
-    let all_dfs: Vec<f64> = all.iter().map(|s| s.df_residual).collect();
-    let (s20, df0) = fit_f_dist(&all_vars, &all_dfs);
-    let mut mod_t = Vec::with_capacity(all.len());
-    let mut raw_p = Vec::with_capacity(all.len());
-    for s in &all {
-        let s2p = if df0.is_finite() {
-            (df0 * s20 + s.df_residual * s.residual_var) / (df0 + s.df_residual)
-        } else {
-            s.residual_var
-        };
-        let t = s.log_fc / (s2p.sqrt() * s.stdev_unscaled);
-        let df_tot = s.df_residual + df0;
-        let tdist = StudentsT::new(0.0, 1.0, df_tot).unwrap_or_else(|_| StudentsT::new(0.0, 1.0, 100.0).unwrap());
-        mod_t.push(t);
-        raw_p.push(2.0 * tdist.sf(t.abs()));
+    let combp_step = p["combp_step"].as_f64().unwrap_or(50.0);
+    if combp_step <= 0.0 {
+        bail!("combp_step must be positive");
     }
-    let adj_p = bh_adjust(&raw_p);
+    let track_names = match dmrcate_tracks::requested_tracks(&p["tracks"]) {
+        Ok(t) => t,
+        Err(e) => bail!("{}", e),
+    };
 
-    let ri: Vec<usize> = (0..all.len())
-        .filter(|&i| all[i].chr == qchr && all[i].start >= qstart && all[i].start <= qstop)
-        .collect();
-    if ri.is_empty() {
-        println!(
-            "{}",
-            json!({"dmrs":[],"diagnostic":{"probes":{"positions":[],"mean_group1":[],"mean_group2":[],"fdr":[],"logFC":[]},"probe_spacings":[]}})
-        );
-        return;
-    }
-    let rpos: Vec<i64> = ri.iter().map(|&i| all[i].start).collect();
-    let rt: Vec<f64> = ri.iter().map(|&i| mod_t[i]).collect();
-    let rfdr: Vec<f64> = ri.iter().map(|&i| adj_p[i]).collect();
-    let rlfc: Vec<f64> = ri.iter().map(|&i| all[i].log_fc).collect();
+    let options = match PreprocessOptions::from_input(&p) {
+        Ok(o) => o,
+        Err(e) => bail!("{}", e),
+    };
+    let types = if options.bmiq {
+        match read_probe_types(&file) {
+            Ok(t) => Some(t),
+            Err(e) => bail!("Type I/II correction needs the probe types: {}", e),
+        }
+    } else {
+        None
+    };
 
-    let (mut mg1, mut mg2) = (Vec::new(), Vec::new());
-    let ds = file.dataset("beta/values").ok();
-    for &idx in &ri {
-        let pid = &all[idx].probe_id;
-        if let (Some(abs), Some(ref d)) = (probe_ids.iter().position(|p| p == pid), &ds) {
-            let sel = hdf5::Selection::from((abs..abs + 1, ..));
-            if let Ok(r2d) = d.read_slice_2d::<f32, _>(sel) {
-                let row = r2d.into_raw_vec_and_offset().0;
-                let (mut cs, mut cc, mut ks, mut kc) = (0.0, 0, 0.0, 0);
-                for &si in &ki {
-                    if si < row.len() {
-                        let v = row[si] as f64;
-                        if v.is_finite() {
-                            ks += v;
-                            kc += 1;
-                        }
-                    }
+    // The genome-wide fit is shared by all region queries of a comparison, so it is read from the cache when possible,
+    // together with the summary of the preprocessing pass
+    let model_key = format!("{}|{}", design.key(), options.key());
+    let cache_file = dmrcate_cache::cache_path(&dmrcate_dir, h5_path, &ci, &ki, min_spg, &model_key);
+    let summary_file = dmrcate_preprocess::summary_path(&cache_file);
+    let cached_summary = if dmrcate_cache::is_current(&cache_file, h5_path) {
+        dmrcate_preprocess::read_summary(&summary_file)
+    } else {
+        None
+    };
+    let from_cache = cached_summary.is_some();
+    // A cache that cannot be written or evicted only makes later queries slower, so the error is reported in the
+    // diagnostic block instead of failing the query
+    let mut cache_error: Option<String> = None;
+    let (fit, summary, prep) = match cached_summary {
+        Some(mut summary) => {
+            let normalization = summary
+                .bmiq_fits
+                .take()
+                .zip(types)
+                .map(|(fits, types)| TypeNormalization { types, fits });
+            let prep = Preprocessing {
+                excluded: vec![],
+                normalization,
+            };
+            (None, summary, prep)
+        }
+        None => {
+            let probe_ids = if options.needs_probe_ids() {
+                match read_probe_ids(&file) {
+                    Ok(ids) => Some(ids),
+                    Err(e) => bail!("{}", e),
                 }
-                for &si in &ci {
-                    if si < row.len() {
-                        let v = row[si] as f64;
-                        if v.is_finite() {
-                            cs += v;
-                            cc += 1;
+            } else {
+                None
+            };
+            let (excluded, removed) = options.excluded_rows(&chr_names, &chr_lens, probe_ids.as_deref());
+            // The BMIQ fits of the type I/II correction are needed before the fit, so they take a separate pass
+            // over beta/values. Otherwise the sample QC is summarized while fitting.
+            let (prepass_qc, fits) = match &types {
+                Some(types) => {
+                    match summarize_samples(&file, &design.samples, &excluded, types, options.detection_cutoff) {
+                        Ok(s) => {
+                            let (qc, fits) = s.finish();
+                            (Some(qc), fits)
                         }
+                        Err(e) => bail!("{}", e),
                     }
                 }
-                mg1.push(if kc > 0 { ks / kc as f64 } else { f64::NAN });
-                mg2.push(if cc > 0 { cs / cc as f64 } else { f64::NAN });
-                continue;
+                None => (None, None),
+            };
+            let mut fit_summary = match prepass_qc {
+                Some(_) => None,
+                None => Some(SampleSummary::new(&design.samples, false, options.detection_cutoff)),
+            };
+            let prep = Preprocessing {
+                excluded,
+                normalization: fits
+                    .clone()
+                    .zip(types)
+                    .map(|(fits, types)| TypeNormalization { types, fits }),
+            };
+            let fit = match fit_genome(
+                &file,
+                &chr_names,
+                &chr_lens,
+                &starts,
+                &ci,
+                &ki,
+                &design,
+                min_spg,
+                &prep,
+                fit_summary.as_mut(),
+            ) {
+                Ok(fit) => fit,
+                Err(e) => bail!("{}", e),
+            };
+            let qc = prepass_qc
+                .or_else(|| fit_summary.map(|s| s.finish().0))
+                .unwrap_or_default();
+            let summary = PreprocessSummary {
+                removed,
+                qc,
+                bmiq_fits: fits,
+            };
+            let written = dmrcate_cache::write_cache(&cache_file, h5_path, &fit)
+                .and_then(|_| dmrcate_preprocess::write_summary(&summary_file, &summary))
+                .and_then(|_| {
+                    dmrcate_cache::evict(
+                        &dmrcate_dir,
+                        &cache_file,
+                        dmrcate_cache::MAX_CACHE_AGE,
+                        dmrcate_cache::MAX_CACHE_BYTES,
+                    )
+                });
+            if let Err(e) = written {
+                cache_error = Some(e);
             }
+            (Some(fit), summary, prep)
         }
-        mg1.push(f64::NAN);
-        mg2.push(f64::NAN);
-    }
-
-    // Kernel smoothing in log space to avoid underflow for extreme t-statistics
-    let log_smoothed = kernel_smooth_log(&rpos, &rt, lambda, c_param);
-    let log_sfdr = bh_adjust_log(&log_smoothed);
-    // Convert log FDR to linear for diagnostic output and Sig. CpGs track
-    let sfdr: Vec<f64> = log_sfdr.iter().map(|&v| v.exp()).collect();
-    // Adaptive threshold matching R's dmrcate(): select the same NUMBER of CpGs
-    // as are per-CpG significant, but ranked by smoothed FDR instead.
-    // Work in log space so extreme p-values maintain proper ordering.
-    let nsig = rfdr.iter().filter(|&&f| f < fdr_cut).count();
-    let adaptive_log_cut = if nsig > 0 && nsig <= log_sfdr.len() {
-        let mut sorted_log: Vec<f64> = log_sfdr.clone();
-        sorted_log.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
-        sorted_log[nsig - 1] // nsig-th smallest log FDR (most negative = most significant)
-    } else {
-        fdr_cut.ln()
     };
-    // Build sig_fdr: probes with log_sfdr <= adaptive_log_cut get 0 (significant), others get 1
-    let sig_fdr: Vec<f64> = log_sfdr
+    let preprocessing = json!({
+        "normalization": if options.bmiq { "bmiq" } else { "none" },
+        "corrected_samples": prep.normalization.as_ref().map_or(0, |n| n.fits.len()),
+        "removed_probes": summary.removed,
+    });
+    let sample_qc: Vec<Value> = summary
+        .qc
         .iter()
-        .map(|&v| if v <= adaptive_log_cut { 0.0 } else { 1.0 })
+        .map(|q| {
+            json!({ "sample": sample_names[q.column], "median_beta": q.median_beta,
+                "detection_failed_fraction": q.detection_failed_fraction })
+        })
         .collect();
-    let mut dmrs = build_dmrs(qchr, &rpos, &sig_fdr, &rlfc, &mg1, &mg2, 0.5, lambda, 2, None, false);
-    for dmr in &mut dmrs {
-        if let (Some(s), Some(e)) = (dmr["start"].as_i64(), dmr["stop"].as_i64()) {
-            let min_sfdr = rpos
-                .iter()
-                .zip(sfdr.iter())
-                .filter(|(&p, _)| p >= s && p <= e)
-                .map(|(_, &f)| f)
-                .fold(f64::INFINITY, f64::min);
-            dmr["min_smoothed_fdr"] = json!(min_sfdr);
+    // Probes of a region, from the in-memory fit when it was just computed, otherwise from the cache
+    let query = |chr: &str, start: i64, stop: i64| -> Result<CachedRegion, String> {
+        match &fit {
+            Some(fit) => Ok(fit.region(chr, start, stop)),
+            None => dmrcate_cache::read_region(&cache_file, h5_path, chr, start, stop)?
+                .ok_or_else(|| "The cached genome-wide fit was modified during the query".to_string()),
+        }
+    };
+    let ds = file.dataset("beta/values").ok();
+    let params = CallParams {
+        lambda,
+        c_param,
+        fdr_cut,
+        min_db,
+    };
+    let export_prefix = p["export_prefix"].as_str();
+
+    if scope == "genome" {
+        // DMRs of every chromosome, ranked by smoothed FDR
+        let mut inputs: Vec<ChromosomeProbes> = Vec::new();
+        let mut total_probes = 0;
+        let (mut s20, mut df0) = (f64::NAN, f64::NAN);
+        for chr in &chr_names {
+            let region = match query(chr, i64::MIN, i64::MAX) {
+                Ok(r) => r,
+                Err(e) => bail!("{}", e),
+            };
+            total_probes = region.total_probes;
+            s20 = region.s20;
+            df0 = region.df0;
+            if region.probes.len() < 2 {
+                continue;
+            }
+            let stats = read_group_stats(ds.as_ref(), &region.probes, &ci, &ki, &prep);
+            inputs.push(ChromosomeProbes::new(chr, &region.probes, stats, test));
+        }
+        let (mut dmrs, sfdr) = if method == "combp" {
+            dmrcate_callers::combp(&inputs, lambda, combp_step, fdr_cut)
+        } else {
+            call_dmrs(&inputs, &params)
+        };
+        if test == "diffvar" {
+            label_variable_regions(&mut dmrs);
+        }
+        if let Err(e) = annotate_dmrs(&p, None, &mut dmrs) {
+            bail!("{}", e);
         }
+        dmrs.sort_by(|a, b| {
+            let key = |d: &Value| {
+                (
+                    d["min_smoothed_fdr"].as_f64().unwrap_or(1.0),
+                    d["HMFDR"].as_f64().unwrap_or(1.0),
+                )
+            };
+            key(a).partial_cmp(&key(b)).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        for (i, dmr) in dmrs.iter_mut().enumerate() {
+            dmr["rank"] = json!(i + 1);
+        }
+        let exported = match export_prefix {
+            Some(prefix) => {
+                let smoothed: Vec<SmoothedFdr> = inputs
+                    .iter()
+                    .zip(&sfdr)
+                    .map(|(c, fdr)| SmoothedFdr {
+                        chr: &c.chr,
+                        positions: &c.pos,
+                        fdr,
+                    })
+                    .collect();
+                match dmrcate_export::export_results(prefix, &dmrs, &smoothed) {
+                    Ok(files) => files,
+                    Err(e) => bail!("{}", e),
+                }
+            }
+            None => Value::Null,
+        };
+        let rss_peak = get_rss_mb();
+        println!(
+            "{}",
+            json!({
+                "dmrs": dmrs,
+                "exported_files": exported,
+                "diagnostic": {
+                    "scope": "genome",
+                    "test": test,
+                    "method": method,
+                    "chromosomes_analyzed": inputs.len(),
+                    "total_probes_analyzed": total_probes,
+                    "prior_var": s20,
+                    "prior_df": if df0.is_finite() { json!(df0) } else { Value::Null },
+                    "from_cache": from_cache,
+                    "cache_error": cache_error,
+                    "preprocessing": preprocessing,
+                    "sample_qc": sample_qc,
+                    "design": { "columns": design.columns, "contrast": design.contrast.as_slice(),
+                        "df_residual": design.df_residual() },
+                    "peak_memory_mb": (rss_peak * 10.0).round() / 10.0,
+                    "start_memory_mb": (rss_start * 10.0).round() / 10.0,
+                    "elapsed_ms": t0.elapsed().as_millis() }
+            })
+        );
+        return;
     }
-    if dmrs.is_empty() {
-        dmrs = build_dmrs(
-            qchr,
-            &rpos,
-            &rfdr,
-            &rlfc,
-            &mg1,
-            &mg2,
-            fdr_cut,
-            lambda,
-            2,
-            Some(min_db),
-            true,
+
+    let region = match query(qchr, qstart, qstop) {
+        Ok(r) => r,
+        Err(e) => bail!("{}", e),
+    };
+    if region.probes.is_empty() {
+        println!(
+            "{}",
+            json!({"dmrs":[],"diagnostic":{"probes":{"positions":[],"mean_group1":[],"mean_group2":[],"fdr":[],"logFC":[]},"probe_spacings":[],
+                "preprocessing": preprocessing, "sample_qc": sample_qc,
+                "cache_error": cache_error}})
         );
+        return;
+    }
+    // Per-group mean and standard deviation of the beta values of each probe (group 1 = control, group 2 = case)
+    let rows = read_region_rows(ds.as_ref(), &region.probes, &prep);
+    let mut stats = GroupStats::missing(rows.len());
+    for (i, row) in rows.iter().enumerate() {
+        if let Some(row) = row {
+            stats.set(i, row, &ci, &ki);
+        }
     }
+    let (mg1, mg2, sd1, sd2) = (
+        stats.mean1.clone(),
+        stats.mean2.clone(),
+        stats.sd1.clone(),
+        stats.sd2.clone(),
+    );
+    let input = ChromosomeProbes::new(qchr, &region.probes, stats, test);
+    let (mut dmrs, sfdr) = match method {
+        "bumphunter" => {
+            let responses = match responses(&rows, &design) {
+                Ok(r) => r,
+                Err(e) => bail!("{}", e),
+            };
+            let bumps = dmrcate_callers::bumphunter(&input, &responses, &design, &bump_params);
+            (bumps, vec![input.fdr.clone()])
+        }
+        "combp" => dmrcate_callers::combp(std::slice::from_ref(&input), lambda, combp_step, fdr_cut),
+        _ => call_dmrs(std::slice::from_ref(&input), &params),
+    };
+    let (rpos, rfdr, rlfc) = (&input.pos, &input.fdr, &input.lfc);
+    if test == "diffvar" {
+        label_variable_regions(&mut dmrs);
+    }
+    if let Err(e) = annotate_dmrs(&p, Some(qchr), &mut dmrs) {
+        bail!("{}", e);
+    }
+    let exported = match export_prefix {
+        Some(prefix) => {
+            let smoothed = [SmoothedFdr {
+                chr: qchr,
+                positions: rpos,
+                fdr: &sfdr[0],
+            }];
+            match dmrcate_export::export_results(prefix, &dmrs, &smoothed) {
+                Ok(files) => files,
+                Err(e) => bail!("{}", e),
+            }
+        }
+        None => Value::Null,
+    };
 
     // LOESS curves for both groups
     let n_eval = 200usize;
     let eval_pos: Vec<f64> = (0..n_eval)
         .map(|i| qstart as f64 + (qstop as f64 - qstart as f64) * i as f64 / (n_eval - 1) as f64)
         .collect();
-    let loess_g1 = loess_fit(&rpos, &mg1, &eval_pos, 0.75);
-    let loess_g2 = loess_fit(&rpos, &mg2, &eval_pos, 0.75);
+    let loess_g1 = loess_fit(rpos, &mg1, &eval_pos, 0.75);
+    let loess_g2 = loess_fit(rpos, &mg2, &eval_pos, 0.75);
     let loess_json = json!({
         "positions": eval_pos.iter().map(|&x| x.round() as i64).collect::<Vec<_>>(),
         "group1_fitted": loess_g1.as_ref().map_or(vec![], |l| l.0.clone()),
@@ -944,10 +1625,10 @@ fn main() {
 
     // Render the complete track as a transparent PNG
     let track_png = render_track_png(
-        &rpos,
+        rpos,
         &mg1,
         &mg2,
-        &rfdr,
+        rfdr,
         &dmrs,
         &loess_g1,
         &loess_g2,
@@ -971,6 +1652,68 @@ fn main() {
             Value::Null
         }
     };
+    // Beta value of every probe in every case/control sample, for the sample-level heatmap (opt-in, as it holds
+    // probes × samples values)
+    let sample_betas = if p["include_sample_betas"].as_bool().unwrap_or(false) {
+        let columns: Vec<usize> = ki.iter().chain(&ci).copied().collect();
+        let beta: Vec<Vec<Value>> = rows
+            .iter()
+            .map(|row| {
+                columns
+                    .iter()
+                    .map(|&c| {
+                        row.as_ref()
+                            .and_then(|r| r.get(c))
+                            .map_or(Value::Null, |&v| r4(v as f64))
+                    })
+                    .collect()
+            })
+            .collect();
+        json!({
+            "samples": columns.iter().map(|&c| &sample_names[c]).collect::<Vec<_>>(),
+            "groups": ki.iter().map(|_| 1).chain(ci.iter().map(|_| 2)).collect::<Vec<_>>(),
+            "probe_ids": read_region_probe_ids(&file, &region.probes),
+            "beta": beta,
+        })
+    } else {
+        Value::Null
+    };
+    let tracks = if track_names.is_empty() {
+        Value::Null
+    } else {
+        let data = dmrcate_tracks::RegionTracks {
+            positions: rpos,
+            mean_group1: &mg1,
+            mean_group2: &mg2,
+            fdr: rfdr,
+            smoothed_fdr: &sfdr[0],
+            dmrs: &dmrs,
+            loess: [&loess_g1, &loess_g2],
+            eval_positions: &eval_pos,
+            rows: &rows,
+            controls: &ki,
+            cases: &ci,
+        };
+        let options = dmrcate_tracks::TrackOptions {
+            start: qstart as f64,
+            stop: qstop as f64,
+            width: block_width,
+            track_height,
+            device_pixel_ratio,
+            fdr_cutoff: fdr_cut,
+            max_loess_region,
+            colors: &render_colors,
+            group_names: [
+                p["group1Name"].as_str().unwrap_or("Control"),
+                p["group2Name"].as_str().unwrap_or("Case"),
+            ],
+            variability: test == "diffvar",
+        };
+        match dmrcate_tracks::render_tracks(&track_names, &data, &options) {
+            Ok(t) => json!(t),
+            Err(e) => bail!("{}", e),
+        }
+    };
     let spacings: Vec<i64> = if rpos.len() > 1 {
         rpos.windows(2).map(|w| w[1] - w[0]).collect()
     } else {
@@ -980,17 +1723,32 @@ fn main() {
         "{}",
         json!({
             "dmrs": dmrs,
+            "exported_files": exported,
             "diagnostic": { "probes": { "positions": rpos,
                 "mean_group1": mg1.iter().map(|&v| r4(v)).collect::<Vec<_>>(),
                 "mean_group2": mg2.iter().map(|&v| r4(v)).collect::<Vec<_>>(),
-                "fdr": rfdr, "logFC": rlfc.iter().map(|&v| r4(v)).collect::<Vec<_>>() },
+                "fdr": rfdr, "logFC": rlfc.iter().map(|&v| r4(v)).collect::<Vec<_>>(),
+                "sd_group1": sd1.iter().map(|&v| r4(v)).collect::<Vec<_>>(),
+                "sd_group2": sd2.iter().map(|&v| r4(v)).collect::<Vec<_>>() },
+                "test": test,
+                "method": method,
                 "loess": loess_json,
                 "probe_spacings": spacings,
-                "total_probes_analyzed": all.len(),
+                "total_probes_analyzed": region.total_probes,
+                "prior_var": region.s20,
+                "prior_df": r4(region.df0),
+                "from_cache": from_cache,
+                "cache_error": cache_error,
+                "preprocessing": preprocessing,
+                "sample_qc": sample_qc,
+                "design": { "columns": design.columns, "contrast": design.contrast.as_slice(),
+                    "df_residual": design.df_residual() },
                 "peak_memory_mb": (rss_peak * 10.0).round() / 10.0,
                 "start_memory_mb": (rss_start * 10.0).round() / 10.0,
                 "elapsed_ms": elapsed_ms,
-                "track_png": track_png }
+                "sample_betas": sample_betas,
+                "track_png": track_png,
+                "tracks": tracks }
         })
     );
 }
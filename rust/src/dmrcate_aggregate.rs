@@ -0,0 +1,244 @@
+// dmrcate_aggregate.rs — Per-sample methylation of a list of regions ("scope":"regions")
+//
+// The regions are the DMRs of a previous call ("regions": [{chr, start, stop, name?}, ...], 1-based closed like the
+// probe positions) or the lines of a BED file ("regions_file", e.g. promoters; 0-based starts are converted, the 4th
+// column names the region). Each region gets the mean or median ("aggregate") beta value of its probes in every
+// sample, skipping missing values. No comparison is needed: the probes are found by position in meta/start, without
+// the genome-wide fit, and every probe of a region is used whatever its number of observed samples. The samples are
+// "samples" (comma-separated, default all the samples of the file), or the controls then the cases when "control"
+// and "case" are given to label the columns by group.
+// The output is the region × sample matrix in the input format of the cluster binary (see cluster.rs): "matrix",
+// comma-separated "row_names" and "col_names", and with case/control a "Group" column annotation. Regions without a
+// value in every sample (no probes, or all of their probes missing in a sample) cannot be clustered and are listed in
+// "dropped_regions" instead.
+
+use crate::dmr_annotation::open_text;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+pub struct Region {
+    pub chr: String,
+    pub start: i64,
+    pub stop: i64,
+    pub name: String,
+}
+
+impl Region {
+    fn new(chr: &str, start: i64, stop: i64, name: Option<&str>) -> Region {
+        // Names are joined with commas in row_names
+        let name = match name {
+            Some(n) if !n.is_empty() && n != "." => n.replace(',', ";"),
+            _ => format!("{}:{}-{}", chr, start, stop),
+        };
+        Region {
+            chr: chr.to_string(),
+            start,
+            stop,
+            name,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Aggregate {
+    Mean,
+    Median,
+}
+
+impl Aggregate {
+    pub fn from_input(aggregate: Option<&str>) -> Result<Aggregate, String> {
+        match aggregate.unwrap_or("mean") {
+            "mean" => Ok(Aggregate::Mean),
+            "median" => Ok(Aggregate::Median),
+            a => Err(format!("Unknown aggregate: {} (expected mean or median)", a)),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Aggregate::Mean => "mean",
+            Aggregate::Median => "median",
+        }
+    }
+}
+
+/// Regions of the "regions" input (e.g. the dmrs of a previous call) or of the BED file "regions_file"
+pub fn read_regions(p: &Value) -> Result<Vec<Region>, String> {
+    let regions = match (p["regions"].as_array(), p["regions_file"].as_str()) {
+        (Some(list), None) => list
+            .iter()
+            .map(|r| match (r["chr"].as_str(), r["start"].as_i64(), r["stop"].as_i64()) {
+                (Some(chr), Some(start), Some(stop)) => Ok(Region::new(chr, start, stop, r["name"].as_str())),
+                _ => Err(format!("Invalid region (expected chr, start and stop): {}", r)),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        (None, Some(path)) => load_bed_regions(path)?,
+        (Some(_), Some(_)) => return Err("Give either regions or regions_file, not both".to_string()),
+        (None, None) => return Err("The regions scope needs regions or regions_file".to_string()),
+    };
+    if regions.is_empty() {
+        return Err("No regions to aggregate".to_string());
+    }
+    Ok(regions)
+}
+
+fn load_bed_regions(path: &str) -> Result<Vec<Region>, String> {
+    let mut regions = Vec::new();
+    for (n, line) in open_text(path)?.lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", path, e))?;
+        if line.is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+            continue;
+        }
+        let f: Vec<&str> = line.split('\t').collect();
+        let bad_line = || format!("{}: invalid line {}", path, n + 1);
+        if f.len() < 3 {
+            return Err(bad_line());
+        }
+        let start = f[1].parse::<i64>().map_err(|_| bad_line())? + 1;
+        let stop = f[2].parse().map_err(|_| bad_line())?;
+        regions.push(Region::new(f[0], start, stop, f.get(3).copied()));
+    }
+    Ok(regions)
+}
+
+/// Samples of the regions matrix: columns of beta/values and, when they are the controls and cases of a comparison,
+/// the group of each column
+pub fn select_samples(p: &Value, sample_names: &[String]) -> Result<(Vec<usize>, Option<Vec<String>>), String> {
+    let names = |key: &str| -> Vec<&str> {
+        p[key]
+            .as_str()
+            .unwrap_or("")
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .collect()
+    };
+    let index: HashMap<&str, usize> = sample_names.iter().enumerate().map(|(i, s)| (s.as_str(), i)).collect();
+    let columns_of = |list: &[&str]| -> Vec<usize> { list.iter().filter_map(|s| index.get(s).copied()).collect() };
+    let (samples, cases, ctrls) = (names("samples"), names("case"), names("control"));
+    let (columns, groups) = match (samples.is_empty(), cases.is_empty() && ctrls.is_empty()) {
+        (false, false) => return Err("Give either samples or case and control, not both".to_string()),
+        (true, false) => {
+            let (ci, ki) = (columns_of(&cases), columns_of(&ctrls));
+            let group_names = [
+                p["group1Name"].as_str().unwrap_or("Control"),
+                p["group2Name"].as_str().unwrap_or("Case"),
+            ];
+            let groups = ki
+                .iter()
+                .map(|_| group_names[0].to_string())
+                .chain(ci.iter().map(|_| group_names[1].to_string()))
+                .collect();
+            (ki.into_iter().chain(ci).collect(), Some(groups))
+        }
+        (false, true) => (columns_of(&samples), None),
+        (true, true) => ((0..sample_names.len()).collect(), None),
+    };
+    if columns.is_empty() {
+        return Err("None of the samples is in the beta values file".to_string());
+    }
+    Ok((columns, groups))
+}
+
+/// Rows of beta/values by position: the (position, row) pairs of each chromosome, sorted by position
+pub struct ProbeIndex {
+    chromosomes: HashMap<String, Vec<(i64, u64)>>,
+}
+
+impl ProbeIndex {
+    /// chr_lens gives the number of consecutive rows of each chromosome and starts the position of every row
+    pub fn new(chr_names: &[String], chr_lens: &[usize], starts: &[i64]) -> ProbeIndex {
+        let mut chromosomes = HashMap::new();
+        let mut row = 0;
+        for (name, &len) in chr_names.iter().zip(chr_lens) {
+            let end = (row + len).min(starts.len());
+            let mut probes: Vec<(i64, u64)> = (row..end).map(|r| (starts[r], r as u64)).collect();
+            probes.sort_unstable();
+            chromosomes.insert(name.clone(), probes);
+            row += len;
+        }
+        ProbeIndex { chromosomes }
+    }
+
+    /// Rows of the probes within the region, in position order
+    pub fn rows(&self, region: &Region) -> Vec<u64> {
+        let Some(probes) = self.chromosomes.get(&region.chr) else {
+            return vec![];
+        };
+        let first = probes.partition_point(|&(pos, _)| pos < region.start);
+        let last = probes.partition_point(|&(pos, _)| pos <= region.stop);
+        probes[first..last.max(first)].iter().map(|&(_, row)| row).collect()
+    }
+}
+
+/// Mean or median of the finite beta values of the probes (rows) in each sample (column), NaN when there is none
+pub fn aggregate_rows(rows: &[Option<Vec<f32>>], columns: &[usize], how: Aggregate) -> Vec<f64> {
+    columns
+        .iter()
+        .map(|&c| {
+            let mut values: Vec<f64> = rows
+                .iter()
+                .filter_map(|row| row.as_ref().and_then(|r| r.get(c)))
+                .map(|&v| v as f64)
+                .filter(|v| v.is_finite())
+                .collect();
+            let n = values.len();
+            if n == 0 {
+                return f64::NAN;
+            }
+            match how {
+                Aggregate::Mean => values.iter().sum::<f64>() / n as f64,
+                Aggregate::Median => {
+                    values.sort_by(|a, b| a.total_cmp(b));
+                    if n % 2 == 1 {
+                        values[n / 2]
+                    } else {
+                        (values[n / 2 - 1] + values[n / 2]) / 2.0
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+/// Region × sample matrix of the regions with a value in every sample, for the cluster binary, and the other regions
+/// in dropped_regions. values[r] holds the aggregated betas of region r in the samples, num_probes[r] its probe count.
+/// groups labels the samples in a "Group" column annotation.
+pub fn region_matrix(
+    regions: &[Region],
+    values: &[Vec<f64>],
+    num_probes: &[usize],
+    samples: &[&str],
+    groups: Option<&[String]>,
+    how: Aggregate,
+) -> Value {
+    let mut matrix = Vec::new();
+    let (mut kept, mut dropped) = (Vec::new(), Vec::new());
+    for ((region, row), &n) in regions.iter().zip(values).zip(num_probes) {
+        let info = json!({ "name": region.name, "chr": region.chr, "start": region.start, "stop": region.stop,
+            "no_cpgs": n });
+        if n > 0 && row.iter().all(|v| v.is_finite()) {
+            matrix.push(
+                row.iter()
+                    .map(|v| (v * 10000.0).round() / 10000.0)
+                    .collect::<Vec<f64>>(),
+            );
+            kept.push(info);
+        } else {
+            dropped.push(info);
+        }
+    }
+    let row_names: Vec<&str> = kept.iter().map(|r| r["name"].as_str().unwrap_or("")).collect();
+    let mut output = json!({
+        "matrix": matrix,
+        "row_names": row_names.join(","),
+        "col_names": samples.iter().map(|s| s.replace(',', ";")).collect::<Vec<_>>().join(","),
+        "aggregate": how.name(),
+        "regions": kept,
+        "dropped_regions": dropped,
+    });
+    if let Some(groups) = groups {
+        output["col_annotations"] = json!([{ "name": "Group", "values": groups }]);
+    }
+    output
+}
@@ -0,0 +1,350 @@
+// dmrcate_cache.rs — On-disk cache of the genome-wide per-probe moderated statistics of dmrcate
+//
+// The genome-wide OLS + eBayes fit only depends on the HDF5 file, the case/control samples and the probe filter, so
+// it is computed once per comparison and every region query of that comparison reads its window from the cache.
+// The cache file is named by a hash of these inputs and records the size and modification time of the HDF5 file;
+// a cache written for an older version of the HDF5 file is ignored and rebuilt. The hash is a 64-bit FNV-1a, which
+// gives the same file name in every build and Rust version, unlike the std DefaultHasher.
+// After a cache is written, caches older than MAX_CACHE_AGE are removed, then the oldest caches until the directory
+// holds at most MAX_CACHE_BYTES.
+//
+// Layout (little endian):
+//   header:      magic "DMRC", version (u32), HDF5 size (u64), HDF5 mtime (i64 s, u32 ns), s20 (f64), df0 (f64),
+//                number of probes (u64), number of chromosomes (u32)
+//   chromosomes: name length (u32), name (UTF-8), index of the first record (u64), number of records (u64)
+//   records:     fixed-size probe records, grouped by chromosome and sorted by position within each chromosome
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 4] = b"DMRC";
+const VERSION: u32 = 1;
+const RECORD_SIZE: u64 = 48; // row (u64), start (i64), log_fc, t, raw_p, adj_p (f64)
+pub const MAX_CACHE_AGE: Duration = Duration::from_secs(30 * 24 * 3600); // Caches are rebuilt after 30 days
+pub const MAX_CACHE_BYTES: u64 = 10 << 30; // 10 GiB, about 250 comparisons of an EPIC array
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Moderated statistics of one probe that passed the filters of the genome-wide fit
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedProbe {
+    pub row: u64, // Row of the probe in beta/values
+    pub start: i64,
+    pub log_fc: f64,
+    pub t: f64,
+    pub raw_p: f64,
+    pub adj_p: f64, // BH-adjusted over the whole genome
+}
+
+/// Result of the genome-wide fit: eBayes prior and the probes of each chromosome, sorted by position
+pub struct GenomeFit {
+    pub s20: f64,
+    pub df0: f64,
+    pub chromosomes: Vec<(String, Vec<CachedProbe>)>,
+}
+
+impl GenomeFit {
+    /// Probes of chr:start-stop (inclusive), like read_region on the cache of this fit
+    pub fn region(&self, chr: &str, start: i64, stop: i64) -> CachedRegion {
+        let probes = self
+            .chromosomes
+            .iter()
+            .filter(|(name, _)| name == chr)
+            .flat_map(|(_, probes)| probes.iter())
+            .filter(|p| p.start >= start && p.start <= stop)
+            .cloned()
+            .collect();
+        CachedRegion {
+            probes,
+            total_probes: self.chromosomes.iter().map(|(_, probes)| probes.len()).sum(),
+            s20: self.s20,
+            df0: self.df0,
+        }
+    }
+}
+
+/// Probes of a region query, together with genome-wide summaries of the fit
+#[derive(Debug)]
+pub struct CachedRegion {
+    pub probes: Vec<CachedProbe>,
+    pub total_probes: usize,
+    pub s20: f64,
+    pub df0: f64,
+}
+
+struct Header {
+    total_probes: usize,
+    s20: f64,
+    df0: f64,
+    chromosomes: Vec<(String, u64, u64)>, // name, first record, number of records
+    data_offset: u64,
+}
+
+/// Path of the cache file for a comparison. Sample indexes are sorted, so the order of the samples in the request
+/// does not matter. design_key identifies the linear model, the contrast and the preprocessing of the beta values.
+pub fn cache_path(
+    cache_dir: &str,
+    h5_path: &str,
+    case_idx: &[usize],
+    ctrl_idx: &[usize],
+    min_spg: usize,
+    design_key: &str,
+) -> String {
+    let mut case_sorted = case_idx.to_vec();
+    case_sorted.sort_unstable();
+    let mut ctrl_sorted = ctrl_idx.to_vec();
+    ctrl_sorted.sort_unstable();
+    let canonical = fs::canonicalize(h5_path).map_or_else(|_| h5_path.to_string(), |p| p.display().to_string());
+    // Variable length fields are prefixed by their length so that different inputs never give the same bytes
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    for text in [canonical.as_str(), design_key] {
+        bytes.extend_from_slice(&(text.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(text.as_bytes());
+    }
+    for indexes in [&case_sorted, &ctrl_sorted] {
+        bytes.extend_from_slice(&(indexes.len() as u64).to_le_bytes());
+        for &i in indexes.iter() {
+            bytes.extend_from_slice(&(i as u64).to_le_bytes());
+        }
+    }
+    bytes.extend_from_slice(&(min_spg as u64).to_le_bytes());
+    format!("{}/{:016x}.bin", cache_dir, fnv1a(&bytes))
+}
+
+/// 64-bit FNV-1a hash
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(FNV_OFFSET_BASIS, |hash, &b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// Removes the caches of cache_dir written more than max_age ago, then the oldest caches until the remaining ones
+/// take at most max_bytes. A cache is its .bin file with the files named after it (preprocessing summary, temporary
+/// files); the cache at `keep` is never removed. Returns the number of caches removed.
+pub fn evict(cache_dir: &str, keep: &str, max_age: Duration, max_bytes: u64) -> Result<usize, String> {
+    let keep_name = Path::new(keep).file_name().and_then(|n| n.to_str()).unwrap_or("");
+    // Files, total size and modification time of every cache, by name of its .bin file
+    let mut caches: HashMap<String, (Vec<PathBuf>, u64, SystemTime)> = HashMap::new();
+    for entry in fs::read_dir(cache_dir).map_err(|e| format!("{}: {}", cache_dir, e))? {
+        let entry = entry.map_err(|e| format!("{}: {}", cache_dir, e))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(end) = name.find(".bin") else {
+            continue;
+        };
+        let meta = entry.metadata().map_err(|e| format!("{}: {}", name, e))?;
+        let modified = meta.modified().map_err(|e| format!("{}: {}", name, e))?;
+        let cache = caches
+            .entry(name[..end + 4].to_string())
+            .or_insert((Vec::new(), 0, UNIX_EPOCH));
+        cache.0.push(entry.path());
+        cache.1 += meta.len();
+        cache.2 = cache.2.max(modified);
+    }
+    let kept_bytes = caches.get(keep_name).map_or(0, |c| c.1);
+    let mut candidates: Vec<(Vec<PathBuf>, u64, SystemTime)> = caches
+        .into_iter()
+        .filter(|(name, _)| name != keep_name)
+        .map(|(_, cache)| cache)
+        .collect();
+    candidates.sort_by_key(|(_, _, modified)| std::cmp::Reverse(*modified)); // Newest first
+    let now = SystemTime::now();
+    let mut total = kept_bytes;
+    let mut removed = 0;
+    for (files, size, modified) in candidates {
+        let age = now.duration_since(modified).unwrap_or(Duration::ZERO);
+        if age <= max_age && total + size <= max_bytes {
+            total += size;
+            continue;
+        }
+        for file in files {
+            match fs::remove_file(&file) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {} // Removed by a concurrent query
+                Err(e) => return Err(format!("{}: {}", file.display(), e)),
+            }
+        }
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+// Size and modification time of the HDF5 file, used to invalidate the cache
+fn h5_fingerprint(h5_path: &str) -> Result<(u64, i64, u32), String> {
+    let meta = fs::metadata(h5_path).map_err(|e| format!("{}: {}", h5_path, e))?;
+    let mtime = meta.modified().map_err(|e| e.to_string())?;
+    let since_epoch = mtime.duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?;
+    Ok((meta.len(), since_epoch.as_secs() as i64, since_epoch.subsec_nanos()))
+}
+
+/// Writes the genome-wide statistics to the cache file. The file is written under a temporary name and renamed, so
+/// concurrent queries never read a partially written cache.
+pub fn write_cache(path: &str, h5_path: &str, fit: &GenomeFit) -> Result<(), String> {
+    let chromosomes = &fit.chromosomes;
+    let (size, secs, nanos) = h5_fingerprint(h5_path)?;
+    let tmp_path = format!("{}.tmp{}", path, std::process::id());
+    let file = File::create(&tmp_path).map_err(|e| format!("{}: {}", tmp_path, e))?;
+    let mut w = BufWriter::new(file);
+    let total: usize = chromosomes.iter().map(|(_, probes)| probes.len()).sum();
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&size.to_le_bytes());
+    bytes.extend_from_slice(&secs.to_le_bytes());
+    bytes.extend_from_slice(&nanos.to_le_bytes());
+    bytes.extend_from_slice(&fit.s20.to_le_bytes());
+    bytes.extend_from_slice(&fit.df0.to_le_bytes());
+    bytes.extend_from_slice(&(total as u64).to_le_bytes());
+    bytes.extend_from_slice(&(chromosomes.len() as u32).to_le_bytes());
+    let mut first = 0u64;
+    for (name, probes) in chromosomes {
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&first.to_le_bytes());
+        bytes.extend_from_slice(&(probes.len() as u64).to_le_bytes());
+        first += probes.len() as u64;
+    }
+    let write_all = |w: &mut BufWriter<File>, b: &[u8]| w.write_all(b).map_err(|e| e.to_string());
+    write_all(&mut w, &bytes)?;
+    for (_, probes) in chromosomes {
+        for p in probes {
+            let mut record = [0u8; RECORD_SIZE as usize];
+            record[0..8].copy_from_slice(&p.row.to_le_bytes());
+            record[8..16].copy_from_slice(&p.start.to_le_bytes());
+            record[16..24].copy_from_slice(&p.log_fc.to_le_bytes());
+            record[24..32].copy_from_slice(&p.t.to_le_bytes());
+            record[32..40].copy_from_slice(&p.raw_p.to_le_bytes());
+            record[40..48].copy_from_slice(&p.adj_p.to_le_bytes());
+            write_all(&mut w, &record)?;
+        }
+    }
+    w.flush().map_err(|e| e.to_string())?;
+    drop(w);
+    fs::rename(&tmp_path, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp_path);
+        format!("{}: {}", path, e)
+    })
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32, String> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b).map_err(|e| e.to_string())?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64, String> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b).map_err(|e| e.to_string())?;
+    Ok(u64::from_le_bytes(b))
+}
+
+fn read_header(r: &mut impl Read, h5_path: &str) -> Result<Option<Header>, String> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic).map_err(|e| e.to_string())?;
+    if &magic != MAGIC || read_u32(r)? != VERSION {
+        return Ok(None);
+    }
+    let (size, secs, nanos) = h5_fingerprint(h5_path)?;
+    if read_u64(r)? != size || read_u64(r)? as i64 != secs || read_u32(r)? != nanos {
+        return Ok(None); // HDF5 file changed since the cache was written
+    }
+    let s20 = f64::from_bits(read_u64(r)?);
+    let df0 = f64::from_bits(read_u64(r)?);
+    let total_probes = read_u64(r)? as usize;
+    let n_chr = read_u32(r)?;
+    let mut data_offset = 4 + 4 + 8 + 8 + 4 + 8 + 8 + 8 + 4;
+    let mut chromosomes = Vec::with_capacity(n_chr as usize);
+    for _ in 0..n_chr {
+        let len = read_u32(r)?;
+        let mut name = vec![0u8; len as usize];
+        r.read_exact(&mut name).map_err(|e| e.to_string())?;
+        let name = String::from_utf8(name).map_err(|e| e.to_string())?;
+        chromosomes.push((name, read_u64(r)?, read_u64(r)?));
+        data_offset += 4 + len as u64 + 16;
+    }
+    Ok(Some(Header {
+        total_probes,
+        s20,
+        df0,
+        chromosomes,
+        data_offset,
+    }))
+}
+
+/// True when the cache file exists and was written for the current version of the HDF5 file
+pub fn is_current(path: &str, h5_path: &str) -> bool {
+    match File::open(path) {
+        Ok(file) => matches!(read_header(&mut BufReader::new(file), h5_path), Ok(Some(_))),
+        Err(_) => false,
+    }
+}
+
+fn read_record(r: &mut (impl Read + Seek), offset: Option<u64>) -> Result<CachedProbe, String> {
+    if let Some(offset) = offset {
+        r.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+    }
+    let mut b = [0u8; RECORD_SIZE as usize];
+    r.read_exact(&mut b).map_err(|e| e.to_string())?;
+    let field = |i: usize| -> [u8; 8] { b[i * 8..i * 8 + 8].try_into().unwrap() };
+    Ok(CachedProbe {
+        row: u64::from_le_bytes(field(0)),
+        start: i64::from_le_bytes(field(1)),
+        log_fc: f64::from_le_bytes(field(2)),
+        t: f64::from_le_bytes(field(3)),
+        raw_p: f64::from_le_bytes(field(4)),
+        adj_p: f64::from_le_bytes(field(5)),
+    })
+}
+
+/// Reads the cached probes of chr:start-stop (inclusive). Only the records of the window are read: the first one is
+/// found by binary search over the position-sorted records of the chromosome. Returns None when there is no cache
+/// for this comparison or when it is stale.
+pub fn read_region(
+    path: &str,
+    h5_path: &str,
+    chr: &str,
+    start: i64,
+    stop: i64,
+) -> Result<Option<CachedRegion>, String> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Ok(None),
+    };
+    let mut r = BufReader::new(file);
+    let header = match read_header(&mut r, h5_path)? {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+    let mut probes = Vec::new();
+    if let Some(&(_, first, count)) = header.chromosomes.iter().find(|(name, _, _)| name == chr) {
+        let offset = |i: u64| header.data_offset + (first + i) * RECORD_SIZE;
+        let (mut lo, mut hi) = (0u64, count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if read_record(&mut r, Some(offset(mid)))?.start < start {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        if lo < count {
+            r.seek(SeekFrom::Start(offset(lo))).map_err(|e| e.to_string())?;
+        }
+        for _ in lo..count {
+            let probe = read_record(&mut r, None)?;
+            if probe.start > stop {
+                break;
+            }
+            probes.push(probe);
+        }
+    }
+    Ok(Some(CachedRegion {
+        probes,
+        total_probes: header.total_probes,
+        s20: header.s20,
+        df0: header.df0,
+    }))
+}
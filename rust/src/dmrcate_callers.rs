@@ -0,0 +1,339 @@
+// dmrcate_callers.rs — Alternative DMR callers of dmrcate: bumphunter and comb-p
+//
+// Both callers start from the per-probe statistics of the genome-wide fit and return DMRs in the same JSON shape as
+// the DMRCate kernel smoothing caller: min_smoothed_fdr holds the FDR of the region and HMFDR the harmonic mean of the
+// per-CpG FDR of its probes.
+//
+// bumphunter (Jaffe et al. 2012): the contrast estimates are smoothed within clusters of probes at most lambda apart
+// (Gaussian kernel, σ = lambda / C) and bumps are runs of ≥ 2 probes whose smoothed estimate is beyond ±cutoff with
+// the same sign. The null distribution of the bump areas (Σ |smoothed estimate|) comes from seeded permutations of
+// the residuals of the null model (the design restricted to contrast·β = 0), which are refitted and smoothed like the
+// observed data. By default the cutoff is the 99th percentile of the smoothed null estimates. Each bump gets
+// area, p_value (fraction of the null bumps with at least its area) and fwer (fraction of the permutations whose
+// largest bump has at least its area); min_smoothed_fdr is the BH adjustment of p_value over the bumps.
+//
+// comb-p (Pedersen et al. 2012): probe p-values are converted to z-scores, their autocorrelation is estimated in
+// distance bins of step bp up to lambda, and every probe gets the Stouffer–Liptak–Kechris (SLK) combination of the
+// p-values of the probes within lambda, corrected for that autocorrelation. The combined p-values are BH adjusted
+// over all probes, and regions are runs of probes with q < fdr_cutoff at most lambda apart. Each region gets the SLK
+// p-value of its probes (p_value) and its Šidák correction for the number of regions of that length in the analyzed
+// sequence (p_sidak); min_smoothed_fdr is the smallest q of its probes.
+use crate::dmrcate_design::Design;
+use crate::stats_functions::SeededRng;
+use crate::{ChromosomeProbes, bh_adjust, dmr_json};
+use nalgebra::DVector;
+use serde_json::{Value, json};
+use statrs::distribution::{ContinuousCDF, Normal};
+
+const MIN_PROBES: usize = 2; // Smallest number of probes of a region, as for the DMRCate caller
+const NULL_CUTOFF_QUANTILE: f64 = 0.99;
+const MIN_ACF_PAIRS: usize = 3; // Distance bins with fewer probe pairs are treated as uncorrelated
+
+pub struct BumpParams {
+    pub lambda: f64, // Largest gap between probes of a cluster
+    pub c_param: f64,
+    pub cutoff: Option<f64>,
+    pub num_permutations: usize,
+    pub seed: u64,
+}
+
+/// Index ranges (inclusive) of the clusters of probes at most max_gap apart
+fn clusters(pos: &[i64], max_gap: f64) -> Vec<(usize, usize)> {
+    let mut out = Vec::new();
+    if pos.is_empty() {
+        return out;
+    }
+    let mut first = 0;
+    for i in 1..pos.len() {
+        if (pos[i] - pos[i - 1]) as f64 > max_gap {
+            out.push((first, i - 1));
+            first = i;
+        }
+    }
+    out.push((first, pos.len() - 1));
+    out
+}
+
+/// Gaussian kernel weighted mean of the values within each cluster of probes
+pub fn smooth_estimates(pos: &[i64], values: &[f64], lambda: f64, c_param: f64) -> Vec<f64> {
+    let sigma = lambda / c_param;
+    let (max_d, two_s2) = (5.0 * sigma, 2.0 * sigma * sigma);
+    let mut out = vec![0.0; pos.len()];
+    for (first, last) in clusters(pos, lambda) {
+        for i in first..=last {
+            let (mut sw, mut swy) = (0.0, 0.0);
+            for j in first..=last {
+                let dx = (pos[i] - pos[j]) as f64;
+                if dx.abs() <= max_d {
+                    let w = (-dx * dx / two_s2).exp();
+                    sw += w;
+                    swy += w * values[j];
+                }
+            }
+            out[i] = swy / sw;
+        }
+    }
+    out
+}
+
+/// Runs (inclusive index ranges) of at least MIN_PROBES probes at most max_gap apart whose smoothed estimate is
+/// beyond ±cutoff with the same sign
+pub fn find_bumps(pos: &[i64], smoothed: &[f64], cutoff: f64, max_gap: f64) -> Vec<(usize, usize)> {
+    let n = pos.len();
+    let mut bumps = Vec::new();
+    let mut run: Option<(usize, bool)> = None; // First probe and sign of the current run
+    for i in 0..=n {
+        let sign = if i < n && smoothed[i].abs() > cutoff {
+            Some(smoothed[i] > 0.0)
+        } else {
+            None
+        };
+        if let Some((first, positive)) = run {
+            if sign == Some(positive) && (pos[i] - pos[i - 1]) as f64 <= max_gap {
+                continue;
+            }
+            if i - first >= MIN_PROBES {
+                bumps.push((first, i - 1));
+            }
+        }
+        run = sign.map(|positive| (i, positive));
+    }
+    bumps
+}
+
+fn area(smoothed: &[f64], (first, last): (usize, usize)) -> f64 {
+    smoothed[first..=last].iter().map(|v| v.abs()).sum()
+}
+
+/// bumphunter on the probes of one region. responses holds the fitted response of each probe (M-values, or their
+/// deviations for diffvar) in the order of the design rows; missing values are imputed by the mean of the probe.
+pub fn bumphunter(c: &ChromosomeProbes, responses: &[Vec<f64>], design: &Design, params: &BumpParams) -> Vec<Value> {
+    let smooth = |values: &[f64]| smooth_estimates(&c.pos, values, params.lambda, params.c_param);
+    let smoothed = smooth(&c.lfc);
+
+    // Fitted values and residuals of the null model, permuted together across probes to keep their correlation
+    let (null_hat, weights) = (design.null_hat(), design.contrast_weights());
+    let n = design.samples.len();
+    let null_fits: Vec<(DVector<f64>, DVector<f64>)> = responses
+        .iter()
+        .map(|y| {
+            let observed: Vec<f64> = y.iter().cloned().filter(|v| v.is_finite()).collect();
+            let mean = if observed.is_empty() {
+                0.0
+            } else {
+                observed.iter().sum::<f64>() / observed.len() as f64
+            };
+            let y = DVector::from_iterator(n, y.iter().map(|&v| if v.is_finite() { v } else { mean }));
+            let fitted = &null_hat * &y;
+            let residuals = y - &fitted;
+            (fitted, residuals)
+        })
+        .collect();
+    let mut rng = SeededRng::new(params.seed);
+    let mut order: Vec<usize> = (0..n).collect();
+    let null_smoothed: Vec<Vec<f64>> = (0..params.num_permutations)
+        .map(|_| {
+            rng.shuffle(&mut order);
+            let estimates: Vec<f64> = null_fits
+                .iter()
+                .map(|(fitted, residuals)| (0..n).map(|k| weights[k] * (fitted[k] + residuals[order[k]])).sum())
+                .collect();
+            smooth(&estimates)
+        })
+        .collect();
+
+    let cutoff = params.cutoff.unwrap_or_else(|| {
+        let mut null_values: Vec<f64> = null_smoothed.iter().flatten().map(|v| v.abs()).collect();
+        if null_values.is_empty() {
+            return f64::INFINITY;
+        }
+        null_values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        null_values[((null_values.len() - 1) as f64 * NULL_CUTOFF_QUANTILE).round() as usize]
+    });
+    let null_areas: Vec<Vec<f64>> = null_smoothed
+        .iter()
+        .map(|s| {
+            find_bumps(&c.pos, s, cutoff, params.lambda)
+                .into_iter()
+                .map(|b| area(s, b))
+                .collect()
+        })
+        .collect();
+    let null_max: Vec<f64> = null_areas
+        .iter()
+        .map(|areas| areas.iter().cloned().fold(0.0, f64::max))
+        .collect();
+    let num_null_bumps: usize = null_areas.iter().map(|areas| areas.len()).sum();
+
+    let bumps = find_bumps(&c.pos, &smoothed, cutoff, params.lambda);
+    let areas: Vec<f64> = bumps.iter().map(|&b| area(&smoothed, b)).collect();
+    // Add-one permutation p-values, so a bump beyond every null bump is not given p = 0
+    let p_values: Vec<f64> = areas
+        .iter()
+        .map(|&a| {
+            let exceeding = null_areas.iter().flatten().filter(|&&null| null >= a).count();
+            (exceeding + 1) as f64 / (num_null_bumps + 1) as f64
+        })
+        .collect();
+    let q_values = bh_adjust(&p_values);
+    bumps
+        .iter()
+        .enumerate()
+        .map(|(k, &(first, last))| {
+            let probes: Vec<usize> = (first..=last).collect();
+            let mut dmr = dmr_json(&c.chr, &c.pos, &probes, &c.fdr, &c.g1, &c.g2);
+            let exceeding = null_max.iter().filter(|&&m| m >= areas[k]).count();
+            dmr["min_smoothed_fdr"] = json!(q_values[k]);
+            dmr["area"] = json!(areas[k]);
+            dmr["p_value"] = json!(p_values[k]);
+            dmr["fwer"] = json!((exceeding + 1) as f64 / (params.num_permutations + 1) as f64);
+            dmr
+        })
+        .collect()
+}
+
+fn pearson(pairs: &[(f64, f64)]) -> f64 {
+    let n = pairs.len() as f64;
+    let (mx, my) = (
+        pairs.iter().map(|p| p.0).sum::<f64>() / n,
+        pairs.iter().map(|p| p.1).sum::<f64>() / n,
+    );
+    let (mut sxy, mut sxx, mut syy) = (0.0, 0.0, 0.0);
+    for &(x, y) in pairs {
+        sxy += (x - mx) * (y - my);
+        sxx += (x - mx) * (x - mx);
+        syy += (y - my) * (y - my);
+    }
+    if sxx > 0.0 && syy > 0.0 {
+        sxy / (sxx * syy).sqrt()
+    } else {
+        0.0
+    }
+}
+
+/// Correlation of the z-scores of probe pairs in distance bins (k·step, (k+1)·step] up to lambda, pooled over the
+/// chromosomes. Negative and poorly estimated correlations are set to 0.
+pub fn autocorrelation(chromosomes: &[(&[i64], &[f64])], lambda: f64, step: f64) -> Vec<f64> {
+    let num_bins = (lambda / step).ceil().max(1.0) as usize;
+    let mut bins: Vec<Vec<(f64, f64)>> = vec![Vec::new(); num_bins];
+    for &(pos, z) in chromosomes {
+        for i in 0..pos.len() {
+            for j in i + 1..pos.len() {
+                let d = pos[j] - pos[i];
+                if d as f64 > lambda {
+                    break;
+                }
+                if d > 0 {
+                    bins[(((d - 1) as f64) / step) as usize].push((z[i], z[j]));
+                }
+            }
+        }
+    }
+    bins.iter()
+        .map(|pairs| {
+            if pairs.len() < MIN_ACF_PAIRS {
+                0.0
+            } else {
+                pearson(pairs).clamp(0.0, 1.0)
+            }
+        })
+        .collect()
+}
+
+/// Stouffer–Liptak–Kechris combined p-value of the z-scores of probes first..=last
+fn slk(pos: &[i64], z: &[f64], (first, last): (usize, usize), acf: &[f64], step: f64, normal: &Normal) -> f64 {
+    let correlation = |d: i64| {
+        if d == 0 {
+            1.0
+        } else {
+            acf.get(((d - 1) as f64 / step) as usize).cloned().unwrap_or(0.0)
+        }
+    };
+    let mut variance = 0.0;
+    for i in first..=last {
+        for j in first..=last {
+            variance += correlation((pos[i] - pos[j]).abs());
+        }
+    }
+    let combined = z[first..=last].iter().sum::<f64>() / variance.sqrt();
+    normal.sf(combined)
+}
+
+/// comb-p over the probes of every chromosome. Returns the regions and the BH-adjusted SLK p-value of every probe
+/// of each chromosome.
+pub fn combp(chromosomes: &[ChromosomeProbes], lambda: f64, step: f64, fdr_cut: f64) -> (Vec<Value>, Vec<Vec<f64>>) {
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    let z_scores: Vec<Vec<f64>> = chromosomes
+        .iter()
+        .map(|c| {
+            c.p.iter()
+                .map(|&p| -normal.inverse_cdf(p.clamp(1e-300, 1.0 - 1e-15)))
+                .collect()
+        })
+        .collect();
+    let acf = autocorrelation(
+        &chromosomes
+            .iter()
+            .zip(&z_scores)
+            .map(|(c, z)| (c.pos.as_slice(), z.as_slice()))
+            .collect::<Vec<_>>(),
+        lambda,
+        step,
+    );
+
+    // SLK p-value of the window of each probe, then BH over all the probes
+    let slk_p: Vec<f64> = chromosomes
+        .iter()
+        .zip(&z_scores)
+        .flat_map(|(c, z)| {
+            let (mut l, mut r) = (0, 0);
+            (0..c.pos.len())
+                .map(|i| {
+                    while c.pos[i] - c.pos[l] > lambda as i64 {
+                        l += 1;
+                    }
+                    while r + 1 < c.pos.len() && c.pos[r + 1] - c.pos[i] <= lambda as i64 {
+                        r += 1;
+                    }
+                    slk(&c.pos, z, (l, r), &acf, step, &normal)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let q_all = bh_adjust(&slk_p);
+    let analyzed_bp: f64 = chromosomes
+        .iter()
+        .filter(|c| !c.pos.is_empty())
+        .map(|c| (c.pos[c.pos.len() - 1] - c.pos[0] + 1) as f64)
+        .sum();
+
+    let mut regions = Vec::new();
+    let mut q_per_chr = Vec::with_capacity(chromosomes.len());
+    let mut offset = 0;
+    for (c, z) in chromosomes.iter().zip(&z_scores) {
+        let q = q_all[offset..offset + c.pos.len()].to_vec();
+        offset += c.pos.len();
+        let significant: Vec<usize> = (0..c.pos.len()).filter(|&i| q[i] < fdr_cut).collect();
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for &i in &significant {
+            match runs.last_mut() {
+                Some(run) if (c.pos[i] - c.pos[run.1]) as f64 <= lambda => run.1 = i,
+                _ => runs.push((i, i)),
+            }
+        }
+        for (first, last) in runs.into_iter().filter(|&(f, l)| l - f + 1 >= MIN_PROBES) {
+            let probes: Vec<usize> = (first..=last).collect();
+            let mut dmr = dmr_json(&c.chr, &c.pos, &probes, &c.fdr, &c.g1, &c.g2);
+            let p_value = slk(&c.pos, z, (first, last), &acf, step, &normal);
+            let num_tests = analyzed_bp / (c.pos[last] - c.pos[first] + 1) as f64;
+            // 1 − (1 − p)^num_tests, computed without cancellation for small p
+            let p_sidak = -((-p_value).ln_1p() * num_tests).exp_m1();
+            dmr["min_smoothed_fdr"] = json!(q[first..=last].iter().cloned().fold(f64::INFINITY, f64::min));
+            dmr["p_value"] = json!(p_value);
+            dmr["p_sidak"] = json!(p_sidak.min(1.0));
+            regions.push(dmr);
+        }
+        q_per_chr.push(q);
+    }
+    (regions, q_per_chr)
+}
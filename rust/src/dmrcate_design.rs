@@ -0,0 +1,404 @@
+// dmrcate_design.rs — Design matrix and contrast of the per-probe linear model of dmrcate (limma lmFit + contrasts.fit)
+//
+// The design is either given explicitly ("design": {"samples", "columns", "matrix"} with a "contrast" vector), or built
+// from the case/control groups: intercept + group indicator (case = 1), plus the "covariates" (numeric values become
+// one column each, strings are factors with treatment coding against the first level in sorted order) and "pairs"
+// (sample → pair/subject id, a blocking factor for paired designs). The default contrast of a built design is the
+// group coefficient, i.e. case − control adjusted for the covariates.
+//
+// Designs that are not of full column rank, or contrasts that do not match the design columns, are rejected.
+//
+// For differential variability (DiffVar, Phipson & Oshlack 2014; iEVORA, Teschendorff et al. 2016) the model is fitted
+// to Levene-type residuals instead of M-values: the absolute deviations of each sample from the median of its group
+// (Brown–Forsythe), so the contrast tests the difference in mean absolute deviation between case and control.
+use nalgebra::{DMatrix, DVector};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+
+const RANK_TOLERANCE: f64 = 1e-7;
+
+/// Linear model of the samples of the comparison, with the contrast that is tested
+#[derive(Debug)]
+pub struct Design {
+    pub samples: Vec<usize>, // Columns of beta/values, in the order of the rows of the design matrix
+    pub columns: Vec<String>,
+    pub matrix: DMatrix<f64>,
+    pub contrast: DVector<f64>,
+    /// Group of each design row when fitting absolute deviations from the group medians (DiffVar)
+    pub deviation_groups: Option<Vec<usize>>,
+    xtx_inv: DMatrix<f64>,
+    hat: DMatrix<f64>, // (X'X)^-1 X'
+}
+
+/// Least squares fit of one probe, reduced to the contrast
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeFit {
+    pub coefficient: f64, // Contrast estimate (logFC on the M-value scale)
+    pub residual_var: f64,
+    pub df_residual: f64,
+    pub stdev_unscaled: f64,
+}
+
+fn rank(matrix: &DMatrix<f64>) -> usize {
+    let singular_values = matrix.clone().svd(false, false).singular_values;
+    let max = singular_values.iter().cloned().fold(0.0, f64::max);
+    singular_values.iter().filter(|&&s| s > RANK_TOLERANCE * max).count()
+}
+
+impl Design {
+    pub fn new(
+        samples: Vec<usize>,
+        columns: Vec<String>,
+        matrix: DMatrix<f64>,
+        contrast: DVector<f64>,
+    ) -> Result<Design, String> {
+        let (n, p) = matrix.shape();
+        if n != samples.len() || p != columns.len() {
+            return Err(format!(
+                "Design matrix is {}×{} but has {} samples and {} columns",
+                n,
+                p,
+                samples.len(),
+                columns.len()
+            ));
+        }
+        if contrast.len() != p {
+            return Err(format!(
+                "Contrast has {} values but the design has {} columns",
+                contrast.len(),
+                p
+            ));
+        }
+        if contrast.iter().all(|&c| c == 0.0) {
+            return Err("Contrast is all zero".to_string());
+        }
+        if matrix.iter().any(|v| !v.is_finite()) {
+            return Err("Design matrix has missing or non-finite values".to_string());
+        }
+        if n <= p {
+            return Err(format!(
+                "Design has {} columns for {} samples: no residual degrees of freedom",
+                p, n
+            ));
+        }
+        if rank(&matrix) < p {
+            return Err(format!(
+                "Design matrix is not of full rank: coefficients are not estimable (columns: {})",
+                columns.join(", ")
+            ));
+        }
+        let xtx_inv = (matrix.transpose() * &matrix)
+            .try_inverse()
+            .ok_or("Design matrix is not of full rank: X'X is singular")?;
+        let hat = &xtx_inv * matrix.transpose();
+        Ok(Design {
+            samples,
+            columns,
+            matrix,
+            contrast,
+            deviation_groups: None,
+            xtx_inv,
+            hat,
+        })
+    }
+
+    /// Switches the design to differential variability: the probes are fitted on their absolute deviations from
+    /// the case and control medians. Every design sample must be a case or a control.
+    pub fn with_deviation_groups(mut self, case_idx: &[usize], ctrl_idx: &[usize]) -> Result<Design, String> {
+        let groups = self
+            .samples
+            .iter()
+            .map(|s| {
+                if case_idx.contains(s) {
+                    Ok(0)
+                } else if ctrl_idx.contains(s) {
+                    Ok(1)
+                } else {
+                    Err("Differential variability requires every design sample to be a case or a control".to_string())
+                }
+            })
+            .collect::<Result<Vec<usize>, String>>()?;
+        self.deviation_groups = Some(groups);
+        Ok(self)
+    }
+
+    pub fn df_residual(&self) -> usize {
+        self.matrix.nrows() - self.matrix.ncols()
+    }
+
+    /// Stable description of the design, used in the cache key of the genome-wide fit
+    pub fn key(&self) -> String {
+        let bits = |v: &[f64]| {
+            v.iter()
+                .map(|x| format!("{:x}", x.to_bits()))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        format!(
+            "{:?}|{}|{}|{}|{:?}",
+            self.samples,
+            self.columns.join(","),
+            bits(self.matrix.as_slice()),
+            bits(self.contrast.as_slice()),
+            self.deviation_groups
+        )
+    }
+
+    /// Values that are fitted for y: y itself, or its absolute deviations from the group medians (DiffVar)
+    pub fn response(&self, y: &[f64]) -> Vec<f64> {
+        match &self.deviation_groups {
+            Some(groups) => absolute_deviations(y, groups),
+            None => y.to_vec(),
+        }
+    }
+
+    /// Weights w of the samples such that the contrast estimate of a complete response y is w·y
+    pub fn contrast_weights(&self) -> DVector<f64> {
+        self.hat.transpose() * &self.contrast
+    }
+
+    /// Hat matrix of the null model: the design restricted to the coefficients with contrast·β = 0. The residuals of
+    /// this model are exchangeable between samples under the null hypothesis of the contrast.
+    pub fn null_hat(&self) -> DMatrix<f64> {
+        let p = self.matrix.ncols();
+        let c = &self.contrast;
+        let projector = DMatrix::identity(p, p) - c * c.transpose() / c.norm_squared();
+        let svd = (&self.matrix * projector).svd(true, false);
+        let max = svd.singular_values.iter().cloned().fold(0.0, f64::max);
+        let columns: Vec<usize> = (0..svd.singular_values.len())
+            .filter(|&k| svd.singular_values[k] > RANK_TOLERANCE * max)
+            .collect();
+        let basis = svd.u.unwrap().select_columns(columns.iter());
+        &basis * basis.transpose()
+    }
+
+    /// Fits one probe, with y in the order of the design rows. Missing (non-finite) values drop their rows; the
+    /// probe is skipped (None) when the remaining design is not estimable, has no residual degrees of freedom or
+    /// when the residual variance is zero.
+    pub fn fit(&self, y: &[f64]) -> Option<ProbeFit> {
+        let deviations;
+        let y = match &self.deviation_groups {
+            Some(groups) => {
+                deviations = absolute_deviations(y, groups);
+                &deviations
+            }
+            None => y,
+        };
+        let p = self.matrix.ncols();
+        if y.iter().all(|v| v.is_finite()) {
+            let y = DVector::from_column_slice(y);
+            let coefficients = &self.hat * &y;
+            let rss = (&y - &self.matrix * &coefficients).norm_squared();
+            return self.probe_fit(&coefficients, rss, y.len(), &self.xtx_inv);
+        }
+        let observed: Vec<usize> = (0..y.len()).filter(|&i| y[i].is_finite()).collect();
+        if observed.len() <= p {
+            return None;
+        }
+        let x = self.matrix.select_rows(observed.iter());
+        if rank(&x) < p {
+            return None;
+        }
+        let xtx_inv = (x.transpose() * &x).try_inverse()?;
+        let y = DVector::from_iterator(observed.len(), observed.iter().map(|&i| y[i]));
+        let coefficients = &xtx_inv * x.transpose() * &y;
+        let rss = (&y - &x * &coefficients).norm_squared();
+        self.probe_fit(&coefficients, rss, y.len(), &xtx_inv)
+    }
+
+    fn probe_fit(&self, coefficients: &DVector<f64>, rss: f64, n: usize, xtx_inv: &DMatrix<f64>) -> Option<ProbeFit> {
+        let df_residual = (n - self.matrix.ncols()) as f64;
+        let residual_var = rss / df_residual;
+        if !residual_var.is_finite() || residual_var <= 0.0 {
+            return None;
+        }
+        Some(ProbeFit {
+            coefficient: self.contrast.dot(coefficients),
+            residual_var,
+            df_residual,
+            stdev_unscaled: (self.contrast.transpose() * xtx_inv * &self.contrast)[(0, 0)].sqrt(),
+        })
+    }
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = values.len();
+    if n.is_multiple_of(2) {
+        (values[n / 2 - 1] + values[n / 2]) / 2.0
+    } else {
+        values[n / 2]
+    }
+}
+
+/// |y − median of the group of y| for the finite values, missing values stay missing
+pub fn absolute_deviations(y: &[f64], groups: &[usize]) -> Vec<f64> {
+    let num_groups = groups.iter().max().map_or(0, |g| g + 1);
+    let medians: Vec<f64> = (0..num_groups)
+        .map(|g| {
+            let mut values: Vec<f64> = (0..y.len())
+                .filter(|&i| groups[i] == g && y[i].is_finite())
+                .map(|i| y[i])
+                .collect();
+            if values.is_empty() {
+                f64::NAN
+            } else {
+                median(&mut values)
+            }
+        })
+        .collect();
+    y.iter().zip(groups).map(|(v, &g)| (v - medians[g]).abs()).collect()
+}
+
+fn parse_contrast(value: &Value, p: usize) -> Result<Option<DVector<f64>>, String> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    let values: Vec<f64> = value
+        .as_array()
+        .ok_or("contrast must be an array of numbers")?
+        .iter()
+        .map(|v| v.as_f64().ok_or("contrast must be an array of numbers"))
+        .collect::<Result<_, _>>()?;
+    if values.len() != p {
+        return Err(format!(
+            "Contrast has {} values but the design has {} columns",
+            values.len(),
+            p
+        ));
+    }
+    Ok(Some(DVector::from_vec(values)))
+}
+
+/// Columns of a covariate over the samples: one numeric column, or treatment-coded indicator columns of a factor
+fn covariate_columns(
+    name: &str,
+    values: &HashMap<&str, &Value>,
+    sample_names: &[&str],
+    factor: bool,
+) -> Result<Vec<(String, Vec<f64>)>, String> {
+    let mut cells = Vec::with_capacity(sample_names.len());
+    for s in sample_names {
+        match values.get(s) {
+            Some(v) if v.is_number() || v.is_string() => cells.push(*v),
+            _ => return Err(format!("Covariate {} has no value for sample {}", name, s)),
+        }
+    }
+    if !factor && cells.iter().all(|v| v.is_number()) {
+        return Ok(vec![(
+            name.to_string(),
+            cells.iter().map(|v| v.as_f64().unwrap()).collect(),
+        )]);
+    }
+    // Factor: numbers mixed with strings are treated as levels too
+    let labels: Vec<String> = cells
+        .iter()
+        .map(|v| v.as_str().map_or_else(|| v.to_string(), |s| s.to_string()))
+        .collect();
+    let levels: BTreeSet<&String> = labels.iter().collect();
+    Ok(levels
+        .into_iter()
+        .skip(1)
+        .map(|level| {
+            let column = labels.iter().map(|l| if l == level { 1.0 } else { 0.0 }).collect();
+            (format!("{}{}", name, level), column)
+        })
+        .collect())
+}
+
+/// Design of the request: the explicit "design" when given, otherwise case/control with "covariates" and "pairs"
+pub fn build_design(
+    p: &Value,
+    sample_index: &HashMap<&str, usize>,
+    case_names: &[&str],
+    control_names: &[&str],
+) -> Result<Design, String> {
+    let design = &p["design"];
+    if !design.is_null() {
+        let names: Vec<&str> = design["samples"]
+            .as_array()
+            .ok_or("design.samples must be an array of sample names")?
+            .iter()
+            .map(|s| s.as_str().ok_or("design.samples must be an array of sample names"))
+            .collect::<Result<_, _>>()?;
+        let samples: Vec<usize> = names
+            .iter()
+            .map(|s| {
+                sample_index
+                    .get(s)
+                    .copied()
+                    .ok_or(format!("Design sample {} not found", s))
+            })
+            .collect::<Result<_, _>>()?;
+        let columns: Vec<String> = design["columns"]
+            .as_array()
+            .ok_or("design.columns must be an array of column names")?
+            .iter()
+            .map(|c| c.as_str().unwrap_or("").to_string())
+            .collect();
+        let rows = design["matrix"]
+            .as_array()
+            .ok_or("design.matrix must be an array of rows")?;
+        if rows.len() != samples.len() {
+            return Err(format!(
+                "design.matrix has {} rows for {} samples",
+                rows.len(),
+                samples.len()
+            ));
+        }
+        let mut values = Vec::with_capacity(rows.len() * columns.len());
+        for row in rows {
+            let row = row
+                .as_array()
+                .filter(|r| r.len() == columns.len())
+                .ok_or(format!("Each row of design.matrix must have {} numbers", columns.len()))?;
+            for v in row {
+                values.push(v.as_f64().ok_or("design.matrix must contain only numbers")?);
+            }
+        }
+        let matrix = DMatrix::from_row_slice(rows.len(), columns.len(), &values);
+        let contrast =
+            parse_contrast(&p["contrast"], columns.len())?.ok_or("An explicit design requires a contrast")?;
+        return Design::new(samples, columns, matrix, contrast);
+    }
+
+    let names: Vec<&str> = case_names.iter().chain(control_names.iter()).copied().collect();
+    let samples: Vec<usize> = names.iter().map(|s| sample_index[s]).collect();
+    let mut columns: Vec<(String, Vec<f64>)> = vec![
+        ("Intercept".to_string(), vec![1.0; names.len()]),
+        (
+            "group".to_string(),
+            (0..names.len())
+                .map(|i| if i < case_names.len() { 1.0 } else { 0.0 })
+                .collect(),
+        ),
+    ];
+    if let Some(covariates) = p["covariates"].as_object() {
+        for (name, values) in covariates {
+            let values: HashMap<&str, &Value> = values
+                .as_object()
+                .ok_or(format!("Covariate {} must map sample names to values", name))?
+                .iter()
+                .map(|(s, v)| (s.as_str(), v))
+                .collect();
+            columns.extend(covariate_columns(name, &values, &names, false)?);
+        }
+    }
+    if let Some(pairs) = p["pairs"].as_object() {
+        let values: HashMap<&str, &Value> = pairs.iter().map(|(s, v)| (s.as_str(), v)).collect();
+        columns.extend(covariate_columns("pair", &values, &names, true)?);
+    }
+    let p_cols = columns.len();
+    let matrix = DMatrix::from_fn(names.len(), p_cols, |i, j| columns[j].1[i]);
+    let contrast = parse_contrast(&p["contrast"], p_cols)?.unwrap_or_else(|| {
+        let mut c = DVector::zeros(p_cols);
+        c[1] = 1.0;
+        c
+    });
+    Design::new(
+        samples,
+        columns.into_iter().map(|(name, _)| name).collect(),
+        matrix,
+        contrast,
+    )
+}
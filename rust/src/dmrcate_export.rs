@@ -0,0 +1,136 @@
+// dmrcate_export.rs — Export of dmrcate results as files that can be downloaded or loaded as tracks
+//
+// Given an output prefix, writes:
+//   {prefix}.dmrs.bed                BED9 of the DMRs (name = rank, score = −10·log10 min smoothed FDR capped at 1000,
+//                                    itemRgb red for hyper(variable) and blue for hypo(variable) regions)
+//   {prefix}.smoothed_fdr.bedGraph   Kernel-smoothed FDR of every probe (comb-p: BH-adjusted SLK p-values,
+//                                    bumphunter: per-CpG FDR)
+//   {prefix}.dmrs.tsv                Full DMR table, including the gene annotation when present
+// Probe positions and DMR start/stop are 1-based and closed; they are written as 0-based half-open BED intervals,
+// so a probe at pos is pos - 1 to pos and a DMR is start - 1 to stop.
+use serde_json::{Value, json};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Smoothed FDR of the probes of one chromosome
+pub struct SmoothedFdr<'a> {
+    pub chr: &'a str,
+    pub positions: &'a [i64],
+    pub fdr: &'a [f64],
+}
+
+const TSV_COLUMNS: [&str; 15] = [
+    "rank",
+    "chr",
+    "start",
+    "stop",
+    "no_cpgs",
+    "min_smoothed_fdr",
+    "HMFDR",
+    "maxdiff",
+    "meandiff",
+    "direction",
+    "overlapping_genes",
+    "nearest_genes",
+    "promoter_genes",
+    "cpg_context",
+    "promoter_overlap",
+];
+
+fn create(path: &str) -> Result<BufWriter<File>, String> {
+    File::create(path)
+        .map(BufWriter::new)
+        .map_err(|e| format!("Cannot write {}: {}", path, e))
+}
+
+// Text of a DMR field in the TSV: strings unquoted, lists joined with commas, missing values empty
+fn tsv_cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                // Nearest genes are written as GENE(distance to TSS)
+                Value::Object(o) => format!(
+                    "{}({})",
+                    o.get("gene").and_then(|g| g.as_str()).unwrap_or(""),
+                    o.get("distance_to_tss").map_or(String::new(), |d| d.to_string())
+                ),
+                other => tsv_cell(other),
+            })
+            .collect::<Vec<_>>()
+            .join(","),
+        other => other.to_string(),
+    }
+}
+
+fn write_bed(path: &str, dmrs: &[Value]) -> Result<(), String> {
+    let mut w = create(path)?;
+    let mut lines = vec!["track name=\"DMRs\" itemRgb=\"On\"".to_string()];
+    for (i, dmr) in dmrs.iter().enumerate() {
+        let start = dmr["start"].as_i64().unwrap_or(1) - 1;
+        let end = dmr["stop"].as_i64().unwrap_or(start + 1);
+        let fdr = dmr["min_smoothed_fdr"].as_f64().unwrap_or(1.0).max(1e-100);
+        let score = (-10.0 * fdr.log10()).round().clamp(0.0, 1000.0) as i64;
+        let direction = dmr["direction"].as_str().unwrap_or("");
+        let rgb = if direction.starts_with("hyper") {
+            "215,48,39"
+        } else {
+            "69,117,180"
+        };
+        let name = dmr["rank"]
+            .as_u64()
+            .map_or(format!("DMR{}", i + 1), |r| format!("DMR{}", r));
+        lines.push(format!(
+            "{}\t{}\t{}\t{}\t{}\t.\t{}\t{}\t{}",
+            dmr["chr"].as_str().unwrap_or(""),
+            start,
+            end,
+            name,
+            score,
+            start,
+            end,
+            rgb
+        ));
+    }
+    writeln!(w, "{}", lines.join("\n")).map_err(|e| e.to_string())
+}
+
+fn write_bedgraph(path: &str, smoothed: &[SmoothedFdr]) -> Result<(), String> {
+    let mut w = create(path)?;
+    writeln!(w, "track type=bedGraph name=\"Smoothed FDR\"").map_err(|e| e.to_string())?;
+    for chromosome in smoothed {
+        for (pos, fdr) in chromosome.positions.iter().zip(chromosome.fdr) {
+            writeln!(w, "{}\t{}\t{}\t{:e}", chromosome.chr, pos - 1, pos, fdr).map_err(|e| e.to_string())?;
+        }
+    }
+    w.flush().map_err(|e| e.to_string())
+}
+
+fn write_tsv(path: &str, dmrs: &[Value]) -> Result<(), String> {
+    let mut w = create(path)?;
+    writeln!(w, "{}", TSV_COLUMNS.join("\t")).map_err(|e| e.to_string())?;
+    for (i, dmr) in dmrs.iter().enumerate() {
+        let cells: Vec<String> = TSV_COLUMNS
+            .iter()
+            .map(|&column| match column {
+                "rank" if dmr["rank"].is_null() => (i + 1).to_string(),
+                _ => tsv_cell(&dmr[column]),
+            })
+            .collect();
+        writeln!(w, "{}", cells.join("\t")).map_err(|e| e.to_string())?;
+    }
+    w.flush().map_err(|e| e.to_string())
+}
+
+/// Writes the BED, bedGraph and TSV files and returns their paths
+pub fn export_results(prefix: &str, dmrs: &[Value], smoothed: &[SmoothedFdr]) -> Result<Value, String> {
+    let bed = format!("{}.dmrs.bed", prefix);
+    let bedgraph = format!("{}.smoothed_fdr.bedGraph", prefix);
+    let tsv = format!("{}.dmrs.tsv", prefix);
+    write_bed(&bed, dmrs)?;
+    write_bedgraph(&bedgraph, smoothed)?;
+    write_tsv(&tsv, dmrs)?;
+    Ok(json!({"bed": bed, "bedgraph": bedgraph, "tsv": tsv}))
+}
@@ -0,0 +1,572 @@
+// dmrcate_preprocess.rs — Optional preprocessing of the beta values of dmrcate and per-sample QC
+//
+// Probe filters: probes listed in "cross_reactive_probes" or "snp_probes" (arrays of probe IDs, or paths of text
+// files with one probe ID in the first column of each line, optionally gzipped) and, with "remove_sex_chromosomes",
+// the probes of chrX/chrY are left out of the genome-wide fit, and therefore out of every region query.
+//
+// Type I/II correction ("normalization":"bmiq", needs /meta/probe/type): BMIQ (Teschendorff et al. 2013,
+// Bioinformatics 29:189). A three-state (unmethylated, hemimethylated, methylated) beta mixture is fitted by EM to the
+// type I and to the type II beta values of each sample, with the M step of RPMM's blc (weighted method of moments
+// refined by maximum likelihood). Type II values of the unmethylated and methylated states are mapped onto the type I
+// distribution of the same state by quantile matching of the fitted beta distributions, and the hemimethylated ones
+// are shifted and dilated to fill the gap between the corrected unmethylated and methylated values. The mixtures are
+// fitted to histograms of all the probes that pass the filters, so the correction needs one extra pass over
+// beta/values before the genome-wide fit. Samples where a state of either type is empty are not corrected.
+//
+// Sample QC: median beta and fraction of failed detections of every case/control sample. A detection fails when the
+// beta value is missing or, when /detection/pvalues is present, when its detection p-value is above
+// "detection_p_cutoff" (default 0.01).
+//
+// The QC and the BMIQ fits of the correction only depend on the inputs of the genome-wide fit, so they are stored
+// next to its cache file ({cache}.prep.json) and reused by the region queries that read the cache.
+use crate::dmr_annotation::open_text;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use statrs::function::gamma::digamma;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::BufRead;
+
+const HIST_BINS: usize = 2000;
+const NUM_QUANTILES: usize = 1001;
+const MIN_TYPE_PROBES: u64 = 100; // Samples with fewer type I or type II values are not corrected
+// BMIQ defaults: initial state thresholds of the type I fit, EM iterations and tolerance on the state means
+const TYPE1_THRESHOLDS: [f64; 2] = [0.2, 0.75];
+const EM_ITERATIONS: usize = 5;
+const EM_TOLERANCE: f64 = 0.001;
+const MODE_WINDOW: usize = 10; // Half width in bins of the moving average used to find the modes of the states
+
+/// Preprocessing requested by the input
+pub struct PreprocessOptions {
+    pub bmiq: bool,
+    pub cross_reactive: Option<HashSet<String>>,
+    pub snp: Option<HashSet<String>>,
+    pub remove_sex_chromosomes: bool,
+    pub detection_cutoff: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RemovedProbes {
+    pub cross_reactive: usize,
+    pub snp: usize,
+    pub sex_chromosomes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SampleQc {
+    pub column: usize, // Column of the sample in beta/values
+    pub median_beta: Option<f64>,
+    pub detection_failed_fraction: f64,
+}
+
+/// Parameters of a beta distribution
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BetaParams {
+    pub a: f64,
+    pub b: f64,
+}
+
+/// Three-state beta mixture fitted to the beta values of one probe type
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mixture {
+    pub states: [BetaParams; 3],
+    pub proportions: [f64; 3],
+    pub thresholds: [f64; 2], // Values below the first are unmethylated, above the second methylated
+}
+
+/// BMIQ correction of the type II beta values of one sample
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BmiqFit {
+    type1: [BetaParams; 2], // Unmethylated and methylated states of the type I mixture
+    type2: [BetaParams; 2], // Unmethylated and methylated states of the type II mixture
+    thresholds: [f64; 2],   // State thresholds of the type II values
+    modes: [f64; 2],        // Modes of the unmethylated and methylated type II values
+    hemi: [f64; 3],         // Hemimethylated values: v -> hemi[1] + hemi[2] * (v - hemi[0])
+}
+
+/// Type I/II correction: probe type of every row of beta/values and BMIQ fits of the corrected samples
+pub struct TypeNormalization {
+    pub types: Vec<u8>,
+    pub fits: HashMap<usize, BmiqFit>,
+}
+
+/// Preprocessing applied while reading beta/values
+#[derive(Default)]
+pub struct Preprocessing {
+    pub excluded: Vec<bool>, // Rows left out of the genome-wide fit (empty when the fit is read from the cache)
+    pub normalization: Option<TypeNormalization>,
+}
+
+impl Preprocessing {
+    pub fn is_excluded(&self, row: usize) -> bool {
+        self.excluded.get(row).copied().unwrap_or(false)
+    }
+
+    /// Type I/II correction of one row of beta/values, when requested
+    pub fn correct(&self, row: usize, values: &mut [f32]) {
+        if let Some(normalization) = &self.normalization {
+            normalization.apply(row, values);
+        }
+    }
+}
+
+/// Results of the preprocessing pass, stored next to the cache of the genome-wide fit
+#[derive(Serialize, Deserialize)]
+pub struct PreprocessSummary {
+    pub removed: RemovedProbes,
+    pub qc: Vec<SampleQc>,
+    pub bmiq_fits: Option<HashMap<usize, BmiqFit>>,
+}
+
+/// Probe IDs from an array of strings or from the first column of a text file
+fn probe_list(value: &Value, name: &str) -> Result<Option<HashSet<String>>, String> {
+    match value {
+        Value::Null => Ok(None),
+        Value::Array(ids) => Ok(Some(
+            ids.iter()
+                .map(|id| {
+                    id.as_str()
+                        .map(str::to_string)
+                        .ok_or(format!("{} must hold probe IDs", name))
+                })
+                .collect::<Result<_, _>>()?,
+        )),
+        Value::String(path) => {
+            let mut ids = HashSet::new();
+            for line in open_text(path)?.lines() {
+                let line = line.map_err(|e| format!("{}: {}", path, e))?;
+                if let Some(id) = line.split(['\t', ',', ' ']).next().filter(|id| !id.is_empty()) {
+                    ids.insert(id.to_string());
+                }
+            }
+            Ok(Some(ids))
+        }
+        _ => Err(format!(
+            "{} must be a list of probe IDs or the path of a file of probe IDs",
+            name
+        )),
+    }
+}
+
+impl PreprocessOptions {
+    pub fn from_input(p: &Value) -> Result<PreprocessOptions, String> {
+        let bmiq = match p["normalization"].as_str().unwrap_or("none") {
+            "none" => false,
+            "bmiq" => true,
+            other => return Err(format!("Unknown normalization: {} (expected none or bmiq)", other)),
+        };
+        Ok(PreprocessOptions {
+            bmiq,
+            cross_reactive: probe_list(&p["cross_reactive_probes"], "cross_reactive_probes")?,
+            snp: probe_list(&p["snp_probes"], "snp_probes")?,
+            remove_sex_chromosomes: p["remove_sex_chromosomes"].as_bool().unwrap_or(false),
+            detection_cutoff: p["detection_p_cutoff"].as_f64().unwrap_or(0.01),
+        })
+    }
+
+    /// True when probe IDs are needed to apply the filters
+    pub fn needs_probe_ids(&self) -> bool {
+        self.cross_reactive.is_some() || self.snp.is_some()
+    }
+
+    /// Stable description of the options, part of the cache key of the genome-wide fit
+    pub fn key(&self) -> String {
+        let sorted = |ids: &Option<HashSet<String>>| {
+            ids.as_ref()
+                .map(|ids| ids.iter().cloned().collect::<BTreeSet<String>>())
+        };
+        format!(
+            "{}|{:?}|{:?}|{}|{:x}",
+            if self.bmiq { "bmiq" } else { "none" },
+            sorted(&self.cross_reactive),
+            sorted(&self.snp),
+            self.remove_sex_chromosomes,
+            self.detection_cutoff.to_bits()
+        )
+    }
+
+    /// Rows of beta/values left out of the analysis, with the number of probes removed by each filter. A probe on
+    /// several lists is counted once, for the first filter that removes it.
+    pub fn excluded_rows(
+        &self,
+        chr_names: &[String],
+        chr_lens: &[usize],
+        probe_ids: Option<&[String]>,
+    ) -> (Vec<bool>, RemovedProbes) {
+        let total: usize = chr_lens.iter().sum();
+        let mut excluded = vec![false; total];
+        let mut removed = RemovedProbes::default();
+        let mut row = 0;
+        for (name, &len) in chr_names.iter().zip(chr_lens) {
+            let bare = name.trim_start_matches("chr").to_ascii_uppercase();
+            if self.remove_sex_chromosomes && (bare == "X" || bare == "Y") {
+                excluded[row..row + len].iter_mut().for_each(|e| *e = true);
+                removed.sex_chromosomes += len;
+            }
+            row += len;
+        }
+        if let Some(ids) = probe_ids {
+            for (i, id) in ids.iter().enumerate().take(total) {
+                if excluded[i] {
+                    continue;
+                }
+                if self.cross_reactive.as_ref().is_some_and(|list| list.contains(id)) {
+                    removed.cross_reactive += 1;
+                    excluded[i] = true;
+                } else if self.snp.as_ref().is_some_and(|list| list.contains(id)) {
+                    removed.snp += 1;
+                    excluded[i] = true;
+                }
+            }
+        }
+        (excluded, removed)
+    }
+}
+
+/// Path of the preprocessing summary stored with a cache file
+pub fn summary_path(cache_file: &str) -> String {
+    format!("{}.prep.json", cache_file)
+}
+
+pub fn write_summary(path: &str, summary: &PreprocessSummary) -> Result<(), String> {
+    let text = serde_json::to_string(summary).map_err(|e| e.to_string())?;
+    let tmp_path = format!("{}.tmp{}", path, std::process::id());
+    std::fs::write(&tmp_path, text).map_err(|e| format!("{}: {}", tmp_path, e))?;
+    std::fs::rename(&tmp_path, path).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Summary stored with the cache, None when it is missing or unreadable
+pub fn read_summary(path: &str) -> Option<PreprocessSummary> {
+    let text = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+fn bin(v: f64) -> usize {
+    ((v.clamp(0.0, 1.0) * HIST_BINS as f64) as usize).min(HIST_BINS - 1)
+}
+
+/// NUM_QUANTILES evenly spaced quantiles of a histogram over [0, 1], interpolated linearly within bins
+fn histogram_quantiles(hist: &[u64]) -> Vec<f64> {
+    let total: u64 = hist.iter().sum();
+    let mut quantiles = Vec::with_capacity(NUM_QUANTILES);
+    let (mut b, mut before) = (0usize, 0u64); // Current bin and number of values in the bins below it
+    for k in 0..NUM_QUANTILES {
+        let target = total as f64 * k as f64 / (NUM_QUANTILES - 1) as f64;
+        while b + 1 < HIST_BINS && (before + hist[b]) as f64 <= target && before + hist[b] < total {
+            before += hist[b];
+            b += 1;
+        }
+        let within = if hist[b] > 0 {
+            ((target - before as f64) / hist[b] as f64).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        quantiles.push((b as f64 + within) / HIST_BINS as f64);
+    }
+    quantiles
+}
+
+/// Center of a histogram bin
+fn bin_center(b: usize) -> f64 {
+    (b as f64 + 0.5) / HIST_BINS as f64
+}
+
+/// Trigamma function: recurrence up to x >= 6, then the asymptotic expansion
+fn trigamma(mut x: f64) -> f64 {
+    let mut value = 0.0;
+    while x < 6.0 {
+        value += 1.0 / (x * x);
+        x += 1.0;
+    }
+    let x2 = 1.0 / (x * x);
+    value + 1.0 / x + x2 / 2.0 + x2 / x * (1.0 / 6.0 - x2 * (1.0 / 30.0 - x2 * (1.0 / 42.0 - x2 / 30.0)))
+}
+
+/// Weighted beta fit of the values xs (betaEst of RPMM): method of moments estimate refined by maximum likelihood
+/// (Newton iterations, the log-likelihood is concave in a and b). None when the weights are empty or the values are
+/// all equal.
+pub fn fit_beta(xs: &[f64], weights: &[f64]) -> Option<BetaParams> {
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let weighted = |f: &dyn Fn(f64) -> f64| xs.iter().zip(weights).map(|(&x, &w)| w * f(x)).sum::<f64>() / total;
+    let mean = weighted(&|x| x);
+    let var = weighted(&|x| x * x) - mean * mean;
+    if var <= 0.0 {
+        return None;
+    }
+    let common = (mean * (1.0 - mean) / var - 1.0).max(1e-6);
+    let (mut a, mut b) = (mean * common, (1.0 - mean) * common);
+    let mean_log = weighted(&|x| x.ln());
+    let mean_log1m = weighted(&|x| (1.0 - x).ln());
+    for _ in 0..100 {
+        let (d_ab, t_ab) = (digamma(a + b), trigamma(a + b));
+        let grad = [d_ab - digamma(a) + mean_log, d_ab - digamma(b) + mean_log1m];
+        let (h11, h22, h12) = (t_ab - trigamma(a), t_ab - trigamma(b), t_ab);
+        let det = h11 * h22 - h12 * h12;
+        let mut step = [
+            (h22 * grad[0] - h12 * grad[1]) / det,
+            (h11 * grad[1] - h12 * grad[0]) / det,
+        ];
+        if !(step[0].is_finite() && step[1].is_finite()) {
+            break;
+        }
+        // Halve the step until both parameters stay positive
+        while a - step[0] <= 0.0 || b - step[1] <= 0.0 {
+            step = [step[0] / 2.0, step[1] / 2.0];
+        }
+        a -= step[0];
+        b -= step[1];
+        if step[0].abs() < 1e-10 * a && step[1].abs() < 1e-10 * b {
+            break;
+        }
+    }
+    Some(BetaParams { a, b })
+}
+
+/// Three-state beta mixture fitted by EM to a histogram of beta values (blc of RPMM), starting from the states given
+/// by thresholds. The states of the bins are then given by their largest posterior probability, and the thresholds
+/// between states are the midpoints between the largest value of a state and the smallest value of the next one.
+/// None when a state is empty.
+pub fn fit_mixture(hist: &[u64], thresholds: [f64; 2]) -> Option<Mixture> {
+    let bins: Vec<usize> = (0..hist.len()).filter(|&b| hist[b] > 0).collect();
+    let xs: Vec<f64> = bins.iter().map(|&b| bin_center(b)).collect();
+    let counts: Vec<f64> = bins.iter().map(|&b| hist[b] as f64).collect();
+    let total: f64 = counts.iter().sum();
+    let initial_state = |x: f64| usize::from(x > thresholds[0]) + usize::from(x > thresholds[1]);
+    let mut posterior: Vec<[f64; 3]> = xs
+        .iter()
+        .map(|&x| {
+            let mut w = [0.0; 3];
+            w[initial_state(x)] = 1.0;
+            w
+        })
+        .collect();
+    let mut states = [BetaParams { a: 1.0, b: 1.0 }; 3];
+    let mut proportions = [0.0; 3];
+    let mut means = [f64::INFINITY; 3];
+    for _ in 0..EM_ITERATIONS {
+        // M step
+        let previous_means = means;
+        for k in 0..3 {
+            let weights: Vec<f64> = counts.iter().zip(&posterior).map(|(c, w)| c * w[k]).collect();
+            proportions[k] = weights.iter().sum::<f64>() / total;
+            states[k] = fit_beta(&xs, &weights)?;
+            means[k] = states[k].a / (states[k].a + states[k].b);
+        }
+        // E step
+        for (x, w) in xs.iter().zip(posterior.iter_mut()) {
+            let log_density: Vec<f64> = (0..3)
+                .map(|k| r_mathlib::beta_pdf(*x, states[k].a, states[k].b, true) + proportions[k].ln())
+                .collect();
+            let max = log_density.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let sum: f64 = log_density.iter().map(|l| (l - max).exp()).sum();
+            for k in 0..3 {
+                w[k] = (log_density[k] - max).exp() / sum;
+            }
+        }
+        let change = (0..3).map(|k| (means[k] - previous_means[k]).abs()).fold(0.0, f64::max);
+        if change < EM_TOLERANCE {
+            break;
+        }
+    }
+    // Range of the values assigned to each state
+    let mut ranges = [(f64::INFINITY, f64::NEG_INFINITY); 3];
+    for (&x, w) in xs.iter().zip(&posterior) {
+        let k = (0..3).fold(0, |best, k| if w[k] > w[best] { k } else { best });
+        ranges[k] = (ranges[k].0.min(x), ranges[k].1.max(x));
+    }
+    if ranges.iter().any(|r| r.0 > r.1) {
+        return None;
+    }
+    Some(Mixture {
+        states,
+        proportions,
+        thresholds: [(ranges[0].1 + ranges[1].0) / 2.0, (ranges[1].1 + ranges[2].0) / 2.0],
+    })
+}
+
+/// Mode of the values of a histogram within (lo, hi), from a moving average over the bins
+fn histogram_mode(hist: &[u64], lo: f64, hi: f64) -> Option<f64> {
+    let bins: Vec<usize> = (0..hist.len())
+        .filter(|&b| bin_center(b) > lo && bin_center(b) < hi)
+        .collect();
+    let (first, last) = (*bins.first()?, *bins.last()?);
+    if hist[first..=last].iter().all(|&c| c == 0) {
+        return None;
+    }
+    let smoothed = |b: usize| -> u64 {
+        hist[b.saturating_sub(MODE_WINDOW).max(first)..=(b + MODE_WINDOW).min(last)]
+            .iter()
+            .sum()
+    };
+    let best = bins
+        .iter()
+        .copied()
+        .max_by_key(|&b| (smoothed(b), std::cmp::Reverse(b)))?;
+    Some(bin_center(best))
+}
+
+/// Maps v from the distribution `from` to the same quantile of `to`, with the tail of v (relative to the mode of its
+/// state) for precision
+fn match_quantile(v: f64, from: BetaParams, to: BetaParams, upper_tail: bool) -> f64 {
+    let p = r_mathlib::beta_cdf(v, from.a, from.b, !upper_tail, false);
+    r_mathlib::beta_quantile(p, to.a, to.b, !upper_tail, false)
+}
+
+impl BmiqFit {
+    /// BMIQ fit of one sample from the histograms of its type I and type II beta values
+    pub fn new(type1: &[u64], type2: &[u64]) -> Option<BmiqFit> {
+        let mixture1 = fit_mixture(type1, TYPE1_THRESHOLDS)?;
+        // The type II states start from the type I thresholds, shifted by the differences between the modes
+        let modes1 = [
+            histogram_mode(type1, 0.0, mixture1.thresholds[0])?,
+            histogram_mode(type1, mixture1.thresholds[1], 1.0)?,
+        ];
+        let modes2 = [histogram_mode(type2, 0.0, 0.4)?, histogram_mode(type2, 0.6, 1.0)?];
+        let mixture2 = fit_mixture(
+            type2,
+            [
+                mixture1.thresholds[0] + modes2[0] - modes1[0],
+                mixture1.thresholds[1] + modes2[1] - modes1[1],
+            ],
+        )?;
+        let mut fit = BmiqFit {
+            type1: [mixture1.states[0], mixture1.states[2]],
+            type2: [mixture2.states[0], mixture2.states[2]],
+            thresholds: mixture2.thresholds,
+            modes: modes2,
+            hemi: [0.0, 0.0, 1.0],
+        };
+        // Range of the type II values of each state; only the methylated values above the mode bound the
+        // hemimethylated ones from above, as in BMIQ
+        let (mut max_u, mut min_h, mut max_h, mut min_m) =
+            (f64::NEG_INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::INFINITY);
+        for x in (0..type2.len()).filter(|&b| type2[b] > 0).map(bin_center) {
+            if x < fit.thresholds[0] {
+                max_u = max_u.max(x);
+            } else if x <= fit.thresholds[1] {
+                min_h = min_h.min(x);
+                max_h = max_h.max(x);
+            } else if x > fit.modes[1] {
+                min_m = min_m.min(x);
+            }
+        }
+        if !(max_u.is_finite() && min_h.is_finite() && min_m.is_finite()) {
+            return None;
+        }
+        // The corrected hemimethylated values keep their distances to the unmethylated and methylated values
+        let new_min_h = fit.apply(max_u) + (min_h - max_u);
+        let new_max_h = fit.apply(min_m) - (min_m - max_h);
+        let dilation = if max_h > min_h {
+            (new_max_h - new_min_h) / (max_h - min_h)
+        } else {
+            1.0
+        };
+        fit.hemi = [min_h, new_min_h, dilation];
+        Some(fit)
+    }
+
+    /// Type I equivalent of a type II beta value
+    pub fn apply(&self, v: f64) -> f64 {
+        if v < self.thresholds[0] {
+            match_quantile(v, self.type2[0], self.type1[0], v > self.modes[0])
+        } else if v > self.thresholds[1] {
+            match_quantile(v, self.type2[1], self.type1[1], v > self.modes[1])
+        } else {
+            self.hemi[1] + self.hemi[2] * (v - self.hemi[0])
+        }
+    }
+}
+
+impl TypeNormalization {
+    /// Corrects in place the beta values of one row of beta/values (all the samples of the row)
+    pub fn apply(&self, row: usize, values: &mut [f32]) {
+        if self.types.get(row) != Some(&2) {
+            return;
+        }
+        for (&column, fit) in &self.fits {
+            if let Some(v) = values.get_mut(column).filter(|v| v.is_finite()) {
+                *v = fit.apply(*v as f64) as f32;
+            }
+        }
+    }
+}
+
+/// Streaming per-sample summaries of the rows of beta/values: QC and, for the type I/II correction, histograms of
+/// the beta values of each probe type
+pub struct SampleSummary {
+    columns: Vec<usize>,
+    detection_cutoff: f64,
+    histograms: Vec<Vec<u64>>,
+    type_histograms: Option<[Vec<Vec<u64>>; 2]>,
+    failed: Vec<u64>,
+    rows: u64,
+}
+
+impl SampleSummary {
+    pub fn new(columns: &[usize], by_type: bool, detection_cutoff: f64) -> SampleSummary {
+        let empty = || vec![vec![0u64; HIST_BINS]; columns.len()];
+        SampleSummary {
+            columns: columns.to_vec(),
+            detection_cutoff,
+            histograms: empty(),
+            type_histograms: if by_type { Some([empty(), empty()]) } else { None },
+            failed: vec![0; columns.len()],
+            rows: 0,
+        }
+    }
+
+    /// Adds one probe: its beta values and detection p-values over all the samples, and its type (1 or 2)
+    pub fn add_row(&mut self, values: &[f32], detection: Option<&[f32]>, probe_type: Option<u8>) {
+        self.rows += 1;
+        for (k, &column) in self.columns.iter().enumerate() {
+            let v = values.get(column).map_or(f64::NAN, |&v| v as f64);
+            let detected = detection.is_none_or(|d| d.get(column).is_some_and(|&p| p as f64 <= self.detection_cutoff));
+            if !v.is_finite() || !detected {
+                self.failed[k] += 1;
+            }
+            if !v.is_finite() {
+                continue;
+            }
+            self.histograms[k][bin(v)] += 1;
+            if let (Some(by_type), Some(t @ 1..=2)) = (&mut self.type_histograms, probe_type) {
+                by_type[t as usize - 1][k][bin(v)] += 1;
+            }
+        }
+    }
+
+    /// QC of each sample and, when probe types were given, the BMIQ fits of the samples with enough probes of both
+    /// types
+    pub fn finish(self) -> (Vec<SampleQc>, Option<HashMap<usize, BmiqFit>>) {
+        let qc = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(k, &column)| SampleQc {
+                column,
+                median_beta: if self.histograms[k].iter().any(|&c| c > 0) {
+                    Some(histogram_quantiles(&self.histograms[k])[NUM_QUANTILES / 2])
+                } else {
+                    None
+                },
+                detection_failed_fraction: if self.rows > 0 {
+                    self.failed[k] as f64 / self.rows as f64
+                } else {
+                    0.0
+                },
+            })
+            .collect();
+        let fits = self.type_histograms.as_ref().map(|[type1, type2]| {
+            self.columns
+                .iter()
+                .enumerate()
+                .filter(|&(k, _)| {
+                    type1[k].iter().sum::<u64>() >= MIN_TYPE_PROBES && type2[k].iter().sum::<u64>() >= MIN_TYPE_PROBES
+                })
+                .filter_map(|(k, &column)| BmiqFit::new(&type1[k], &type2[k]).map(|fit| (column, fit)))
+                .collect()
+        });
+        (qc, fits)
+    }
+}
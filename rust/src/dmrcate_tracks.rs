@@ -0,0 +1,326 @@
+// dmrcate_tracks.rs — Genome browser tracks of a dmrcate region query
+//
+// Each requested track is laid out as an enrichment_plots::Scene over the region (x = genomic position), so it comes as
+// a PNG without text, the positions of its labels, and a standalone SVG for publication figures. Every track has a
+// legend row of LEGEND_HEIGHT px above a plot area of track_height px:
+//   means         the per-CpG group means with the LOESS curves and 95% CI (the plot of track_png)
+//   smoothed_fdr  -log10 of the smoothed FDR of the DMR caller, with the FDR cutoff
+//   dmrs          one rectangle per DMR, colored by direction (hyper/hypo)
+//   heatmap       the beta value of every probe (column) in every sample (row), controls then cases
+
+use crate::enrichment_plots::{Anchor, FONT_SIZE, Scene, Shape, interpolate_color};
+use crate::hex_to_rgba;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+
+pub const TRACK_NAMES: [&str; 4] = ["means", "smoothed_fdr", "dmrs", "heatmap"];
+const LEGEND_HEIGHT: f32 = 18.0;
+const SWATCH_SIZE: f32 = 10.0;
+const FDR_COLOR: [u8; 4] = [117, 112, 179, 255];
+const CUTOFF_COLOR: [u8; 4] = [153, 153, 153, 255];
+const BETA_LOW_COLOR: [u8; 4] = [33, 102, 172, 255]; // β = 0
+const BETA_MID_COLOR: [u8; 4] = [247, 247, 247, 255]; // β = 0.5
+const BETA_HIGH_COLOR: [u8; 4] = [178, 24, 43, 255]; // β = 1
+
+/// LOESS fitted values and lower and upper 95% CI bounds at the evaluation positions
+type LoessBands = (Vec<f64>, Vec<f64>, Vec<f64>);
+
+/// Region, image size and colors shared by all the tracks
+pub struct TrackOptions<'a> {
+    pub start: f64,
+    pub stop: f64,
+    pub width: u32,
+    pub track_height: u32,
+    pub device_pixel_ratio: f32,
+    pub fdr_cutoff: f64,
+    pub max_loess_region: f64,
+    pub colors: &'a HashMap<String, String>,
+    pub group_names: [&'a str; 2], // Control (group 1), case (group 2)
+    pub variability: bool,         // diffvar: the DMRs are hyper/hypovariable
+}
+
+/// Per-probe data of the region, in the order of the probe positions
+pub struct RegionTracks<'a> {
+    pub positions: &'a [i64],
+    pub mean_group1: &'a [f64],
+    pub mean_group2: &'a [f64],
+    pub fdr: &'a [f64],
+    pub smoothed_fdr: &'a [f64],
+    pub dmrs: &'a [Value],
+    pub loess: [&'a Option<LoessBands>; 2],
+    pub eval_positions: &'a [f64],
+    pub rows: &'a [Option<Vec<f32>>], // Beta values of each probe in all the samples of the HDF5 file
+    pub controls: &'a [usize],        // Columns of the control and case samples in rows
+    pub cases: &'a [usize],
+}
+
+/// Track names of the "tracks" input, which must be among TRACK_NAMES
+pub fn requested_tracks(tracks: &Value) -> Result<Vec<String>, String> {
+    if tracks.is_null() {
+        return Ok(Vec::new());
+    }
+    let names = tracks.as_array().ok_or("tracks must be an array of track names")?;
+    let mut requested = Vec::with_capacity(names.len());
+    for name in names {
+        match name.as_str() {
+            Some(n) if TRACK_NAMES.contains(&n) => requested.push(n.to_string()),
+            _ => {
+                return Err(format!(
+                    "Unknown track: {} (expected one of {})",
+                    name,
+                    TRACK_NAMES.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(requested)
+}
+
+/// Rendered tracks: {name, png, svg, labels, legend, width, height, devicePixelRatio} in the requested order
+pub fn render_tracks(names: &[String], data: &RegionTracks, options: &TrackOptions) -> Result<Vec<Value>, String> {
+    let mut tracks = Vec::with_capacity(names.len());
+    for name in names {
+        let mut scene = Scene::new(options.width as f32, LEGEND_HEIGHT + options.track_height as f32);
+        let legend = match name.as_str() {
+            "means" => means_track(&mut scene, data, options),
+            "smoothed_fdr" => smoothed_fdr_track(&mut scene, data, options),
+            "dmrs" => dmr_track(&mut scene, data, options),
+            _ => heatmap_track(&mut scene, data, options),
+        };
+        draw_legend(&mut scene, &legend);
+        let mut track = scene.render(options.device_pixel_ratio)?;
+        track["name"] = json!(name);
+        track["legend"] = legend
+            .iter()
+            .map(|(label, color)| json!({ "label": label, "color": to_hex(*color) }))
+            .collect();
+        tracks.push(track);
+    }
+    Ok(tracks)
+}
+
+fn color(options: &TrackOptions, key: &str, default: &str) -> [u8; 4] {
+    let (r, g, b, a) = hex_to_rgba(options.colors.get(key).map_or(default, |s| s.as_str()), 255);
+    [r, g, b, a]
+}
+
+fn to_hex(color: [u8; 4]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+fn scale_x(options: &TrackOptions) -> impl Fn(f64) -> f32 {
+    let (start, width) = (options.start, options.width as f32);
+    let x_range = (options.stop - options.start).max(1.0);
+    move |pos: f64| ((pos - start) / x_range) as f32 * width
+}
+
+/// One swatch and label per entry, left to right
+fn draw_legend(scene: &mut Scene, legend: &[(String, [u8; 4])]) {
+    let mut x = 4.0;
+    for (label, color) in legend {
+        scene.shapes.push(Shape::Rect {
+            x,
+            y: (LEGEND_HEIGHT - SWATCH_SIZE) / 2.0,
+            width: SWATCH_SIZE,
+            height: SWATCH_SIZE,
+            fill: *color,
+        });
+        scene.text(
+            x + SWATCH_SIZE + 4.0,
+            LEGEND_HEIGHT / 2.0 + FONT_SIZE / 3.0,
+            label.clone(),
+            Anchor::Start,
+        );
+        // Approximate label width, at about 0.6 em per character
+        x += SWATCH_SIZE + 16.0 + label.chars().count() as f32 * FONT_SIZE * 0.6;
+    }
+}
+
+fn means_track(scene: &mut Scene, data: &RegionTracks, options: &TrackOptions) -> Vec<(String, [u8; 4])> {
+    let height = options.track_height as f32;
+    let scale_x = scale_x(options);
+    let scale_y = |beta: f64| -> f32 { LEGEND_HEIGHT + height - beta.clamp(0.0, 1.0) as f32 * height };
+    let group_colors = [color(options, "group1", "#3b5ee6"), color(options, "group2", "#c04e00")];
+
+    // LOESS curves (if the region is small enough): CI bounds as dashed lines under the fitted curve
+    if options.stop - options.start <= options.max_loess_region {
+        for (loess, c) in data.loess.iter().zip(group_colors) {
+            if let Some((fitted, ci_lo, ci_hi)) = loess {
+                if fitted.is_empty() {
+                    continue;
+                }
+                let curve = |values: &[f64]| -> Vec<(f32, f32)> {
+                    data.eval_positions
+                        .iter()
+                        .zip(values)
+                        .map(|(&pos, &v)| (scale_x(pos), scale_y(v)))
+                        .collect()
+                };
+                for band in [ci_hi, ci_lo] {
+                    scene.dashed_line(curve(band), [c[0], c[1], c[2], 128], 1.0);
+                }
+                scene.line(curve(fitted), [c[0], c[1], c[2], 204], 2.0);
+            }
+        }
+    }
+
+    // Group means, opaque at significant probes
+    for (i, &pos) in data.positions.iter().enumerate() {
+        let alpha = if data.fdr[i] < options.fdr_cutoff { 217 } else { 77 };
+        for (means, c) in [data.mean_group1, data.mean_group2].iter().zip(group_colors) {
+            if means[i].is_finite() {
+                scene.shapes.push(Shape::Circle {
+                    x: scale_x(pos as f64),
+                    y: scale_y(means[i]),
+                    radius: 4.0,
+                    fill: [c[0], c[1], c[2], alpha],
+                });
+            }
+        }
+    }
+    vec![
+        (options.group_names[0].to_string(), group_colors[0]),
+        (options.group_names[1].to_string(), group_colors[1]),
+    ]
+}
+
+fn smoothed_fdr_track(scene: &mut Scene, data: &RegionTracks, options: &TrackOptions) -> Vec<(String, [u8; 4])> {
+    let height = options.track_height as f32;
+    let scale_x = scale_x(options);
+    let neg_log10 = |fdr: f64| -> f64 { -fdr.max(1e-300).log10() };
+    let cutoff = neg_log10(options.fdr_cutoff);
+    let y_max = data
+        .smoothed_fdr
+        .iter()
+        .map(|&f| neg_log10(f))
+        .fold(cutoff, f64::max)
+        .max(1.0)
+        * 1.1;
+    let scale_y = |v: f64| -> f32 { LEGEND_HEIGHT + height - (v / y_max) as f32 * height };
+
+    scene.dashed_line(
+        vec![(0.0, scale_y(cutoff)), (options.width as f32, scale_y(cutoff))],
+        CUTOFF_COLOR,
+        1.0,
+    );
+    let points: Vec<(f32, f32)> = data
+        .positions
+        .iter()
+        .zip(data.smoothed_fdr)
+        .map(|(&pos, &f)| (scale_x(pos as f64), scale_y(neg_log10(f))))
+        .collect();
+    if points.len() == 1 {
+        scene.shapes.push(Shape::Circle {
+            x: points[0].0,
+            y: points[0].1,
+            radius: 2.0,
+            fill: FDR_COLOR,
+        });
+    } else if !points.is_empty() {
+        scene.line(points, FDR_COLOR, 1.5);
+    }
+    scene.text(2.0, LEGEND_HEIGHT + FONT_SIZE, format!("{:.1}", y_max), Anchor::Start);
+    vec![
+        ("-log10 smoothed FDR".to_string(), FDR_COLOR),
+        (format!("FDR = {}", options.fdr_cutoff), CUTOFF_COLOR),
+    ]
+}
+
+fn dmr_track(scene: &mut Scene, data: &RegionTracks, options: &TrackOptions) -> Vec<(String, [u8; 4])> {
+    let height = options.track_height as f32;
+    let scale_x = scale_x(options);
+    let hyper = color(options, "hyper", "#e66101");
+    let hypo = color(options, "hypo", "#5e81f4");
+    for dmr in data.dmrs {
+        let (Some(start), Some(stop)) = (dmr["start"].as_f64(), dmr["stop"].as_f64()) else {
+            continue;
+        };
+        let x0 = scale_x(start.max(options.start));
+        let x1 = scale_x(stop.min(options.stop));
+        let direction = dmr["direction"].as_str().unwrap_or("");
+        scene.shapes.push(Shape::Rect {
+            x: x0,
+            y: LEGEND_HEIGHT + height * 0.2,
+            width: (x1 - x0).max(1.0),
+            height: height * 0.6,
+            fill: if direction.starts_with("hyper") { hyper } else { hypo },
+        });
+    }
+    let labels = if options.variability {
+        ["Hypervariable", "Hypovariable"]
+    } else {
+        ["Hypermethylated", "Hypomethylated"]
+    };
+    vec![(labels[0].to_string(), hyper), (labels[1].to_string(), hypo)]
+}
+
+fn heatmap_track(scene: &mut Scene, data: &RegionTracks, options: &TrackOptions) -> Vec<(String, [u8; 4])> {
+    const MAX_HALF_WIDTH: f32 = 4.0; // Probe columns are at most 8 px wide, so that sparse probes stay distinct
+    let height = options.track_height as f32;
+    let scale_x = scale_x(options);
+    let columns: Vec<usize> = data.controls.iter().chain(data.cases).copied().collect();
+    let row_height = height / columns.len().max(1) as f32;
+    let xs: Vec<f32> = data.positions.iter().map(|&p| scale_x(p as f64)).collect();
+    for (i, row) in data.rows.iter().enumerate() {
+        let Some(row) = row else {
+            continue;
+        };
+        // Column from halfway to the previous probe to halfway to the next one
+        let left = if i > 0 {
+            (xs[i] - xs[i - 1]) / 2.0
+        } else {
+            MAX_HALF_WIDTH
+        };
+        let right = if i + 1 < xs.len() {
+            (xs[i + 1] - xs[i]) / 2.0
+        } else {
+            MAX_HALF_WIDTH
+        };
+        let x0 = xs[i] - left.min(MAX_HALF_WIDTH);
+        let width = (left.min(MAX_HALF_WIDTH) + right.min(MAX_HALF_WIDTH)).max(1.0);
+        for (r, &c) in columns.iter().enumerate() {
+            let beta = row.get(c).map_or(f64::NAN, |&v| v as f64);
+            if !beta.is_finite() {
+                continue;
+            }
+            scene.shapes.push(Shape::Rect {
+                x: x0,
+                y: LEGEND_HEIGHT + r as f32 * row_height,
+                width,
+                height: row_height,
+                fill: beta_color(beta),
+            });
+        }
+    }
+    // Line between the controls and the cases, labelled at the start of each group
+    if !data.controls.is_empty() && !data.cases.is_empty() {
+        let y = LEGEND_HEIGHT + data.controls.len() as f32 * row_height;
+        scene.line(vec![(0.0, y), (options.width as f32, y)], [0, 0, 0, 255], 1.0);
+    }
+    let groups = [(0, data.controls.len()), (data.controls.len(), data.cases.len())];
+    for (g, &(first_row, size)) in groups.iter().enumerate() {
+        if size == 0 {
+            continue;
+        }
+        scene.text(
+            2.0,
+            LEGEND_HEIGHT + first_row as f32 * row_height + FONT_SIZE,
+            options.group_names[g].to_string(),
+            Anchor::Start,
+        );
+    }
+    vec![
+        ("β = 0".to_string(), BETA_LOW_COLOR),
+        ("β = 0.5".to_string(), BETA_MID_COLOR),
+        ("β = 1".to_string(), BETA_HIGH_COLOR),
+    ]
+}
+
+/// Diverging blue - white - red scale of the beta values
+pub fn beta_color(beta: f64) -> [u8; 4] {
+    if beta < 0.5 {
+        interpolate_color(BETA_LOW_COLOR, BETA_MID_COLOR, beta / 0.5)
+    } else {
+        interpolate_color(BETA_MID_COLOR, BETA_HIGH_COLOR, (beta - 0.5) / 0.5)
+    }
+}
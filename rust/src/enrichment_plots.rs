@@ -0,0 +1,680 @@
+// Server-side enrichment plots: the enrichment curve of one gene set along a ranked gene list with a tick at the rank of
+// every gene of the set (cerno.rs, CERNO and GSEA), and the dot plot of the top ORA terms (genesetORA.rs).
+// Every plot is laid out once as a list of shapes, which is painted into a PNG with tiny-skia (base64 data URL, as in
+// dmrcate.rs and heatmap_render.rs) and written as a standalone SVG. tiny-skia cannot draw text, so the PNG has no labels:
+// their positions (in CSS pixels) are returned in "labels" so that the client can overlay them. The SVG includes them.
+// The same scenes lay out the dmrcate region tracks (dmrcate_tracks.rs).
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use json::JsonValue;
+use serde_json::{Value, json};
+use tiny_skia::{FillRule, Paint, PathBuilder, Pixmap, Rect, Stroke, StrokeDash, Transform};
+
+const MARGIN_TOP: f32 = 30.0; // Room for the title
+const MARGIN_BOTTOM: f32 = 45.0; // Room for the x axis tick labels and title
+const MARGIN_LEFT: f32 = 60.0; // Room for the y axis tick labels and title
+const MARGIN_RIGHT: f32 = 15.0;
+const TICK_LENGTH: f32 = 4.0;
+pub const FONT_SIZE: f32 = 11.0;
+const AXIS_COLOR: [u8; 4] = [51, 51, 51, 255];
+
+/// Size of the image in CSS pixels and device pixel ratio of the PNG, read from the same keys as in cluster.rs
+#[allow(dead_code)]
+pub struct PlotOptions {
+    pub width: u32,
+    pub height: u32,
+    pub device_pixel_ratio: f32,
+}
+
+#[allow(dead_code)]
+impl PlotOptions {
+    pub fn from_json(json_string: &JsonValue, default_width: u32, default_height: u32) -> PlotOptions {
+        PlotOptions {
+            width: json_string["image_width"].as_u32().unwrap_or(default_width),
+            height: json_string["image_height"].as_u32().unwrap_or(default_height),
+            device_pixel_ratio: json_string["devicePixelRatio"].as_f32().unwrap_or(1.0),
+        }
+    }
+}
+
+/// Horizontal line drawn for reference under the enrichment curve
+#[allow(dead_code)]
+pub enum Reference {
+    Zero,     // GSEA running sum: y = 0
+    Diagonal, // CERNO evidence curve: fraction of the gene set expected from a random ranking
+}
+
+/// Enrichment curve of a gene set along a ranked list of num_genes genes
+#[allow(dead_code)]
+pub struct EnrichmentCurve<'a> {
+    pub title: &'a str,
+    pub y_label: &'a str,
+    pub curve: &'a [(usize, f64)], // Vertices (number of genes walked, value), the curve is linear between vertices
+    pub hits: &'a [usize],         // 0-based positions of the genes of the set in the ranked list
+    pub num_genes: usize,
+    pub reference: Reference,
+}
+
+/// One term of the ORA dot plot
+#[allow(dead_code)]
+pub struct DotPlotTerm {
+    pub name: String,
+    pub gene_ratio: f64, // Number of query genes in the term / number of query genes
+    pub count: usize,    // Number of query genes in the term
+    pub fdr: f64,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Anchor {
+    Start,
+    Middle,
+    End,
+}
+
+#[allow(dead_code)]
+pub enum Shape {
+    Polyline {
+        points: Vec<(f32, f32)>,
+        color: [u8; 4],
+        width: f32,
+        dashed: bool,
+    },
+    Circle {
+        x: f32,
+        y: f32,
+        radius: f32,
+        fill: [u8; 4],
+    },
+    Rect {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        fill: [u8; 4],
+    },
+    Text {
+        x: f32,
+        y: f32,
+        text: String,
+        anchor: Anchor,
+        size: f32,
+        vertical: bool, // Rotated by -90 degrees around (x, y)
+    },
+}
+
+/// Shapes of a plot in CSS pixels
+pub struct Scene {
+    pub width: f32,
+    pub height: f32,
+    pub shapes: Vec<Shape>,
+}
+
+#[allow(dead_code)]
+impl Scene {
+    pub fn new(width: f32, height: f32) -> Scene {
+        Scene {
+            width,
+            height,
+            shapes: Vec::new(),
+        }
+    }
+
+    pub fn line(&mut self, points: Vec<(f32, f32)>, color: [u8; 4], width: f32) {
+        self.shapes.push(Shape::Polyline {
+            points,
+            color,
+            width,
+            dashed: false,
+        });
+    }
+
+    pub fn dashed_line(&mut self, points: Vec<(f32, f32)>, color: [u8; 4], width: f32) {
+        self.shapes.push(Shape::Polyline {
+            points,
+            color,
+            width,
+            dashed: true,
+        });
+    }
+
+    pub fn text(&mut self, x: f32, y: f32, text: String, anchor: Anchor) {
+        self.shapes.push(Shape::Text {
+            x,
+            y,
+            text,
+            anchor,
+            size: FONT_SIZE,
+            vertical: false,
+        });
+    }
+
+    fn title(&mut self, text: &str) {
+        self.shapes.push(Shape::Text {
+            x: self.width / 2.0,
+            y: MARGIN_TOP / 2.0 + 4.0,
+            text: text.to_string(),
+            anchor: Anchor::Middle,
+            size: FONT_SIZE + 2.0,
+            vertical: false,
+        });
+    }
+
+    fn vertical_text(&mut self, x: f32, y: f32, text: &str) {
+        self.shapes.push(Shape::Text {
+            x,
+            y,
+            text: text.to_string(),
+            anchor: Anchor::Middle,
+            size: FONT_SIZE,
+            vertical: true,
+        });
+    }
+
+    /// x axis along y = `y` from `x0` to `x1` with labelled ticks at `ticks`, mapped by `scale`
+    fn x_axis(&mut self, y: f32, x0: f32, x1: f32, ticks: &[f64], scale: &dyn Fn(f64) -> f32, title: &str) {
+        self.line(vec![(x0, y), (x1, y)], AXIS_COLOR, 1.0);
+        for &tick in ticks {
+            let x = scale(tick);
+            self.line(vec![(x, y), (x, y + TICK_LENGTH)], AXIS_COLOR, 1.0);
+            self.text(x, y + TICK_LENGTH + FONT_SIZE, format_tick(tick), Anchor::Middle);
+        }
+        self.text(
+            (x0 + x1) / 2.0,
+            y + TICK_LENGTH + 2.5 * FONT_SIZE + 4.0,
+            title.to_string(),
+            Anchor::Middle,
+        );
+    }
+
+    /// y axis along x = `x` from `y0` (bottom) to `y1` (top) with labelled ticks at `ticks`, mapped by `scale`
+    fn y_axis(&mut self, x: f32, y0: f32, y1: f32, ticks: &[f64], scale: &dyn Fn(f64) -> f32, title: &str) {
+        self.line(vec![(x, y0), (x, y1)], AXIS_COLOR, 1.0);
+        for &tick in ticks {
+            let y = scale(tick);
+            self.line(vec![(x - TICK_LENGTH, y), (x, y)], AXIS_COLOR, 1.0);
+            self.text(
+                x - TICK_LENGTH - 2.0,
+                y + FONT_SIZE / 3.0,
+                format_tick(tick),
+                Anchor::End,
+            );
+        }
+        if !title.is_empty() {
+            self.vertical_text(x - MARGIN_LEFT + FONT_SIZE + 2.0, (y0 + y1) / 2.0, title);
+        }
+    }
+
+    /// PNG (without text), SVG and layout of the plot
+    pub fn render(&self, device_pixel_ratio: f32) -> Result<Value, String> {
+        let png = self.to_png(device_pixel_ratio)?;
+        let labels: Vec<Value> = self
+            .shapes
+            .iter()
+            .filter_map(|shape| match shape {
+                Shape::Text {
+                    x,
+                    y,
+                    text,
+                    anchor,
+                    size,
+                    vertical,
+                } => Some(json!({
+                    "text": text,
+                    "x": x,
+                    "y": y,
+                    "anchor": anchor_name(*anchor),
+                    "size": size,
+                    "vertical": vertical,
+                })),
+                _ => None,
+            })
+            .collect();
+        Ok(json!({
+            "png": png,
+            "svg": self.to_svg(),
+            "width": self.width,
+            "height": self.height,
+            "devicePixelRatio": device_pixel_ratio,
+            "labels": labels,
+        }))
+    }
+
+    fn to_png(&self, dpr: f32) -> Result<String, String> {
+        let mut pixmap = Pixmap::new((self.width * dpr).ceil() as u32, (self.height * dpr).ceil() as u32)
+            .ok_or_else(|| "Invalid image size".to_string())?;
+        // Transparent background (default)
+        for shape in &self.shapes {
+            match shape {
+                Shape::Polyline {
+                    points,
+                    color,
+                    width,
+                    dashed,
+                } => {
+                    let mut pb = PathBuilder::new();
+                    for (i, &(x, y)) in points.iter().enumerate() {
+                        if i == 0 {
+                            pb.move_to(x * dpr, y * dpr);
+                        } else {
+                            pb.line_to(x * dpr, y * dpr);
+                        }
+                    }
+                    if let Some(path) = pb.finish() {
+                        let stroke = Stroke {
+                            width: width * dpr,
+                            dash: if *dashed {
+                                StrokeDash::new(vec![4.0 * dpr, 4.0 * dpr], 0.0)
+                            } else {
+                                None
+                            },
+                            ..Stroke::default()
+                        };
+                        pixmap.stroke_path(&path, &paint(*color), &stroke, Transform::identity(), None);
+                    }
+                }
+                Shape::Circle { x, y, radius, fill } => {
+                    let mut pb = PathBuilder::new();
+                    pb.push_circle(x * dpr, y * dpr, radius * dpr);
+                    if let Some(path) = pb.finish() {
+                        pixmap.fill_path(&path, &paint(*fill), FillRule::Winding, Transform::identity(), None);
+                    }
+                }
+                Shape::Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                    fill,
+                } => {
+                    if let Some(rect) = Rect::from_xywh(x * dpr, y * dpr, width * dpr, height * dpr) {
+                        pixmap.fill_rect(rect, &paint(*fill), Transform::identity(), None);
+                    }
+                }
+                Shape::Text { .. } => {} // Drawn by the client from the labels of the layout
+            }
+        }
+        let png_bytes = pixmap.encode_png().map_err(|e| format!("PNG encoding: {}", e))?;
+        Ok(format!("data:image/png;base64,{}", BASE64.encode(&png_bytes)))
+    }
+
+    fn to_svg(&self) -> String {
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}" font-family="Arial, sans-serif">"#,
+            self.width, self.height, self.width, self.height
+        );
+        for shape in &self.shapes {
+            match shape {
+                Shape::Polyline {
+                    points,
+                    color,
+                    width,
+                    dashed,
+                } => {
+                    let coordinates: Vec<String> = points.iter().map(|(x, y)| format!("{:.2},{:.2}", x, y)).collect();
+                    svg += &format!(
+                        r#"<polyline points="{}" fill="none" stroke="{}" stroke-opacity="{:.3}" stroke-width="{}"{}/>"#,
+                        coordinates.join(" "),
+                        rgb(*color),
+                        color[3] as f32 / 255.0,
+                        width,
+                        if *dashed { r#" stroke-dasharray="4,4""# } else { "" }
+                    );
+                }
+                Shape::Circle { x, y, radius, fill } => {
+                    svg += &format!(
+                        r#"<circle cx="{:.2}" cy="{:.2}" r="{:.2}" fill="{}" fill-opacity="{:.3}"/>"#,
+                        x,
+                        y,
+                        radius,
+                        rgb(*fill),
+                        fill[3] as f32 / 255.0
+                    );
+                }
+                Shape::Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                    fill,
+                } => {
+                    svg += &format!(
+                        r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" fill="{}" fill-opacity="{:.3}"/>"#,
+                        x,
+                        y,
+                        width,
+                        height,
+                        rgb(*fill),
+                        fill[3] as f32 / 255.0
+                    );
+                }
+                Shape::Text {
+                    x,
+                    y,
+                    text,
+                    anchor,
+                    size,
+                    vertical,
+                } => {
+                    let rotation = if *vertical {
+                        format!(r#" transform="rotate(-90 {:.2} {:.2})""#, x, y)
+                    } else {
+                        String::new()
+                    };
+                    svg += &format!(
+                        r#"<text x="{:.2}" y="{:.2}" font-size="{}" text-anchor="{}" fill="{}"{}>{}</text>"#,
+                        x,
+                        y,
+                        size,
+                        anchor_name(*anchor),
+                        rgb(AXIS_COLOR),
+                        rotation,
+                        escape_xml(text)
+                    );
+                }
+            }
+        }
+        svg += "</svg>";
+        svg
+    }
+}
+
+fn paint(color: [u8; 4]) -> Paint<'static> {
+    let mut paint = Paint::default();
+    paint.set_color_rgba8(color[0], color[1], color[2], color[3]);
+    paint.anti_alias = true;
+    paint
+}
+
+fn rgb(color: [u8; 4]) -> String {
+    format!("rgb({},{},{})", color[0], color[1], color[2])
+}
+
+fn anchor_name(anchor: Anchor) -> &'static str {
+    match anchor {
+        Anchor::Start => "start",
+        Anchor::Middle => "middle",
+        Anchor::End => "end",
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn format_tick(value: f64) -> String {
+    if value == value.round() && value.abs() < 1e9 {
+        format!("{}", value as i64)
+    } else {
+        let formatted = format!("{:.3}", value);
+        formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+}
+
+/// About `count` round tick values (1, 2 or 5 × 10^k apart) within [min, max]
+pub fn nice_ticks(min: f64, max: f64, count: usize) -> Vec<f64> {
+    if max.partial_cmp(&min) != Some(std::cmp::Ordering::Greater) {
+        return vec![min];
+    }
+    let raw_step = (max - min) / count.max(1) as f64;
+    let magnitude = 10f64.powf(raw_step.log10().floor());
+    let step = [1.0, 2.0, 5.0, 10.0]
+        .iter()
+        .map(|m| m * magnitude)
+        .find(|&step| step >= raw_step)
+        .unwrap();
+    let first = (min / step).ceil() as i64;
+    let last = (max / step).floor() as i64;
+    (first..=last).map(|i| i as f64 * step).collect()
+}
+
+#[allow(dead_code)]
+pub fn interpolate_color(low: [u8; 4], high: [u8; 4], t: f64) -> [u8; 4] {
+    let t = t.clamp(0.0, 1.0);
+    let mut color = [0u8; 4];
+    for i in 0..4 {
+        color[i] = (low[i] as f64 + (high[i] as f64 - low[i] as f64) * t).round() as u8;
+    }
+    color
+}
+
+/// Vertices of the CERNO evidence curve: fraction of the genes of the set found among the top x genes of the ranked list
+#[allow(dead_code)]
+pub fn evidence_curve(hits: &[usize], num_genes: usize) -> Vec<(usize, f64)> {
+    let k = hits.len() as f64;
+    let mut curve = Vec::with_capacity(2 * hits.len() + 2);
+    curve.push((0, 0.0));
+    for (j, &h) in hits.iter().enumerate() {
+        if h > 0 {
+            curve.push((h, j as f64 / k));
+        }
+        curve.push((h + 1, (j + 1) as f64 / k));
+    }
+    if curve.last().map(|p| p.0) != Some(num_genes) {
+        curve.push((num_genes, 1.0));
+    }
+    curve
+}
+
+/// Enrichment curve (top panel) above one tick per gene of the set at its rank (bottom panel)
+#[allow(dead_code)]
+pub fn render_enrichment_curve(plot: &EnrichmentCurve, options: &PlotOptions) -> Result<Value, String> {
+    const CURVE_COLOR: [u8; 4] = [44, 160, 44, 255];
+    const RANK_TICK_COLOR: [u8; 4] = [0, 0, 0, 160];
+    const REFERENCE_COLOR: [u8; 4] = [153, 153, 153, 255];
+    if plot.num_genes == 0 {
+        return Err("Cannot plot an empty ranked list".to_string());
+    }
+    let (width, height) = (options.width as f32, options.height as f32);
+    let mut scene = Scene::new(width, height);
+    let (x0, x1) = (MARGIN_LEFT, width - MARGIN_RIGHT);
+    let inner_height = height - MARGIN_TOP - MARGIN_BOTTOM;
+    let ticks_height = (inner_height * 0.2).min(30.0);
+    let curve_bottom = MARGIN_TOP + inner_height - ticks_height - 5.0;
+    let n = plot.num_genes as f64;
+    let scale_x = |rank: f64| -> f32 { x0 + (rank / n) as f32 * (x1 - x0) };
+
+    let (mut y_min, mut y_max) = match plot.reference {
+        Reference::Zero => (0.0f64, 0.0f64),
+        Reference::Diagonal => (0.0, 1.0),
+    };
+    for &(_, value) in plot.curve {
+        y_min = y_min.min(value);
+        y_max = y_max.max(value);
+    }
+    let padding = ((y_max - y_min) * 0.05).max(1e-6);
+    let (y_min, y_max) = (y_min - padding, y_max + padding);
+    let scale_y =
+        |value: f64| -> f32 { curve_bottom - ((value - y_min) / (y_max - y_min)) as f32 * (curve_bottom - MARGIN_TOP) };
+
+    scene.title(plot.title);
+    match plot.reference {
+        Reference::Zero => scene.dashed_line(vec![(x0, scale_y(0.0)), (x1, scale_y(0.0))], REFERENCE_COLOR, 1.0),
+        Reference::Diagonal => scene.dashed_line(
+            vec![(scale_x(0.0), scale_y(0.0)), (scale_x(n), scale_y(1.0))],
+            REFERENCE_COLOR,
+            1.0,
+        ),
+    }
+    let points: Vec<(f32, f32)> = plot
+        .curve
+        .iter()
+        .map(|&(rank, value)| (scale_x(rank as f64), scale_y(value)))
+        .collect();
+    scene.line(points, CURVE_COLOR, 2.0);
+    scene.y_axis(
+        x0,
+        curve_bottom,
+        MARGIN_TOP,
+        &nice_ticks(y_min, y_max, 5),
+        &scale_y,
+        plot.y_label,
+    );
+
+    // Rank ticks, at the center of the rank of every gene of the set
+    let ticks_top = curve_bottom + 5.0;
+    for &hit in plot.hits {
+        let x = scale_x(hit as f64 + 0.5);
+        scene.line(
+            vec![(x, ticks_top), (x, ticks_top + ticks_height)],
+            RANK_TICK_COLOR,
+            1.0,
+        );
+    }
+    let rank_ticks = nice_ticks(0.0, n, 5);
+    scene.x_axis(
+        ticks_top + ticks_height,
+        x0,
+        x1,
+        &rank_ticks,
+        &scale_x,
+        "Rank in ordered gene list",
+    );
+    scene.render(options.device_pixel_ratio)
+}
+
+/// Dot plot of ORA terms: gene ratio (x) for every term (y, in decreasing order of gene ratio), sized by count and
+/// colored by FDR
+#[allow(dead_code)]
+pub fn render_dot_plot(terms: &[DotPlotTerm], options: &PlotOptions) -> Result<Value, String> {
+    const LOW_FDR_COLOR: [u8; 4] = [255, 0, 0, 255]; // enrichplot dotplot default: red (low) to blue (high adjusted p-value)
+    const HIGH_FDR_COLOR: [u8; 4] = [0, 0, 255, 255];
+    const LEGEND_WIDTH: f32 = 110.0; // Room right of the plot for the FDR and count legends
+    const MAX_TERM_NAME_LENGTH: usize = 50; // Longer term names are truncated
+    if terms.is_empty() {
+        return Err("No terms to plot".to_string());
+    }
+    let mut terms: Vec<&DotPlotTerm> = terms.iter().collect();
+    terms.sort_by(|a, b| {
+        b.gene_ratio
+            .partial_cmp(&a.gene_ratio)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let names: Vec<String> = terms
+        .iter()
+        .map(|term| {
+            if term.name.chars().count() > MAX_TERM_NAME_LENGTH {
+                term.name.chars().take(MAX_TERM_NAME_LENGTH - 3).collect::<String>() + "..."
+            } else {
+                term.name.clone()
+            }
+        })
+        .collect();
+    // Approximate width of the longest name, at about 0.6 em per character
+    let names_width = names.iter().map(|name| name.chars().count()).max().unwrap_or(0) as f32 * FONT_SIZE * 0.6;
+
+    let (width, height) = (options.width as f32, options.height as f32);
+    let mut scene = Scene::new(width, height);
+    let x0 = names_width + 10.0;
+    let x1 = width - LEGEND_WIDTH - MARGIN_RIGHT;
+    let (y_top, y_bottom) = (MARGIN_TOP, height - MARGIN_BOTTOM);
+    if x1 <= x0 + 20.0 || y_bottom <= y_top {
+        return Err("Image too small for the dot plot".to_string());
+    }
+
+    let min_ratio = terms.iter().map(|t| t.gene_ratio).fold(f64::INFINITY, f64::min);
+    let max_ratio = terms.iter().map(|t| t.gene_ratio).fold(f64::NEG_INFINITY, f64::max);
+    let padding = ((max_ratio - min_ratio) * 0.1).max(max_ratio.abs() * 0.05).max(1e-3);
+    let (ratio_min, ratio_max) = ((min_ratio - padding).max(0.0), max_ratio + padding);
+    let scale_x = |ratio: f64| -> f32 { x0 + ((ratio - ratio_min) / (ratio_max - ratio_min)) as f32 * (x1 - x0) };
+    let row_height = (y_bottom - y_top) / terms.len() as f32;
+
+    let min_count = terms.iter().map(|t| t.count).min().unwrap();
+    let max_count = terms.iter().map(|t| t.count).max().unwrap();
+    let max_radius = (row_height / 2.0 - 1.0).clamp(2.0, 10.0);
+    let radius_of = |count: usize| -> f32 {
+        if max_count == min_count {
+            max_radius * 0.7
+        } else {
+            let t = ((count - min_count) as f32 / (max_count - min_count) as f32).sqrt();
+            2.0 + t * (max_radius - 2.0)
+        }
+    };
+    let min_fdr = terms.iter().map(|t| t.fdr).fold(f64::INFINITY, f64::min);
+    let max_fdr = terms.iter().map(|t| t.fdr).fold(f64::NEG_INFINITY, f64::max);
+    let color_of = |fdr: f64| -> [u8; 4] {
+        if max_fdr > min_fdr {
+            interpolate_color(LOW_FDR_COLOR, HIGH_FDR_COLOR, (fdr - min_fdr) / (max_fdr - min_fdr))
+        } else {
+            LOW_FDR_COLOR
+        }
+    };
+
+    scene.title("Over-representation analysis");
+    // Light grid line and dot for every term
+    for (i, term) in terms.iter().enumerate() {
+        let y = y_top + (i as f32 + 0.5) * row_height;
+        scene.line(vec![(x0, y), (x1, y)], [230, 230, 230, 255], 1.0);
+        scene.shapes.push(Shape::Circle {
+            x: scale_x(term.gene_ratio),
+            y,
+            radius: radius_of(term.count),
+            fill: color_of(term.fdr),
+        });
+        scene.text(x0 - 6.0, y + FONT_SIZE / 3.0, names[i].clone(), Anchor::End);
+    }
+    scene.line(vec![(x0, y_top), (x0, y_bottom)], AXIS_COLOR, 1.0);
+    scene.x_axis(
+        y_bottom,
+        x0,
+        x1,
+        &nice_ticks(ratio_min, ratio_max, 4),
+        &scale_x,
+        "Gene ratio",
+    );
+
+    // Legends: FDR color bar (lowest at the top) and dot size of the smallest and largest counts
+    let legend_x = x1 + 25.0;
+    scene.text(legend_x, y_top + FONT_SIZE, "FDR".to_string(), Anchor::Start);
+    let bar_top = y_top + FONT_SIZE + 6.0;
+    let bar_height = 80.0f32.min((y_bottom - y_top) / 2.0);
+    let steps = bar_height.round().max(1.0) as usize;
+    for i in 0..steps {
+        scene.shapes.push(Shape::Rect {
+            x: legend_x,
+            y: bar_top + i as f32 * bar_height / steps as f32,
+            width: 12.0,
+            height: bar_height / steps as f32 + 0.5,
+            fill: interpolate_color(LOW_FDR_COLOR, HIGH_FDR_COLOR, (i as f64 + 0.5) / steps as f64),
+        });
+    }
+    scene.text(
+        legend_x + 16.0,
+        bar_top + FONT_SIZE / 2.0,
+        format!("{:.2e}", min_fdr),
+        Anchor::Start,
+    );
+    scene.text(
+        legend_x + 16.0,
+        bar_top + bar_height,
+        format!("{:.2e}", max_fdr),
+        Anchor::Start,
+    );
+    let count_top = bar_top + bar_height + 2.0 * FONT_SIZE;
+    scene.text(legend_x, count_top, "Count".to_string(), Anchor::Start);
+    let mut y = count_top + 6.0;
+    let mut legend_counts = vec![min_count];
+    if max_count != min_count {
+        legend_counts.push(max_count);
+    }
+    for count in legend_counts {
+        let radius = radius_of(count);
+        y += radius + 2.0;
+        scene.shapes.push(Shape::Circle {
+            x: legend_x + max_radius,
+            y,
+            radius,
+            fill: [128, 128, 128, 255],
+        });
+        scene.text(
+            legend_x + 2.0 * max_radius + 6.0,
+            y + FONT_SIZE / 3.0,
+            count.to_string(),
+            Anchor::Start,
+        );
+        y += radius + 2.0;
+    }
+    scene.render(options.device_pixel_ratio)
+}
@@ -1,22 +1,47 @@
 // The hypergeometric distribution is computed based on the implementation in https://rdrr.io/github/GuangchuangYu/DOSE/src/R/enricher_internal.R
 // Syntax: cd .. && cargo build --release && cat ~/sjpp/test.txt | target/release/genesetORA
+// Gene sets are read from the msigdb database (msigdb, gene_set_group) unless custom gene sets are given with
+// gene_sets (inline {name: [genes]} object) or gmt_file (path of a GMT file). Gene symbols of custom gene sets are
+// normalized against the genedb codingGenes table and the symbols that cannot be mapped are listed in unmapped_genes.
+// The background_genes (or all the coding genes of genedb when not given, with the query genes when
+// filter_non_coding_genes is false) are the universe of the test: sample genes and gene set members outside of the
+// universe are removed. The output has the effective numbers of sample genes
+// (num_sample_genes) and background genes (num_background_genes), and the effective_gene_set_size of every pathway.
+// Optional inputs:
+//   min_gs_size, max_gs_size: Gene sets with fewer/more genes in the universe are not tested (applied before the FDR
+//     correction)
+//   num_items_output: Number of top pathways in the output (default 100)
+//   redundancy: {"metric": "jaccard" | "kappa", "threshold", "fdr_cutoff"} clusters the significant pathways by gene
+//     set similarity and adds "clusters": [{"representative", "members"}] to the output
+//   dag_method: "classic" (default), "elim" or "weight" (topGO) to decorrelate the p-values along the term hierarchy
+//   dag_cutoff: P-value cutoff of the elim algorithm (default 0.01)
+//   semantic_similarity: "wang" or "resnik" adds "semantic_similarity": {"terms", "matrix"} for the output pathways
+//     with an adjusted p-value <= similarity_fdr_cutoff (default 0.05)
+//   obo_file or go_edges_file: GO hierarchy, needed for dag_method and semantic_similarity. go_edges_file is
+//     tab-delimited: child GO id, parent GO id, is_a | part_of.
+//   go_id_file: Tab-delimited gene set id and GO id, places gene sets that are not named by their GO id (e.g. msigdb
+//     GOBP_ gene sets) in the GO hierarchy. Every gene set must be placed, otherwise the analysis fails.
+//   dot_plot: true adds "dot_plot": {"png", "svg", "labels", ...} (see enrichment_plots.rs), the dot plot of the gene
+//     ratio (hits / num_sample_genes) of the num_dot_plot_terms (default 20) most significant pathways, sized by the
+//     number of hits and colored by adjusted p-value. image_width, image_height and devicePixelRatio set the image size.
 #![allow(non_snake_case)]
 use json::JsonValue;
-use r_mathlib;
 use rusqlite::{Connection, Result};
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io;
 //use std::time::Instant;
 
-#[allow(non_camel_case_types)]
-#[allow(non_snake_case)]
-#[derive(Debug)]
-struct GO_pathway {
-    GO_id: String,
-}
+mod enrichment_plots;
+mod geneset_functions;
+mod go_dag;
+#[cfg(test)]
+mod test_genesetORA; // Tests comparing genesetORA with clusterProfiler::enricher
+#[cfg(test)]
+mod test_geneset_functions; // Tests for gene set loading from the sqlite database
+#[cfg(test)]
+mod test_go_dag; // Tests of the elim and weight algorithms and semantic similarity on a small GO hierarchy
 
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
@@ -28,6 +53,7 @@ struct pathway_p_value {
     p_value_adjusted: Option<f64>,
     gene_set_hits: String,
     gene_set_size: usize,
+    effective_gene_set_size: usize, // Number of genes of the gene set in the background universe
 }
 
 fn calculate_hypergeometric_p_value(
@@ -39,10 +65,10 @@ fn calculate_hypergeometric_p_value(
 
     let gene_intersections: HashSet<String> = genes_in_pathway.intersection(sample_genes).cloned().collect();
     for gene in &gene_intersections {
-        gene_set_hits += &(gene.to_string() + &",");
+        gene_set_hits += &(gene.to_string() + ",");
     }
 
-    if gene_intersections.len() > 0 {
+    if !gene_intersections.is_empty() {
         gene_set_hits.pop();
     }
 
@@ -67,6 +93,145 @@ fn calculate_hypergeometric_p_value(
     (p_value, gene_intersections.len() as f64, gene_set_hits)
 }
 
+/// Query genes restricted to the genes of the universe, as the gene sets are by restrict_to_universe
+fn restrict_query_to_universe(query_genes: &mut HashSet<String>, universe: &HashSet<String>) {
+    query_genes.retain(|gene| universe.contains(gene));
+}
+
+/// Query genes of the test and universe. The universe is background_genes when given (e.g. the genes of a DE analysis),
+/// otherwise the coding genes of genedb, with the query genes added when non-coding genes are not filtered out so that
+/// they are not dropped by the restriction to the universe. The query genes are restricted to the universe.
+fn query_and_universe(
+    sample_genes: &[&str],
+    coding_genes: HashSet<String>,
+    background_genes: Option<&str>,
+    filter_non_coding_genes: bool,
+) -> Result<(HashSet<String>, HashSet<String>), String> {
+    let mut query_genes: HashSet<String> =
+        geneset_functions::filter_query_genes(sample_genes.to_vec(), &coding_genes, filter_non_coding_genes)
+            .into_iter()
+            .map(|gene| gene.to_string())
+            .collect();
+    if query_genes.is_empty() {
+        return Err("All query genes are non-coding".to_string());
+    }
+    let universe: HashSet<String> = match background_genes {
+        Some(genes) => genes
+            .split(",")
+            .filter(|g| !g.is_empty())
+            .map(|g| g.to_string())
+            .collect(),
+        None if filter_non_coding_genes => coding_genes,
+        None => coding_genes.into_iter().chain(query_genes.iter().cloned()).collect(),
+    };
+    if universe.is_empty() {
+        return Err("No background genes provided".to_string());
+    }
+    restrict_query_to_universe(&mut query_genes, &universe);
+    if query_genes.is_empty() {
+        return Err("None of the query genes are in the background genes".to_string());
+    }
+    Ok((query_genes, universe))
+}
+
+/// Gene sets restricted to the genes of the universe, keeping those whose restricted size passes the size filter.
+/// Also returns the original size of the kept gene sets.
+fn restrict_to_universe(
+    gene_sets: &[geneset_functions::GeneSet],
+    universe: &HashSet<String>,
+    size_filter: &geneset_functions::SizeFilter,
+) -> (Vec<geneset_functions::GeneSet>, Vec<usize>) {
+    let mut restricted = Vec::with_capacity(gene_sets.len());
+    let mut sizes = Vec::with_capacity(gene_sets.len());
+    for gene_set in gene_sets {
+        let genes: HashSet<String> = gene_set.genes.intersection(universe).cloned().collect();
+        if size_filter.accepts(genes.len()) {
+            restricted.push(geneset_functions::GeneSet {
+                id: gene_set.id.clone(),
+                genes,
+            });
+            sizes.push(gene_set.genes.len());
+        }
+    }
+    (restricted, sizes)
+}
+
+/// Hypergeometric test of every gene set (already restricted to the universe) with at least one sample gene.
+/// `dag_p_values` replaces the p-values when the elim or weight algorithm is used.
+fn test_gene_sets(
+    sample_genes: &HashSet<String>,
+    num_background_genes: usize,
+    gene_sets: &[geneset_functions::GeneSet],
+    gene_set_sizes: &[usize],
+    dag_p_values: Option<&[f64]>,
+) -> Vec<pathway_p_value> {
+    let mut pathway_p_values = Vec::with_capacity(gene_sets.len());
+    for (i, gene_set) in gene_sets.iter().enumerate() {
+        let (mut p_value, matches, gene_set_hits) =
+            calculate_hypergeometric_p_value(sample_genes, num_background_genes, gene_set.genes.clone());
+        if let Some(dag_p_values) = dag_p_values {
+            p_value = dag_p_values[i];
+        }
+        if matches >= 1.0 && !p_value.is_nan() {
+            pathway_p_values.push(pathway_p_value {
+                pathway_name: gene_set.id.clone(),
+                p_value_original: p_value,
+                p_value_adjusted: None,
+                gene_set_hits,
+                gene_set_size: gene_set_sizes[i],
+                effective_gene_set_size: gene_set.genes.len(),
+            })
+        }
+    }
+    pathway_p_values
+}
+
+/// Hypergeometric p-value of a gene set whose genes have weights (topGO elim and weight algorithms). The numbers of
+/// genes in the gene set and of sample genes in the gene set are the rounded sums of their weights.
+fn weighted_hypergeometric_p_value(
+    sample_genes: &HashSet<String>,
+    num_background_genes: usize,
+    weights: &HashMap<&str, f64>,
+) -> f64 {
+    let num_genes_in_pathway = weights.values().sum::<f64>().round();
+    let num_hits = weights
+        .iter()
+        .filter(|(gene, _)| sample_genes.contains(**gene))
+        .map(|(_, weight)| weight)
+        .sum::<f64>()
+        .round();
+    if num_hits < 1.0 {
+        return 1.0;
+    }
+    r_mathlib::hypergeometric_cdf(
+        num_hits - 1.0,
+        num_genes_in_pathway,
+        num_background_genes as f64 - num_genes_in_pathway,
+        sample_genes.len() as f64,
+        false,
+        false,
+    )
+}
+
+/// GO hierarchy from the obo_file or go_edges_file of the input json, with the gene sets placed in it
+fn load_dag(json_string: &JsonValue, gene_sets: &[geneset_functions::GeneSet]) -> Result<go_dag::TermDag, String> {
+    let read = |file: &str| std::fs::read_to_string(file).map_err(|e| format!("Cannot read {}: {}", file, e));
+    let mut dag = match (json_string["obo_file"].as_str(), json_string["go_edges_file"].as_str()) {
+        (Some(obo_file), _) => go_dag::parse_obo(&read(obo_file)?)?,
+        (None, Some(go_edges_file)) => go_dag::parse_edges(&read(go_edges_file)?)?,
+        (None, None) => {
+            return Err("obo_file or go_edges_file is needed for dag_method and semantic_similarity".to_string());
+        }
+    };
+    let go_ids = match json_string["go_id_file"].as_str() {
+        Some(go_id_file) => go_dag::parse_go_ids(&read(go_id_file)?)?,
+        None => HashMap::new(),
+    };
+    let terms: Vec<&str> = gene_sets.iter().map(|gs| gs.id.as_str()).collect();
+    dag.place_terms(&terms, &go_ids)?;
+    Ok(dag)
+}
+
 fn main() -> Result<()> {
     let mut input = String::new();
     match io::stdin().read_line(&mut input) {
@@ -76,145 +241,198 @@ fn main() -> Result<()> {
             match input_json {
                 Ok(json_string) => {
                     //let run_time = Instant::now();
+                    // Gene sets are read from an inline gene_sets object or a gmt_file if given, otherwise from the msigdb database
+                    let custom_gene_sets = match geneset_functions::load_custom_gene_sets(&json_string) {
+                        Ok(custom_gene_sets) => custom_gene_sets,
+                        Err(error) => panic!("{}", error),
+                    };
                     let msigdb_input: &JsonValue = &json_string["msigdb"];
-                    let msigdb;
-                    match msigdb_input.as_str() {
-                        Some(db_string) => msigdb = db_string.to_string(),
+                    let msigdb = match msigdb_input.as_str() {
+                        Some(db_string) => db_string.to_string(),
+                        None if custom_gene_sets.is_some() => String::new(),
                         None => panic!("msigdb file path is missing"),
-                    }
-                    let genesetgroup;
+                    };
                     let genesetgroup_input: &JsonValue = &json_string["gene_set_group"];
-                    match genesetgroup_input.as_str() {
-                        Some(genesetgroup_string) => genesetgroup = genesetgroup_string.to_string(),
+                    let genesetgroup = match genesetgroup_input.as_str() {
+                        Some(genesetgroup_string) => genesetgroup_string.to_string(),
+                        None if custom_gene_sets.is_some() => String::new(),
                         None => panic!("genesetgroup is missing"),
-                    }
+                    };
                     let sample_genes_input: &JsonValue = &json_string["sample_genes"];
                     let sample_genes: Vec<&str> = sample_genes_input.as_str().unwrap().split(",").collect();
                     let mut pathway_p_values: Vec<pathway_p_value> = Vec::with_capacity(10000);
 
                     let genedb_input: &JsonValue = &json_string["genedb"];
-                    let genedb;
-                    match genedb_input.as_str() {
-                        Some(gene_db_string) => genedb = gene_db_string.to_string(),
+                    let genedb = match genedb_input.as_str() {
+                        Some(gene_db_string) => gene_db_string.to_string(),
                         None => panic!("genedb file path is missing"),
-                    }
+                    };
 
                     let filter_non_coding_genes_input: &JsonValue = &json_string["filter_non_coding_genes"];
                     let filter_non_coding_genes: bool = filter_non_coding_genes_input.as_bool().unwrap();
 
                     let genedbconn = Connection::open(genedb)?;
-                    let genedb_result = genedbconn.prepare(&("select * from codingGenes"));
-                    let mut num_coding_genes: usize = 0;
-                    let mut sample_coding_genes: HashSet<String> = HashSet::with_capacity(24000);
-                    match genedb_result {
-                        Ok(mut x) => {
-                            let mut genes = x.query([])?;
-                            while let Some(coding_gene) = genes.next()? {
-                                num_coding_genes += 1;
-                                //println!("coding_gene:{:?}", coding_gene);
-                                for sample_gene in &sample_genes {
-                                    let code_gene: String = coding_gene.get(0).unwrap();
-                                    if filter_non_coding_genes == true && code_gene == *sample_gene {
-                                        sample_coding_genes.insert(code_gene);
-                                    } else if filter_non_coding_genes == false {
-                                        sample_coding_genes.insert(code_gene);
-                                    }
-                                }
-                            }
-                        }
-                        Err(_) => {}
-                    }
-
-                    if sample_coding_genes.len() == 0 {
-                        panic!("All query genes are non-coding");
-                    }
-
-                    let background_genes_input: &JsonValue = &json_string["background_genes"];
-                    let num_background_genes;
-                    match background_genes_input.as_str() {
-                        Some(x) => {
-                            let background_genes_str: Vec<&str> = x.split(",").collect(); // Background genes is defined for e.g in case of DE analysis
-                            num_background_genes = background_genes_str.len();
-                        }
-                        None => {
-                            // Background genes not present for e.g. in hierarchial clustering
-                            // Get background genes from the gene database
-                            num_background_genes = num_coding_genes;
-                        }
-                    }
+                    // Coding genes are loaded once and looked up in a HashSet
+                    let coding_genes_list = match geneset_functions::load_coding_genes(&genedbconn) {
+                        Ok(coding_genes) => coding_genes,
+                        Err(error) => panic!("{}", error),
+                    };
+                    let coding_genes: HashSet<String> = coding_genes_list.iter().cloned().collect();
+                    // The background genes are the universe of the test: query genes and gene set members outside of it
+                    // are not counted (as the universe argument of clusterProfiler::enricher)
+                    let (sample_coding_genes, universe) = match query_and_universe(
+                        &sample_genes,
+                        coding_genes,
+                        json_string["background_genes"].as_str(),
+                        filter_non_coding_genes,
+                    ) {
+                        Ok(genes) => genes,
+                        Err(error) => panic!("{}", error),
+                    };
+                    let num_background_genes = universe.len();
                     //println!("sample_genes:{:?}", sample_genes);
                     //println!("background_genes:{:?}", background_genes);
 
-                    if sample_genes.len() == 0 {
+                    if sample_genes.is_empty() {
                         panic!("No sample genes provided");
                     } else if num_background_genes == 0 {
                         panic!("No background genes provided");
                     }
-                    let num_items_output = 100; // Number of top pathways to be specified in the output
+                    let num_items_output = json_string["num_items_output"].as_usize().unwrap_or(100); // Number of top pathways to be specified in the output
+                    let size_filter = match geneset_functions::SizeFilter::from_json(&json_string) {
+                        Ok(size_filter) => size_filter,
+                        Err(error) => panic!("{}", error),
+                    };
+                    let redundancy = match geneset_functions::RedundancyOptions::from_json(&json_string) {
+                        Ok(redundancy) => redundancy,
+                        Err(error) => panic!("{}", error),
+                    };
 
-                    let msigdbconn = Connection::open(msigdb)?;
-                    let stmt_result = msigdbconn
-                        .prepare(&("select id from terms where parent_id='".to_owned() + &genesetgroup + "'"));
-                    match stmt_result {
-                        Ok(mut stmt) => {
-                            #[allow(non_snake_case)]
-                            let GO_iter = stmt.query_map([], |row| Ok(GO_pathway { GO_id: row.get(0)? }))?;
-                            #[allow(non_snake_case)]
-                            for GO_term in GO_iter {
-                                match GO_term {
-                                    Ok(n) => {
-                                        //println!("GO term {:?}", n);
-                                        let sql_statement =
-                                            "select genes from term2genes where id='".to_owned() + &n.GO_id + &"'";
-                                        //println!("sql_statement:{}", sql_statement);
-                                        let mut gene_stmt = msigdbconn.prepare(&(sql_statement))?;
-                                        //println!("gene_stmt:{:?}", gene_stmt);
+                    let mut unmapped_genes: Option<Vec<String>> = None;
+                    let gene_sets = match custom_gene_sets {
+                        Some(mut gene_sets) => {
+                            unmapped_genes = Some(geneset_functions::normalize_gene_sets(
+                                &mut gene_sets,
+                                &coding_genes_list,
+                            ));
+                            gene_sets
+                        }
+                        None => {
+                            let pool = match geneset_functions::open_pool(&msigdb) {
+                                Ok(pool) => pool,
+                                Err(error) => panic!("{}", error),
+                            };
+                            let msigdbconn = pool.get().expect("Cannot get a connection to the sqlite database");
+                            match geneset_functions::load_gene_sets(&msigdbconn, &genesetgroup) {
+                                Ok(gene_sets) => gene_sets,
+                                Err(error) => panic!("{}", error),
+                            }
+                        }
+                    };
+                    let dag_method =
+                        match go_dag::DagMethod::from_str(json_string["dag_method"].as_str().unwrap_or("classic")) {
+                            Ok(dag_method) => dag_method,
+                            Err(error) => panic!("{}", error),
+                        };
+                    let similarity_method = match json_string["semantic_similarity"].as_str() {
+                        Some(method) => match go_dag::SemanticSimilarity::from_str(method) {
+                            Ok(method) => Some(method),
+                            Err(error) => panic!("{}", error),
+                        },
+                        None => None,
+                    };
+                    let dag = if dag_method != go_dag::DagMethod::Classic || similarity_method.is_some() {
+                        match load_dag(&json_string, &gene_sets) {
+                            Ok(dag) => Some(dag),
+                            Err(error) => panic!("{}", error),
+                        }
+                    } else {
+                        None
+                    };
 
-                                        let mut rows = gene_stmt.query([])?;
-                                        let mut names = HashSet::<String>::new();
-                                        while let Some(row) = rows.next()? {
-                                            let a: String = row.get(0)?;
-                                            let input_gene_json = json::parse(&a);
-                                            match input_gene_json {
-                                                Ok(json_genes) => {
-                                                    for json_iter in 0..json_genes.len() {
-                                                        names.insert(json_genes[json_iter]["symbol"].to_string());
-                                                    }
-                                                }
-                                                Err(_) => {
-                                                    panic!("Symbol, ensg, enstCanonical structure is missing!")
-                                                }
-                                            }
-                                        }
-                                        let gene_set_size = names.len();
-                                        let (p_value, matches, gene_set_hits) = calculate_hypergeometric_p_value(
-                                            &sample_coding_genes,
-                                            num_background_genes,
-                                            names,
-                                        );
-                                        if matches >= 1.0 && p_value.is_nan() == false {
-                                            pathway_p_values.push(pathway_p_value {
-                                                pathway_name: n.GO_id,
-                                                p_value_original: p_value,
-                                                p_value_adjusted: None,
-                                                gene_set_hits: gene_set_hits,
-                                                gene_set_size: gene_set_size,
-                                            })
-                                        }
-                                    }
-                                    Err(_) => {
-                                        println!("GO term not found!")
-                                    }
+                    let (tested_gene_sets, gene_set_sizes) = restrict_to_universe(&gene_sets, &universe, &size_filter);
+                    // P-values decorrelated along the term hierarchy with the elim or weight algorithm
+                    let dag_p_values = match &dag {
+                        Some(dag) if dag_method != go_dag::DagMethod::Classic => {
+                            let cutoff = json_string["dag_cutoff"].as_f64().unwrap_or(0.01);
+                            let score = |weights: &HashMap<&str, f64>| {
+                                weighted_hypergeometric_p_value(&sample_coding_genes, num_background_genes, weights)
+                            };
+                            Some(go_dag::dag_p_values(dag, &tested_gene_sets, dag_method, cutoff, &score))
+                        }
+                        _ => None,
+                    };
+                    pathway_p_values.extend(test_gene_sets(
+                        &sample_coding_genes,
+                        num_background_genes,
+                        &tested_gene_sets,
+                        &gene_set_sizes,
+                        dag_p_values.as_deref(),
+                    ));
+                    let num_pathways = pathway_p_values.len();
+                    let adjusted_p_values = adjust_p_values(pathway_p_values);
+                    let mut output_string = "{\"num_pathways\":".to_string()
+                        + &num_pathways.to_string()
+                        + ",\"num_sample_genes\":"
+                        + &sample_coding_genes.len().to_string()
+                        + ",\"num_background_genes\":"
+                        + &num_background_genes.to_string()
+                        + ",\"pathways\":"
+                        + &pathways_to_string(&adjusted_p_values, num_items_output);
+                    if let Some(unmapped_genes) = unmapped_genes {
+                        // Symbols of the custom gene sets not found in genedb
+                        output_string +=
+                            &(",\"unmapped_genes\":".to_string() + &serde_json::to_string(&unmapped_genes).unwrap());
+                    }
+                    if let Some(redundancy) = redundancy {
+                        // Significant pathways clustered by gene set similarity, one representative per cluster
+                        let genes_of: HashMap<&str, &HashSet<String>> =
+                            gene_sets.iter().map(|gs| (gs.id.as_str(), &gs.genes)).collect();
+                        let significant: Vec<(&str, &HashSet<String>)> = adjusted_p_values
+                            .iter()
+                            .filter(|p| p.p_value_adjusted.unwrap() <= redundancy.fdr_cutoff)
+                            .map(|p| (p.pathway_name.as_str(), genes_of[p.pathway_name.as_str()]))
+                            .collect();
+                        let clusters =
+                            geneset_functions::cluster_redundant_terms(&significant, &redundancy, num_background_genes);
+                        output_string += &(",\"clusters\":".to_string() + &serde_json::to_string(&clusters).unwrap());
+                    }
+                    if let (Some(dag), Some(similarity_method)) = (&dag, similarity_method) {
+                        // Semantic similarity between the significant pathways of the output
+                        let fdr_cutoff = json_string["similarity_fdr_cutoff"].as_f64().unwrap_or(0.05);
+                        let terms: Vec<&str> = adjusted_p_values
+                            .iter()
+                            .take(num_items_output)
+                            .filter(|p| p.p_value_adjusted.unwrap() <= fdr_cutoff)
+                            .map(|p| p.pathway_name.as_str())
+                            .collect();
+                        let matrix = go_dag::semantic_similarity(dag, &terms, &gene_sets, similarity_method);
+                        output_string += &(",\"semantic_similarity\":".to_string()
+                            + &serde_json::json!({ "terms": terms, "matrix": matrix }).to_string());
+                    }
+                    if json_string["dot_plot"].as_bool().unwrap_or(false) && !adjusted_p_values.is_empty() {
+                        let num_terms = json_string["num_dot_plot_terms"].as_usize().unwrap_or(20);
+                        let terms: Vec<enrichment_plots::DotPlotTerm> = adjusted_p_values
+                            .iter()
+                            .take(num_terms)
+                            .map(|p| {
+                                let count = p.gene_set_hits.split(",").filter(|g| !g.is_empty()).count();
+                                enrichment_plots::DotPlotTerm {
+                                    name: p.pathway_name.clone(),
+                                    gene_ratio: count as f64 / sample_coding_genes.len() as f64,
+                                    count,
+                                    fdr: p.p_value_adjusted.unwrap(),
                                 }
-                            }
+                            })
+                            .collect();
+                        let height = (75 + 22 * terms.len() as u32).max(230); // Room for the legends
+                        let options = enrichment_plots::PlotOptions::from_json(&json_string, 700, height);
+                        match enrichment_plots::render_dot_plot(&terms, &options) {
+                            Ok(plot) => output_string += &(",\"dot_plot\":".to_string() + &plot.to_string()),
+                            Err(error) => panic!("Dot plot rendering failed:{}", error),
                         }
-                        Err(_) => panic!("sqlite database file not found"),
                     }
-                    let output_string = "{\"num_pathways\":".to_string()
-                        + &pathway_p_values.len().to_string()
-                        + &",\"pathways\":"
-                        + &adjust_p_values(pathway_p_values, num_items_output)
-                        + &"}";
+                    output_string += "}";
                     println!("{}", output_string);
                     //println!("Time for calculating gene overrepresentation:{:?}", run_time.elapsed());
                 }
@@ -226,7 +444,7 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn adjust_p_values(mut original_p_values: Vec<pathway_p_value>, mut num_items_output: usize) -> String {
+fn adjust_p_values(mut original_p_values: Vec<pathway_p_value>) -> Vec<pathway_p_value> {
     // Sorting p-values in ascending order
     original_p_values.as_mut_slice().sort_by(|a, b| {
         (a.p_value_original)
@@ -248,10 +466,8 @@ fn adjust_p_values(mut original_p_values: Vec<pathway_p_value>, mut num_items_ou
         }
         //println!("Original p_value:{}", original_p_values[i].p_value);
         //println!("Raw adjusted p_value:{}", adjusted_p_value);
-        if i != original_p_values.len() - 1 {
-            if adjusted_p_val > old_p_value {
-                adjusted_p_val = old_p_value;
-            }
+        if i != original_p_values.len() - 1 && adjusted_p_val > old_p_value {
+            adjusted_p_val = old_p_value;
         }
         old_p_value = adjusted_p_val;
         //println!("adjusted_p_value:{}", adjusted_p_val);
@@ -263,6 +479,7 @@ fn adjust_p_values(mut original_p_values: Vec<pathway_p_value>, mut num_items_ou
             p_value_adjusted: Some(adjusted_p_val),
             gene_set_hits: original_p_values[i].gene_set_hits.clone(),
             gene_set_size: original_p_values[i].gene_set_size,
+            effective_gene_set_size: original_p_values[i].effective_gene_set_size,
         });
     }
     adjusted_p_values.as_mut_slice().sort_by(|a, b| {
@@ -271,17 +488,21 @@ fn adjust_p_values(mut original_p_values: Vec<pathway_p_value>, mut num_items_ou
             .unwrap_or(Ordering::Equal)
     });
 
+    adjusted_p_values
+}
+
+fn pathways_to_string(adjusted_p_values: &[pathway_p_value], mut num_items_output: usize) -> String {
     if num_items_output > adjusted_p_values.len() {
         num_items_output = adjusted_p_values.len()
     }
 
     let mut output_string = "[".to_string();
-    for i in 0..num_items_output {
-        output_string += &serde_json::to_string(&adjusted_p_values[i]).unwrap();
+    for (i, p_value) in adjusted_p_values.iter().take(num_items_output).enumerate() {
+        output_string += &serde_json::to_string(p_value).unwrap();
         if i < num_items_output - 1 {
-            output_string += &",".to_string();
+            output_string += ",";
         }
     }
-    output_string += &"]".to_string();
+    output_string += "]";
     output_string
 }
@@ -0,0 +1,367 @@
+// Gene set database access shared by genesetORA.rs and cerno.rs
+// The gene set databases (e.g. msigdb) contain the tables:
+//   terms:      id, parent_id (the gene set group the term belongs to), ...
+//   term2genes: id, genes (JSON array of {"symbol":..,"ensg":..,"enstCanonical":..})
+// All queries use bound parameters, so group and term IDs may contain any character (e.g. quotes).
+// Custom gene set collections can be given instead of the database, either as a GMT file
+// (https://software.broadinstitute.org/cancer/software/gsea/wiki/index.php/Data_formats#GMT) or as an inline
+// {name: [genes]} JSON object. Their gene symbols are normalized against the genedb codingGenes table.
+// Also contains the gene set size filter and the redundancy reduction of significant terms shared by both tools.
+use json::JsonValue;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+
+#[derive(Debug, Clone)]
+pub struct GeneSet {
+    pub id: String,
+    pub genes: HashSet<String>, // Gene symbols
+}
+
+/// Gene sets in order of first appearance; gene sets with the same ID are merged
+#[derive(Default)]
+struct GeneSetCollection {
+    gene_sets: Vec<GeneSet>,
+    index_of: HashMap<String, usize>,
+}
+
+impl GeneSetCollection {
+    fn add(&mut self, id: String, genes: impl Iterator<Item = String>) {
+        let index = match self.index_of.get(&id) {
+            Some(&index) => index,
+            None => {
+                self.index_of.insert(id.clone(), self.gene_sets.len());
+                self.gene_sets.push(GeneSet {
+                    id,
+                    genes: HashSet::new(),
+                });
+                self.gene_sets.len() - 1
+            }
+        };
+        self.gene_sets[index].genes.extend(genes);
+    }
+}
+
+/// Opens a read-only connection pool on a sqlite database. Fails if the file does not exist.
+#[allow(dead_code)]
+pub fn open_pool(db: &str) -> Result<DbPool, String> {
+    if !Path::new(db).is_file() {
+        return Err(format!("sqlite database file not found: {}", db));
+    }
+    let manager = SqliteConnectionManager::file(db).with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY);
+    r2d2::Pool::new(manager).map_err(|e| format!("Cannot open sqlite database {}: {}", db, e))
+}
+
+/// Loads all the gene sets of a gene set group with a single query. Gene sets are returned sorted by term id, so that the
+/// output does not depend on the storage order of the database; several term2genes rows for the same term are merged.
+#[allow(dead_code)]
+pub fn load_gene_sets(conn: &Connection, genesetgroup: &str) -> Result<Vec<GeneSet>, String> {
+    let mut stmt = conn
+        .prepare(
+            "select terms.id, term2genes.genes from terms join term2genes on term2genes.id = terms.id where terms.parent_id = ?1 order by terms.id, term2genes.rowid",
+        )
+        .map_err(|e| format!("Cannot query gene sets: {}", e))?;
+    let mut rows = stmt
+        .query([genesetgroup])
+        .map_err(|e| format!("Cannot query gene sets: {}", e))?;
+    let mut gene_sets = GeneSetCollection::default();
+    while let Some(row) = rows.next().map_err(|e| format!("Cannot read gene sets: {}", e))? {
+        let id: String = row.get(0).map_err(|e| format!("Cannot read term id: {}", e))?;
+        let genes_json: String = row.get(1).map_err(|e| format!("Cannot read genes of {}: {}", id, e))?;
+        let json_genes = json::parse(&genes_json)
+            .map_err(|_| format!("Symbol, ensg, enstCanonical structure is missing for {}!", id))?;
+        gene_sets.add(id, (0..json_genes.len()).map(|i| json_genes[i]["symbol"].to_string()));
+    }
+    Ok(gene_sets.gene_sets)
+}
+
+/// Parses a GMT file: one gene set per line as name<TAB>description<TAB>gene1<TAB>gene2...
+/// Empty lines and lines starting with '#' are skipped. Gene sets with the same name are merged.
+#[allow(dead_code)]
+pub fn parse_gmt(content: &str) -> Result<Vec<GeneSet>, String> {
+    let mut gene_sets = GeneSetCollection::default();
+    for (line_num, line) in content.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let name = fields[0].trim();
+        if fields.len() < 2 || name.is_empty() {
+            return Err(format!(
+                "Invalid GMT line {}: expected name, description and genes",
+                line_num + 1
+            ));
+        }
+        let genes = fields[2.min(fields.len())..]
+            .iter()
+            .map(|g| g.trim())
+            .filter(|g| !g.is_empty())
+            .map(|g| g.to_string());
+        gene_sets.add(name.to_string(), genes);
+    }
+    Ok(gene_sets.gene_sets)
+}
+
+/// Parses an inline {name: [genes]} JSON object
+#[allow(dead_code)]
+pub fn parse_inline_gene_sets(input: &JsonValue) -> Result<Vec<GeneSet>, String> {
+    if !input.is_object() {
+        return Err("gene_sets must be an object of {name: [genes]}".to_string());
+    }
+    let mut gene_sets = GeneSetCollection::default();
+    for (name, genes) in input.entries() {
+        if !genes.is_array() {
+            return Err(format!("Genes of gene set {} must be an array", name));
+        }
+        let mut symbols = Vec::with_capacity(genes.len());
+        for gene in genes.members() {
+            match gene.as_str() {
+                Some(g) if !g.trim().is_empty() => symbols.push(g.trim().to_string()),
+                Some(_) => {}
+                None => return Err(format!("Gene set {} contains a non-string gene: {}", name, gene)),
+            }
+        }
+        gene_sets.add(name.to_string(), symbols.into_iter());
+    }
+    Ok(gene_sets.gene_sets)
+}
+
+/// Reads the custom gene sets of the input json: an inline "gene_sets" object or a "gmt_file" path.
+/// Returns None when neither is given, in which case the gene sets are read from the database.
+#[allow(dead_code)]
+pub fn load_custom_gene_sets(json_string: &JsonValue) -> Result<Option<Vec<GeneSet>>, String> {
+    if !json_string["gene_sets"].is_null() {
+        return parse_inline_gene_sets(&json_string["gene_sets"]).map(Some);
+    }
+    match json_string["gmt_file"].as_str() {
+        Some(gmt_file) => {
+            let content =
+                fs::read_to_string(gmt_file).map_err(|e| format!("Cannot read GMT file {}: {}", gmt_file, e))?;
+            parse_gmt(&content).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reads the gene symbols of the codingGenes table of genedb
+#[allow(dead_code)]
+pub fn load_coding_genes(conn: &Connection) -> Result<Vec<String>, String> {
+    let mut stmt = conn
+        .prepare("select * from codingGenes")
+        .map_err(|e| format!("Cannot query codingGenes: {}", e))?;
+    let mut rows = stmt.query([]).map_err(|e| format!("Cannot query codingGenes: {}", e))?;
+    let mut coding_genes = Vec::new();
+    while let Some(row) = rows.next().map_err(|e| format!("Cannot read codingGenes: {}", e))? {
+        coding_genes.push(row.get(0).map_err(|e| format!("Cannot read codingGenes: {}", e))?);
+    }
+    Ok(coding_genes)
+}
+
+/// Query genes kept for the analysis, in input order: only those in `coding_genes` when filter_non_coding_genes is set,
+/// otherwise all of them
+#[allow(dead_code)]
+pub fn filter_query_genes<T: AsRef<str>>(
+    query_genes: Vec<T>,
+    coding_genes: &HashSet<String>,
+    filter_non_coding_genes: bool,
+) -> Vec<T> {
+    if !filter_non_coding_genes {
+        return query_genes;
+    }
+    query_genes
+        .into_iter()
+        .filter(|gene| coding_genes.contains(gene.as_ref()))
+        .collect()
+}
+
+/// Maps the gene symbols of user-provided gene sets to the official symbols of `coding_genes`: symbols are matched
+/// exactly first, then case-insensitively (e.g. "tp53" -> "TP53"). Symbols that cannot be mapped are removed from the
+/// gene sets and returned, sorted and without duplicates, so that they can be reported.
+#[allow(dead_code)]
+pub fn normalize_gene_sets(gene_sets: &mut [GeneSet], coding_genes: &[String]) -> Vec<String> {
+    let exact: HashSet<&str> = coding_genes.iter().map(|g| g.as_str()).collect();
+    let mut uppercase = HashMap::<String, &str>::with_capacity(coding_genes.len());
+    for gene in coding_genes {
+        uppercase.entry(gene.to_uppercase()).or_insert(gene.as_str());
+    }
+    let mut unmapped = HashSet::<String>::new();
+    for gene_set in gene_sets.iter_mut() {
+        let mut normalized = HashSet::with_capacity(gene_set.genes.len());
+        for gene in gene_set.genes.drain() {
+            if exact.contains(gene.as_str()) {
+                normalized.insert(gene);
+            } else if let Some(symbol) = uppercase.get(&gene.to_uppercase()) {
+                normalized.insert(symbol.to_string());
+            } else {
+                unmapped.insert(gene);
+            }
+        }
+        gene_set.genes = normalized;
+    }
+    let mut unmapped: Vec<String> = unmapped.into_iter().collect();
+    unmapped.sort();
+    unmapped
+}
+
+/// Minimum and maximum gene set sizes (min_gs_size, max_gs_size in the input json) as minGSSize/maxGSSize in
+/// clusterProfiler. Gene sets outside the limits are not tested, so they do not count in the multiple testing correction.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeFilter {
+    pub min_size: Option<usize>,
+    pub max_size: Option<usize>,
+}
+
+#[allow(dead_code)]
+impl SizeFilter {
+    pub fn from_json(json_string: &JsonValue) -> Result<SizeFilter, String> {
+        let size_option = |key: &str| -> Result<Option<usize>, String> {
+            let value = &json_string[key];
+            if value.is_null() {
+                return Ok(None);
+            }
+            value
+                .as_usize()
+                .map(Some)
+                .ok_or_else(|| format!("{} must be a non-negative integer", key))
+        };
+        let filter = SizeFilter {
+            min_size: size_option("min_gs_size")?,
+            max_size: size_option("max_gs_size")?,
+        };
+        if let (Some(min_size), Some(max_size)) = (filter.min_size, filter.max_size) {
+            if min_size > max_size {
+                return Err(format!(
+                    "min_gs_size ({}) is larger than max_gs_size ({})",
+                    min_size, max_size
+                ));
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn accepts(&self, size: usize) -> bool {
+        self.min_size.is_none_or(|min_size| size >= min_size) && self.max_size.is_none_or(|max_size| size <= max_size)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SimilarityMetric {
+    Jaccard,
+    Kappa, // Cohen's kappa of gene membership, as in DAVID functional annotation clustering
+}
+
+/// Options of the redundancy reduction of significant terms, given in the input json as
+/// "redundancy": {"metric": "jaccard" | "kappa", "threshold": number, "fdr_cutoff": number}
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct RedundancyOptions {
+    pub metric: SimilarityMetric,
+    pub threshold: f64, // Terms with a similarity >= threshold to a representative join its cluster (default 0.5 for jaccard, 0.35 for kappa)
+    pub fdr_cutoff: f64, // Only terms with an adjusted p-value <= fdr_cutoff are clustered (default 0.05)
+}
+
+#[allow(dead_code)]
+impl RedundancyOptions {
+    /// Returns None when the input json has no "redundancy" object
+    pub fn from_json(json_string: &JsonValue) -> Result<Option<RedundancyOptions>, String> {
+        let input = &json_string["redundancy"];
+        if input.is_null() {
+            return Ok(None);
+        }
+        let metric = match input["metric"].as_str().unwrap_or("jaccard") {
+            "jaccard" => SimilarityMetric::Jaccard,
+            "kappa" => SimilarityMetric::Kappa,
+            other => return Err(format!("Unknown redundancy metric {}, must be jaccard or kappa", other)),
+        };
+        let default_threshold = match metric {
+            SimilarityMetric::Jaccard => 0.5,
+            SimilarityMetric::Kappa => 0.35,
+        };
+        Ok(Some(RedundancyOptions {
+            metric,
+            threshold: input["threshold"].as_f64().unwrap_or(default_threshold),
+            fdr_cutoff: input["fdr_cutoff"].as_f64().unwrap_or(0.05),
+        }))
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TermCluster {
+    pub representative: String,
+    pub members: Vec<String>, // All the terms of the cluster, starting with the representative
+}
+
+/// Similarity of the gene memberships of two terms. `universe_size` is the number of genes tested (needed by kappa).
+#[allow(dead_code)]
+pub fn term_similarity(
+    genes1: &HashSet<String>,
+    genes2: &HashSet<String>,
+    metric: SimilarityMetric,
+    universe_size: usize,
+) -> f64 {
+    let both = genes1.intersection(genes2).count() as f64;
+    let only1 = genes1.len() as f64 - both;
+    let only2 = genes2.len() as f64 - both;
+    match metric {
+        SimilarityMetric::Jaccard => {
+            let union = both + only1 + only2;
+            if union == 0.0 { 0.0 } else { both / union }
+        }
+        SimilarityMetric::Kappa => {
+            let total = (universe_size as f64).max(both + only1 + only2);
+            if total == 0.0 {
+                return 0.0;
+            }
+            let neither = total - both - only1 - only2;
+            let observed = (both + neither) / total;
+            let expected = ((both + only1) * (both + only2) + (only2 + neither) * (only1 + neither)) / (total * total);
+            if expected >= 1.0 {
+                0.0
+            } else {
+                (observed - expected) / (1.0 - expected)
+            }
+        }
+    }
+}
+
+/// Greedy clustering of significant terms, given in order of decreasing significance: every term joins the cluster of
+/// the most similar representative if the similarity reaches the threshold, otherwise it becomes a new representative.
+/// The most significant term of every cluster is therefore its representative.
+#[allow(dead_code)]
+pub fn cluster_redundant_terms(
+    terms: &[(&str, &HashSet<String>)],
+    options: &RedundancyOptions,
+    universe_size: usize,
+) -> Vec<TermCluster> {
+    let mut clusters = Vec::<TermCluster>::new();
+    let mut representative_genes = Vec::<&HashSet<String>>::new();
+    for (name, genes) in terms {
+        let mut best: Option<(usize, f64)> = None;
+        for (i, rep_genes) in representative_genes.iter().enumerate() {
+            let similarity = term_similarity(genes, rep_genes, options.metric, universe_size);
+            if similarity >= options.threshold && best.is_none_or(|(_, s)| similarity > s) {
+                best = Some((i, similarity));
+            }
+        }
+        match best {
+            Some((i, _)) => clusters[i].members.push(name.to_string()),
+            None => {
+                clusters.push(TermCluster {
+                    representative: name.to_string(),
+                    members: vec![name.to_string()],
+                });
+                representative_genes.push(genes);
+            }
+        }
+    }
+    clusters
+}
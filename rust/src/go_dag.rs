@@ -0,0 +1,495 @@
+// Directed acyclic graph of gene set terms (e.g. GO) for DAG-aware enrichment and semantic similarity
+// The DAG is held in a petgraph graph with edges going from child terms to parent terms. It is read either from a GO OBO
+// file (is_a and part_of relationships) or from a tab-delimited edge file (child, parent, is_a | part_of). The
+// parent_id column of the msigdb terms table is the gene set collection of a term, not its GO parent, so it cannot be
+// used. Gene sets are placed in the DAG by their GO id: the gene set id itself when it is a GO term of the DAG (e.g.
+// custom gene sets named GO:0006915), otherwise the GO id given for the gene set in a gene set to GO id file (msigdb
+// gene sets such as GOBP_APOPTOTIC_PROCESS).
+//
+// Enrichment algorithms (Alexa et al. 2006, https://doi.org/10.1093/bioinformatics/btl140, as implemented in topGO):
+//   elim:   terms are scored from the bottom of the DAG up. The genes of significant terms are removed from all
+//           their ancestors before the ancestors are scored.
+//   weight: every child term is compared with its parent. The genes of the less significant of the two are
+//           down-weighted by the ratio of their p-values, and both terms are scored again with the new weights.
+// Semantic similarity between terms:
+//   wang:   Wang et al. 2007 (https://doi.org/10.1093/bioinformatics/btm087), from the DAG topology only
+//           (0.8 for is_a edges and 0.6 for part_of edges)
+//   resnik: Resnik 1995, information content of the most informative common ancestor. The annotation frequency of a
+//           term is the number of genes of its gene set, or of the union of the gene sets below it.
+use crate::geneset_functions::GeneSet;
+use petgraph::Direction;
+use petgraph::algo::toposort;
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::{Dfs, EdgeRef, Reversed};
+use std::collections::{HashMap, HashSet};
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Relation {
+    IsA,
+    PartOf,
+}
+
+#[allow(dead_code)]
+impl Relation {
+    /// Semantic contribution factor of the edge in the Wang similarity
+    fn wang_weight(&self) -> f64 {
+        match self {
+            Relation::IsA => 0.8,
+            Relation::PartOf => 0.6,
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DagMethod {
+    Classic, // Every term is tested independently
+    Elim,
+    Weight,
+}
+
+#[allow(dead_code)]
+impl DagMethod {
+    pub fn from_str(method: &str) -> Result<DagMethod, String> {
+        match method {
+            "classic" => Ok(DagMethod::Classic),
+            "elim" => Ok(DagMethod::Elim),
+            "weight" => Ok(DagMethod::Weight),
+            _ => Err(format!(
+                "Unknown DAG method {}, must be classic, elim or weight",
+                method
+            )),
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SemanticSimilarity {
+    Wang,
+    Resnik,
+}
+
+#[allow(dead_code)]
+impl SemanticSimilarity {
+    pub fn from_str(method: &str) -> Result<SemanticSimilarity, String> {
+        match method {
+            "wang" => Ok(SemanticSimilarity::Wang),
+            "resnik" => Ok(SemanticSimilarity::Resnik),
+            _ => Err(format!(
+                "Unknown semantic similarity {}, must be wang or resnik",
+                method
+            )),
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub struct TermDag {
+    graph: DiGraph<String, Relation>,  // Edges go from child to parent
+    index: HashMap<String, NodeIndex>, // GO ids, and the gene set ids placed in the DAG with place_terms
+    order: Vec<NodeIndex>,             // Nodes ordered so that every term comes before its parents
+}
+
+#[allow(dead_code)]
+impl TermDag {
+    /// Builds the DAG from (child, parent, relation) edges. Fails if the edges contain a cycle.
+    pub fn from_edges(edges: impl IntoIterator<Item = (String, String, Relation)>) -> Result<TermDag, String> {
+        let mut dag = TermDag {
+            graph: DiGraph::new(),
+            index: HashMap::new(),
+            order: Vec::new(),
+        };
+        let mut seen = HashSet::new();
+        for (child, parent, relation) in edges {
+            if child == parent || !seen.insert((child.clone(), parent.clone())) {
+                continue;
+            }
+            let child_node = dag.node(&child);
+            let parent_node = dag.node(&parent);
+            dag.graph.add_edge(child_node, parent_node, relation);
+        }
+        match toposort(&dag.graph, None) {
+            Ok(order) => dag.order = order,
+            Err(cycle) => {
+                return Err(format!(
+                    "Term hierarchy has a cycle through {}",
+                    dag.graph[cycle.node_id()]
+                ));
+            }
+        }
+        Ok(dag)
+    }
+
+    /// Places the gene sets `terms` in the DAG: a term that is a GO id of the DAG is its own node, other terms are
+    /// placed at the node of their GO id in `go_ids`. Fails if a term cannot be placed, or if two terms have the same
+    /// node.
+    pub fn place_terms(&mut self, terms: &[&str], go_ids: &HashMap<String, String>) -> Result<(), String> {
+        let mut term_of: HashMap<NodeIndex, &str> = HashMap::new();
+        for &term in terms {
+            let node = match self.index.get(term) {
+                Some(&node) => node,
+                None => {
+                    let go_id = go_ids
+                        .get(term)
+                        .ok_or_else(|| format!("Gene set {} has no GO id to place it in the GO hierarchy", term))?;
+                    *self
+                        .index
+                        .get(go_id)
+                        .ok_or_else(|| format!("GO id {} of gene set {} is not in the GO hierarchy", go_id, term))?
+                }
+            };
+            if let Some(other) = term_of.insert(node, term) {
+                return Err(format!(
+                    "Gene sets {} and {} are both placed at {}",
+                    other, term, self.graph[node]
+                ));
+            }
+        }
+        for (node, term) in term_of {
+            self.index.insert(term.to_string(), node);
+        }
+        Ok(())
+    }
+
+    fn node(&mut self, term: &str) -> NodeIndex {
+        match self.index.get(term) {
+            Some(&node) => node,
+            None => {
+                let node = self.graph.add_node(term.to_string());
+                self.index.insert(term.to_string(), node);
+                node
+            }
+        }
+    }
+
+    pub fn contains(&self, term: &str) -> bool {
+        self.index.contains_key(term)
+    }
+
+    /// All the ancestors of a node (excluding itself)
+    fn ancestors(&self, node: NodeIndex) -> HashSet<NodeIndex> {
+        let mut ancestors = HashSet::new();
+        let mut dfs = Dfs::new(&self.graph, node);
+        while let Some(ancestor) = dfs.next(&self.graph) {
+            if ancestor != node {
+                ancestors.insert(ancestor);
+            }
+        }
+        ancestors
+    }
+
+    /// All the descendants of a node (excluding itself)
+    fn descendants(&self, node: NodeIndex) -> HashSet<NodeIndex> {
+        let reversed = Reversed(&self.graph);
+        let mut descendants = HashSet::new();
+        let mut dfs = Dfs::new(reversed, node);
+        while let Some(descendant) = dfs.next(reversed) {
+            if descendant != node {
+                descendants.insert(descendant);
+            }
+        }
+        descendants
+    }
+}
+
+/// Reads a tab-delimited edge file: child GO id, parent GO id and relation (is_a or part_of) on every line. Empty lines
+/// and lines starting with '#' are skipped.
+#[allow(dead_code)]
+pub fn parse_edges(content: &str) -> Result<TermDag, String> {
+    let mut edges = Vec::new();
+    for (line_num, line) in content.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(|f| f.trim()).collect();
+        if fields.len() < 3 || fields[0].is_empty() || fields[1].is_empty() {
+            return Err(format!(
+                "Line {} of the GO edges must be child<TAB>parent<TAB>relation",
+                line_num + 1
+            ));
+        }
+        let relation = match fields[2] {
+            "is_a" => Relation::IsA,
+            "part_of" => Relation::PartOf,
+            other => {
+                return Err(format!(
+                    "Unknown relation {} on line {} of the GO edges, must be is_a or part_of",
+                    other,
+                    line_num + 1
+                ));
+            }
+        };
+        edges.push((fields[0].to_string(), fields[1].to_string(), relation));
+    }
+    TermDag::from_edges(edges)
+}
+
+/// Reads the GO id of gene sets from tab-delimited lines: gene set id and GO id (the EXACT_SOURCE of MSigDB GO gene
+/// sets). Empty lines and lines starting with '#' are skipped.
+#[allow(dead_code)]
+pub fn parse_go_ids(content: &str) -> Result<HashMap<String, String>, String> {
+    let mut go_ids = HashMap::new();
+    for (line_num, line) in content.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(|f| f.trim()).collect();
+        if fields.len() < 2 || fields[0].is_empty() || fields[1].is_empty() {
+            return Err(format!(
+                "Line {} of the GO ids must be gene_set<TAB>GO id",
+                line_num + 1
+            ));
+        }
+        go_ids.insert(fields[0].to_string(), fields[1].to_string());
+    }
+    Ok(go_ids)
+}
+
+/// Reads the is_a and part_of relationships of the [Term] stanzas of an OBO file. Obsolete terms are skipped.
+#[allow(dead_code)]
+pub fn parse_obo(content: &str) -> Result<TermDag, String> {
+    let mut edges = Vec::new();
+    let mut in_term = false;
+    let mut id: Option<String> = None;
+    let mut term_edges: Vec<(String, Relation)> = Vec::new();
+    let mut obsolete = false;
+    let mut flush = |id: &mut Option<String>, term_edges: &mut Vec<(String, Relation)>, obsolete: &mut bool| {
+        if let Some(id) = id.take() {
+            if !*obsolete {
+                for (parent, relation) in term_edges.drain(..) {
+                    edges.push((id.clone(), parent, relation));
+                }
+            }
+        }
+        term_edges.clear();
+        *obsolete = false;
+    };
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            flush(&mut id, &mut term_edges, &mut obsolete);
+            in_term = line == "[Term]";
+            continue;
+        }
+        if !in_term {
+            continue;
+        }
+        // Trailing modifiers and comments ("is_a: GO:0008150 ! biological_process") are ignored
+        let value_of = |tag: &str| {
+            line.strip_prefix(tag)
+                .map(|v| v.split('!').next().unwrap_or("").trim().to_string())
+        };
+        if let Some(value) = value_of("id:") {
+            id = Some(value);
+        } else if let Some(value) = value_of("is_a:") {
+            term_edges.push((value.split_whitespace().next().unwrap_or("").to_string(), Relation::IsA));
+        } else if let Some(value) = value_of("relationship:") {
+            let fields: Vec<&str> = value.split_whitespace().collect();
+            if fields.len() >= 2 && fields[0] == "part_of" {
+                term_edges.push((fields[1].to_string(), Relation::PartOf));
+            }
+        } else if line == "is_obsolete: true" {
+            obsolete = true;
+        }
+    }
+    flush(&mut id, &mut term_edges, &mut obsolete);
+    TermDag::from_edges(edges.into_iter().filter(|(_, parent, _)| !parent.is_empty()))
+}
+
+/// Weight 1 for all the genes of a gene set
+fn full_weights(gene_set: &GeneSet) -> HashMap<&str, f64> {
+    gene_set.genes.iter().map(|g| (g.as_str(), 1.0)).collect()
+}
+
+/// P-values of the gene sets with the elim or weight algorithm (Classic scores every gene set once).
+/// `score` returns the p-value of a gene set given the weights of its genes (genes with weight 0 are removed).
+/// Gene sets are expected to be placed in the DAG (see place_terms); any that is not is scored independently.
+#[allow(dead_code)]
+pub fn dag_p_values(
+    dag: &TermDag,
+    gene_sets: &[GeneSet],
+    method: DagMethod,
+    cutoff: f64, // Significance cutoff of the elim algorithm (0.01 in topGO)
+    score: &dyn Fn(&HashMap<&str, f64>) -> f64,
+) -> Vec<f64> {
+    let mut p_values: Vec<f64> = vec![f64::NAN; gene_sets.len()];
+    let gene_set_of: HashMap<NodeIndex, usize> = gene_sets
+        .iter()
+        .enumerate()
+        .filter_map(|(i, gs)| dag.index.get(&gs.id).map(|&node| (node, i)))
+        .collect();
+    for (i, gs) in gene_sets.iter().enumerate() {
+        if method == DagMethod::Classic || !dag.contains(&gs.id) {
+            p_values[i] = score(&full_weights(gs));
+        }
+    }
+    if method == DagMethod::Classic {
+        return p_values;
+    }
+
+    let mut weights: Vec<HashMap<&str, f64>> = gene_sets.iter().map(full_weights).collect();
+    for &node in &dag.order {
+        let Some(&u) = gene_set_of.get(&node) else {
+            continue;
+        };
+        match method {
+            DagMethod::Elim => {
+                p_values[u] = score(&weights[u]);
+                if p_values[u] < cutoff {
+                    // Genes of a significant term no longer count for its ancestors
+                    for ancestor in dag.ancestors(node) {
+                        if let Some(&a) = gene_set_of.get(&ancestor) {
+                            for gene in &gene_sets[u].genes {
+                                if let Some(w) = weights[a].get_mut(gene.as_str()) {
+                                    *w = 0.0;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            DagMethod::Weight => {
+                p_values[u] = score(&weights[u]);
+                let children: Vec<usize> = dag
+                    .graph
+                    .edges_directed(node, Direction::Incoming)
+                    .filter_map(|e| gene_set_of.get(&e.source()).cloned())
+                    .collect();
+                for c in children {
+                    if p_values[c].is_nan() || p_values[u].is_nan() || p_values[c] <= 0.0 || p_values[u] <= 0.0 {
+                        continue;
+                    }
+                    let ratio = p_values[u] / p_values[c]; // > 1 if the child is more significant than the parent
+                    if ratio > 1.0 {
+                        // The parent is down-weighted for the genes it shares with the more significant child
+                        for gene in &gene_sets[c].genes {
+                            if let Some(w) = weights[u].get_mut(gene.as_str()) {
+                                *w /= ratio;
+                            }
+                        }
+                    } else {
+                        // The child is down-weighted, its significance is explained by the parent
+                        for w in weights[c].values_mut() {
+                            *w *= ratio;
+                        }
+                        p_values[c] = score(&weights[c]);
+                    }
+                }
+                p_values[u] = score(&weights[u]);
+            }
+            DagMethod::Classic => {}
+        }
+    }
+    p_values
+}
+
+/// Pairwise semantic similarity of `terms`. Terms absent from the DAG only have a similarity of 1 with themselves.
+#[allow(dead_code)]
+pub fn semantic_similarity(
+    dag: &TermDag,
+    terms: &[&str],
+    gene_sets: &[GeneSet],
+    method: SemanticSimilarity,
+) -> Vec<Vec<f64>> {
+    let nodes: Vec<Option<NodeIndex>> = terms.iter().map(|t| dag.index.get(*t).cloned()).collect();
+    let n = terms.len();
+    let mut matrix = vec![vec![0.0; n]; n];
+    match method {
+        SemanticSimilarity::Wang => {
+            let s_values: Vec<Option<HashMap<NodeIndex, f64>>> = nodes
+                .iter()
+                .map(|node| node.map(|node| wang_s_values(dag, node)))
+                .collect();
+            for i in 0..n {
+                matrix[i][i] = 1.0;
+                for j in (i + 1)..n {
+                    if let (Some(a), Some(b)) = (&s_values[i], &s_values[j]) {
+                        let common: f64 = a.iter().filter_map(|(t, sa)| b.get(t).map(|sb| sa + sb)).sum();
+                        let total: f64 = a.values().sum::<f64>() + b.values().sum::<f64>();
+                        matrix[i][j] = common / total;
+                        matrix[j][i] = matrix[i][j];
+                    }
+                }
+            }
+        }
+        SemanticSimilarity::Resnik => {
+            let information_content = resnik_information_content(dag, &nodes, gene_sets);
+            let ancestors: Vec<Option<HashSet<NodeIndex>>> = nodes
+                .iter()
+                .map(|node| {
+                    node.map(|node| {
+                        let mut ancestors = dag.ancestors(node);
+                        ancestors.insert(node);
+                        ancestors
+                    })
+                })
+                .collect();
+            for i in 0..n {
+                for j in i..n {
+                    if let (Some(a), Some(b)) = (&ancestors[i], &ancestors[j]) {
+                        matrix[i][j] = a
+                            .intersection(b)
+                            .filter_map(|t| information_content.get(t).cloned())
+                            .fold(0.0, f64::max);
+                        matrix[j][i] = matrix[i][j];
+                    }
+                }
+            }
+        }
+    }
+    matrix
+}
+
+/// S-values of the Wang similarity: contribution of every ancestor (and the term itself) to the semantics of the term
+fn wang_s_values(dag: &TermDag, term: NodeIndex) -> HashMap<NodeIndex, f64> {
+    let mut s_values = HashMap::new();
+    s_values.insert(term, 1.0);
+    // Children come before parents in the topological order, so every term is final before its parents are updated
+    for &node in &dag.order {
+        let Some(&s) = s_values.get(&node) else {
+            continue;
+        };
+        for edge in dag.graph.edges_directed(node, Direction::Outgoing) {
+            let contribution = s * edge.weight().wang_weight();
+            let parent = s_values.entry(edge.target()).or_insert(0.0);
+            if contribution > *parent {
+                *parent = contribution;
+            }
+        }
+    }
+    s_values
+}
+
+/// Information content -ln(p(t)) of the common ancestors of `nodes`, where p(t) is the fraction of all the annotated
+/// genes that are annotated to t or one of its descendants
+fn resnik_information_content(
+    dag: &TermDag,
+    nodes: &[Option<NodeIndex>],
+    gene_sets: &[GeneSet],
+) -> HashMap<NodeIndex, f64> {
+    let genes_of: HashMap<NodeIndex, &HashSet<String>> = gene_sets
+        .iter()
+        .filter_map(|gs| dag.index.get(&gs.id).map(|&node| (node, &gs.genes)))
+        .collect();
+    let all_genes: HashSet<&String> = genes_of.values().flat_map(|genes| genes.iter()).collect();
+    let mut candidates = HashSet::new();
+    for node in nodes.iter().flatten() {
+        candidates.insert(*node);
+        candidates.extend(dag.ancestors(*node));
+    }
+    let mut information_content = HashMap::new();
+    for node in candidates {
+        let mut annotated: HashSet<&String> = HashSet::new();
+        for term in dag.descendants(node).into_iter().chain(std::iter::once(node)) {
+            if let Some(genes) = genes_of.get(&term) {
+                annotated.extend(genes.iter());
+            }
+        }
+        if !annotated.is_empty() {
+            information_content.insert(node, -(annotated.len() as f64 / all_genes.len() as f64).ln());
+        }
+    }
+    information_content
+}
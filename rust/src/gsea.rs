@@ -0,0 +1,314 @@
+// Preranked gene set enrichment analysis (GSEA) with the weighted Kolmogorov-Smirnov enrichment score
+// Subramanian et al. 2005 (https://doi.org/10.1073/pnas.0506580102)
+// P-values are computed from gene set permutations as in the fgsea R package (https://doi.org/10.1101/060012). For gene
+// sets where permutations cannot resolve the p-value (few or no random gene sets are as extreme as the observed one),
+// the p-value is refined with the adaptive multilevel splitting Monte Carlo procedure of fgseaMultilevel.
+use crate::stats_functions::SeededRng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+const MULTILEVEL_SAMPLE_SIZE: usize = 101; // Number of random gene sets kept at every level of the multilevel procedure
+const MULTILEVEL_MIN_EXCEEDING: usize = 10; // Multilevel is used when fewer random gene sets than this are as extreme as the observed one
+const MIN_P_VALUE: f64 = 1e-50; // Lower bound for multilevel p-values (same default as fgsea eps)
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GseaResult {
+    pub es: f64,
+    pub nes: f64,
+    pub p_value: f64,
+    pub leading_edge: Vec<String>,
+    pub running_sum: Vec<(usize, f64)>, // Vertices of the running-sum curve as (number of genes walked, running sum)
+    pub gene_set_size: usize,           // Number of genes of the gene set found in the ranked list
+}
+
+/// Ranked gene list shared by all the gene sets tested in one run
+#[allow(dead_code)]
+pub struct RankedList {
+    genes: Vec<String>,                // Gene names sorted by decreasing statistic
+    weights: Vec<f64>,                 // |statistic|^weight in the same order
+    positions: HashMap<String, usize>, // Gene name -> position in the ranked list
+}
+
+#[allow(dead_code)]
+impl RankedList {
+    /// `genes` must be sorted by decreasing statistic. `weight` is the GSEA exponent p (0 = classic KS, 1 = default).
+    pub fn new(genes: Vec<String>, statistics: &[f64], weight: f64) -> RankedList {
+        let weights = statistics.iter().map(|s| s.abs().powf(weight)).collect();
+        let mut positions = HashMap::with_capacity(genes.len());
+        for (i, gene) in genes.iter().enumerate() {
+            positions.entry(gene.clone()).or_insert(i);
+        }
+        RankedList {
+            genes,
+            weights,
+            positions,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.genes.len()
+    }
+
+    /// Sorted positions of the genes of a gene set in the ranked list. Genes not in the list are ignored.
+    pub fn hit_positions(&self, gene_set: &HashSet<String>) -> Vec<usize> {
+        let mut hits: Vec<usize> = gene_set.iter().filter_map(|g| self.positions.get(g).cloned()).collect();
+        hits.sort();
+        hits.dedup();
+        hits
+    }
+
+    /// Vertices of the running-sum curve of a gene set (see running_sum_curve)
+    pub fn running_sum(&self, gene_set: &HashSet<String>) -> Vec<(usize, f64)> {
+        running_sum_curve(&self.weights, &self.hit_positions(gene_set))
+    }
+
+    fn reversed(&self) -> RankedList {
+        let genes: Vec<String> = self.genes.iter().rev().cloned().collect();
+        let weights = self.weights.iter().rev().cloned().collect();
+        let positions = genes.iter().enumerate().map(|(i, g)| (g.clone(), i)).collect();
+        RankedList {
+            genes,
+            weights,
+            positions,
+        }
+    }
+}
+
+/// Enrichment score of the gene set at the sorted positions `hits`. Returns (ES, index in `hits` of the peak).
+/// The running sum only changes direction at hits, so the maximum deviation is found in O(hits).
+fn enrichment_score(weights: &[f64], hits: &[usize]) -> (f64, usize) {
+    let n = weights.len();
+    let k = hits.len();
+    let mut nr: f64 = hits.iter().map(|&h| weights[h]).sum();
+    let equal_weights = nr == 0.0; // All hits have a zero statistic, fall back to the unweighted score
+    if equal_weights {
+        nr = k as f64;
+    }
+    let miss_penalty = 1.0 / (n - k) as f64;
+    let mut cumulative = 0.0;
+    let mut max_top = f64::NEG_INFINITY;
+    let mut max_index = 0;
+    let mut min_bottom = f64::INFINITY;
+    let mut min_index = 0;
+    for (j, &h) in hits.iter().enumerate() {
+        let bottom = cumulative - (h - j) as f64 * miss_penalty;
+        let w = if equal_weights { 1.0 } else { weights[h] };
+        cumulative += w / nr;
+        let top = bottom + w / nr;
+        if top > max_top {
+            max_top = top;
+            max_index = j;
+        }
+        if bottom < min_bottom {
+            min_bottom = bottom;
+            min_index = j;
+        }
+    }
+    if max_top >= -min_bottom {
+        (max_top, max_index)
+    } else {
+        (min_bottom, min_index)
+    }
+}
+
+/// Vertices of the running-sum curve: the curve is linear between hits, so the points just before and after every hit
+/// (plus both ends) describe it exactly.
+fn running_sum_curve(weights: &[f64], hits: &[usize]) -> Vec<(usize, f64)> {
+    let n = weights.len();
+    let k = hits.len();
+    let mut nr: f64 = hits.iter().map(|&h| weights[h]).sum();
+    let equal_weights = nr == 0.0;
+    if equal_weights {
+        nr = k as f64;
+    }
+    let miss_penalty = 1.0 / (n - k) as f64;
+    let mut curve = Vec::with_capacity(2 * k + 2);
+    curve.push((0, 0.0));
+    let mut cumulative = 0.0;
+    for (j, &h) in hits.iter().enumerate() {
+        let bottom = cumulative - (h - j) as f64 * miss_penalty;
+        let w = if equal_weights { 1.0 } else { weights[h] };
+        cumulative += w / nr;
+        if h > 0 {
+            curve.push((h, bottom));
+        }
+        curve.push((h + 1, bottom + w / nr));
+    }
+    if curve.last().map(|p| p.0) != Some(n) {
+        curve.push((n, 0.0));
+    }
+    curve
+}
+
+/// Draws `k` distinct positions from 0..n in O(k) (Floyd's algorithm), sorted in ascending order
+fn sample_positions(rng: &mut SeededRng, n: usize, k: usize) -> Vec<usize> {
+    let mut chosen = HashSet::with_capacity(k);
+    for j in (n - k)..n {
+        let t = rng.gen_range(j + 1);
+        if !chosen.insert(t) {
+            chosen.insert(j);
+        }
+    }
+    let mut positions: Vec<usize> = chosen.into_iter().collect();
+    positions.sort();
+    positions
+}
+
+/// Enrichment scores of `num_permutations` random gene sets of size `k`
+fn permutation_scores(weights: &[f64], k: usize, num_permutations: usize, rng: &mut SeededRng) -> Vec<f64> {
+    (0..num_permutations)
+        .map(|_| enrichment_score(weights, &sample_positions(rng, weights.len(), k)).0)
+        .collect()
+}
+
+/// Probability that a random gene set of size `k` has an enrichment score >= `es` (> 0), estimated with adaptive
+/// multilevel splitting: the random sample is repeatedly restricted to gene sets scoring above the sample median, which
+/// halves the probability at every level, and refilled by MCMC moves that swap one gene of the set with one outside it.
+fn multilevel_p_value(weights: &[f64], k: usize, es: f64, rng: &mut SeededRng) -> f64 {
+    let n = weights.len();
+    let mut sample: Vec<(Vec<usize>, f64)> = (0..MULTILEVEL_SAMPLE_SIZE)
+        .map(|_| {
+            let hits = sample_positions(rng, n, k);
+            let score = enrichment_score(weights, &hits).0;
+            (hits, score)
+        })
+        .collect();
+    let num_moves = k.clamp(10, 100);
+    let mut log_p = 0.0;
+    loop {
+        let mut scores: Vec<f64> = sample.iter().map(|s| s.1).collect();
+        scores.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let threshold = scores[MULTILEVEL_SAMPLE_SIZE / 2];
+        if threshold >= es {
+            break;
+        }
+        let survivors: Vec<(Vec<usize>, f64)> = sample.iter().filter(|s| s.1 > threshold).cloned().collect();
+        if survivors.is_empty() {
+            break;
+        }
+        log_p += (survivors.len() as f64 / MULTILEVEL_SAMPLE_SIZE as f64).ln();
+        if log_p < MIN_P_VALUE.ln() {
+            return MIN_P_VALUE;
+        }
+        sample = (0..MULTILEVEL_SAMPLE_SIZE)
+            .map(|i| survivors[i % survivors.len()].clone())
+            .collect();
+        for (hits, score) in sample.iter_mut() {
+            for _ in 0..num_moves {
+                let out = rng.gen_range(k);
+                let mut candidate = rng.gen_range(n);
+                while hits.binary_search(&candidate).is_ok() {
+                    candidate = rng.gen_range(n);
+                }
+                let mut proposal = hits.clone();
+                proposal.remove(out);
+                let insert_at = proposal.binary_search(&candidate).unwrap_err();
+                proposal.insert(insert_at, candidate);
+                let proposal_score = enrichment_score(weights, &proposal).0;
+                if proposal_score > threshold {
+                    *hits = proposal;
+                    *score = proposal_score;
+                }
+            }
+        }
+    }
+    let exceeding = sample.iter().filter(|s| s.1 >= es).count();
+    (log_p.exp() * (exceeding + 1) as f64 / (MULTILEVEL_SAMPLE_SIZE + 1) as f64).max(MIN_P_VALUE)
+}
+
+/// Runs preranked GSEA on all the gene sets. Gene sets with no genes (or all the genes) of the ranked list get `None`.
+/// Random gene sets are drawn once per distinct gene set size and shared by all gene sets of that size.
+#[allow(dead_code)]
+pub fn gsea(
+    ranked: &RankedList,
+    gene_sets: &[&HashSet<String>],
+    num_permutations: usize,
+    seed: u64,
+    max_threads: usize,
+) -> Vec<Option<GseaResult>> {
+    let n = ranked.len();
+    let hits: Vec<Vec<usize>> = gene_sets.iter().map(|gs| ranked.hit_positions(gs)).collect();
+    let mut sizes: Vec<usize> = hits.iter().map(|h| h.len()).filter(|&k| k > 0 && k < n).collect();
+    sizes.sort();
+    sizes.dedup();
+
+    // Null distribution of enrichment scores for every gene set size. Every size has its own seeded generator so that
+    // results do not depend on the number of threads.
+    let null_scores: HashMap<usize, Vec<f64>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..max_threads.max(1))
+            .map(|thread_num| {
+                let sizes = &sizes;
+                scope.spawn(move || {
+                    let mut scores = Vec::new();
+                    for (i, &k) in sizes.iter().enumerate() {
+                        if i % max_threads.max(1) == thread_num {
+                            let mut rng = SeededRng::new(seed ^ (k as u64).wrapping_mul(0x9E3779B97F4A7C15));
+                            scores.push((k, permutation_scores(&ranked.weights, k, num_permutations, &mut rng)));
+                        }
+                    }
+                    scores
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    });
+
+    let reversed = ranked.reversed();
+    let mut results = Vec::with_capacity(gene_sets.len());
+    for gene_set_hits in &hits {
+        let k = gene_set_hits.len();
+        if k == 0 || k >= n {
+            results.push(None);
+            continue;
+        }
+        let (es, peak) = enrichment_score(&ranked.weights, gene_set_hits);
+        let null = &null_scores[&k];
+        let (same_sign, exceeding): (Vec<f64>, usize) = if es >= 0.0 {
+            let same_sign: Vec<f64> = null.iter().cloned().filter(|&s| s >= 0.0).collect();
+            let exceeding = same_sign.iter().filter(|&&s| s >= es).count();
+            (same_sign, exceeding)
+        } else {
+            let same_sign: Vec<f64> = null.iter().cloned().filter(|&s| s < 0.0).collect();
+            let exceeding = same_sign.iter().filter(|&&s| s <= es).count();
+            (same_sign, exceeding)
+        };
+        let mean_same_sign = if same_sign.is_empty() {
+            f64::NAN
+        } else {
+            same_sign.iter().sum::<f64>() / same_sign.len() as f64
+        };
+        let nes = es / mean_same_sign.abs();
+        let mut p_value = (exceeding + 1) as f64 / (same_sign.len() + 1) as f64;
+        if exceeding < MULTILEVEL_MIN_EXCEEDING && !same_sign.is_empty() {
+            // Negative scores are handled as positive scores of the reversed ranking
+            let sign_fraction = same_sign.len() as f64 / null.len() as f64;
+            let mut rng = SeededRng::new(seed ^ (k as u64) ^ es.to_bits());
+            p_value = if es >= 0.0 {
+                multilevel_p_value(&ranked.weights, k, es, &mut rng)
+            } else {
+                multilevel_p_value(&reversed.weights, k, -es, &mut rng)
+            };
+            p_value = (p_value / sign_fraction).min(1.0);
+        }
+        // Leading edge: hits contributing to the running sum before (positive ES) or after (negative ES) the peak
+        let leading_edge_hits = if es >= 0.0 {
+            &gene_set_hits[..=peak]
+        } else {
+            &gene_set_hits[peak..]
+        };
+        let mut leading_edge: Vec<String> = leading_edge_hits.iter().map(|&h| ranked.genes[h].clone()).collect();
+        if es < 0.0 {
+            leading_edge.reverse(); // Most extreme gene first
+        }
+        results.push(Some(GseaResult {
+            es,
+            nes,
+            p_value,
+            leading_edge,
+            running_sum: running_sum_curve(&ranked.weights, gene_set_hits),
+            gene_set_size: k,
+        }));
+    }
+    results
+}
@@ -0,0 +1,503 @@
+// Single-sample gene set scores: ssGSEA (Barbie et al. 2009), GSVA (Hänzelmann et al. 2013), PLAGE (Tomfohr et al. 2005)
+// and combined z-score (Lee et al. 2008), computed as in the GSVA R package (https://doi.org/10.1186/1471-2105-14-7)
+// Syntax: cd .. && cargo build --release && json='{"input_file":"/Users/rpaul1/pp_data/files/hg38/ALL-pharmacotyping/rnaseq/counts.h5","method":"gsva","db":"/Users/rpaul1/pp_data/msigdb/db","geneset_group":"H: hallmark gene sets"}' && time echo $json | target/release/gsva
+// The expression matrix is read from an HDF5 file with the same layout as DEanalysis (item, samples, matrix datasets).
+// Gene sets are read from the msigdb database (db, geneset_group) unless custom gene sets are given with gene_sets
+// (inline {name: [genes]} object) or gmt_file (path of a GMT file). Gene set members not in the expression matrix are
+// ignored. Genes with constant expression across the samples are removed before scoring (GSVA filterRows).
+// Inputs:
+//   method: "ssgsea", "gsva" (default), "plage" or "zscore"
+//   samples: Comma separated samples to score (default all the samples of the HDF5 file)
+//   log_cpm: Scores are computed on log2(CPM + 1) of the counts (default true); set to false for an already normalized
+//     matrix. Not used by GSVA with the Poisson kernel, which takes the counts.
+//   kcdf: "Gaussian" (default) or "Poisson" kernel of GSVA
+//   tau: Weight of the rank scores in the GSVA random walk (default 1)
+//   mx_diff: GSVA enrichment score as the difference between the largest positive and negative deviations of the random
+//     walk (default true), otherwise the largest absolute deviation
+//   alpha: Weight of the ranks in the ssGSEA random walk (default 0.25)
+//   ssgsea_norm: Scale the ssGSEA scores by their range (default true)
+//   min_gs_size, max_gs_size: Gene sets with fewer/more genes in the expression matrix are not scored
+// Output is the pathway × sample matrix {"method", "samples", "pathways", "num_genes", "matrix"} where matrix[i][j] is the
+// score of pathways[i] in samples[j].
+use geneset_functions::GeneSet;
+use json::JsonValue;
+use nalgebra::DMatrix;
+use serde::Serialize;
+use statrs::distribution::{ContinuousCDF, DiscreteCDF, Normal, Poisson};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io;
+
+mod geneset_functions;
+mod hdf5_expression; // Reading expression matrices in the DEanalysis HDF5 layout
+#[cfg(test)]
+mod test_gsva; // Tests of the single-sample scores against reference values of the GSVA package
+
+#[allow(non_upper_case_globals)]
+const max_threads: usize = 6; // Max number of threads used for the per-gene and per-sample computations
+// The standard normal CDF of the Gaussian kernel is looked up in a table of PRECOMPUTED_RESOLUTION + 1 values over
+// [0, MAX_PRECOMPUTED], as in the GSVA C code (kernel_estimation.c), so that the kernel CDFs are the same as GSVA
+const PRECOMPUTED_RESOLUTION: usize = 10000;
+const MAX_PRECOMPUTED: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScoreMethod {
+    Ssgsea,
+    Gsva,
+    Plage,
+    Zscore,
+}
+
+impl ScoreMethod {
+    fn from_str(method: &str) -> Result<ScoreMethod, String> {
+        match method {
+            "ssgsea" => Ok(ScoreMethod::Ssgsea),
+            "gsva" => Ok(ScoreMethod::Gsva),
+            "plage" => Ok(ScoreMethod::Plage),
+            "zscore" => Ok(ScoreMethod::Zscore),
+            _ => Err(format!(
+                "Unknown method {}, must be ssgsea, gsva, plage or zscore",
+                method
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kernel {
+    Gaussian,
+    Poisson,
+}
+
+#[derive(Debug, Serialize)]
+struct ScoreMatrix {
+    method: String,
+    samples: Vec<String>,
+    pathways: Vec<String>,
+    num_genes: Vec<usize>, // Number of genes of each pathway found in the expression matrix
+    matrix: Vec<Vec<f64>>, // pathway × sample
+}
+
+// log2(CPM + 1) of a counts matrix
+fn log_cpm(counts: &DMatrix<f64>) -> DMatrix<f64> {
+    let mut output = counts.clone();
+    for (col, mut column) in output.column_iter_mut().enumerate() {
+        let lib_size: f64 = counts.column(col).sum();
+        for value in column.iter_mut() {
+            let cpm = if lib_size > 0.0 { *value / lib_size * 1e6 } else { 0.0 };
+            *value = (cpm + 1.0).log2();
+        }
+    }
+    output
+}
+
+// Runs f(i) for i in 0..n on max_threads threads and returns the results in order
+fn parallel_map<T: Send, F: Fn(usize) -> T + Sync>(n: usize, f: F) -> Vec<T> {
+    let f = &f;
+    let mut indexed: Vec<(usize, T)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..max_threads)
+            .map(|thread_num| {
+                scope.spawn(move || {
+                    (0..n)
+                        .filter(|i| i % max_threads == thread_num)
+                        .map(|i| (i, f(i)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    });
+    indexed.sort_by_key(|(i, _)| *i);
+    indexed.into_iter().map(|(_, value)| value).collect()
+}
+
+// Positions (0-based) of the genes in decreasing order of values, ties kept in gene order
+fn decreasing_order(values: &[f64]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[b].partial_cmp(&values[a]).unwrap_or(Ordering::Equal));
+    order
+}
+
+// Gene-wise mean and standard deviation (n - 1 denominator) of the rows of x
+fn row_mean_sd(x: &DMatrix<f64>, row: usize) -> (f64, f64) {
+    let n = x.ncols() as f64;
+    let mean = x.row(row).iter().sum::<f64>() / n;
+    let var = x.row(row).iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    (mean, var.sqrt())
+}
+
+/// ssGSEA score of every gene set (gene indexes) in every sample, as GSVA .ssgsea with ranks as.integer(rank(x))
+fn ssgsea(x: &DMatrix<f64>, gene_sets: &[Vec<usize>], alpha: f64, normalize: bool) -> Vec<Vec<f64>> {
+    let n = x.nrows();
+    let per_sample: Vec<Vec<f64>> = parallel_map(x.ncols(), |col| {
+        let values: Vec<f64> = x.column(col).iter().copied().collect();
+        let ranks = integer_ranks(&values);
+        let order = decreasing_order(&ranks);
+        let mut position = vec![0; n];
+        for (pos, &gene) in order.iter().enumerate() {
+            position[gene] = pos;
+        }
+        gene_sets
+            .iter()
+            .map(|genes| {
+                // Sum over the walk of (CDF in set - CDF out of set), where a gene at 0-based position p contributes
+                // to the (n - p) cumulative sums from p to the end of the walk
+                let k = genes.len() as f64;
+                let mut weight_sum = 0.0;
+                let mut weighted_positions = 0.0;
+                let mut hit_positions = 0.0;
+                for &gene in genes {
+                    let weight = ranks[gene].abs().powf(alpha);
+                    let remaining = (n - position[gene]) as f64;
+                    weight_sum += weight;
+                    weighted_positions += weight * remaining;
+                    hit_positions += remaining;
+                }
+                let all_positions = (n * (n + 1)) as f64 / 2.0;
+                weighted_positions / weight_sum - (all_positions - hit_positions) / (n as f64 - k)
+            })
+            .collect()
+    });
+    let mut scores: Vec<Vec<f64>> = (0..gene_sets.len())
+        .map(|i| per_sample.iter().map(|sample| sample[i]).collect())
+        .collect();
+    if normalize {
+        let min = scores.iter().flatten().cloned().fold(f64::INFINITY, f64::min);
+        let max = scores.iter().flatten().cloned().fold(f64::NEG_INFINITY, f64::max);
+        if max > min {
+            for value in scores.iter_mut().flatten() {
+                *value /= max - min;
+            }
+        }
+    }
+    scores
+}
+
+// as.integer(rank(x)) in R: average ranks of ties, truncated
+fn integer_ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[a].partial_cmp(&values[b]).unwrap_or(Ordering::Equal));
+    let mut ranks = vec![0.0; values.len()];
+    let mut i = 0;
+    while i < order.len() {
+        let mut j = i;
+        while j + 1 < order.len() && values[order[j + 1]] == values[order[i]] {
+            j += 1;
+        }
+        let average_rank = ((i + 1 + j + 1) as f64 / 2.0).trunc();
+        for &index in &order[i..=j] {
+            ranks[index] = average_rank;
+        }
+        i = j + 1;
+    }
+    ranks
+}
+
+/// Standard normal CDF at i / PRECOMPUTED_RESOLUTION * MAX_PRECOMPUTED for i in 0..=PRECOMPUTED_RESOLUTION
+fn precomputed_normal_cdf() -> Vec<f64> {
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    (0..=PRECOMPUTED_RESOLUTION)
+        .map(|i| normal.cdf(MAX_PRECOMPUTED * i as f64 / PRECOMPUTED_RESOLUTION as f64))
+        .collect()
+}
+
+/// Normal CDF of x / sigma from the precomputed table (precomputedCdf in GSVA)
+fn lookup_normal_cdf(table: &[f64], x: f64, sigma: f64) -> f64 {
+    let v = x / sigma;
+    if v < -MAX_PRECOMPUTED {
+        0.0
+    } else if v > MAX_PRECOMPUTED {
+        1.0
+    } else {
+        let cdf = table[(v.abs() / MAX_PRECOMPUTED * PRECOMPUTED_RESOLUTION as f64) as usize];
+        if v < 0.0 { 1.0 - cdf } else { cdf }
+    }
+}
+
+/// Kernel estimation of the cumulative distribution of every gene across the samples, as log odds (GSVA kcdf).
+/// Every value of a gene is compared with all the values of the gene, so the cost is O(genes × samples²) as in GSVA.
+/// The normal CDF is looked up in a precomputed table and the genes are spread over max_threads threads.
+fn kernel_cdf(x: &DMatrix<f64>, kernel: Kernel) -> DMatrix<f64> {
+    let n = x.ncols();
+    let table = precomputed_normal_cdf();
+    let rows: Vec<Vec<f64>> = parallel_map(x.nrows(), |row| {
+        let values: Vec<f64> = x.row(row).iter().copied().collect();
+        let bandwidth = row_mean_sd(x, row).1 / 4.0;
+        // Poisson distributions with mean x + 0.5 of every sample, evaluated at every count of the gene
+        let poissons: Vec<Poisson> = match kernel {
+            Kernel::Poisson => values
+                .iter()
+                .map(|&v| Poisson::new(v.max(0.0) + 0.5).unwrap())
+                .collect(),
+            Kernel::Gaussian => Vec::new(),
+        };
+        values
+            .iter()
+            .map(|&y| {
+                let left_tail = match kernel {
+                    Kernel::Gaussian => values
+                        .iter()
+                        .map(|&v| lookup_normal_cdf(&table, y - v, bandwidth))
+                        .sum::<f64>(),
+                    Kernel::Poisson => {
+                        let count = y.max(0.0).round() as u64;
+                        poissons.iter().map(|poisson| poisson.cdf(count)).sum::<f64>()
+                    }
+                } / n as f64;
+                -((1.0 - left_tail) / left_tail).ln()
+            })
+            .collect()
+    });
+    DMatrix::from_fn(x.nrows(), n, |row, col| rows[row][col])
+}
+
+/// GSVA score of every gene set in every sample from the kernel CDF log odds
+fn gsva(density: &DMatrix<f64>, gene_sets: &[Vec<usize>], tau: f64, mx_diff: bool) -> Vec<Vec<f64>> {
+    let n = density.nrows();
+    let per_sample: Vec<Vec<f64>> = parallel_map(density.ncols(), |col| {
+        let values: Vec<f64> = density.column(col).iter().copied().collect();
+        let order = decreasing_order(&values);
+        // Genes are scored by their distance to the middle of the ranking
+        let mut rank_scores = vec![0.0; n];
+        let mut position = vec![0; n];
+        for (pos, &gene) in order.iter().enumerate() {
+            rank_scores[gene] = ((n - pos) as f64 - n as f64 / 2.0).abs(); // abs(seq(from = p, to = 1) - p / 2) in GSVA
+            position[gene] = pos;
+        }
+        gene_sets
+            .iter()
+            .map(|genes| {
+                let mut hits: Vec<usize> = genes.iter().map(|&gene| position[gene]).collect();
+                hits.sort();
+                let weights: Vec<f64> = hits.iter().map(|&pos| rank_scores[order[pos]].powf(tau)).collect();
+                let weight_sum: f64 = weights.iter().sum();
+                let decrement = 1.0 / (n - hits.len()) as f64;
+                // The walk only goes up at hits, so its extremes are just before or at a hit
+                let mut max_positive: f64 = 0.0;
+                let mut max_negative: f64 = 0.0;
+                let mut cumulative = 0.0;
+                for (h, (&pos, &weight)) in hits.iter().zip(weights.iter()).enumerate() {
+                    let misses = (pos - h) as f64;
+                    max_negative = max_negative.min(cumulative - misses * decrement);
+                    cumulative += weight / weight_sum;
+                    max_positive = max_positive.max(cumulative - misses * decrement);
+                }
+                if mx_diff {
+                    max_positive + max_negative
+                } else if max_positive > -max_negative {
+                    max_positive
+                } else {
+                    max_negative
+                }
+            })
+            .collect()
+    });
+    (0..gene_sets.len())
+        .map(|i| per_sample.iter().map(|sample| sample[i]).collect())
+        .collect()
+}
+
+// Genes standardized to mean 0 and standard deviation 1 across the samples
+fn standardize(x: &DMatrix<f64>) -> DMatrix<f64> {
+    let mut z = x.clone();
+    for row in 0..x.nrows() {
+        let (mean, sd) = row_mean_sd(x, row);
+        for col in 0..x.ncols() {
+            z[(row, col)] = (x[(row, col)] - mean) / sd;
+        }
+    }
+    z
+}
+
+/// Combined z-score: sum of the standardized expression of the genes of the set divided by sqrt(size)
+fn zscore(x: &DMatrix<f64>, gene_sets: &[Vec<usize>]) -> Vec<Vec<f64>> {
+    let z = standardize(x);
+    gene_sets
+        .iter()
+        .map(|genes| {
+            let scale = (genes.len() as f64).sqrt();
+            (0..z.ncols())
+                .map(|col| genes.iter().map(|&gene| z[(gene, col)]).sum::<f64>() / scale)
+                .collect()
+        })
+        .collect()
+}
+
+/// PLAGE: first right singular vector of the standardized expression of the genes of the set. The sign of a singular
+/// vector is arbitrary, so it is oriented to correlate positively with the mean standardized expression of the set.
+fn plage(x: &DMatrix<f64>, gene_sets: &[Vec<usize>]) -> Vec<Vec<f64>> {
+    let z = standardize(x);
+    gene_sets
+        .iter()
+        .map(|genes| {
+            let subset = DMatrix::from_fn(genes.len(), z.ncols(), |i, col| z[(genes[i], col)]);
+            let svd = subset.clone().svd(false, true);
+            let v_t = svd.v_t.unwrap();
+            let first = svd
+                .singular_values
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(Ordering::Equal))
+                .map(|(i, _)| i)
+                .unwrap();
+            let mut scores: Vec<f64> = v_t.row(first).iter().copied().collect();
+            let direction: f64 = (0..subset.ncols())
+                .map(|col| scores[col] * subset.column(col).sum())
+                .sum();
+            if direction < 0.0 {
+                scores.iter_mut().for_each(|value| *value = -*value);
+            }
+            scores
+        })
+        .collect()
+}
+
+fn main() {
+    let mut input = String::new();
+    match io::stdin().read_line(&mut input) {
+        // Accepting the piped input from nodejs (or command line from testing)
+        Ok(_n) => {
+            let input_json = json::parse(&input);
+            match input_json {
+                Ok(json_string) => {
+                    let method = match ScoreMethod::from_str(json_string["method"].as_str().unwrap_or("gsva")) {
+                        Ok(method) => method,
+                        Err(error) => panic!("{}", error),
+                    };
+                    let kernel = match json_string["kcdf"].as_str().unwrap_or("Gaussian") {
+                        "Gaussian" => Kernel::Gaussian,
+                        "Poisson" => Kernel::Poisson,
+                        kcdf => panic!("Unknown kcdf {}, must be Gaussian or Poisson", kcdf),
+                    };
+                    let file_name = match json_string["input_file"].as_str() {
+                        Some(file_name) => file_name.to_string(),
+                        None => panic!("input_file is missing"),
+                    };
+                    let samples_string = json_string["samples"].as_str().map(|samples| samples.to_string());
+                    let requested_samples: Option<Vec<&str>> =
+                        samples_string.as_ref().map(|samples| samples.split(",").collect());
+
+                    let genesets = load_genesets(&json_string);
+                    let size_filter = match geneset_functions::SizeFilter::from_json(&json_string) {
+                        Ok(size_filter) => size_filter,
+                        Err(error) => panic!("{}", error),
+                    };
+
+                    let hdf5_expression::ExpressionMatrix {
+                        matrix: counts,
+                        gene_names,
+                        sample_names,
+                    } = match hdf5_expression::read_expression_matrix(&file_name, requested_samples.as_deref()) {
+                        Ok(expression) => expression,
+                        Err(error) => panic!("{}", error),
+                    };
+                    // Requested samples not in the file are skipped, as in DEanalysis
+                    if sample_names.is_empty() {
+                        panic!("None of the samples were found in the HDF5 file");
+                    }
+                    let use_log_cpm = json_string["log_cpm"].as_bool().unwrap_or(true);
+                    let expression = if use_log_cpm && !(method == ScoreMethod::Gsva && kernel == Kernel::Poisson) {
+                        log_cpm(&counts)
+                    } else {
+                        counts
+                    };
+
+                    // Constant genes carry no information on the samples and break the standardization
+                    let kept_rows: Vec<usize> = (0..expression.nrows())
+                        .filter(|&row| row_mean_sd(&expression, row).1 > 0.0)
+                        .collect();
+                    let expression = expression.select_rows(kept_rows.iter());
+                    let kept_genes: Vec<&str> = kept_rows.iter().map(|&row| gene_names[row].as_str()).collect();
+
+                    let (names, gene_indexes) = gene_set_indexes(&genesets, &kept_genes, &size_filter);
+                    let matrix = match method {
+                        ScoreMethod::Ssgsea => ssgsea(
+                            &expression,
+                            &gene_indexes,
+                            json_string["alpha"].as_f64().unwrap_or(0.25),
+                            json_string["ssgsea_norm"].as_bool().unwrap_or(true),
+                        ),
+                        ScoreMethod::Gsva => gsva(
+                            &kernel_cdf(&expression, kernel),
+                            &gene_indexes,
+                            json_string["tau"].as_f64().unwrap_or(1.0),
+                            json_string["mx_diff"].as_bool().unwrap_or(true),
+                        ),
+                        ScoreMethod::Plage => plage(&expression, &gene_indexes),
+                        ScoreMethod::Zscore => zscore(&expression, &gene_indexes),
+                    };
+                    let output = ScoreMatrix {
+                        method: json_string["method"].as_str().unwrap_or("gsva").to_string(),
+                        samples: sample_names,
+                        pathways: names,
+                        num_genes: gene_indexes.iter().map(|genes| genes.len()).collect(),
+                        matrix,
+                    };
+                    println!("{}", serde_json::to_string(&output).unwrap());
+                }
+                Err(error) => panic!("Incorrect json: {}", error),
+            }
+        }
+        Err(error) => panic!("Piping error: {}", error),
+    }
+}
+
+// Gene sets from gene_sets/gmt_file, otherwise from the msigdb database
+fn load_genesets(json_string: &JsonValue) -> Vec<GeneSet> {
+    let custom_gene_sets = match geneset_functions::load_custom_gene_sets(json_string) {
+        Ok(custom_gene_sets) => custom_gene_sets,
+        Err(error) => panic!("{}", error),
+    };
+    match custom_gene_sets {
+        Some(genesets) => genesets,
+        None => {
+            let msigdb = match json_string["db"].as_str() {
+                Some(db_string) => db_string.to_string(),
+                None => panic!("msigdb file path is missing"),
+            };
+            let genesetgroup = match json_string["geneset_group"].as_str() {
+                Some(genesetgroup_string) => genesetgroup_string.to_string(),
+                None => panic!("genesetgroup is missing"),
+            };
+            let pool = match geneset_functions::open_pool(&msigdb) {
+                Ok(pool) => pool,
+                Err(error) => panic!("{}", error),
+            };
+            let msigdbconn = pool.get().expect("Cannot get a connection to the sqlite database");
+            match geneset_functions::load_gene_sets(&msigdbconn, &genesetgroup) {
+                Ok(genesets) => genesets,
+                Err(error) => panic!("{}", error),
+            }
+        }
+    }
+}
+
+// Row indexes in the expression matrix of the genes of every gene set within the size limits. Gene sets covering all
+// the genes cannot be compared to the rest of the genes and are skipped.
+fn gene_set_indexes(
+    genesets: &[GeneSet],
+    gene_names: &[&str],
+    size_filter: &geneset_functions::SizeFilter,
+) -> (Vec<String>, Vec<Vec<usize>>) {
+    let mut row_of: HashMap<&str, usize> = HashMap::with_capacity(gene_names.len());
+    for (row, gene) in gene_names.iter().enumerate() {
+        row_of.entry(gene).or_insert(row); // First row of duplicated gene names
+    }
+    let mut names = Vec::new();
+    let mut indexes = Vec::new();
+    for geneset in genesets {
+        let mut rows: Vec<usize> = geneset
+            .genes
+            .iter()
+            .filter_map(|gene| row_of.get(gene.as_str()).copied())
+            .collect();
+        rows.sort();
+        rows.dedup();
+        if rows.is_empty() || rows.len() >= gene_names.len() || !size_filter.accepts(rows.len()) {
+            continue;
+        }
+        names.push(geneset.id.clone());
+        indexes.push(rows);
+    }
+    (names, indexes)
+}
@@ -0,0 +1,109 @@
+// Reads a (genes x samples) expression matrix from an HDF5 file in the layout used by DEanalysis.rs:
+//   item:    1D array of gene names (one per matrix row)
+//   samples: 1D array of sample names (one per matrix column)
+//   matrix:  2D (genes x samples) array of expression values
+use hdf5::File as HDF5File;
+use hdf5::types::VarLenUnicode;
+use nalgebra::DMatrix;
+use ndarray::Array2;
+use ndarray::s;
+use std::collections::HashMap;
+
+const ROW_BLOCK_SIZE: usize = 1000; // Number of matrix rows read per hyperslab
+
+#[allow(dead_code)]
+pub struct ExpressionMatrix {
+    pub matrix: DMatrix<f64>, // genes x selected samples
+    pub gene_names: Vec<String>,
+    pub sample_names: Vec<String>, // Selected samples, in the same order as the matrix columns
+}
+
+/// Same content as ExpressionMatrix, stored as single precision (row major) values to halve the memory footprint
+#[allow(dead_code)]
+pub struct ExpressionValues {
+    pub values: Vec<f32>, // genes x selected samples, row major
+    pub gene_names: Vec<String>,
+    pub sample_names: Vec<String>,
+}
+
+/// Reads the requested samples (all samples when `samples` is None). Requested samples missing from the file are skipped.
+/// Like DEanalysis, only the span of columns between the first and last selected sample is read. The matrix is read in
+/// blocks of ROW_BLOCK_SIZE rows so that the temporary buffer stays small whatever the number of genes.
+#[allow(dead_code)]
+pub fn read_expression_matrix(hdf5_filename: &str, samples: Option<&[&str]>) -> Result<ExpressionMatrix, String> {
+    let expression = read_expression_values(hdf5_filename, samples)?;
+    let num_samples = expression.sample_names.len();
+    let matrix = DMatrix::<f64>::from_fn(expression.gene_names.len(), num_samples, |gene, sample| {
+        expression.values[gene * num_samples + sample] as f64
+    });
+    Ok(ExpressionMatrix {
+        matrix,
+        gene_names: expression.gene_names,
+        sample_names: expression.sample_names,
+    })
+}
+
+#[allow(dead_code)]
+pub fn read_expression_values(hdf5_filename: &str, samples: Option<&[&str]>) -> Result<ExpressionValues, String> {
+    let file = HDF5File::open(hdf5_filename).map_err(|e| format!("HDF5 open {}: {}", hdf5_filename, e))?;
+    let gene_names: Vec<String> = file
+        .dataset("item")
+        .map_err(|e| format!("item: {}", e))?
+        .read_1d::<VarLenUnicode>()
+        .map_err(|e| format!("item: {}", e))?
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    let file_samples: Vec<String> = file
+        .dataset("samples")
+        .map_err(|e| format!("samples: {}", e))?
+        .read_1d::<VarLenUnicode>()
+        .map_err(|e| format!("samples: {}", e))?
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    let ds_matrix = file.dataset("matrix").map_err(|e| format!("matrix: {}", e))?;
+    let num_genes = ds_matrix.shape()[0];
+    if num_genes != gene_names.len() {
+        return Err(format!(
+            "Number of matrix rows ({}) does not match number of genes ({})",
+            num_genes,
+            gene_names.len()
+        ));
+    }
+
+    let column_of: HashMap<&str, usize> = file_samples
+        .iter()
+        .enumerate()
+        .map(|(index, name)| (name.as_str(), index))
+        .collect();
+    let (selected_columns, sample_names): (Vec<usize>, Vec<String>) = match samples {
+        Some(requested) => requested
+            .iter()
+            .filter_map(|s| column_of.get(s).map(|&c| (c, s.to_string())))
+            .unzip(),
+        None => (0..file_samples.len()).zip(file_samples.iter().cloned()).unzip(),
+    };
+    let num_selected = selected_columns.len();
+    let mut values = vec![0.0f32; num_genes * num_selected];
+    if num_selected > 0 {
+        let first_column = *selected_columns.iter().min().unwrap();
+        let last_column = *selected_columns.iter().max().unwrap();
+        for block_start in (0..num_genes).step_by(ROW_BLOCK_SIZE) {
+            let block_end = (block_start + ROW_BLOCK_SIZE).min(num_genes);
+            let block: Array2<f32> = ds_matrix
+                .read_slice_2d(s![block_start..block_end, first_column..last_column + 1])
+                .map_err(|e| format!("matrix: {}", e))?;
+            for gene in block_start..block_end {
+                for (out_column, &src_column) in selected_columns.iter().enumerate() {
+                    values[gene * num_selected + out_column] = block[[gene - block_start, src_column - first_column]];
+                }
+            }
+        }
+    }
+    Ok(ExpressionValues {
+        values,
+        gene_names,
+        sample_names,
+    })
+}
@@ -0,0 +1,385 @@
+// Server-side heatmap rendering for cluster.rs. The (clustered) matrix, its row/column dendrograms, annotation color bars and
+// the color scale legend are painted into a single PNG with tiny-skia and returned as a base64 data URL, as in dmrcate.rs.
+// No text is drawn: the layout of every element (in CSS pixels) is returned alongside the image so that the client can
+// overlay row/column labels, annotation names and legend ticks.
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use nalgebra::DMatrix;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use tiny_skia::{Paint, PathBuilder, Pixmap, PremultipliedColorU8, Rect, Stroke, Transform};
+
+const LEGEND_GAP: f32 = 20.0; // Space between the heatmap and the legend color bar
+const LEGEND_WIDTH: f32 = 12.0;
+const LEGEND_MAX_HEIGHT: f32 = 150.0;
+const LEGEND_LABEL_SPACE: f32 = 60.0; // Room left right of the legend for the tick labels drawn by the client
+const ANNOTATION_GAP: f32 = 2.0;
+const COLOR_LUT_SIZE: usize = 256;
+const MISSING_VALUE_COLOR: [u8; 4] = [204, 204, 204, 255];
+const DENDROGRAM_COLOR: [u8; 4] = [85, 85, 85, 255];
+// d3.schemeCategory10, used for annotation categories without a user supplied color
+const CATEGORY_COLORS: [&str; 10] = [
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f", "#bcbd22", "#17becf",
+];
+
+/// A categorical annotation (e.g. sample subtype) shown as a color bar along the rows or the columns of the heatmap
+pub struct Annotation {
+    pub name: String,
+    pub values: Vec<String>,             // One value per row/column in the input (unsorted) order
+    pub colors: HashMap<String, String>, // Optional color of each value, missing values get a category10 color
+}
+
+pub struct HeatmapOptions {
+    pub width: u32,  // Width of the heatmap body in CSS pixels
+    pub height: u32, // Height of the heatmap body in CSS pixels
+    pub dendrogram_size: u32,
+    pub annotation_size: u32,
+    pub device_pixel_ratio: f32,
+    pub colors: Vec<String>, // Color scale from low to high, e.g. blue, white, red
+    pub domain: Vec<f64>,    // Value of each color in `colors`
+}
+
+/// Order of the leaves (0..num_leaves) obtained by walking the dendrogram from its root, left child first.
+/// Merges follow the kodama convention: cluster ids below num_leaves are leaves, id num_leaves + i is the cluster formed at step i.
+pub fn leaf_order(merges: &[(usize, usize, f64)], num_leaves: usize) -> Vec<usize> {
+    if merges.len() + 1 != num_leaves {
+        // No (or an incomplete) dendrogram, keep the input order
+        return (0..num_leaves).collect();
+    }
+    let mut order = Vec::with_capacity(num_leaves);
+    let mut stack = vec![num_leaves + merges.len() - 1];
+    while let Some(node) = stack.pop() {
+        if node < num_leaves {
+            order.push(node);
+        } else {
+            let (left, right, _) = merges[node - num_leaves];
+            stack.push(right);
+            stack.push(left);
+        }
+    }
+    order
+}
+
+/// Default diverging domain: symmetric around 0 when the data has both signs, otherwise centered between min and max
+pub fn default_domain(matrix: &DMatrix<f64>) -> Vec<f64> {
+    let (mut min, mut max) = (f64::INFINITY, f64::NEG_INFINITY);
+    for v in matrix.iter().filter(|v| v.is_finite()) {
+        min = min.min(*v);
+        max = max.max(*v);
+    }
+    if !min.is_finite() {
+        return vec![-1.0, 0.0, 1.0];
+    }
+    if min < 0.0 && max > 0.0 {
+        let m = min.abs().max(max);
+        vec![-m, 0.0, m]
+    } else if min == max {
+        vec![min - 1.0, min, min + 1.0]
+    } else {
+        vec![min, (min + max) / 2.0, max]
+    }
+}
+
+fn parse_color(color: &str) -> Option<[u8; 4]> {
+    colorgrad::Color::from_html(color).ok().map(|c| c.to_rgba8())
+}
+
+fn fill_rect(pixmap: &mut Pixmap, x: f32, y: f32, w: f32, h: f32, color: [u8; 4]) {
+    if let Some(rect) = Rect::from_xywh(x, y, w, h) {
+        let mut paint = Paint::default();
+        paint.set_color_rgba8(color[0], color[1], color[2], color[3]);
+        pixmap.fill_rect(rect, &paint, Transform::identity(), None);
+    }
+}
+
+/// Position of each item of `order` along an axis of `length` device pixels starting at `start`
+fn item_span(rank: usize, num_items: usize, start: f32, length: f32) -> (f32, f32) {
+    let size = length / num_items as f32;
+    (start + rank as f32 * size, size)
+}
+
+/// Draws a dendrogram as elbow lines. Leaves sit at `base` and the root at `base - size` (top dendrogram) or, with
+/// `vertical` false, at `base - size` along x (left dendrogram). `leaf_position` is the center of each leaf along the other axis.
+fn draw_dendrogram(
+    pixmap: &mut Pixmap,
+    merges: &[(usize, usize, f64)],
+    leaf_position: &[f32],
+    base: f32,
+    size: f32,
+    vertical: bool,
+    line_width: f32,
+) {
+    let num_leaves = leaf_position.len();
+    if merges.len() + 1 != num_leaves {
+        return;
+    }
+    let max_height = merges.iter().map(|m| m.2).fold(0.0, f64::max);
+    let height_of = |h: f64| -> f32 {
+        if max_height > 0.0 {
+            base - (h / max_height) as f32 * size
+        } else {
+            base
+        }
+    };
+    // Position and height of every node, leaves first followed by the merged clusters
+    let mut position: Vec<f32> = leaf_position.to_vec();
+    let mut height: Vec<f32> = vec![base; num_leaves];
+    let mut pb = PathBuilder::new();
+    for &(c1, c2, dissimilarity) in merges {
+        let h = height_of(dissimilarity);
+        let (p1, p2) = (position[c1], position[c2]);
+        let mut line = |a: (f32, f32), b: (f32, f32)| {
+            if vertical {
+                pb.move_to(a.0, a.1);
+                pb.line_to(b.0, b.1);
+            } else {
+                pb.move_to(a.1, a.0);
+                pb.line_to(b.1, b.0);
+            }
+        };
+        line((p1, height[c1]), (p1, h));
+        line((p1, h), (p2, h));
+        line((p2, h), (p2, height[c2]));
+        position.push((p1 + p2) / 2.0);
+        height.push(h);
+    }
+    if let Some(path) = pb.finish() {
+        let mut paint = Paint::default();
+        paint.set_color_rgba8(
+            DENDROGRAM_COLOR[0],
+            DENDROGRAM_COLOR[1],
+            DENDROGRAM_COLOR[2],
+            DENDROGRAM_COLOR[3],
+        );
+        paint.anti_alias = true;
+        let stroke = Stroke {
+            width: line_width,
+            ..Stroke::default()
+        };
+        pixmap.stroke_path(&path, &paint, &stroke, Transform::identity(), None);
+    }
+}
+
+/// Assigns a color to every distinct annotation value, keeping user supplied colors
+fn annotation_colors(annotation: &Annotation) -> Vec<(String, [u8; 4])> {
+    let mut assigned: Vec<(String, [u8; 4])> = Vec::new();
+    for value in &annotation.values {
+        if assigned.iter().any(|(v, _)| v == value) {
+            continue;
+        }
+        let color = annotation
+            .colors
+            .get(value)
+            .and_then(|c| parse_color(c))
+            .unwrap_or_else(|| parse_color(CATEGORY_COLORS[assigned.len() % CATEGORY_COLORS.len()]).unwrap());
+        assigned.push((value.clone(), color));
+    }
+    assigned
+}
+
+fn to_hex(color: [u8; 4]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+/// Renders `matrix` (rows x columns, in input order) reordered by the row and column dendrograms.
+/// Returns the base64 PNG together with the layout of the heatmap, annotations and legend in CSS pixels.
+pub fn render_heatmap(
+    matrix: &DMatrix<f64>,
+    row_merges: &[(usize, usize, f64)],
+    col_merges: &[(usize, usize, f64)],
+    row_annotations: &[Annotation],
+    col_annotations: &[Annotation],
+    options: &HeatmapOptions,
+) -> Result<Value, String> {
+    let (num_rows, num_cols) = (matrix.nrows(), matrix.ncols());
+    if options.colors.len() < 2 || options.colors.len() != options.domain.len() {
+        return Err("The color scale needs at least two colors and one domain value per color".to_string());
+    }
+    if options.domain.windows(2).any(|w| w[0] >= w[1]) {
+        return Err("The color scale domain must be strictly increasing".to_string());
+    }
+    for annotation in row_annotations {
+        if annotation.values.len() != num_rows {
+            return Err(format!(
+                "Row annotation {} does not have {} values",
+                annotation.name, num_rows
+            ));
+        }
+    }
+    for annotation in col_annotations {
+        if annotation.values.len() != num_cols {
+            return Err(format!(
+                "Column annotation {} does not have {} values",
+                annotation.name, num_cols
+            ));
+        }
+    }
+    let color_names: Vec<&str> = options.colors.iter().map(|c| c.as_str()).collect();
+    let gradient = colorgrad::CustomGradient::new()
+        .html_colors(&color_names)
+        .domain(&options.domain)
+        .mode(colorgrad::BlendMode::Rgb)
+        .build()
+        .map_err(|e| format!("Invalid color scale: {}", e))?;
+    let (domain_min, domain_max) = (options.domain[0], options.domain[options.domain.len() - 1]);
+    let lut: Vec<[u8; 4]> = (0..COLOR_LUT_SIZE)
+        .map(|i| {
+            let value = domain_min + (domain_max - domain_min) * i as f64 / (COLOR_LUT_SIZE - 1) as f64;
+            gradient.at(value).to_rgba8()
+        })
+        .collect();
+    let color_of = |value: f64| -> [u8; 4] {
+        if !value.is_finite() {
+            return MISSING_VALUE_COLOR;
+        }
+        let t = ((value - domain_min) / (domain_max - domain_min)).clamp(0.0, 1.0);
+        lut[(t * (COLOR_LUT_SIZE - 1) as f64).round() as usize]
+    };
+
+    // Layout in CSS pixels
+    let row_order = leaf_order(row_merges, num_rows);
+    let col_order = leaf_order(col_merges, num_cols);
+    let has_row_dendrogram = row_merges.len() + 1 == num_rows;
+    let has_col_dendrogram = col_merges.len() + 1 == num_cols;
+    let dendrogram_size = options.dendrogram_size as f32;
+    let annotation_step = options.annotation_size as f32 + ANNOTATION_GAP;
+    let row_dendrogram_width = if has_row_dendrogram { dendrogram_size } else { 0.0 };
+    let col_dendrogram_height = if has_col_dendrogram { dendrogram_size } else { 0.0 };
+    let heatmap_x = row_dendrogram_width + row_annotations.len() as f32 * annotation_step;
+    let heatmap_y = col_dendrogram_height + col_annotations.len() as f32 * annotation_step;
+    let (heatmap_width, heatmap_height) = (options.width as f32, options.height as f32);
+    let legend_x = heatmap_x + heatmap_width + LEGEND_GAP;
+    let legend_height = heatmap_height.min(LEGEND_MAX_HEIGHT);
+    let total_width = legend_x + LEGEND_WIDTH + LEGEND_LABEL_SPACE;
+    let total_height = heatmap_y + heatmap_height;
+
+    let dpr = options.device_pixel_ratio;
+    let mut pixmap = Pixmap::new((total_width * dpr).ceil() as u32, (total_height * dpr).ceil() as u32)
+        .ok_or_else(|| "Invalid image size".to_string())?;
+    // Transparent background (default)
+
+    // 1. Heatmap body, one color lookup per device pixel so that the cost does not depend on the matrix size
+    let (body_x, body_y) = ((heatmap_x * dpr).round() as usize, (heatmap_y * dpr).round() as usize);
+    let body_width = ((heatmap_width * dpr).round() as usize).min(pixmap.width() as usize - body_x);
+    let body_height = ((heatmap_height * dpr).round() as usize).min(pixmap.height() as usize - body_y);
+    let pixmap_width = pixmap.width() as usize;
+    let col_of_pixel: Vec<usize> = (0..body_width)
+        .map(|px| col_order[((px as f64 + 0.5) / body_width as f64 * num_cols as f64) as usize])
+        .collect();
+    let pixels = pixmap.pixels_mut();
+    for py in 0..body_height {
+        let row = row_order[((py as f64 + 0.5) / body_height as f64 * num_rows as f64) as usize];
+        let line =
+            &mut pixels[(body_y + py) * pixmap_width + body_x..(body_y + py) * pixmap_width + body_x + body_width];
+        for (px, pixel) in line.iter_mut().enumerate() {
+            let c = color_of(matrix[(row, col_of_pixel[px])]);
+            *pixel = PremultipliedColorU8::from_rgba(c[0], c[1], c[2], c[3]).unwrap();
+        }
+    }
+
+    // 2. Dendrograms
+    if has_col_dendrogram {
+        let mut leaf_position = vec![0.0f32; num_cols];
+        for (rank, &col) in col_order.iter().enumerate() {
+            let (start, size) = item_span(rank, num_cols, heatmap_x * dpr, heatmap_width * dpr);
+            leaf_position[col] = start + size / 2.0;
+        }
+        let base = (col_dendrogram_height - ANNOTATION_GAP) * dpr;
+        let size = (dendrogram_size - 2.0 * ANNOTATION_GAP) * dpr;
+        draw_dendrogram(&mut pixmap, col_merges, &leaf_position, base, size, true, dpr);
+    }
+    if has_row_dendrogram {
+        let mut leaf_position = vec![0.0f32; num_rows];
+        for (rank, &row) in row_order.iter().enumerate() {
+            let (start, size) = item_span(rank, num_rows, heatmap_y * dpr, heatmap_height * dpr);
+            leaf_position[row] = start + size / 2.0;
+        }
+        let base = (row_dendrogram_width - ANNOTATION_GAP) * dpr;
+        let size = (dendrogram_size - 2.0 * ANNOTATION_GAP) * dpr;
+        draw_dendrogram(&mut pixmap, row_merges, &leaf_position, base, size, false, dpr);
+    }
+
+    // 3. Annotation color bars
+    let mut row_annotation_layout = Vec::new();
+    for (i, annotation) in row_annotations.iter().enumerate() {
+        let colors = annotation_colors(annotation);
+        let color_map: HashMap<&str, [u8; 4]> = colors.iter().map(|(v, c)| (v.as_str(), *c)).collect();
+        let x = row_dendrogram_width + i as f32 * annotation_step;
+        for (rank, &row) in row_order.iter().enumerate() {
+            let (start, size) = item_span(rank, num_rows, heatmap_y * dpr, heatmap_height * dpr);
+            let color = color_map[annotation.values[row].as_str()];
+            fill_rect(
+                &mut pixmap,
+                x * dpr,
+                start,
+                options.annotation_size as f32 * dpr,
+                size,
+                color,
+            );
+        }
+        row_annotation_layout.push(json!({
+            "name": annotation.name,
+            "x": x,
+            "width": options.annotation_size,
+            "colors": colors.iter().map(|(v, c)| (v.clone(), Value::from(to_hex(*c)))).collect::<serde_json::Map<String, Value>>(),
+        }));
+    }
+    let mut col_annotation_layout = Vec::new();
+    for (i, annotation) in col_annotations.iter().enumerate() {
+        let colors = annotation_colors(annotation);
+        let color_map: HashMap<&str, [u8; 4]> = colors.iter().map(|(v, c)| (v.as_str(), *c)).collect();
+        let y = col_dendrogram_height + i as f32 * annotation_step;
+        for (rank, &col) in col_order.iter().enumerate() {
+            let (start, size) = item_span(rank, num_cols, heatmap_x * dpr, heatmap_width * dpr);
+            let color = color_map[annotation.values[col].as_str()];
+            fill_rect(
+                &mut pixmap,
+                start,
+                y * dpr,
+                size,
+                options.annotation_size as f32 * dpr,
+                color,
+            );
+        }
+        col_annotation_layout.push(json!({
+            "name": annotation.name,
+            "y": y,
+            "height": options.annotation_size,
+            "colors": colors.iter().map(|(v, c)| (v.clone(), Value::from(to_hex(*c)))).collect::<serde_json::Map<String, Value>>(),
+        }));
+    }
+
+    // 4. Legend: vertical color bar with the maximum at the top
+    let legend_pixels = (legend_height * dpr).round() as usize;
+    for i in 0..legend_pixels {
+        let value = domain_max - (domain_max - domain_min) * (i as f64 + 0.5) / legend_pixels as f64;
+        fill_rect(
+            &mut pixmap,
+            legend_x * dpr,
+            heatmap_y * dpr + i as f32,
+            LEGEND_WIDTH * dpr,
+            1.0,
+            color_of(value),
+        );
+    }
+
+    let png_bytes = pixmap.encode_png().map_err(|e| format!("PNG encoding: {}", e))?;
+    Ok(json!({
+        "png": format!("data:image/png;base64,{}", BASE64.encode(&png_bytes)),
+        "width": total_width,
+        "height": total_height,
+        "devicePixelRatio": dpr,
+        "heatmap": {"x": heatmap_x, "y": heatmap_y, "width": heatmap_width, "height": heatmap_height},
+        "row_order": row_order,
+        "col_order": col_order,
+        "row_annotations": row_annotation_layout,
+        "col_annotations": col_annotation_layout,
+        "legend": {
+            "x": legend_x,
+            "y": heatmap_y,
+            "width": LEGEND_WIDTH,
+            "height": legend_height,
+            "colors": options.colors,
+            "domain": options.domain,
+        },
+    }))
+}
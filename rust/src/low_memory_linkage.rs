@@ -0,0 +1,311 @@
+// Hierarchical clustering without a condensed dissimilarity matrix, for inputs such as 20k genes x 2k samples where the
+// n(n-1)/2 distances of cluster.rs/kodama do not fit in memory. Euclidean distances are computed on the fly from the items:
+//   Single:   minimum spanning tree with Prim's algorithm (Gower & Ross 1969)
+//   Ward:     nearest-neighbor chain (Murtagh 1983), valid since Ward's method is reducible
+//   Centroid, Median: generic algorithm of Müllner (2011, arXiv:1109.2378) with lazily refreshed nearest neighbors
+// Complete, Average and Weighted linkage need the dissimilarities between all members of two clusters and are not supported.
+//
+// Cluster centroids overwrite the items they replace, so apart from the items themselves (4 bytes per value) only O(n)
+// memory is used. For Ward, Centroid and Median the dissimilarity is the Euclidean distance between the two centroids
+// (multiplied by sqrt(2 n_a n_b / (n_a + n_b)) for Ward), which equals the input distance for two single items.
+// Steps are returned in the kodama format: leaves are 0..n, the cluster created at step i has id n + i.
+use kodama::Step;
+use rayon::prelude::*;
+
+pub const SUPPORTED_METHODS: [&str; 4] = ["Single", "Ward", "Centroid", "Median"];
+const PAR_CUTOFF: usize = 1000; // Scans over fewer active clusters than this are not parallelized
+
+/// Items stored contiguously (item-major): item i occupies values[i * dim..(i + 1) * dim]
+pub struct ItemMatrix {
+    pub dim: usize,
+    pub values: Vec<f32>,
+}
+
+impl ItemMatrix {
+    pub fn num_items(&self) -> usize {
+        self.values.len().checked_div(self.dim).unwrap_or(0)
+    }
+
+    fn item(&self, i: usize) -> &[f32] {
+        &self.values[i * self.dim..(i + 1) * self.dim]
+    }
+
+    /// Items are the rows of a row major (num_rows x num_cols) matrix, the values are reused as is
+    pub fn from_rows(values: Vec<f32>, num_cols: usize) -> ItemMatrix {
+        ItemMatrix { dim: num_cols, values }
+    }
+
+    /// Items are the columns of a row major (num_rows x num_cols) matrix
+    pub fn from_columns(values: &[f32], num_cols: usize) -> ItemMatrix {
+        let num_rows = values.len().checked_div(num_cols).unwrap_or(0);
+        let mut transposed = vec![0.0f32; values.len()];
+        for row in 0..num_rows {
+            for col in 0..num_cols {
+                transposed[col * num_rows + row] = values[row * num_cols + col];
+            }
+        }
+        ItemMatrix {
+            dim: num_rows,
+            values: transposed,
+        }
+    }
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f64 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| {
+            let diff = (*x - *y) as f64;
+            diff * diff
+        })
+        .sum()
+}
+
+/// Nearest active item to `item` among `candidates` as (index, distance). Ties go to the first candidate.
+fn nearest(item: usize, candidates: &[usize], distance: &(dyn Fn(usize, usize) -> f64 + Sync)) -> Option<(usize, f64)> {
+    let better = |a: (usize, f64), b: (usize, f64)| if b.1 < a.1 || (b.1 == a.1 && b.0 < a.0) { b } else { a };
+    if candidates.len() < PAR_CUTOFF {
+        candidates
+            .iter()
+            .filter(|&&j| j != item)
+            .map(|&j| (j, distance(item, j)))
+            .reduce(better)
+    } else {
+        candidates
+            .par_iter()
+            .filter(|&&j| j != item)
+            .map(|&j| (j, distance(item, j)))
+            .reduce_with(better)
+    }
+}
+
+/// Converts merges of (item a, item b, dissimilarity) into kodama steps. Merges are sorted by dissimilarity (stable) and the
+/// clusters containing a and b are looked up with a union-find structure, as done by fastcluster for the NN-chain algorithm.
+fn merges_to_steps(mut merges: Vec<(usize, usize, f64)>, num_items: usize, sort: bool) -> Vec<Step<f64>> {
+    if sort {
+        merges.sort_by(|x, y| x.2.partial_cmp(&y.2).unwrap_or(std::cmp::Ordering::Equal));
+    }
+    let mut parent: Vec<usize> = (0..2 * num_items).collect();
+    let mut size: Vec<usize> = vec![1; 2 * num_items];
+    fn find(parent: &mut [usize], mut x: usize) -> usize {
+        while parent[x] != x {
+            parent[x] = parent[parent[x]];
+            x = parent[x];
+        }
+        x
+    }
+    let mut steps = Vec::with_capacity(merges.len());
+    for (i, (a, b, dissimilarity)) in merges.into_iter().enumerate() {
+        let (root_a, root_b) = (find(&mut parent, a), find(&mut parent, b));
+        let new_cluster = num_items + i;
+        parent[root_a] = new_cluster;
+        parent[root_b] = new_cluster;
+        size[new_cluster] = size[root_a] + size[root_b];
+        steps.push(Step {
+            cluster1: root_a.min(root_b),
+            cluster2: root_a.max(root_b),
+            dissimilarity,
+            size: size[new_cluster],
+        });
+    }
+    steps
+}
+
+/// Single linkage from the minimum spanning tree (Prim's algorithm, O(n^2) distance evaluations)
+fn single_linkage(items: &ItemMatrix) -> Vec<Step<f64>> {
+    let n = items.num_items();
+    let mut remaining: Vec<usize> = (1..n).collect();
+    let mut best: Vec<(f64, usize)> = vec![(f64::INFINITY, 0); n]; // Distance to the tree and closest tree item
+    let mut merges = Vec::with_capacity(n.saturating_sub(1));
+    let mut current = 0;
+    while !remaining.is_empty() {
+        let current_item = items.item(current);
+        let distances: Vec<f64> = if remaining.len() < PAR_CUTOFF {
+            remaining
+                .iter()
+                .map(|&j| squared_distance(current_item, items.item(j)))
+                .collect()
+        } else {
+            remaining
+                .par_iter()
+                .map(|&j| squared_distance(current_item, items.item(j)))
+                .collect()
+        };
+        let mut closest = 0;
+        for (k, &j) in remaining.iter().enumerate() {
+            if distances[k] < best[j].0 {
+                best[j] = (distances[k], current);
+            }
+            if best[j].0 < best[remaining[closest]].0 {
+                closest = k;
+            }
+        }
+        let next = remaining.swap_remove(closest);
+        merges.push((best[next].1, next, best[next].0.sqrt()));
+        current = next;
+    }
+    merges_to_steps(merges, n, true)
+}
+
+/// Replaces the centroid stored at slot `keep` by the centroid of the union of the clusters at `keep` and `other`
+fn merge_centroids(items: &mut ItemMatrix, keep: usize, other: usize, weight_keep: f64, weight_other: f64) {
+    let dim = items.dim;
+    let total = weight_keep + weight_other;
+    for d in 0..dim {
+        let merged = (items.values[keep * dim + d] as f64 * weight_keep
+            + items.values[other * dim + d] as f64 * weight_other)
+            / total;
+        items.values[keep * dim + d] = merged as f32;
+    }
+}
+
+/// Ward linkage with the nearest-neighbor chain algorithm
+fn ward_linkage(items: &mut ItemMatrix) -> Vec<Step<f64>> {
+    let n = items.num_items();
+    let mut size: Vec<usize> = vec![1; n];
+    let mut active: Vec<usize> = (0..n).collect();
+    let mut chain: Vec<usize> = Vec::new();
+    let mut merges = Vec::with_capacity(n.saturating_sub(1));
+    while active.len() > 1 {
+        if chain.is_empty() {
+            chain.push(active[0]);
+        }
+        let (a, b, dissimilarity) = loop {
+            let a = *chain.last().unwrap();
+            let previous = if chain.len() >= 2 {
+                Some(chain[chain.len() - 2])
+            } else {
+                None
+            };
+            let view: &ItemMatrix = items;
+            let ward = |i: usize, j: usize| -> f64 {
+                let (ni, nj) = (size[i] as f64, size[j] as f64);
+                (2.0 * ni * nj / (ni + nj) * squared_distance(view.item(i), view.item(j))).sqrt()
+            };
+            let (mut b, mut distance_b) = nearest(a, &active, &ward).unwrap();
+            // Prefer the previous chain element on ties so that the chain always terminates
+            if let Some(p) = previous {
+                let distance_p = ward(a, p);
+                if distance_p <= distance_b {
+                    b = p;
+                    distance_b = distance_p;
+                }
+            }
+            if Some(b) == previous {
+                break (a, b, distance_b);
+            }
+            chain.push(b);
+        };
+        chain.pop();
+        chain.pop();
+        let (keep, other) = (a.min(b), a.max(b));
+        merge_centroids(items, keep, other, size[keep] as f64, size[other] as f64);
+        size[keep] += size[other];
+        active.retain(|&i| i != other);
+        merges.push((keep, other, dissimilarity));
+    }
+    merges_to_steps(merges, n, true)
+}
+
+/// Centroid or median linkage with Müllner's generic algorithm. For each active cluster i the nearest neighbor among the
+/// active clusters j > i is cached together with a lower bound of its distance; stale entries are refreshed when selected.
+fn generic_linkage(items: &mut ItemMatrix, median: bool) -> Vec<Step<f64>> {
+    let n = items.num_items();
+    let mut size: Vec<usize> = vec![1; n];
+    let mut is_active = vec![true; n];
+    let mut active: Vec<usize> = (0..n).collect(); // Kept sorted
+    let mut neighbor: Vec<Option<usize>> = vec![None; n];
+    let mut min_distance: Vec<f64> = vec![f64::INFINITY; n];
+    let mut stale = vec![false; n];
+    let centroid_distance =
+        |items: &ItemMatrix, i: usize, j: usize| squared_distance(items.item(i), items.item(j)).sqrt();
+
+    let refresh = |items: &ItemMatrix, active: &[usize], i: usize| -> (Option<usize>, f64) {
+        let start = active.partition_point(|&j| j <= i);
+        let distance = |x: usize, y: usize| centroid_distance(items, x, y);
+        match nearest(i, &active[start..], &distance) {
+            Some((j, d)) => (Some(j), d),
+            None => (None, f64::INFINITY),
+        }
+    };
+    for i in 0..n {
+        let (j, d) = refresh(items, &active, i);
+        neighbor[i] = j;
+        min_distance[i] = d;
+    }
+
+    let mut merges = Vec::with_capacity(n.saturating_sub(1));
+    while active.len() > 1 {
+        // Closest pair, refreshing the cached nearest neighbors that are no longer exact
+        let (a, b, dissimilarity) = loop {
+            let mut a = active[0];
+            for &i in &active {
+                if min_distance[i] < min_distance[a] {
+                    a = i;
+                }
+            }
+            match neighbor[a] {
+                Some(b) if is_active[b] && !stale[a] => break (a, b, min_distance[a]),
+                _ => {
+                    let (j, d) = refresh(items, &active, a);
+                    neighbor[a] = j;
+                    min_distance[a] = d;
+                    stale[a] = false;
+                }
+            }
+        };
+        // The merged cluster replaces b (a < b)
+        let (weight_a, weight_b) = if median {
+            (1.0, 1.0)
+        } else {
+            (size[a] as f64, size[b] as f64)
+        };
+        merge_centroids(items, b, a, weight_b, weight_a);
+        size[b] += size[a];
+        is_active[a] = false;
+        active.retain(|&i| i != a);
+        merges.push((a, b, dissimilarity));
+
+        let view: &ItemMatrix = items;
+        let lower: Vec<usize> = active.iter().copied().take_while(|&i| i < b).collect();
+        let distances_to_b: Vec<f64> = if lower.len() < PAR_CUTOFF {
+            lower.iter().map(|&i| centroid_distance(view, i, b)).collect()
+        } else {
+            lower.par_iter().map(|&i| centroid_distance(view, i, b)).collect()
+        };
+        for (k, &i) in lower.iter().enumerate() {
+            if distances_to_b[k] < min_distance[i] {
+                neighbor[i] = Some(b);
+                min_distance[i] = distances_to_b[k];
+                stale[i] = false;
+            } else if neighbor[i] == Some(b) || neighbor[i] == Some(a) {
+                // The cached distance is still a lower bound but may no longer be attained
+                stale[i] = true;
+            }
+        }
+        let (j, d) = refresh(view, &active, b);
+        neighbor[b] = j;
+        min_distance[b] = d;
+        stale[b] = false;
+    }
+    // Centroid and median linkage are not monotone, merges are kept in the order they were made
+    merges_to_steps(merges, n, false)
+}
+
+/// Clusters the items, whose values are overwritten by the cluster centroids
+pub fn linkage(items: &mut ItemMatrix, cluster_method: &str) -> Result<Vec<Step<f64>>, String> {
+    if items.num_items() < 2 {
+        return Err("At least two items are needed for clustering".to_string());
+    }
+    match cluster_method {
+        "Single" => Ok(single_linkage(items)),
+        "Ward" => Ok(ward_linkage(items)),
+        "Centroid" => Ok(generic_linkage(items, false)),
+        "Median" => Ok(generic_linkage(items, true)),
+        _ => Err(format!(
+            "Low memory clustering supports the {} methods, not {}",
+            SUPPORTED_METHODS.join(", "),
+            cluster_method
+        )),
+    }
+}
@@ -0,0 +1,503 @@
+// Non-hierarchical clustering methods used by cluster.rs
+//
+// 1) KMeans: Lloyd's algorithm with k-means++ seeding (Arthur & Vassilvitskii 2007). The best of `n_start` random starts (lowest total within-cluster sum of squares) is kept.
+// 2) PAM: Partitioning Around Medoids / k-medoids with the BUILD and SWAP phases (Kaufman & Rousseeuw 1990). PAM is deterministic.
+// 3) Consensus: ConsensusClusterPlus-style resampling (Monti et al. 2003, Wilkerson & Hayes 2010). Items and features are subsampled `reps` times, clustered with an inner method for every k in 2..=max_k, and the fraction of times each pair of items lands in the same cluster (when both are sampled) forms the consensus matrix. Final assignments come from average-linkage clustering of 1 - consensus.
+//
+// All methods cluster the rows of the input matrix using euclidean distance. Cluster indexes in the output are 0-based. Randomness is drawn from stats_functions::SeededRng so the same seed always gives the same result.
+
+use crate::stats_functions::SeededRng;
+use kodama::{Method, linkage};
+use nalgebra::DMatrix;
+use serde::Serialize;
+
+const CONSENSUS_CDF_BINS: usize = 100; // Same number of histogram breaks used by ConsensusClusterPlus for the consensus CDF
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KMeansResult {
+    pub assignments: Vec<usize>,   // Cluster index of each item
+    pub centroids: Vec<Vec<f64>>,  // Coordinates of each cluster centroid
+    pub cluster_sizes: Vec<usize>, // Number of items in each cluster
+    pub within_ss: Vec<f64>,       // Within-cluster sum of squares of each cluster
+    pub total_within_ss: f64,      // Sum of within_ss
+    pub iterations: usize,         // Number of Lloyd iterations in the best start
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PamResult {
+    pub assignments: Vec<usize>,           // Cluster index of each item
+    pub medoids: Vec<usize>,               // Item index of the medoid of each cluster
+    pub medoid_coordinates: Vec<Vec<f64>>, // Coordinates of each medoid
+    pub cluster_sizes: Vec<usize>,         // Number of items in each cluster
+    pub total_cost: f64,                   // Sum of distances of each item to its medoid
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsensusK {
+    pub k: usize,
+    pub assignments: Vec<usize>, // Final cluster index of each item, from average linkage on 1 - consensus
+    pub consensus_matrix: Vec<Vec<f64>>, // items x items consensus values in [0,1]
+    pub cdf_x: Vec<f64>,         // Upper edges of the consensus histogram bins
+    pub cdf_y: Vec<f64>,         // Empirical CDF of the consensus values (upper triangle) at cdf_x
+    pub area: f64,               // Area under the consensus CDF
+    pub delta_area: f64,         // Relative change in area compared to k - 1 (area itself for k = 2)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsensusResult {
+    pub inner_method: String,
+    pub reps: usize,
+    pub p_item: f64,
+    pub p_feature: f64,
+    pub results: Vec<ConsensusK>,
+}
+
+fn squared_distance_to(data: &DMatrix<f64>, row: usize, point: &[f64]) -> f64 {
+    let mut dist = 0.0;
+    for col in 0..data.ncols() {
+        let diff = data[(row, col)] - point[col];
+        dist += diff * diff;
+    }
+    dist
+}
+
+fn row_vector(data: &DMatrix<f64>, row: usize) -> Vec<f64> {
+    (0..data.ncols()).map(|col| data[(row, col)]).collect()
+}
+
+/// Full (items x items) euclidean distance matrix stored row-major
+fn full_distance_matrix(data: &DMatrix<f64>) -> Vec<f64> {
+    let n = data.nrows();
+    let mut dist = vec![0.0; n * n];
+    for i in 0..n {
+        for j in i + 1..n {
+            let mut d = 0.0;
+            for col in 0..data.ncols() {
+                let diff = data[(i, col)] - data[(j, col)];
+                d += diff * diff;
+            }
+            let d = d.sqrt();
+            dist[i * n + j] = d;
+            dist[j * n + i] = d;
+        }
+    }
+    dist
+}
+
+fn kmeans_plus_plus_init(data: &DMatrix<f64>, k: usize, rng: &mut SeededRng) -> Vec<Vec<f64>> {
+    let n = data.nrows();
+    let mut centroids: Vec<Vec<f64>> = Vec::with_capacity(k);
+    centroids.push(row_vector(data, rng.gen_range(n)));
+    let mut closest: Vec<f64> = (0..n).map(|i| squared_distance_to(data, i, &centroids[0])).collect();
+    while centroids.len() < k {
+        let total: f64 = closest.iter().sum();
+        let chosen = if total > 0.0 {
+            // Pick the next centroid with probability proportional to the squared distance to the closest centroid
+            let target = rng.next_f64() * total;
+            let mut cumulative = 0.0;
+            let mut chosen = n - 1;
+            for (i, &d) in closest.iter().enumerate() {
+                cumulative += d;
+                if cumulative > target {
+                    chosen = i;
+                    break;
+                }
+            }
+            chosen
+        } else {
+            // All remaining items coincide with an existing centroid
+            rng.gen_range(n)
+        };
+        let centroid = row_vector(data, chosen);
+        for (i, closest_i) in closest.iter_mut().enumerate() {
+            let d = squared_distance_to(data, i, &centroid);
+            if d < *closest_i {
+                *closest_i = d;
+            }
+        }
+        centroids.push(centroid);
+    }
+    centroids
+}
+
+fn kmeans_single_start(data: &DMatrix<f64>, k: usize, max_iter: usize, rng: &mut SeededRng) -> KMeansResult {
+    let n = data.nrows();
+    let p = data.ncols();
+    let mut centroids = kmeans_plus_plus_init(data, k, rng);
+    let mut assignments = vec![usize::MAX; n];
+    let mut iterations = 0;
+    for _ in 0..max_iter {
+        iterations += 1;
+        // Assignment step
+        let mut changed = false;
+        for (i, assignment) in assignments.iter_mut().enumerate() {
+            let mut best = 0;
+            let mut best_dist = f64::INFINITY;
+            for (c, centroid) in centroids.iter().enumerate() {
+                let d = squared_distance_to(data, i, centroid);
+                if d < best_dist {
+                    best_dist = d;
+                    best = c;
+                }
+            }
+            if *assignment != best {
+                *assignment = best;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+        // Update step
+        let mut sums = vec![vec![0.0; p]; k];
+        let mut counts = vec![0usize; k];
+        for i in 0..n {
+            counts[assignments[i]] += 1;
+            for col in 0..p {
+                sums[assignments[i]][col] += data[(i, col)];
+            }
+        }
+        for c in 0..k {
+            if counts[c] > 0 {
+                centroids[c] = sums[c].iter().map(|s| s / counts[c] as f64).collect();
+            } else {
+                // Empty cluster: move its centroid to the item farthest from its current centroid
+                let mut farthest = 0;
+                let mut farthest_dist = -1.0;
+                for i in 0..n {
+                    let d = squared_distance_to(data, i, &centroids[assignments[i]]);
+                    if d > farthest_dist {
+                        farthest_dist = d;
+                        farthest = i;
+                    }
+                }
+                centroids[c] = row_vector(data, farthest);
+            }
+        }
+    }
+
+    let mut cluster_sizes = vec![0usize; k];
+    let mut within_ss = vec![0.0; k];
+    for i in 0..n {
+        cluster_sizes[assignments[i]] += 1;
+        within_ss[assignments[i]] += squared_distance_to(data, i, &centroids[assignments[i]]);
+    }
+    KMeansResult {
+        assignments,
+        centroids,
+        cluster_sizes,
+        total_within_ss: within_ss.iter().sum(),
+        within_ss,
+        iterations,
+    }
+}
+
+/// K-means clustering of the rows of `data`. Returns the best of `n_start` k-means++ starts.
+pub fn kmeans(data: &DMatrix<f64>, k: usize, n_start: usize, max_iter: usize, rng: &mut SeededRng) -> KMeansResult {
+    if k == 0 || k > data.nrows() {
+        panic!(
+            "Number of clusters k={} must be between 1 and the number of items ({})",
+            k,
+            data.nrows()
+        );
+    }
+    let mut best: Option<KMeansResult> = None;
+    for _ in 0..n_start.max(1) {
+        let result = kmeans_single_start(data, k, max_iter, rng);
+        let is_better = match &best {
+            Some(b) => result.total_within_ss < b.total_within_ss,
+            None => true,
+        };
+        if is_better {
+            best = Some(result);
+        }
+    }
+    best.unwrap()
+}
+
+/// PAM (k-medoids) clustering of the rows of `data` using euclidean distance
+pub fn pam(data: &DMatrix<f64>, k: usize, max_iter: usize) -> PamResult {
+    let n = data.nrows();
+    if k == 0 || k > n {
+        panic!(
+            "Number of clusters k={} must be between 1 and the number of items ({})",
+            k, n
+        );
+    }
+    let dist = full_distance_matrix(data);
+    let d = |i: usize, j: usize| dist[i * n + j];
+
+    // BUILD phase: start with the item with the smallest sum of distances, then greedily add the item that decreases the total cost the most
+    let mut medoids: Vec<usize> = Vec::with_capacity(k);
+    let distance_sums: Vec<f64> = (0..n).map(|i| (0..n).map(|j| d(i, j)).sum()).collect();
+    let first = (0..n)
+        .min_by(|&a, &b| {
+            distance_sums[a]
+                .partial_cmp(&distance_sums[b])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .unwrap();
+    medoids.push(first);
+    let mut nearest_dist: Vec<f64> = (0..n).map(|j| d(first, j)).collect();
+    while medoids.len() < k {
+        let mut best_candidate = usize::MAX;
+        let mut best_gain = -1.0;
+        for candidate in 0..n {
+            if medoids.contains(&candidate) {
+                continue;
+            }
+            let gain: f64 = (0..n).map(|j| (nearest_dist[j] - d(candidate, j)).max(0.0)).sum();
+            if gain > best_gain {
+                best_gain = gain;
+                best_candidate = candidate;
+            }
+        }
+        medoids.push(best_candidate);
+        for (j, nearest_j) in nearest_dist.iter_mut().enumerate() {
+            *nearest_j = nearest_j.min(d(best_candidate, j));
+        }
+    }
+
+    // SWAP phase: repeatedly apply the (medoid, non-medoid) swap with the largest decrease in total cost
+    for _ in 0..max_iter {
+        // Distance to the nearest and second nearest medoid of every item
+        let mut nearest = vec![0usize; n];
+        let mut nearest_d = vec![f64::INFINITY; n];
+        let mut second_d = vec![f64::INFINITY; n];
+        for j in 0..n {
+            for (m, &medoid) in medoids.iter().enumerate() {
+                let dj = d(medoid, j);
+                if dj < nearest_d[j] {
+                    second_d[j] = nearest_d[j];
+                    nearest_d[j] = dj;
+                    nearest[j] = m;
+                } else if dj < second_d[j] {
+                    second_d[j] = dj;
+                }
+            }
+        }
+        let mut best_change = 0.0;
+        let mut best_swap: Option<(usize, usize)> = None;
+        for m in 0..k {
+            for h in 0..n {
+                if medoids.contains(&h) {
+                    continue;
+                }
+                let mut change = 0.0;
+                for j in 0..n {
+                    let djh = d(h, j);
+                    if nearest[j] == m {
+                        change += djh.min(second_d[j]) - nearest_d[j];
+                    } else if djh < nearest_d[j] {
+                        change += djh - nearest_d[j];
+                    }
+                }
+                if change < best_change - 1e-12 {
+                    best_change = change;
+                    best_swap = Some((m, h));
+                }
+            }
+        }
+        match best_swap {
+            Some((m, h)) => medoids[m] = h,
+            None => break,
+        }
+    }
+
+    let mut assignments = vec![0usize; n];
+    let mut cluster_sizes = vec![0usize; k];
+    let mut total_cost = 0.0;
+    for (j, assignment) in assignments.iter_mut().enumerate() {
+        let mut best = 0;
+        for m in 1..k {
+            if d(medoids[m], j) < d(medoids[best], j) {
+                best = m;
+            }
+        }
+        *assignment = best;
+        cluster_sizes[best] += 1;
+        total_cost += d(medoids[best], j);
+    }
+    PamResult {
+        assignments,
+        medoid_coordinates: medoids.iter().map(|&m| row_vector(data, m)).collect(),
+        medoids,
+        cluster_sizes,
+        total_cost,
+    }
+}
+
+/// Cuts a kodama dendrogram into `k` flat clusters. Cluster indexes are assigned in order of first appearance.
+pub fn cut_dendrogram(steps: &[kodama::Step<f64>], num_items: usize, k: usize) -> Vec<usize> {
+    let mut parent: Vec<usize> = (0..2 * num_items).collect();
+    let num_merges = num_items.saturating_sub(k).min(steps.len());
+    for (s, step) in steps.iter().take(num_merges).enumerate() {
+        // kodama labels the cluster created in step s as num_items + s
+        parent[step.cluster1] = num_items + s;
+        parent[step.cluster2] = num_items + s;
+    }
+    let root = |mut node: usize| {
+        while parent[node] != node {
+            node = parent[node];
+        }
+        node
+    };
+    let mut root_to_cluster = std::collections::HashMap::<usize, usize>::new();
+    let mut assignments = Vec::with_capacity(num_items);
+    for i in 0..num_items {
+        let r = root(i);
+        let next_index = root_to_cluster.len();
+        assignments.push(*root_to_cluster.entry(r).or_insert(next_index));
+    }
+    assignments
+}
+
+/// Average linkage hierarchical clustering of the rows of `data`, cut into `k` clusters
+fn hierarchical_cut(data: &DMatrix<f64>, k: usize) -> Vec<usize> {
+    let n = data.nrows();
+    let mut condensed = Vec::with_capacity(n * (n - 1) / 2);
+    for i in 0..n {
+        for j in i + 1..n {
+            let mut d = 0.0;
+            for col in 0..data.ncols() {
+                let diff = data[(i, col)] - data[(j, col)];
+                d += diff * diff;
+            }
+            condensed.push(d.sqrt());
+        }
+    }
+    let dend = linkage(&mut condensed, n, Method::Average);
+    cut_dendrogram(dend.steps(), n, k)
+}
+
+/// ConsensusClusterPlus-style consensus clustering of the rows of `data` for k = 2..=max_k
+pub fn consensus_clustering(
+    data: &DMatrix<f64>,
+    max_k: usize,
+    reps: usize,
+    p_item: f64,
+    p_feature: f64,
+    inner_method: &str,
+    rng: &mut SeededRng,
+) -> ConsensusResult {
+    let n = data.nrows();
+    let p = data.ncols();
+    if max_k < 2 || max_k > n {
+        panic!("max_k={} must be between 2 and the number of items ({})", max_k, n);
+    }
+    let is_fraction = |x: f64| x > 0.0 && x <= 1.0;
+    if !is_fraction(p_item) || !is_fraction(p_feature) {
+        panic!("p_item and p_feature must be in (0,1]");
+    }
+    let num_sampled_items = ((n as f64 * p_item).floor() as usize).max(2);
+    let num_sampled_features = ((p as f64 * p_feature).floor() as usize).max(1);
+
+    // co_sampled[i*n+j]: number of resamplings with both i and j; co_clustered[k-2][i*n+j]: number of those where they share a cluster
+    let mut co_sampled = vec![0u32; n * n];
+    let mut co_clustered = vec![vec![0u32; n * n]; max_k - 1];
+    for _ in 0..reps {
+        let items = rng.sample_indices(n, num_sampled_items);
+        let features = rng.sample_indices(p, num_sampled_features);
+        let subset = data.select_rows(&items).select_columns(&features);
+        for a in 0..items.len() {
+            for b in 0..items.len() {
+                co_sampled[items[a] * n + items[b]] += 1;
+            }
+        }
+        for k in 2..=max_k {
+            if k > items.len() {
+                continue;
+            }
+            let labels = match inner_method {
+                "KMeans" => kmeans(&subset, k, 1, 100, rng).assignments,
+                "PAM" => pam(&subset, k, 100).assignments,
+                "Hierarchical" => hierarchical_cut(&subset, k),
+                _ => panic!(
+                    "Unknown inner clustering method for consensus clustering:{}",
+                    inner_method
+                ),
+            };
+            let counts = &mut co_clustered[k - 2];
+            for a in 0..items.len() {
+                for b in 0..items.len() {
+                    if labels[a] == labels[b] {
+                        counts[items[a] * n + items[b]] += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut results: Vec<ConsensusK> = Vec::with_capacity(max_k - 1);
+    let mut previous_area = 0.0;
+    for k in 2..=max_k {
+        let counts = &co_clustered[k - 2];
+        let mut consensus_matrix = vec![vec![0.0; n]; n];
+        for i in 0..n {
+            for j in 0..n {
+                consensus_matrix[i][j] = if i == j {
+                    1.0
+                } else if co_sampled[i * n + j] > 0 {
+                    counts[i * n + j] as f64 / co_sampled[i * n + j] as f64
+                } else {
+                    0.0
+                };
+            }
+        }
+
+        // Empirical CDF of the upper triangle consensus values over CONSENSUS_CDF_BINS right-closed bins
+        let mut histogram = vec![0usize; CONSENSUS_CDF_BINS];
+        let mut num_pairs = 0;
+        for (i, consensus_row) in consensus_matrix.iter().enumerate() {
+            for &value in &consensus_row[i + 1..] {
+                let bin = ((value * CONSENSUS_CDF_BINS as f64).ceil() as usize).max(1) - 1;
+                histogram[bin.min(CONSENSUS_CDF_BINS - 1)] += 1;
+                num_pairs += 1;
+            }
+        }
+        let mut cdf_x = Vec::with_capacity(CONSENSUS_CDF_BINS);
+        let mut cdf_y = Vec::with_capacity(CONSENSUS_CDF_BINS);
+        let mut cumulative = 0;
+        let mut area = 0.0;
+        for (bin, &count) in histogram.iter().enumerate() {
+            cumulative += count;
+            let y = cumulative as f64 / num_pairs.max(1) as f64;
+            cdf_x.push((bin + 1) as f64 / CONSENSUS_CDF_BINS as f64);
+            cdf_y.push(y);
+            area += y / CONSENSUS_CDF_BINS as f64;
+        }
+        let delta_area = if k == 2 {
+            area
+        } else {
+            (area - previous_area) / previous_area
+        };
+        previous_area = area;
+
+        // Final clusters: average linkage on 1 - consensus
+        let mut condensed = Vec::with_capacity(n * (n - 1) / 2);
+        for (i, consensus_row) in consensus_matrix.iter().enumerate() {
+            for &value in &consensus_row[i + 1..] {
+                condensed.push(1.0 - value);
+            }
+        }
+        let dend = linkage(&mut condensed, n, Method::Average);
+        let assignments = cut_dendrogram(dend.steps(), n, k);
+
+        results.push(ConsensusK {
+            k,
+            assignments,
+            consensus_matrix,
+            cdf_x,
+            cdf_y,
+            area,
+            delta_area,
+        });
+    }
+    ConsensusResult {
+        inner_method: inner_method.to_string(),
+        reps,
+        p_item,
+        p_feature,
+        results,
+    }
+}
@@ -3,6 +3,7 @@ use fishers_exact::fishers_exact;
 //use r_mathlib;
 use r_mathlib::chi_squared_cdf;
 use statrs::distribution::{ChiSquared, ContinuousCDF};
+use statrs::function::gamma::digamma;
 use std::collections::HashSet;
 use std::panic;
 
@@ -417,6 +418,12 @@ pub struct gene_order {
     pub rank: Option<usize>,
 }
 
+impl AsRef<str> for gene_order {
+    fn as_ref(&self) -> &str {
+        &self.gene_name
+    }
+}
+
 #[allow(dead_code)]
 pub fn cerno(
     genes_descending: &Vec<gene_order>,
@@ -486,3 +493,187 @@ pub fn cerno(
     }
     (p_value as f32, AUC, cES, N1, gene_set_hits, cerno)
 }
+
+/// Small seeded pseudo-random number generator (xoshiro256** seeded through splitmix64).
+/// Used wherever an analysis needs reproducible randomness (cluster initialization, resampling,
+/// permutations) so that the same seed always gives the same output across platforms.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    state: [u64; 4],
+}
+
+#[allow(dead_code)]
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        let mut sm = seed;
+        let mut state = [0u64; 4];
+        for s in state.iter_mut() {
+            sm = sm.wrapping_add(0x9E3779B97F4A7C15);
+            let mut z = sm;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            *s = z ^ (z >> 31);
+        }
+        SeededRng { state }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let result = self.state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.state[1] << 17;
+        self.state[2] ^= self.state[0];
+        self.state[3] ^= self.state[1];
+        self.state[1] ^= self.state[2];
+        self.state[0] ^= self.state[3];
+        self.state[2] ^= t;
+        self.state[3] = self.state[3].rotate_left(45);
+        result
+    }
+
+    /// Uniform f64 in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+    }
+
+    /// Uniform integer in [0, n)
+    pub fn gen_range(&mut self, n: usize) -> usize {
+        if n == 0 {
+            return 0;
+        }
+        (self.next_f64() * n as f64) as usize % n
+    }
+
+    /// Standard normal deviate (Box-Muller)
+    pub fn next_gaussian(&mut self) -> f64 {
+        let mut u1 = self.next_f64();
+        while u1 <= f64::MIN_POSITIVE {
+            u1 = self.next_f64();
+        }
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    /// Fisher-Yates shuffle
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.gen_range(i + 1);
+            items.swap(i, j);
+        }
+    }
+
+    /// Draw `k` distinct indices from 0..n (sorted in ascending order)
+    pub fn sample_indices(&mut self, n: usize, k: usize) -> Vec<usize> {
+        let mut indices: Vec<usize> = (0..n).collect();
+        let k = k.min(n);
+        for i in 0..k {
+            let j = i + self.gen_range(n - i);
+            indices.swap(i, j);
+        }
+        let mut sampled = indices[..k].to_vec();
+        sampled.sort();
+        sampled
+    }
+}
+
+#[allow(dead_code)]
+pub fn trigamma(mut x: f64) -> f64 {
+    if x <= 0.0 {
+        return f64::NAN;
+    }
+    let mut r = 0.0;
+    while x < 6.0 {
+        r += 1.0 / (x * x);
+        x += 1.0;
+    }
+    let x2 = x * x;
+    r + 1.0 / x + 1.0 / (2.0 * x2) + 1.0 / (6.0 * x2 * x) - 1.0 / (30.0 * x2 * x2 * x) + 1.0 / (42.0 * x2 * x2 * x2 * x)
+}
+
+#[allow(dead_code)]
+pub fn trigamma_deriv(mut x: f64) -> f64 {
+    let mut r = 0.0;
+    while x < 6.0 {
+        r -= 2.0 / (x * x * x);
+        x += 1.0;
+    }
+    let x2 = x * x;
+    r - 1.0 / x2 - 1.0 / (x2 * x) - 1.0 / (2.0 * x2 * x2) + 1.0 / (6.0 * x2 * x2 * x2)
+}
+
+#[allow(dead_code)]
+pub fn trigamma_inverse(x: f64) -> f64 {
+    if x.is_nan() || x <= 0.0 {
+        return f64::NAN;
+    }
+    let mut y = if x > 1e-6 { 1.0 / x.sqrt() } else { 1.0 / x };
+    for _ in 0..8 {
+        let delta = (trigamma(y) - x) / trigamma_deriv(y);
+        y -= delta;
+        if y <= 0.0 {
+            y = 0.5 * (y + delta);
+        }
+        if delta.abs() < 1e-12 * y.abs() {
+            break;
+        }
+    }
+    y
+}
+
+/// Moment estimation of the prior of the gene-wise variances (limma fitFDist). Returns (s20, df0)
+#[allow(dead_code)]
+pub fn fit_f_dist(vars: &[f64], dfs: &[f64]) -> (f64, f64) {
+    if vars.len() < 3 {
+        return (1.0, 0.0);
+    }
+    // Match R's fitFDist pre-processing:
+    // 1. Filter to ok probes (finite df > 1e-15, finite var > -1e-15)
+    // 2. Clamp var to max(var, 0), then floor at 1e-5 * median(var)
+    let ok: Vec<usize> = (0..vars.len())
+        .filter(|&i| dfs[i].is_finite() && dfs[i] > 1e-15 && vars[i].is_finite() && vars[i] > -1e-15)
+        .collect();
+    if ok.len() < 3 {
+        return (1.0, 0.0);
+    }
+    let mut xv: Vec<f64> = ok.iter().map(|&i| vars[i].max(0.0)).collect();
+    let xdf: Vec<f64> = ok.iter().map(|&i| dfs[i]).collect();
+    // Median of variances
+    let mut sorted_v = xv.clone();
+    sorted_v.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median_v = if sorted_v.len().is_multiple_of(2) {
+        (sorted_v[sorted_v.len() / 2 - 1] + sorted_v[sorted_v.len() / 2]) / 2.0
+    } else {
+        sorted_v[sorted_v.len() / 2]
+    };
+    if median_v == 0.0 {
+        return (1.0, f64::INFINITY);
+    }
+    // Floor small variances at 1e-5 * median (matches R's fitFDist)
+    let floor = 1e-5 * median_v;
+    for v in &mut xv {
+        if *v < floor {
+            *v = floor;
+        }
+    }
+    let n = xv.len() as f64;
+    // e = log(var) + logmdigamma(df/2) where logmdigamma(a) = log(a) - digamma(a)
+    let e: Vec<f64> = xv
+        .iter()
+        .zip(xdf.iter())
+        .map(|(&v, &d)| v.ln() + (d / 2.0).ln() - digamma(d / 2.0))
+        .collect();
+    let me = e.iter().sum::<f64>() / n;
+    let ve = e.iter().map(|&ei| (ei - me).powi(2)).sum::<f64>() / (n - 1.0);
+    let mean_tri: f64 = xdf.iter().map(|&d| trigamma(d / 2.0)).sum::<f64>() / n;
+    let target = ve - mean_tri;
+    let df0 = if target > 0.0 {
+        2.0 * trigamma_inverse(target)
+    } else {
+        f64::INFINITY
+    };
+    let s20 = if df0.is_finite() {
+        (me - (df0 / 2.0).ln() + digamma(df0 / 2.0)).exp()
+    } else {
+        xv.iter().sum::<f64>() / n
+    };
+    (s20, df0)
+}
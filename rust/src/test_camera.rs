@@ -0,0 +1,100 @@
+// For capturing output from a test, run: cd .. && cargo test -- --nocapture
+#[allow(dead_code)]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::camera::{GeneStatistics, camera, two_group_statistics};
+    use crate::geneset_functions::GeneSet;
+    use crate::stats_functions::SeededRng;
+    use nalgebra::DMatrix;
+    use statrs::distribution::{ContinuousCDF, StudentsT};
+
+    fn geneset(id: &str, genes: &[String]) -> GeneSet {
+        GeneSet {
+            id: id.to_string(),
+            genes: genes.iter().cloned().collect(),
+        }
+    }
+
+    // With a correlation of zero, camera reduces to a pooled two-sample t-test of the statistics in vs out of the set
+    #[test]
+    fn zero_correlation_is_two_sample_t_test() {
+        let statistics = vec![2.1, 1.7, 2.5, 0.3, -0.4, 0.1, -1.2, 0.8, -0.6, 0.0, 0.5, -0.9];
+        let genes: Vec<String> = (0..statistics.len()).map(|i| format!("G{}", i)).collect();
+        let gene_statistics = GeneStatistics {
+            genes: genes.clone(),
+            statistics: statistics.clone(),
+            residuals: None,
+        };
+        let results = camera(&gene_statistics, &[geneset("UP", &genes[..3])], Some(0.0), &|_| true).unwrap();
+        assert_eq!(results.len(), 1);
+
+        let (inside, outside) = statistics.split_at(3);
+        let mean = |x: &[f64]| x.iter().sum::<f64>() / x.len() as f64;
+        let sum_squares = |x: &[f64]| x.iter().map(|v| (v - mean(x)).powi(2)).sum::<f64>();
+        let pooled_var = (sum_squares(inside) + sum_squares(outside)) / (statistics.len() - 2) as f64;
+        let t = (mean(inside) - mean(outside)) / (pooled_var * (1.0 / 3.0 + 1.0 / 9.0)).sqrt();
+        let expected_p_value = 2.0 * StudentsT::new(0.0, 1.0, 10.0).unwrap().sf(t.abs());
+        assert!((results[0].p_value - expected_p_value).abs() < 1e-12);
+        assert_eq!(results[0].direction, "Up");
+        assert!(results[0].p_value < 1e-3);
+        assert!(results[0].fdr >= results[0].p_value);
+    }
+
+    // Genes sharing a common sample effect get a high estimated correlation and a less significant p-value
+    #[test]
+    fn estimated_correlation_of_co_expressed_genes() {
+        let mut rng = SeededRng::new(7);
+        let num_genes = 200;
+        let num_samples = 12;
+        let case_indexes: Vec<usize> = (0..6).collect();
+        let control_indexes: Vec<usize> = (6..12).collect();
+        let shared: Vec<f64> = (0..num_samples).map(|_| rng.next_gaussian()).collect();
+        let mut log_expr = DMatrix::<f64>::zeros(num_genes, num_samples);
+        for i in 0..num_genes {
+            for j in 0..num_samples {
+                let mut value = 5.0 + rng.next_gaussian();
+                if i < 20 {
+                    value += 2.0 * shared[j];
+                    if j < 6 {
+                        value += 1.0;
+                    }
+                }
+                log_expr[(i, j)] = value;
+            }
+        }
+        let genes: Vec<String> = (0..num_genes).map(|i| format!("G{}", i)).collect();
+        let gene_statistics = two_group_statistics(genes.clone(), &log_expr, &case_indexes, &control_indexes).unwrap();
+        assert_eq!(gene_statistics.residuals.as_ref().unwrap().ncols(), 10);
+
+        let genesets = [
+            geneset("CORRELATED", &genes[..20]),
+            geneset("INDEPENDENT", &genes[100..120]),
+        ];
+        let estimated = camera(&gene_statistics, &genesets, None, &|_| true).unwrap();
+        let correlated = estimated.iter().find(|r| r.name == "CORRELATED").unwrap();
+        let independent = estimated.iter().find(|r| r.name == "INDEPENDENT").unwrap();
+        assert!(correlated.correlation > 0.5, "correlation = {}", correlated.correlation);
+        assert!(
+            independent.correlation.abs() < 0.1,
+            "correlation = {}",
+            independent.correlation
+        );
+        assert_eq!(correlated.direction, "Up");
+
+        let fixed = camera(&gene_statistics, &genesets, Some(0.01), &|_| true).unwrap();
+        let correlated_fixed = fixed.iter().find(|r| r.name == "CORRELATED").unwrap();
+        assert!(correlated_fixed.p_value < correlated.p_value);
+    }
+
+    #[test]
+    fn correlation_cannot_be_estimated_from_precomputed_statistics() {
+        let gene_statistics = GeneStatistics {
+            genes: vec!["A".to_string(), "B".to_string(), "C".to_string()],
+            statistics: vec![1.0, 0.0, -1.0],
+            residuals: None,
+        };
+        assert!(camera(&gene_statistics, &[], None, &|_| true).is_err());
+    }
+}
@@ -4,7 +4,9 @@ fn main() {}
 
 #[cfg(test)]
 mod tests {
-    use crate::stats_functions::cerno;
+    use crate::geneset_functions::{GeneSet, SizeFilter};
+    use crate::stats_functions::{cerno, gene_order};
+    use crate::{filter_gene_set_sizes, output_object, pathway_p_value, pathways_to_string};
     use flate2::read::GzDecoder;
     use json::JsonValue;
     use std::cmp::Ordering;
@@ -128,4 +130,71 @@ mod tests {
             Err(error) => println!("Incorrect json:{}", error),
         }
     }
+
+    #[test]
+    fn results_are_nested_under_pathways() {
+        let fields = vec![r#""unmapped_genes":["FOO1"]"#.to_string()];
+        // A gene set named like an output field does not collide with it
+        let output: serde_json::Value = serde_json::from_str(&output_object(
+            r#"{"SET_A":{"pval":0.5},"unmapped_genes":{"pval":0.1}}"#.to_string(),
+            &fields,
+        ))
+        .unwrap();
+        assert_eq!(output["pathways"]["SET_A"]["pval"], 0.5);
+        assert_eq!(output["pathways"]["unmapped_genes"]["pval"], 0.1);
+        assert_eq!(output["unmapped_genes"], serde_json::json!(["FOO1"]));
+        // No gene set could be tested
+        assert_eq!(
+            output_object("{}".to_string(), &fields),
+            r#"{"pathways":{},"unmapped_genes":["FOO1"]}"#
+        );
+        assert_eq!(output_object("{}".to_string(), &[]), r#"{"pathways":{}}"#);
+    }
+
+    #[test]
+    fn pathway_names_are_escaped() {
+        // Custom gene set names may contain quotes and backslashes
+        let name = r#"SET "A" \ B"#.to_string();
+        let adjusted_p_values = vec![pathway_p_value {
+            pathway_name: name.clone(),
+            p_value_original: 0.5,
+            p_value_adjusted: Some(0.5),
+            gene_set_hits: "TP53".to_string(),
+            auc: 0.6,
+            es: 1.2,
+            gene_set_size: 3,
+        }];
+        let output: serde_json::Value = serde_json::from_str(&pathways_to_string(&adjusted_p_values)).unwrap();
+        assert_eq!(output[&name]["pval"], 0.5);
+    }
+
+    #[test]
+    fn size_filter_counts_ranked_genes() {
+        let ranked: Vec<gene_order> = ["TP53", "KRAS", "MYC", "EGFR"]
+            .iter()
+            .enumerate()
+            .map(|(i, g)| gene_order {
+                gene_name: g.to_string(),
+                fold_change: 4.0 - i as f32,
+                rank: Some(i),
+            })
+            .collect();
+        let gene_set = |id: &str, genes: &[&str]| GeneSet {
+            id: id.to_string(),
+            genes: genes.iter().map(|g| g.to_string()).collect(),
+        };
+        // 2, 3 and 1 genes in the ranked list out of 4, 3 and 4 genes
+        let mut genesets = vec![
+            gene_set("SET_A", &["TP53", "KRAS", "FOO1", "FOO2"]),
+            gene_set("SET_B", &["TP53", "MYC", "EGFR"]),
+            gene_set("SET_C", &["MYC", "FOO1", "FOO2", "FOO3"]),
+        ];
+        let size_filter = SizeFilter {
+            min_size: Some(2),
+            max_size: Some(2),
+        };
+        filter_gene_set_sizes(&mut genesets, &ranked, &size_filter);
+        let kept: Vec<&str> = genesets.iter().map(|gs| gs.id.as_str()).collect();
+        assert_eq!(kept, vec!["SET_A"]);
+    }
 }
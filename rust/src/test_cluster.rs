@@ -0,0 +1,310 @@
+// For capturing output from a test, run: cd .. && cargo test -- --nocapture
+#[allow(dead_code)]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::biclustering::{Bicluster, bicluster_result, cheng_church, spectral_coclustering};
+    use crate::heatmap_render::{default_domain, leaf_order};
+    use crate::low_memory_linkage::{self, ItemMatrix};
+    use crate::partition_clustering::{consensus_clustering, kmeans, pam};
+    use crate::stats_functions::SeededRng;
+    use crate::{Steps, heatmap_output};
+    use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+    use nalgebra::DMatrix;
+
+    // 12 items in 3 well-separated groups of 4 (items 0-3, 4-7 and 8-11) in 2 dimensions
+    fn three_groups() -> DMatrix<f64> {
+        let centers = [(0.0, 0.0), (10.0, 0.0), (0.0, 10.0)];
+        let offsets = [(0.1, 0.2), (-0.2, 0.1), (0.3, -0.1), (-0.1, -0.3)];
+        DMatrix::from_fn(12, 2, |i, j| {
+            let (c, o) = (centers[i / 4], offsets[i % 4]);
+            if j == 0 { c.0 + o.0 } else { c.1 + o.1 }
+        })
+    }
+
+    // Same partition up to the numbering of the clusters
+    fn same_partition(a: &[usize], b: &[usize]) -> bool {
+        (0..a.len()).all(|i| (0..a.len()).all(|j| (a[i] == a[j]) == (b[i] == b[j])))
+    }
+
+    fn groups() -> Vec<usize> {
+        (0..12).map(|i| i / 4).collect()
+    }
+
+    #[test]
+    fn kmeans_recovers_groups() {
+        let data = three_groups();
+        let result = kmeans(&data, 3, 10, 100, &mut SeededRng::new(1));
+        assert!(same_partition(&result.assignments, &groups()));
+        assert_eq!(result.cluster_sizes, vec![4, 4, 4]);
+        // The offsets of each group have squared norms summing to 0.15 in each dimension and mean (0.025, -0.025)
+        let expected_ss = 3.0 * (0.15 + 0.15 - 4.0 * 2.0 * 0.025 * 0.025);
+        assert!((result.total_within_ss - expected_ss).abs() < 1e-10);
+        // The same seed gives the same result
+        let again = kmeans(&data, 3, 10, 100, &mut SeededRng::new(1));
+        assert_eq!(result.assignments, again.assignments);
+    }
+
+    #[test]
+    fn pam_recovers_groups() {
+        let data = three_groups();
+        let result = pam(&data, 3, 100);
+        assert!(same_partition(&result.assignments, &groups()));
+        for (c, &medoid) in result.medoids.iter().enumerate() {
+            assert_eq!(result.assignments[medoid], c);
+            assert_eq!(result.medoid_coordinates[c], vec![data[(medoid, 0)], data[(medoid, 1)]]);
+        }
+        let cost: f64 = (0..12)
+            .map(|i| {
+                let m = result.medoids[result.assignments[i]];
+                ((data[(i, 0)] - data[(m, 0)]).powi(2) + (data[(i, 1)] - data[(m, 1)]).powi(2)).sqrt()
+            })
+            .sum();
+        assert!((result.total_cost - cost).abs() < 1e-10);
+    }
+
+    #[test]
+    fn consensus_of_separated_groups() {
+        let data = three_groups();
+        let result = consensus_clustering(&data, 3, 50, 0.8, 1.0, "Hierarchical", &mut SeededRng::new(7));
+        assert_eq!(result.results.len(), 2);
+        let k3 = &result.results[1];
+        assert_eq!(k3.k, 3);
+        assert!(same_partition(&k3.assignments, &groups()));
+        // Pairs of the same group always cluster together and the others never: 18 of the 66 pairs have consensus 1
+        for i in 0..12 {
+            for j in 0..12 {
+                let expected = if i / 4 == j / 4 { 1.0 } else { 0.0 };
+                assert_eq!(k3.consensus_matrix[i][j], expected);
+            }
+        }
+        assert_eq!(k3.cdf_x.len(), 100);
+        assert!(k3.cdf_y[..99].iter().all(|&y| (y - 48.0 / 66.0).abs() < 1e-12));
+        assert_eq!(k3.cdf_y[99], 1.0);
+        let area = 0.99 * 48.0 / 66.0 + 0.01;
+        assert!((k3.area - area).abs() < 1e-12);
+        let k2 = &result.results[0];
+        assert!((k3.delta_area - (k3.area - k2.area) / k2.area).abs() < 1e-12);
+        assert_eq!(k2.delta_area, k2.area);
+    }
+
+    fn steps(merges: &[(usize, usize, f64)]) -> Vec<Steps> {
+        merges
+            .iter()
+            .map(|&(cluster1, cluster2, dissimilarity)| Steps {
+                cluster1,
+                cluster2,
+                dissimilarity,
+                size: 0,
+            })
+            .collect()
+    }
+
+    // Row dendrogram ((0, 1), 2) and column dendrogram (3, (0, (1, 2)))
+    const ROW_MERGES: [(usize, usize, f64); 2] = [(0, 1, 1.0), (3, 2, 2.0)];
+    const COL_MERGES: [(usize, usize, f64); 3] = [(1, 2, 0.5), (0, 4, 1.0), (3, 5, 2.0)];
+
+    fn heatmap_input(extra: &str) -> json::JsonValue {
+        json::parse(&format!(
+            r#"{{"image_width":40,"image_height":30,"dendrogram_size":20,"annotation_size":5,"devicePixelRatio":2,
+            "color_scale":{{"colors":["blue","white","red"],"domain":[-1,0,1]}},
+            "col_annotations":[{{"name":"Group","values":["a","b","a","b"],"colors":{{"a":"green"}}}}]{}}}"#,
+            extra
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn leaf_order_walks_dendrogram() {
+        assert_eq!(leaf_order(&ROW_MERGES, 3), vec![0, 1, 2]);
+        assert_eq!(leaf_order(&COL_MERGES, 4), vec![3, 0, 1, 2]);
+        // Without a complete dendrogram the input order is kept
+        assert_eq!(leaf_order(&COL_MERGES[..2], 4), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn default_domain_of_heatmap() {
+        let both_signs = DMatrix::from_row_slice(1, 3, &[-2.0, 1.0, f64::NAN]);
+        assert_eq!(default_domain(&both_signs), vec![-2.0, 0.0, 2.0]);
+        let positive = DMatrix::from_row_slice(1, 3, &[1.0, 3.0, 2.0]);
+        assert_eq!(default_domain(&positive), vec![1.0, 2.0, 3.0]);
+        let constant = DMatrix::from_row_slice(1, 2, &[5.0, 5.0]);
+        assert_eq!(default_domain(&constant), vec![4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn heatmap_png_and_layout() {
+        let mut matrix = DMatrix::from_fn(3, 4, |i, j| (i as f64 - j as f64) / 4.0);
+        matrix[(0, 3)] = 1.0; // First row and column in display order, drawn in red
+        matrix[(2, 2)] = f64::NAN; // Last row and column in display order, drawn in grey
+        let heatmap = heatmap_output(&heatmap_input(""), &matrix, &steps(&ROW_MERGES), &steps(&COL_MERGES));
+        assert_eq!(heatmap["row_order"], serde_json::json!([0, 1, 2]));
+        assert_eq!(heatmap["col_order"], serde_json::json!([3, 0, 1, 2]));
+        // Row dendrogram (20) on the left, column dendrogram (20) and one annotation (5 + 2) on top
+        assert_eq!(
+            heatmap["heatmap"],
+            serde_json::json!({"x": 20.0, "y": 27.0, "width": 40.0, "height": 30.0})
+        );
+        // Legend gap (20), width (12) and label space (60) on the right
+        assert_eq!(heatmap["width"], 152.0);
+        assert_eq!(heatmap["height"], 57.0);
+
+        let png = heatmap["png"]
+            .as_str()
+            .unwrap()
+            .strip_prefix("data:image/png;base64,")
+            .unwrap();
+        let pixmap = tiny_skia::Pixmap::decode_png(&BASE64.decode(png).unwrap()).unwrap();
+        assert_eq!((pixmap.width(), pixmap.height()), (304, 114));
+        let rgba = |x: u32, y: u32| {
+            let c = pixmap.pixel(x, y).unwrap().demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        };
+        // Cells are 20 x 20 device pixels from (40, 54)
+        assert_eq!(rgba(50, 64), [255, 0, 0, 255]);
+        assert_eq!(rgba(110, 104), [204, 204, 204, 255]);
+        // Annotation bar above the first column: value "b" gets the second category10 color (#ff7f0e)
+        assert_eq!(rgba(50, 45), [255, 127, 14, 255]);
+    }
+
+    #[test]
+    fn heatmap_errors_are_returned_as_json() {
+        let matrix = DMatrix::from_fn(3, 4, |i, j| (i + j) as f64);
+        let bad_domain = heatmap_input(r#","color_scale":{"colors":["blue","red"],"domain":[1,0]}"#);
+        let heatmap = heatmap_output(&bad_domain, &matrix, &steps(&ROW_MERGES), &steps(&COL_MERGES));
+        assert_eq!(
+            heatmap,
+            serde_json::json!({"error": "Heatmap rendering failed: The color scale domain must be strictly increasing"})
+        );
+        let short_annotation = heatmap_input(r#","row_annotations":[{"name":"Pathway","values":["x"]}]"#);
+        let heatmap = heatmap_output(&short_annotation, &matrix, &steps(&ROW_MERGES), &steps(&COL_MERGES));
+        assert_eq!(
+            heatmap["error"],
+            "Heatmap rendering failed: Row annotation Pathway does not have 3 values"
+        );
+    }
+
+    #[test]
+    fn low_memory_linkage_matches_kodama() {
+        // 9 items in 3 dimensions with distinct pairwise distances
+        let mut rng = SeededRng::new(5);
+        let values: Vec<f64> = (0..27).map(|_| rng.next_f64() * 10.0).collect();
+        let n = 9;
+        let distance = |i: usize, j: usize| -> f64 {
+            (0..3)
+                .map(|d| (values[i * 3 + d] - values[j * 3 + d]).powi(2))
+                .sum::<f64>()
+                .sqrt()
+        };
+        let methods = [
+            ("Single", kodama::Method::Single),
+            ("Ward", kodama::Method::Ward),
+            ("Centroid", kodama::Method::Centroid),
+            ("Median", kodama::Method::Median),
+        ];
+        for (name, method) in methods {
+            let mut condensed: Vec<f64> = (0..n)
+                .flat_map(|i| ((i + 1)..n).map(move |j| (i, j)))
+                .map(|(i, j)| distance(i, j))
+                .collect();
+            let expected = kodama::linkage(&mut condensed, n, method);
+            let mut items = ItemMatrix::from_rows(values.iter().map(|&v| v as f32).collect(), 3);
+            let steps = low_memory_linkage::linkage(&mut items, name).unwrap();
+            assert_eq!(steps.len(), n - 1);
+            for (step, kodama_step) in steps.iter().zip(expected.steps()) {
+                assert_eq!(
+                    (step.cluster1, step.cluster2, step.size),
+                    (kodama_step.cluster1, kodama_step.cluster2, kodama_step.size),
+                    "{}",
+                    name
+                );
+                // Single precision items
+                assert!(
+                    (step.dissimilarity - kodama_step.dissimilarity).abs() < 1e-4,
+                    "{}",
+                    name
+                );
+            }
+        }
+        let mut items = ItemMatrix::from_rows(vec![0.0; 6], 3);
+        assert!(low_memory_linkage::linkage(&mut items, "Average").is_err());
+    }
+
+    // Random permutation of 0..n
+    fn shuffled(n: usize, rng: &mut SeededRng) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..n).collect();
+        for i in (1..n).rev() {
+            order.swap(i, rng.gen_range(i + 1));
+        }
+        order
+    }
+
+    #[test]
+    fn spectral_coclustering_recovers_planted_blocks() {
+        // 3 blocks of 8, 6 and 6 rows by 5 columns with values around 10 on a background around 1, with shuffled rows and columns
+        let mut rng = SeededRng::new(42);
+        let (row_block, col_block) = (
+            |r: usize| [0, 8, 14].iter().filter(|&&s| r >= s).count() - 1,
+            |c: usize| c / 5,
+        );
+        let (row_order, col_order) = (shuffled(20, &mut rng), shuffled(15, &mut rng));
+        let data = DMatrix::from_fn(20, 15, |r, c| {
+            let same = row_block(row_order[r]) == col_block(col_order[c]);
+            (if same { 10.0 } else { 1.0 }) + rng.next_f64()
+        });
+        let biclusters = spectral_coclustering(&data, 3, 10, 100, &mut SeededRng::new(1)).unwrap();
+        assert_eq!(biclusters.len(), 3);
+        for bicluster in &biclusters {
+            let block = row_block(row_order[bicluster.rows[0]]);
+            let planted_rows: Vec<usize> = (0..20).filter(|&r| row_block(row_order[r]) == block).collect();
+            let planted_cols: Vec<usize> = (0..15).filter(|&c| col_block(col_order[c]) == block).collect();
+            assert_eq!(bicluster.rows, planted_rows);
+            assert_eq!(bicluster.cols, planted_cols);
+        }
+        // Negative values are rejected
+        assert!(spectral_coclustering(&data.map(|v| v - 5.0), 3, 10, 100, &mut SeededRng::new(1)).is_err());
+    }
+
+    #[test]
+    fn cheng_church_recovers_planted_bicluster() {
+        // Additive bicluster (row effect + column effect, MSR 0) of 12 rows and 10 columns in a 30 x 20 uniform background
+        let mut rng = SeededRng::new(42);
+        let mut data = DMatrix::from_fn(30, 20, |_, _| 10.0 * rng.next_f64());
+        let mut planted_rows = shuffled(30, &mut rng)[..12].to_vec();
+        let mut planted_cols = shuffled(20, &mut rng)[..10].to_vec();
+        planted_rows.sort_unstable();
+        planted_cols.sort_unstable();
+        for (i, &r) in planted_rows.iter().enumerate() {
+            for (j, &c) in planted_cols.iter().enumerate() {
+                data[(r, c)] = 2.0 + 0.5 * i as f64 + 0.8 * j as f64;
+            }
+        }
+        let biclusters = cheng_church(&data, 1, Some(0.05), 1.2, &mut SeededRng::new(1));
+        assert_eq!(biclusters.len(), 1);
+        assert_eq!(biclusters[0].rows, planted_rows);
+        assert_eq!(biclusters[0].cols, planted_cols);
+        assert!(biclusters[0].msr < 1e-12);
+    }
+
+    #[test]
+    fn bicluster_result_orders_by_bicluster() {
+        let data = DMatrix::from_fn(4, 3, |r, c| (r * 3 + c) as f64);
+        let biclusters = vec![
+            Bicluster {
+                rows: vec![2, 3],
+                cols: vec![1],
+                msr: 0.0,
+            },
+            Bicluster {
+                rows: vec![3, 0],
+                cols: vec![2, 1],
+                msr: 0.0,
+            },
+        ];
+        let result = bicluster_result("ChengChurch", &data, biclusters);
+        assert_eq!(result.sorted_row_elements, vec![2, 3, 0, 1]);
+        assert_eq!(result.sorted_col_elements, vec![1, 2, 0]);
+        assert_eq!(result.sorted_matrix[0], vec![7.0, 8.0, 6.0]);
+    }
+}
@@ -0,0 +1,114 @@
+// For capturing output from a test, run: cd .. && cargo test -- --nocapture
+#[allow(dead_code)]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats_functions::SeededRng;
+    use crate::{VpTree, pca, run, squared_distance, tsne, umap};
+    use nalgebra::DMatrix;
+    use serde_json::json;
+
+    const TOLERANCE: f64 = 1e-10;
+
+    // Two groups of 15 samples in 5 dimensions, 10 apart along the first one
+    fn two_groups() -> DMatrix<f64> {
+        let mut rng = SeededRng::new(3);
+        DMatrix::from_fn(30, 5, |i, j| {
+            let offset = if i < 15 && j == 0 { 10.0 } else { 0.0 };
+            offset + rng.next_gaussian()
+        })
+    }
+
+    // Whether the nearest embedded sample of every sample is in its own group
+    fn groups_separated(y: &[f64], dims: usize) -> bool {
+        let distance = |i: usize, j: usize| {
+            (0..dims)
+                .map(|d| (y[i * dims + d] - y[j * dims + d]).powi(2))
+                .sum::<f64>()
+        };
+        (0..30).all(|i| {
+            let nearest = (0..30)
+                .filter(|&j| j != i)
+                .min_by(|&a, &b| distance(i, a).total_cmp(&distance(i, b)))
+                .unwrap();
+            (i < 15) == (nearest < 15)
+        })
+    }
+
+    #[test]
+    fn pca_recovers_known_components() {
+        // Samples a_s (1, 1, 0) / sqrt(2) + b_s (0, 0, 1) with centered, uncorrelated a and b and var(a) > var(b)
+        let a = [-3.0, -1.0, 1.0, 3.0];
+        let b = [1.0, -1.0, -1.0, 1.0];
+        let h = 0.5f64.sqrt();
+        let x = DMatrix::from_fn(4, 3, |s, f| if f < 2 { a[s] * h } else { b[s] });
+        let result = pca(&x, 2);
+        let expected_loadings = [[h, h, 0.0], [0.0, 0.0, 1.0]];
+        for (c, expected) in expected_loadings.iter().enumerate() {
+            for (f, &e) in expected.iter().enumerate() {
+                assert!((result.loadings[(f, c)] - e).abs() < TOLERANCE);
+            }
+        }
+        // Scores are a and b up to the sign fixed by the loadings
+        let sign = |c: usize| (result.scores[(3, c)] / [a[3], b[3]][c]).signum();
+        for s in 0..4 {
+            assert!((result.scores[(s, 0)] - sign(0) * a[s]).abs() < TOLERANCE);
+            assert!((result.scores[(s, 1)] - sign(1) * b[s]).abs() < TOLERANCE);
+        }
+        assert!((result.sdev[0] - (20.0f64 / 3.0).sqrt()).abs() < TOLERANCE);
+        assert!((result.sdev[1] - (4.0f64 / 3.0).sqrt()).abs() < TOLERANCE);
+        assert!((result.variance_explained[0] - 20.0 / 24.0).abs() < TOLERANCE);
+        assert!((result.variance_explained[1] - 4.0 / 24.0).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn vp_tree_finds_nearest_neighbors() {
+        let mut rng = SeededRng::new(11);
+        let x = DMatrix::from_fn(200, 3, |_, _| rng.next_f64());
+        let tree = VpTree::new(&x);
+        for i in 0..x.nrows() {
+            let mut brute_force: Vec<(usize, f64)> = (0..x.nrows())
+                .filter(|&j| j != i)
+                .map(|j| (j, squared_distance(&x, i, j).sqrt()))
+                .collect();
+            brute_force.sort_by(|a, b| a.1.total_cmp(&b.1));
+            brute_force.truncate(10);
+            assert_eq!(tree.nearest(i, 10), brute_force);
+        }
+        // Asking for more neighbors than there are other rows returns all of them
+        assert_eq!(tree.nearest(0, 500).len(), 199);
+    }
+
+    #[test]
+    fn tsne_is_deterministic_and_separates_groups() {
+        let x = two_groups();
+        // The default learning rate of 200 overshoots with 30 samples under early exaggeration (exaggeration times
+        // learning rate far above the number of samples), so the embedding of the test uses a smaller one
+        let learning_rate = 10.0;
+        for theta in [0.0, 0.5] {
+            let y = tsne(&x, 2, 5.0, theta, 1000, learning_rate, &mut SeededRng::new(1));
+            assert_eq!(y, tsne(&x, 2, 5.0, theta, 1000, learning_rate, &mut SeededRng::new(1)));
+            assert_ne!(y, tsne(&x, 2, 5.0, theta, 1000, learning_rate, &mut SeededRng::new(2)));
+            assert!(groups_separated(&y, 2), "theta {}", theta);
+        }
+    }
+
+    #[test]
+    fn umap_is_deterministic_and_separates_groups() {
+        let x = two_groups();
+        let y = umap(&x, 2, 5, 0.1, 1.0, 200, 5, &mut SeededRng::new(1));
+        assert_eq!(y, umap(&x, 2, 5, 0.1, 1.0, 200, 5, &mut SeededRng::new(1)));
+        assert!(groups_separated(&y, 2));
+    }
+
+    #[test]
+    fn invalid_input_is_an_error() {
+        let error = run(&json!({"matrix": [[1, 2], [3, 4]]})).unwrap_err();
+        assert_eq!(error, "At least 3 samples are required, found 2");
+        let error = run(&json!({"matrix": [[1, 2, 3], [3, 4, 5]], "method": "mds"})).unwrap_err();
+        assert_eq!(error, "Unknown method: mds");
+        let output = run(&json!({"matrix": [[1, 2, 3, 4], [4, 5, 6, 9], [7, 8, 10, 1]]})).unwrap();
+        assert_eq!(output["coordinates"].as_array().unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,894 @@
+// For capturing output from a test, run: cd .. && cargo test -- --nocapture
+#[allow(dead_code)]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::dmr_annotation::{
+        Gene, IntervalTree, RegionAnnotation, load_gene_file, load_genedb_genes, load_islands,
+    };
+    use crate::dmrcate_aggregate::{
+        Aggregate, ProbeIndex, aggregate_rows, read_regions, region_matrix, select_samples,
+    };
+    use crate::dmrcate_cache::{CachedProbe, GenomeFit, cache_path, evict, fnv1a, read_region, write_cache};
+    use crate::dmrcate_callers::{BumpParams, bumphunter, combp, find_bumps};
+    use crate::dmrcate_design::{Design, absolute_deviations, build_design};
+    use crate::dmrcate_export::{SmoothedFdr, export_results};
+    use crate::dmrcate_preprocess::{
+        BmiqFit, PreprocessOptions, RemovedProbes, SampleSummary, TypeNormalization, fit_beta, fit_mixture,
+    };
+    use crate::dmrcate_tracks::{RegionTracks, TrackOptions, beta_color, render_tracks, requested_tracks};
+    use crate::{CallParams, ChromosomeProbes, GroupStats, call_dmrs, responses};
+    use nalgebra::{DMatrix, DVector};
+    use rusqlite::Connection;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn probe(row: u64, start: i64) -> CachedProbe {
+        CachedProbe {
+            row,
+            start,
+            log_fc: row as f64 * 0.5,
+            t: -(row as f64),
+            raw_p: 1e-300,
+            adj_p: 0.01 * row as f64,
+        }
+    }
+
+    #[test]
+    fn cache_round_trip_and_invalidation() {
+        let dir = std::env::temp_dir().join(format!("dmrcate_cache_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        let h5_path = format!("{}/beta.h5", dir);
+        std::fs::write(&h5_path, b"placeholder").unwrap();
+
+        let path = cache_path(&dir, &h5_path, &[2, 0, 1], &[3, 4, 5], 3, "design");
+        assert_eq!(path, cache_path(&dir, &h5_path, &[0, 1, 2], &[5, 4, 3], 3, "design")); // Sample order does not matter
+        assert_ne!(path, cache_path(&dir, &h5_path, &[0, 1, 2], &[3, 4, 6], 3, "design"));
+        assert_ne!(
+            path,
+            cache_path(&dir, &h5_path, &[0, 1, 2], &[3, 4, 5], 3, "other design")
+        );
+        assert!(read_region(&path, &h5_path, "chr1", 0, 100).unwrap().is_none());
+
+        let fit = GenomeFit {
+            s20: 0.3,
+            df0: 4.5,
+            chromosomes: vec![
+                (
+                    "chr1".to_string(),
+                    vec![probe(0, 10), probe(1, 20), probe(2, 30), probe(3, 40)],
+                ),
+                ("chr2".to_string(), vec![probe(4, 15), probe(5, 25)]),
+            ],
+        };
+        write_cache(&path, &h5_path, &fit).unwrap();
+        let region = read_region(&path, &h5_path, "chr1", 20, 35).unwrap().unwrap();
+        assert_eq!(region.probes, vec![probe(1, 20), probe(2, 30)]);
+        assert_eq!(region.total_probes, 6);
+        assert_eq!((region.s20, region.df0), (0.3, 4.5));
+        let region = read_region(&path, &h5_path, "chr2", 0, 1000).unwrap().unwrap();
+        assert_eq!(region.probes, fit.chromosomes[1].1);
+        assert!(
+            read_region(&path, &h5_path, "chr1", 41, 1000)
+                .unwrap()
+                .unwrap()
+                .probes
+                .is_empty()
+        );
+        assert!(
+            read_region(&path, &h5_path, "chrX", 0, 1000)
+                .unwrap()
+                .unwrap()
+                .probes
+                .is_empty()
+        );
+
+        // Rewriting the HDF5 file invalidates the cache
+        std::fs::write(&h5_path, b"a different placeholder").unwrap();
+        assert!(read_region(&path, &h5_path, "chr1", 0, 100).unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cache_file_name_is_stable() {
+        // Test vectors of the 64-bit FNV-1a hash
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+        // The name of a cache does not change between builds
+        assert_eq!(
+            cache_path("/cache", "/nonexistent/beta.h5", &[2, 1, 0], &[3, 4, 5], 3, "design"),
+            "/cache/01c36b306965ee4a.bin"
+        );
+    }
+
+    #[test]
+    fn old_and_oversized_caches_are_evicted() {
+        use std::time::{Duration, SystemTime};
+        let dir = std::env::temp_dir().join(format!("dmrcate_evict_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let now = SystemTime::now();
+        // Cache files (with their preprocessing summary) written hours_ago, 100 bytes each
+        let write = |name: &str, hours_ago: u64| {
+            for file in [name.to_string(), format!("{}.prep.json", name)] {
+                let path = dir.join(file);
+                std::fs::write(&path, [0u8; 50]).unwrap();
+                let file = std::fs::File::options().write(true).open(&path).unwrap();
+                file.set_modified(now - Duration::from_secs(hours_ago * 3600)).unwrap();
+            }
+        };
+        write("new.bin", 0);
+        write("recent.bin", 1);
+        write("older.bin", 2);
+        write("expired.bin", 100);
+        std::fs::write(dir.join("unrelated.txt"), b"not a cache").unwrap();
+        let dir_name = dir.to_str().unwrap();
+        let keep = format!("{}/new.bin", dir_name);
+
+        // The expired cache is removed, and then the oldest caches until 200 bytes are left with the kept one
+        let removed = evict(dir_name, &keep, Duration::from_secs(48 * 3600), 200).unwrap();
+        assert_eq!(removed, 2);
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            vec![
+                "new.bin",
+                "new.bin.prep.json",
+                "recent.bin",
+                "recent.bin.prep.json",
+                "unrelated.txt"
+            ]
+        );
+        // The kept cache stays even when it is over the size limit on its own
+        assert_eq!(evict(dir_name, &keep, Duration::from_secs(48 * 3600), 10).unwrap(), 1);
+        assert!(dir.join("new.bin").exists() && !dir.join("recent.bin").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interval_tree_matches_brute_force() {
+        // Pseudo-random intervals, including nested and zero-length ones
+        let mut state = 12345u64;
+        let mut next = |m: i64| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((state >> 33) % m as u64) as i64
+        };
+        let intervals: Vec<(i64, i64, usize)> = (0..200)
+            .map(|i| {
+                let start = next(10000);
+                (start, start + next(500), i)
+            })
+            .collect();
+        let tree = IntervalTree::new(intervals.clone());
+        for _ in 0..200 {
+            let start = next(10500) - 200;
+            let stop = start + next(300);
+            let mut found: Vec<usize> = tree.query(start, stop).into_iter().copied().collect();
+            let mut expected: Vec<usize> = intervals
+                .iter()
+                .filter(|iv| iv.0 <= stop && iv.1 >= start)
+                .map(|iv| iv.2)
+                .collect();
+            found.sort_unstable();
+            expected.sort_unstable();
+            assert_eq!(found, expected);
+        }
+        assert!(IntervalTree::<()>::new(vec![]).query(0, 10).is_empty());
+    }
+
+    fn gene(name: &str, start: i64, stop: i64, strand: char) -> Gene {
+        Gene {
+            name: name.to_string(),
+            chr: "chr1".to_string(),
+            start,
+            stop,
+            strand,
+        }
+    }
+
+    #[test]
+    fn annotate_dmr() {
+        let genes = vec![
+            gene("A", 10000, 20000, '+'),
+            gene("B", 15000, 30000, '-'),
+            gene("C", 50000, 60000, '+'),
+        ];
+        let islands = vec![("chr1".to_string(), 40000, 41000), ("chr1".to_string(), 70000, 71000)];
+        let annotation = RegionAnnotation::new(genes, Some(islands), 1500, 500);
+
+        // Inside A and B, 6 kb downstream of the TSS of A
+        let mut dmr = json!({"chr": "chr1", "start": 16000, "stop": 16500, "overlapping_genes": null});
+        annotation.annotate(&mut dmr);
+        assert_eq!(dmr["overlapping_genes"], "A, B");
+        assert_eq!(
+            dmr["nearest_genes"],
+            json!([{"gene": "A", "strand": "+", "distance_to_tss": 6000}])
+        );
+        assert_eq!(dmr["promoter_overlap"], false);
+        assert_eq!(dmr["cpg_context"], "open_sea");
+
+        // 1 kb upstream of the TSS of B (on the minus strand) and 3 kb from the first island
+        let mut dmr = json!({"chr": "chr1", "start": 30800, "stop": 31000});
+        annotation.annotate(&mut dmr);
+        assert_eq!(dmr["overlapping_genes"], json!(null));
+        assert_eq!(dmr["nearest_genes"][0]["gene"], "B");
+        assert_eq!(dmr["nearest_genes"][0]["distance_to_tss"], -800);
+        assert_eq!(dmr["promoter_genes"], json!(["B"]));
+        let mut dmr = json!({"chr": "chr1", "start": 44000, "stop": 44100});
+        annotation.annotate(&mut dmr);
+        assert_eq!(dmr["cpg_context"], "shelf");
+        let mut dmr = json!({"chr": "chr1", "start": 68500, "stop": 70000});
+        annotation.annotate(&mut dmr);
+        assert_eq!(dmr["cpg_context"], "island");
+        let mut dmr = json!({"chr": "chr1", "start": 71500, "stop": 71600});
+        annotation.annotate(&mut dmr);
+        assert_eq!(dmr["cpg_context"], "shore");
+
+        // Chromosome without genes or islands
+        let mut dmr = json!({"chr": "chr2", "start": 100, "stop": 200});
+        annotation.annotate(&mut dmr);
+        assert_eq!(dmr["nearest_genes"], json!([]));
+        assert_eq!(dmr["cpg_context"], "open_sea");
+    }
+
+    #[test]
+    fn load_gene_annotation_sources() {
+        let dir = std::env::temp_dir().join(format!("dmr_annotation_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gtf = dir.join("genes.gtf");
+        std::fs::write(
+            &gtf,
+            "#comment\n\
+             chr1\tsrc\tgene\t101\t200\t.\t-\t.\tgene_id \"G1\"; gene_name \"TP53\";\n\
+             chr1\tsrc\texon\t101\t150\t.\t-\t.\tgene_id \"G1\"; gene_name \"TP53\";\n\
+             chr2\tsrc\tgene\t1\t50\t.\t+\t.\tgene_id \"G2\";\n",
+        )
+        .unwrap();
+        let genes = load_gene_file(gtf.to_str().unwrap(), None).unwrap();
+        assert_eq!(genes.len(), 2);
+        assert_eq!(
+            (genes[0].name.as_str(), genes[0].start, genes[0].stop, genes[0].strand),
+            ("TP53", 101, 200, '-')
+        );
+        assert_eq!(genes[1].name, "G2");
+        assert_eq!(load_gene_file(gtf.to_str().unwrap(), Some("chr2")).unwrap().len(), 1);
+
+        // The same gene in a BED file, and a CpG island covering its first base: both give 1-based closed intervals
+        let bed = dir.join("genes.bed");
+        std::fs::write(&bed, "track name=genes\nchr1\t100\t200\tTP53\t0\t-\n").unwrap();
+        let genes = load_gene_file(bed.to_str().unwrap(), None).unwrap();
+        assert_eq!((genes[0].start, genes[0].stop), (101, 200));
+        let islands_bed = dir.join("islands.bed");
+        std::fs::write(&islands_bed, "chr1\t100\t101\nchr2\t0\t10\n").unwrap();
+        let islands = load_islands(islands_bed.to_str().unwrap(), Some("chr1")).unwrap();
+        assert_eq!(islands, vec![("chr1".to_string(), 101, 101)]);
+        let annotation = RegionAnnotation::new(genes, Some(islands), 0, 0);
+        let mut dmr = json!({"chr": "chr1", "start": 90, "stop": 101});
+        annotation.annotate(&mut dmr);
+        assert_eq!(dmr["overlapping_genes"], "TP53");
+        assert_eq!(dmr["cpg_context"], "island");
+        let mut dmr = json!({"chr": "chr1", "start": 90, "stop": 100});
+        annotation.annotate(&mut dmr);
+        assert_eq!(dmr["overlapping_genes"], json!(null));
+        assert_eq!(dmr["cpg_context"], "shore");
+
+        let db = dir.join("genedb.db");
+        let conn = Connection::open(&db).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE genes (name TEXT, isoform TEXT, isdefault INTEGER, genemodel TEXT);
+             INSERT INTO genes VALUES ('TP53', 'NM_000546', 1, '{\"chr\":\"chr17\",\"start\":7668420,\"stop\":7687490,\"strand\":\"-\"}');
+             INSERT INTO genes VALUES ('TP53', 'NM_001126112', 0, '{\"chr\":\"chr17\",\"start\":7668420,\"stop\":7687538,\"strand\":\"-\"}');
+             INSERT INTO genes VALUES ('KRAS', 'NM_004985', 1, '{\"chr\":\"chr12\",\"start\":25205245,\"stop\":25250929,\"strand\":\"-\"}');",
+        )
+        .unwrap();
+        let genes = load_genedb_genes(db.to_str().unwrap(), Some("chr17")).unwrap();
+        assert_eq!(genes.len(), 1);
+        assert_eq!(
+            (genes[0].start, genes[0].stop, genes[0].strand),
+            (7668421, 7687490, '-')
+        );
+        assert_eq!(load_genedb_genes(db.to_str().unwrap(), None).unwrap().len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    const FIT_CUTOFF: f64 = 1e-10;
+
+    fn sample_index() -> HashMap<&'static str, usize> {
+        ["s0", "s1", "s2", "s3", "s4", "s5", "s6"]
+            .iter()
+            .enumerate()
+            .map(|(i, &s)| (s, i))
+            .collect()
+    }
+
+    #[test]
+    fn two_group_and_paired_designs() {
+        let smap = sample_index();
+        // Two groups: the contrast is the difference of the group means with the pooled variance
+        let design = build_design(&json!({}), &smap, &["s0", "s1", "s2"], &["s3", "s4", "s5"]).unwrap();
+        let y = [1.0, 2.0, 4.0, 0.5, 0.0, 1.0];
+        let fit = design.fit(&y).unwrap();
+        assert!((fit.coefficient - (7.0 / 3.0 - 0.5)).abs() < FIT_CUTOFF);
+        let pooled =
+            ((1.0f64 - 7.0 / 3.0).powi(2) + (2.0f64 - 7.0 / 3.0).powi(2) + (4.0f64 - 7.0 / 3.0).powi(2) + 0.5) / 4.0;
+        assert!((fit.residual_var - pooled).abs() < FIT_CUTOFF);
+        assert!((fit.stdev_unscaled - (2.0f64 / 3.0).sqrt()).abs() < FIT_CUTOFF);
+        assert_eq!(fit.df_residual, 4.0);
+        // A missing value drops its sample from the fit
+        let fit = design.fit(&[1.0, 2.0, f64::NAN, 0.5, 0.0, 1.0]).unwrap();
+        assert!((fit.coefficient - 1.0).abs() < FIT_CUTOFF);
+        assert_eq!(fit.df_residual, 3.0);
+
+        // Paired design: same t statistic as the paired t-test on the differences
+        let p = json!({"pairs": {"s0": 1, "s1": 2, "s2": 3, "s3": 1, "s4": 2, "s5": 3}});
+        let design = build_design(&p, &smap, &["s0", "s1", "s2"], &["s3", "s4", "s5"]).unwrap();
+        assert_eq!(design.columns, vec!["Intercept", "group", "pair2", "pair3"]);
+        let fit = design.fit(&y).unwrap();
+        let d = [0.5, 2.0, 3.0];
+        let mean_d = d.iter().sum::<f64>() / 3.0;
+        let var_d = d.iter().map(|x| (x - mean_d).powi(2)).sum::<f64>() / 2.0;
+        let t = fit.coefficient / (fit.residual_var.sqrt() * fit.stdev_unscaled);
+        assert!((fit.coefficient - mean_d).abs() < FIT_CUTOFF);
+        assert!((t - mean_d / (var_d / 3.0).sqrt()).abs() < 1e-9);
+        assert_eq!(fit.df_residual, 2.0);
+    }
+
+    #[test]
+    fn covariates_and_non_estimable_designs() {
+        let smap = sample_index();
+        let (case, control) = (["s0", "s1", "s2"], ["s3", "s4", "s5", "s6"]);
+        let p = json!({"covariates": {"age": {"s0": 30, "s1": 45, "s2": 52, "s3": 38, "s4": 61, "s5": 29, "s6": 44},
+            "batch": {"s0": "b", "s1": "a", "s2": "b", "s3": "a", "s4": "b", "s5": "a", "s6": "a"}}});
+        let design = build_design(&p, &smap, &case, &control).unwrap();
+        assert_eq!(design.columns, vec!["Intercept", "group", "age", "batchb"]);
+        assert_eq!(design.df_residual(), 3);
+        // y depends exactly on the covariates: the group effect is recovered with no residual error left
+        let y: Vec<f64> = (0..7)
+            .map(|i| {
+                let row = design.matrix.row(i);
+                2.0 + 0.7 * row[1] + 0.05 * row[2] - 1.5 * row[3] + [0.1, -0.2, 0.1, 0.0, 0.2, -0.1, -0.1][i]
+            })
+            .collect();
+        assert!(design.fit(&y).is_some());
+
+        // A covariate identical to the group is not estimable
+        let p =
+            json!({"covariates": {"x": {"s0": "t", "s1": "t", "s2": "t", "s3": "n", "s4": "n", "s5": "n", "s6": "n"}}});
+        let error = build_design(&p, &smap, &case, &control).unwrap_err();
+        assert!(error.contains("not of full rank"));
+        // Missing covariate values, explicit designs without a contrast and wrong contrast lengths are rejected
+        let p = json!({"covariates": {"age": {"s0": 30}}});
+        assert!(build_design(&p, &smap, &case, &control).is_err());
+        let p = json!({"design": {"samples": ["s0", "s1", "s2"], "columns": ["a"], "matrix": [[1], [1], [1]]}});
+        assert!(build_design(&p, &smap, &case, &control).is_err());
+        assert!(build_design(&json!({"contrast": [0, 1, 0]}), &smap, &case, &control).is_err());
+
+        // Explicit design with a contrast
+        let p = json!({"design": {"samples": ["s0", "s1", "s2", "s3"], "columns": ["a", "b"],
+            "matrix": [[1, 0], [1, 0], [0, 1], [0, 1]]}, "contrast": [1, -1]});
+        let design = build_design(&p, &smap, &case, &control).unwrap();
+        let fit = design.fit(&[1.0, 3.0, 0.0, 1.0]).unwrap();
+        assert!((fit.coefficient - 1.5).abs() < FIT_CUTOFF);
+        assert!((fit.stdev_unscaled - 1.0).abs() < FIT_CUTOFF);
+    }
+
+    #[test]
+    fn differential_variability() {
+        // Deviations from the median of each group; the missing value stays missing
+        let deviations = absolute_deviations(
+            &[1.0, 5.0, 2.0, f64::NAN, 4.0, 0.0, 2.0, 3.0],
+            &[0, 0, 0, 1, 1, 1, 1, 1],
+        );
+        assert_eq!(&deviations[..3], &[1.0, 3.0, 0.0]);
+        assert!(deviations[3].is_nan());
+        assert_eq!(&deviations[4..], &[1.5, 2.5, 0.5, 0.5]);
+
+        let smap = sample_index();
+        let design = build_design(&json!({}), &smap, &["s0", "s1", "s2"], &["s3", "s4", "s5"])
+            .unwrap()
+            .with_deviation_groups(&[0, 1, 2], &[3, 4, 5])
+            .unwrap();
+        // Case deviations 2, 0, 3 (median 3), control deviations 0.5, 0, 0.5 (median 1)
+        let fit = design.fit(&[1.0, 3.0, 6.0, 0.5, 1.0, 1.5]).unwrap();
+        assert!((fit.coefficient - (5.0 / 3.0 - 1.0 / 3.0)).abs() < FIT_CUTOFF);
+        // Samples that are neither cases nor controls cannot be assigned to a group
+        let design = build_design(&json!({}), &smap, &["s0", "s1", "s2"], &["s3", "s4", "s5"]).unwrap();
+        assert!(design.with_deviation_groups(&[0, 1, 2], &[3, 4]).is_err());
+    }
+
+    // 40 probes 100 bp apart with a strong hypermethylated block at probes 10..20
+    fn chromosome(chr: &str, signal: bool) -> ChromosomeProbes {
+        let probes: Vec<CachedProbe> = (0..40)
+            .map(|i| CachedProbe {
+                row: i,
+                start: 1000 + 100 * i as i64,
+                log_fc: if signal && (10..20).contains(&i) { 2.0 } else { 0.01 },
+                t: if signal && (10..20).contains(&i) {
+                    12.0
+                } else {
+                    0.3 * ((i % 5) as f64 - 2.0)
+                },
+                raw_p: 0.5,
+                adj_p: if signal && (10..20).contains(&i) { 1e-6 } else { 0.9 },
+            })
+            .collect();
+        let stats = GroupStats {
+            mean1: vec![0.2; 40],
+            mean2: (0..40)
+                .map(|i| if signal && (10..20).contains(&i) { 0.7 } else { 0.21 })
+                .collect(),
+            sd1: vec![0.05; 40],
+            sd2: vec![0.05; 40],
+        };
+        ChromosomeProbes::new(chr, &probes, stats, "diffmean")
+    }
+
+    #[test]
+    fn genome_wide_dmrs_and_export() {
+        let params = CallParams {
+            lambda: 1000.0,
+            c_param: 2.0,
+            fdr_cut: 0.05,
+            min_db: 0.05,
+        };
+        let chromosomes = vec![chromosome("chr1", false), chromosome("chr2", true)];
+        let (dmrs, sfdr) = call_dmrs(&chromosomes, &params);
+        assert_eq!(sfdr.len(), 2);
+        assert_eq!(sfdr[1].len(), 40);
+        assert_eq!(dmrs.len(), 1);
+        assert_eq!(dmrs[0]["chr"], "chr2");
+        assert_eq!(dmrs[0]["direction"], "hyper");
+        assert!(dmrs[0]["start"].as_i64().unwrap() <= 2000 && dmrs[0]["stop"].as_i64().unwrap() >= 2900);
+
+        let dir = std::env::temp_dir().join(format!("dmrcate_export_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("result").to_str().unwrap().to_string();
+        let smoothed: Vec<SmoothedFdr> = chromosomes
+            .iter()
+            .zip(&sfdr)
+            .map(|(c, fdr)| SmoothedFdr {
+                chr: &c.chr,
+                positions: &c.pos,
+                fdr,
+            })
+            .collect();
+        let files = export_results(&prefix, &dmrs, &smoothed).unwrap();
+        let bed = std::fs::read_to_string(files["bed"].as_str().unwrap()).unwrap();
+        let fields: Vec<&str> = bed.lines().nth(1).unwrap().split('\t').collect();
+        assert_eq!(fields.len(), 9);
+        assert_eq!((fields[0], fields[3], fields[8]), ("chr2", "DMR1", "215,48,39"));
+        // 1-based closed DMRs are written as 0-based half-open BED intervals
+        assert_eq!(
+            fields[1].parse::<i64>().unwrap(),
+            dmrs[0]["start"].as_i64().unwrap() - 1
+        );
+        assert_eq!(fields[2].parse::<i64>().unwrap(), dmrs[0]["stop"].as_i64().unwrap());
+        let bedgraph = std::fs::read_to_string(files["bedgraph"].as_str().unwrap()).unwrap();
+        assert_eq!(bedgraph.lines().count(), 81);
+        assert!(bedgraph.lines().nth(1).unwrap().starts_with("chr1\t999\t1000\t"));
+        let tsv = std::fs::read_to_string(files["tsv"].as_str().unwrap()).unwrap();
+        let rows: Vec<Vec<&str>> = tsv.lines().map(|l| l.split('\t').collect()).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            (rows[0][0], rows[1][0], rows[1][1], rows[1][9]),
+            ("rank", "1", "chr2", "hyper")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bumphunter_and_combp() {
+        // Null model of the two-group design: the intercept, i.e. the mean of all the samples
+        let smap = sample_index();
+        let design = build_design(&json!({}), &smap, &["s0", "s1", "s2"], &["s3", "s4", "s5"]).unwrap();
+        let null_hat = design.null_hat();
+        assert!(null_hat.iter().all(|&h| (h - 1.0 / 6.0).abs() < FIT_CUTOFF));
+        let weights = design.contrast_weights();
+        assert!((weights[0] - 1.0 / 3.0).abs() < FIT_CUTOFF && (weights[5] + 1.0 / 3.0).abs() < FIT_CUTOFF);
+        assert_eq!(
+            find_bumps(
+                &[0, 100, 200, 300, 5000, 5100],
+                &[0.5, 0.6, -0.7, -0.8, 0.9, 0.9],
+                0.4,
+                1000.0
+            ),
+            vec![(0, 1), (2, 3), (4, 5)]
+        );
+
+        // 8 cases and 8 controls, 30 probes 100 bp apart, cases 2 M-value units higher at probes 10..20
+        let group: Vec<f64> = (0..16).map(|s| if s < 8 { 1.0 } else { 0.0 }).collect();
+        let matrix = DMatrix::from_fn(16, 2, |s, j| if j == 0 { 1.0 } else { group[s] });
+        let design = Design::new(
+            (0..16).collect(),
+            vec!["Intercept".to_string(), "group".to_string()],
+            matrix,
+            DVector::from_vec(vec![0.0, 1.0]),
+        )
+        .unwrap();
+        let responses: Vec<Vec<f64>> = (0..30)
+            .map(|i| {
+                (0..16)
+                    .map(|s| {
+                        let noise = ((i * 31 + s * 17) % 11) as f64 / 11.0 - 0.5;
+                        noise + if s < 8 && (10..20).contains(&i) { 2.0 } else { 0.0 }
+                    })
+                    .collect()
+            })
+            .collect();
+        let probes: Vec<CachedProbe> = responses
+            .iter()
+            .enumerate()
+            .map(|(i, y)| {
+                let fit = design.fit(y).unwrap();
+                CachedProbe {
+                    row: i as u64,
+                    start: 1000 + 100 * i as i64,
+                    log_fc: fit.coefficient,
+                    t: fit.coefficient / (fit.residual_var.sqrt() * fit.stdev_unscaled),
+                    raw_p: if (10..20).contains(&i) {
+                        1e-5
+                    } else {
+                        ((i * 7) % 10) as f64 / 10.0 + 0.05
+                    },
+                    adj_p: if (10..20).contains(&i) { 1e-3 } else { 0.8 },
+                }
+            })
+            .collect();
+        let stats = GroupStats {
+            mean1: vec![0.3; 30],
+            mean2: (0..30).map(|i| if (10..20).contains(&i) { 0.6 } else { 0.3 }).collect(),
+            sd1: vec![0.05; 30],
+            sd2: vec![0.05; 30],
+        };
+        let c = ChromosomeProbes::new("chr1", &probes, stats, "diffmean");
+
+        let params = BumpParams {
+            lambda: 1000.0,
+            c_param: 2.0,
+            cutoff: None,
+            num_permutations: 200,
+            seed: 7,
+        };
+        let bumps = bumphunter(&c, &responses, &design, &params);
+        assert_eq!(bumps.len(), 1);
+        let bump = &bumps[0];
+        assert_eq!(bump["direction"], "hyper");
+        assert!(bump["start"].as_i64().unwrap() <= 2000 && bump["stop"].as_i64().unwrap() >= 2900);
+        // No relabeling of the samples reaches the observed area
+        assert!((bump["fwer"].as_f64().unwrap() - 1.0 / 201.0).abs() < 1e-12);
+        // None of the null bumps reaches it either; with a single bump the BH adjustment leaves p_value unchanged
+        let p_value = bump["p_value"].as_f64().unwrap();
+        assert!(p_value < 0.05 && bump["min_smoothed_fdr"] == bump["p_value"]);
+        assert!((bump["maxdiff"].as_f64().unwrap() - 0.3).abs() < 1e-12);
+        // Seeded permutations are reproducible
+        assert_eq!(bumphunter(&c, &responses, &design, &params), bumps);
+
+        let (regions, q) = combp(std::slice::from_ref(&c), 1000.0, 50.0, 0.05);
+        assert_eq!(q[0].len(), 30);
+        assert_eq!(regions.len(), 1);
+        let region = &regions[0];
+        assert_eq!(region["direction"], "hyper");
+        assert!(region["start"].as_i64().unwrap() <= 2000 && region["stop"].as_i64().unwrap() >= 2900);
+        assert!(region["min_smoothed_fdr"].as_f64().unwrap() < 0.05);
+        let (p, p_sidak) = (region["p_value"].as_f64().unwrap(), region["p_sidak"].as_f64().unwrap());
+        assert!(p < 1e-3 && p_sidak >= p && p_sidak <= 1.0);
+    }
+
+    #[test]
+    fn preprocessing_filters_and_qc() {
+        // Probe lists from arrays or files, sex chromosomes by name
+        let dir = std::env::temp_dir().join(format!("dmrcate_preprocess_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let snp_file = dir.join("snp.txt");
+        std::fs::write(&snp_file, "cg3\trs123\ncg2\n").unwrap();
+        let p = json!({"cross_reactive_probes": ["cg2", "cg4"], "snp_probes": snp_file.to_str().unwrap(),
+            "remove_sex_chromosomes": true});
+        let options = PreprocessOptions::from_input(&p).unwrap();
+        assert!(options.needs_probe_ids() && !options.bmiq);
+        let ids: Vec<String> = (1..=5).map(|i| format!("cg{}", i)).collect();
+        let chr_names = vec!["chr1".to_string(), "chrX".to_string()];
+        let (excluded, removed) = options.excluded_rows(&chr_names, &[3, 2], Some(&ids));
+        assert_eq!(excluded, vec![false, true, true, true, true]);
+        let expected = RemovedProbes {
+            cross_reactive: 1,
+            snp: 1,
+            sex_chromosomes: 2,
+        };
+        assert_eq!(removed, expected);
+        assert_ne!(options.key(), PreprocessOptions::from_input(&json!({})).unwrap().key());
+        assert!(PreprocessOptions::from_input(&json!({"normalization": "swan"})).is_err());
+        let bmiq = PreprocessOptions::from_input(&json!({"normalization": "bmiq"})).unwrap();
+        assert!(bmiq.bmiq && bmiq.key() != PreprocessOptions::from_input(&json!({})).unwrap().key());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // QC of the samples of columns 0 and 2
+        let mut summary = SampleSummary::new(&[0, 2], false, 0.01);
+        for i in 0..2000 {
+            let v = (i as f32 + 0.5) / 2000.0;
+            let first = if i == 0 { f32::NAN } else { v };
+            let detection = [0.0, 0.0, if i % 10 == 0 { 0.05 } else { 0.001 }];
+            summary.add_row(&[first, 0.9, v], Some(&detection), None);
+        }
+        let (qc, fits) = summary.finish();
+        assert!(fits.is_none());
+        assert_eq!((qc[0].column, qc[1].column), (0, 2));
+        assert!((qc[0].median_beta.unwrap() - 0.5).abs() < 2e-3);
+        assert!((qc[0].detection_failed_fraction - 1.0 / 2000.0).abs() < 1e-12);
+        assert!((qc[1].detection_failed_fraction - 0.1).abs() < 1e-12);
+    }
+
+    /// Evenly spaced quantiles of the states of a beta mixture: (number of values, a, b) of each state
+    fn beta_mixture_values(states: &[(usize, f64, f64)]) -> Vec<f64> {
+        states
+            .iter()
+            .flat_map(|&(n, a, b)| {
+                (0..n).map(move |i| r_mathlib::beta_quantile((i as f64 + 0.5) / n as f64, a, b, true, false))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bmiq_type_correction() {
+        let median = |a: f64, b: f64| r_mathlib::beta_quantile(0.5, a, b, true, false);
+        // Weighted beta fit
+        let xs = beta_mixture_values(&[(2000, 2.0, 5.0)]);
+        let fit = fit_beta(&xs, &vec![1.0; xs.len()]).unwrap();
+        assert!((fit.a - 2.0).abs() < 0.05 && (fit.b - 5.0).abs() < 0.1, "{:?}", fit);
+        assert!(fit_beta(&[0.5, 0.5], &[1.0, 1.0]).is_none());
+
+        // Type II states are compressed towards 0.5, and the proportions of the states differ between the types, so
+        // a quantile mapping of the whole distributions would move type II values between states
+        let type1 = beta_mixture_values(&[(4000, 2.0, 30.0), (2000, 10.0, 10.0), (4000, 30.0, 2.0)]);
+        let type2 = beta_mixture_values(&[(3000, 4.0, 20.0), (3000, 10.0, 10.0), (4000, 20.0, 4.0)]);
+        let mut summary = SampleSummary::new(&[0, 2], true, 0.01);
+        for (probe_type, values) in [(1u8, &type1), (2, &type2)] {
+            for &v in values {
+                summary.add_row(&[v as f32, 0.5, v as f32], None, Some(probe_type));
+            }
+        }
+        let mut hist = vec![0u64; 2000];
+        type2.iter().for_each(|&v| hist[(v * 2000.0) as usize] += 1);
+        let mixture = fit_mixture(&hist, [0.35, 0.65]).unwrap();
+        let means: Vec<f64> = mixture.states.iter().map(|s| s.a / (s.a + s.b)).collect();
+        for (k, expected) in [(0, 4.0 / 24.0), (1, 0.5), (2, 20.0 / 24.0)] {
+            assert!((means[k] - expected).abs() < 0.02, "{:?}", mixture);
+        }
+        for (k, expected) in [(0, 0.3), (1, 0.3), (2, 0.4)] {
+            assert!((mixture.proportions[k] - expected).abs() < 0.05, "{:?}", mixture);
+        }
+
+        let fits = summary.finish().1.unwrap();
+        assert_eq!(fits.len(), 2);
+        let fit: BmiqFit = fits[&0].clone();
+        // The unmethylated and methylated type II values are matched to the same quantile of the type I state
+        for ((a2, b2), (a1, b1)) in [((4.0, 20.0), (2.0, 30.0)), ((20.0, 4.0), (30.0, 2.0))] {
+            let corrected = fit.apply(median(a2, b2));
+            assert!(
+                (corrected - median(a1, b1)).abs() < 0.01,
+                "{} -> {}",
+                median(a2, b2),
+                corrected
+            );
+        }
+        // The hemimethylated values stay between them and the correction keeps the order of the values
+        let corrected: Vec<f64> = (1..100).map(|i| fit.apply(i as f64 / 100.0)).collect();
+        assert!(corrected.windows(2).all(|w| w[0] < w[1]), "{:?}", corrected);
+        assert!((fit.apply(0.5) - 0.5).abs() < 0.05);
+
+        // Only the type II rows of the corrected samples change
+        let normalization = TypeNormalization {
+            types: vec![1, 2],
+            fits,
+        };
+        let mut row = [0.1f32, 0.1, 0.1];
+        normalization.apply(0, &mut row);
+        assert_eq!(row, [0.1, 0.1, 0.1]);
+        normalization.apply(1, &mut row);
+        let expected = fit.apply(0.1) as f32;
+        assert!(row[0] == expected && row[1] == 0.1 && row[2] == expected && expected < 0.08);
+    }
+
+    #[test]
+    fn region_row_summaries() {
+        // Rows of a region read at once feed both the group summaries and the model responses
+        let smap = sample_index();
+        let design = build_design(&json!({}), &smap, &["s0", "s1", "s2"], &["s3", "s4", "s5"]).unwrap();
+        let rows = vec![
+            Some(vec![0.5f32, 0.6, 0.7, 0.2, 0.1, f32::NAN, 0.9]),
+            Some(vec![0.5f32; 7]),
+        ];
+        let mut stats = GroupStats::missing(2);
+        stats.set(0, rows[0].as_ref().unwrap(), &[0, 1, 2], &[3, 4, 5]);
+        assert!((stats.mean2[0] - 0.6).abs() < 1e-6 && (stats.mean1[0] - 0.15).abs() < 1e-6);
+        assert!((stats.sd2[0] - 0.1).abs() < 1e-6);
+        assert!(stats.mean1[1].is_nan());
+        let y = responses(&rows, &design).unwrap();
+        assert_eq!(y[0].len(), 6);
+        assert!((y[0][0] - 0.0).abs() < 1e-12 && y[0][5].is_nan());
+        assert!((y[0][3] - (0.2f64 / 0.8).log2()).abs() < 1e-6);
+        assert!(responses(&[rows[0].clone(), None], &design).is_err());
+    }
+
+    #[test]
+    fn region_tracks() {
+        assert!(requested_tracks(&json!(["means", "volcano"])).is_err());
+        let names = requested_tracks(&json!(["dmrs", "smoothed_fdr", "heatmap", "means"])).unwrap();
+        let positions = [1000i64, 1100, 1200, 1300];
+        let rows = vec![
+            Some(vec![0.1f32, 0.2, 0.9, 0.8]),
+            Some(vec![0.1f32, 0.2, 0.9, f32::NAN]),
+            None,
+            Some(vec![0.5f32; 4]),
+        ];
+        let loess = Some((vec![0.2, 0.3], vec![0.1, 0.2], vec![0.3, 0.4]));
+        let data = RegionTracks {
+            positions: &positions,
+            mean_group1: &[0.15, 0.15, f64::NAN, 0.5],
+            mean_group2: &[0.85, 0.9, f64::NAN, 0.5],
+            fdr: &[1e-4, 1e-3, 1.0, 0.5],
+            smoothed_fdr: &[1e-6, 1e-5, 0.2, 0.6],
+            dmrs: &[json!({"start": 1000, "stop": 1100, "direction": "hyper"})],
+            loess: [&loess, &None],
+            eval_positions: &[1000.0, 1300.0],
+            rows: &rows,
+            controls: &[0, 1],
+            cases: &[2, 3],
+        };
+        let colors = HashMap::from([("hyper".to_string(), "#ff0000".to_string())]);
+        let options = TrackOptions {
+            start: 1000.0,
+            stop: 1400.0,
+            width: 400,
+            track_height: 60,
+            device_pixel_ratio: 2.0,
+            fdr_cutoff: 0.05,
+            max_loess_region: 50000.0,
+            colors: &colors,
+            group_names: ["Normal", "Tumor"],
+            variability: false,
+        };
+        let tracks = render_tracks(&names, &data, &options).unwrap();
+        assert_eq!(tracks.len(), 4);
+        assert_eq!(tracks[0]["name"], "dmrs");
+        for track in &tracks {
+            // Legend row above the 60 px plot area, the PNG at twice the size
+            assert_eq!(track["height"], 78.0);
+            assert!(track["png"].as_str().unwrap().starts_with("data:image/png;base64,"));
+            assert!(track["svg"].as_str().unwrap().starts_with("<svg"));
+            assert!(!track["legend"].as_array().unwrap().is_empty());
+        }
+        // DMR track: one DMR rectangle over the first quarter of the region, in the hyper color, and two swatches
+        assert_eq!(
+            tracks[0]["legend"][0],
+            json!({"label": "Hypermethylated", "color": "#ff0000"})
+        );
+        assert_eq!(tracks[0]["svg"].as_str().unwrap().matches("<rect").count(), 3);
+        assert!(
+            tracks[0]["svg"]
+                .as_str()
+                .unwrap()
+                .contains(r#"<rect x="0.00" y="30.00" width="100.00" height="36.00""#)
+        );
+        // Heatmap: one cell per finite beta value (11) and 3 legend swatches, labelled groups
+        let heatmap = tracks[2]["svg"].as_str().unwrap();
+        assert_eq!(heatmap.matches("<rect").count(), 14);
+        assert!(heatmap.contains(">Normal</text>") && heatmap.contains(">Tumor</text>"));
+        assert_eq!(beta_color(0.0), [33, 102, 172, 255]);
+        assert_eq!(beta_color(1.0), [178, 24, 43, 255]);
+        // Means: 6 finite group means, 2 CI bands and the fitted curve of group 1, and the labels of the legend
+        let means = tracks[3]["svg"].as_str().unwrap();
+        assert_eq!(means.matches("<circle").count(), 6);
+        assert_eq!(means.matches("<polyline").count(), 3);
+        let labels: Vec<&str> = tracks[3]["labels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|l| l["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(labels, ["Normal", "Tumor"]);
+    }
+
+    #[test]
+    fn region_matrix_for_clustering() {
+        let dir = std::env::temp_dir().join(format!("dmrcate_aggregate_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let bed = dir.join("promoters.bed");
+        std::fs::write(&bed, "track name=promoters\nchr1\t100\t300\tGENE1\nchr2\t50\t80\n").unwrap();
+        let regions = read_regions(&json!({"regions_file": bed.to_str().unwrap()})).unwrap();
+        assert_eq!(regions.len(), 2);
+        // BED starts are 0-based, regions are 1-based closed like the probe positions
+        assert_eq!((regions[0].start, regions[0].stop), (101, 300));
+        assert_eq!(
+            (regions[0].name.as_str(), regions[1].name.as_str()),
+            ("GENE1", "chr2:51-80")
+        );
+        // DMRs of a previous call, extra fields are ignored
+        let regions = read_regions(
+            &json!({"regions": [{"chr": "chr1", "start": 100, "stop": 300, "no_cpgs": 3},
+            {"chr": "chr1", "start": 500, "stop": 600}, {"chr": "chr3", "start": 1, "stop": 2, "name": "a,b"}]}),
+        )
+        .unwrap();
+        assert_eq!(regions[2].name, "a;b");
+        assert!(read_regions(&json!({"regions": [{"chr": "chr1"}]})).is_err());
+        assert!(read_regions(&json!({})).is_err());
+        assert_eq!(Aggregate::from_input(None), Ok(Aggregate::Mean));
+        assert!(Aggregate::from_input(Some("max")).is_err());
+
+        let rows = vec![
+            Some(vec![0.1f32, 0.9, 0.5, f32::NAN]),
+            Some(vec![0.3f32, 0.7, f32::NAN, f32::NAN]),
+            None,
+            Some(vec![0.8f32, 0.8, 0.2, f32::NAN]),
+        ];
+        let mean = aggregate_rows(&rows, &[0, 1, 2, 3], Aggregate::Mean);
+        assert!((mean[0] - 0.4).abs() < 1e-6 && (mean[1] - 0.8).abs() < 1e-6 && (mean[2] - 0.35).abs() < 1e-6);
+        assert!(mean[3].is_nan());
+        let median = aggregate_rows(&rows, &[0, 2], Aggregate::Median);
+        assert!((median[0] - 0.3).abs() < 1e-6 && (median[1] - 0.35).abs() < 1e-6);
+
+        // The second region has no probes and the third no value in sample s2, so only the first is clustered
+        let values = vec![vec![0.12344, 0.5], vec![f64::NAN, f64::NAN], vec![0.2, f64::NAN]];
+        let out = region_matrix(
+            &regions,
+            &values,
+            &[3, 0, 2],
+            &["s1", "s2"],
+            Some(&["Control".to_string(), "Case".to_string()]),
+            Aggregate::Mean,
+        );
+        assert_eq!(out["matrix"], json!([[0.1234, 0.5]]));
+        assert_eq!(out["row_names"], "chr1:100-300");
+        assert_eq!(out["col_names"], "s1,s2");
+        assert_eq!(
+            out["col_annotations"],
+            json!([{"name": "Group", "values": ["Control", "Case"]}])
+        );
+        assert_eq!(out["dropped_regions"].as_array().unwrap().len(), 2);
+        assert_eq!(out["regions"][0]["no_cpgs"], 3);
+        // Read back the way the cluster binary reads its input
+        let parsed = json::parse(&out.to_string()).unwrap();
+        assert_eq!(parsed["matrix"][0][1].as_f32(), Some(0.5));
+        assert_eq!(parsed["col_names"].as_str().unwrap().split(',').count(), 2);
+        // Without groups there is no column annotation
+        let out = region_matrix(&regions, &values, &[3, 0, 2], &["s1", "s2"], None, Aggregate::Mean);
+        assert!(out["col_annotations"].is_null());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn regions_need_no_comparison() {
+        // All the samples by default, a subset with "samples", or the controls then the cases labeled by group
+        let names: Vec<String> = ["s1", "s2", "s3", "s4"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(select_samples(&json!({}), &names).unwrap(), (vec![0, 1, 2, 3], None));
+        assert_eq!(
+            select_samples(&json!({"samples": "s4,s2,unknown"}), &names).unwrap(),
+            (vec![3, 1], None)
+        );
+        let (columns, groups) = select_samples(&json!({"case": "s1", "control": "s3,s2"}), &names).unwrap();
+        assert_eq!(columns, vec![2, 1, 0]);
+        assert_eq!(groups.unwrap(), ["Control", "Control", "Case"]);
+        assert!(select_samples(&json!({"samples": "s1", "case": "s2"}), &names).is_err());
+        assert!(select_samples(&json!({"samples": "unknown"}), &names).is_err());
+
+        // Probes by position, whatever their order in the rows of a chromosome; region ends are included
+        let index = ProbeIndex::new(
+            &["chr1".to_string(), "chr2".to_string()],
+            &[4, 2],
+            &[300, 100, 200, 500, 100, 150],
+        );
+        let regions = read_regions(&json!({"regions": [{"chr": "chr1", "start": 100, "stop": 300},
+            {"chr": "chr2", "start": 101, "stop": 150}, {"chr": "chr1", "start": 301, "stop": 499},
+            {"chr": "chr3", "start": 1, "stop": 1000}]}))
+        .unwrap();
+        let rows: Vec<Vec<u64>> = regions.iter().map(|r| index.rows(r)).collect();
+        assert_eq!(rows, vec![vec![1, 2, 0], vec![5], vec![], vec![]]);
+    }
+}
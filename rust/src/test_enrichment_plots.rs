@@ -0,0 +1,145 @@
+// For capturing output from a test, run: cd .. && cargo test -- --nocapture
+#[allow(dead_code)]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::enrichment_plots::{
+        Anchor, DotPlotTerm, EnrichmentCurve, PlotOptions, Reference, Scene, evidence_curve, interpolate_color,
+        nice_ticks, render_dot_plot, render_enrichment_curve,
+    };
+    use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+    use serde_json::Value;
+    use tiny_skia::Pixmap;
+
+    fn assert_ticks(ticks: Vec<f64>, expected: &[f64]) {
+        assert_eq!(ticks.len(), expected.len(), "ticks {:?}", ticks);
+        for (tick, e) in ticks.iter().zip(expected) {
+            assert!((tick - e).abs() < 1e-9, "ticks {:?}", ticks);
+        }
+    }
+
+    // Decodes the PNG data URL of a rendered plot and checks its size and the SVG of the plot
+    fn check_rendered(plot: &Value, width: u32, height: u32, device_pixel_ratio: f32) {
+        let png = plot["png"].as_str().unwrap();
+        let bytes = BASE64
+            .decode(png.strip_prefix("data:image/png;base64,").unwrap())
+            .unwrap();
+        let pixmap = Pixmap::decode_png(&bytes).unwrap();
+        assert_eq!(pixmap.width(), (width as f32 * device_pixel_ratio) as u32);
+        assert_eq!(pixmap.height(), (height as f32 * device_pixel_ratio) as u32);
+        // Something else than the white background is painted
+        assert!(
+            pixmap
+                .pixels()
+                .iter()
+                .any(|p| p.red() != 255 || p.green() != 255 || p.blue() != 255)
+        );
+        let svg = plot["svg"].as_str().unwrap();
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>"));
+        assert!(svg.contains(&format!(r#"width="{}" height="{}""#, width, height)));
+        assert_eq!(plot["width"], width as f64);
+        assert_eq!(plot["height"], height as f64);
+    }
+
+    #[test]
+    fn evidence_curve_vertices() {
+        // Genes of the set at ranks 0 and 3 of 6 genes
+        assert_eq!(
+            evidence_curve(&[0, 3], 6),
+            vec![(0, 0.0), (1, 0.5), (3, 0.5), (4, 1.0), (6, 1.0)]
+        );
+        // No final vertex is added when the last gene of the list is in the set
+        assert_eq!(
+            evidence_curve(&[1, 3], 4),
+            vec![(0, 0.0), (1, 0.0), (2, 0.5), (3, 0.5), (4, 1.0)]
+        );
+    }
+
+    #[test]
+    fn nice_ticks_are_round_values() {
+        assert_ticks(nice_ticks(0.0, 1.0, 5), &[0.0, 0.2, 0.4, 0.6, 0.8, 1.0]);
+        assert_ticks(nice_ticks(0.0, 1000.0, 5), &[0.0, 200.0, 400.0, 600.0, 800.0, 1000.0]);
+        // Ticks stay within the range
+        assert_ticks(nice_ticks(-0.35, 0.8, 4), &[0.0, 0.5]);
+        assert_ticks(nice_ticks(-0.05, 0.33, 5), &[0.0, 0.1, 0.2, 0.3]);
+        // Empty range
+        assert_eq!(nice_ticks(3.0, 3.0, 5), vec![3.0]);
+        assert_eq!(nice_ticks(3.0, 1.0, 5), vec![3.0]);
+    }
+
+    #[test]
+    fn colors_are_interpolated_and_clamped() {
+        let (low, high) = ([255, 0, 0, 255], [0, 0, 255, 255]);
+        assert_eq!(interpolate_color(low, high, 0.0), low);
+        assert_eq!(interpolate_color(low, high, 1.0), high);
+        assert_eq!(interpolate_color(low, high, 0.5), [128, 0, 128, 255]);
+        assert_eq!(interpolate_color(low, high, 0.25), [191, 0, 64, 255]);
+        assert_eq!(interpolate_color(low, high, -1.0), low);
+        assert_eq!(interpolate_color(low, high, 2.0), high);
+    }
+
+    #[test]
+    fn scene_renders_png_svg_and_labels() {
+        let mut scene = Scene::new(120.0, 80.0);
+        scene.line(vec![(10.0, 10.0), (110.0, 70.0)], [0, 0, 0, 255], 2.0);
+        scene.dashed_line(vec![(10.0, 70.0), (110.0, 10.0)], [255, 0, 0, 255], 1.0);
+        scene.text(60.0, 40.0, "a & b".to_string(), Anchor::Middle);
+        let plot = scene.render(2.0).unwrap();
+        check_rendered(&plot, 120, 80, 2.0);
+        assert_eq!(plot["devicePixelRatio"], 2.0);
+        // Text is not painted in the PNG but returned as labels, and escaped in the SVG
+        let labels = plot["labels"].as_array().unwrap();
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels[0]["text"], "a & b");
+        assert_eq!(labels[0]["anchor"], "middle");
+        assert!(plot["svg"].as_str().unwrap().contains("a &amp; b"));
+    }
+
+    #[test]
+    fn enrichment_curve_and_dot_plot_render() {
+        let hits = [1, 4, 10];
+        let curve = evidence_curve(&hits, 50);
+        let plot = EnrichmentCurve {
+            title: "GENE_SET",
+            y_label: "Fraction of gene set",
+            curve: &curve,
+            hits: &hits,
+            num_genes: 50,
+            reference: Reference::Diagonal,
+        };
+        let options = PlotOptions::from_json(
+            &json::parse(r#"{"image_width": 300, "image_height": 200}"#).unwrap(),
+            500,
+            350,
+        );
+        let rendered = render_enrichment_curve(&plot, &options).unwrap();
+        check_rendered(&rendered, 300, 200, 1.0);
+        let labels = rendered["labels"].as_array().unwrap();
+        assert!(labels.iter().any(|label| label["text"] == "GENE_SET"));
+
+        let empty = EnrichmentCurve { num_genes: 0, ..plot };
+        assert!(render_enrichment_curve(&empty, &options).is_err());
+
+        let terms = vec![
+            DotPlotTerm {
+                name: "term A".to_string(),
+                gene_ratio: 0.4,
+                count: 8,
+                fdr: 0.001,
+            },
+            DotPlotTerm {
+                name: "term B".to_string(),
+                gene_ratio: 0.1,
+                count: 2,
+                fdr: 0.04,
+            },
+        ];
+        let options = PlotOptions::from_json(&json::parse("{}").unwrap(), 500, 350);
+        let rendered = render_dot_plot(&terms, &options).unwrap();
+        check_rendered(&rendered, 500, 350, 1.0);
+        let labels = rendered["labels"].as_array().unwrap();
+        assert!(labels.iter().any(|label| label["text"] == "term A"));
+        assert!(render_dot_plot(&[], &options).is_err());
+    }
+}
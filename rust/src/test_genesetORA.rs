@@ -0,0 +1,131 @@
+// For capturing output from a test, run: cd .. && cargo test -- --nocapture
+#[allow(dead_code)]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::geneset_functions::{GeneSet, SizeFilter};
+    use crate::{
+        adjust_p_values, query_and_universe, restrict_query_to_universe, restrict_to_universe, test_gene_sets,
+    };
+    use std::collections::HashSet;
+
+    const P_VALUE_CUTOFF: f64 = 1e-10; // Threshold difference between calculated and expected p-value for the test to pass
+
+    fn genes(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|g| g.to_string()).collect()
+    }
+
+    fn numbered(prefix: &str, from: usize, to: usize) -> Vec<String> {
+        (from..=to).map(|i| format!("{}{}", prefix, i)).collect()
+    }
+
+    // Expected values are those of clusterProfiler::enricher(gene, universe = universe, TERM2GENE = term2gene,
+    // minGSSize = 1, pvalueCutoff = 1, qvalueCutoff = 1) for the same input: p-values are
+    // phyper(k - 1, M, N - M, n, lower.tail = FALSE) with M and n counted within the universe (enricher_internal),
+    // and p.adjust = p.adjust(pvalue, method = "BH") over the gene sets with at least one hit.
+    #[test]
+    fn universe_restricts_query_and_gene_sets() {
+        let universe: HashSet<String> = numbered("G", 1, 30).into_iter().collect();
+        let mut sample_genes: HashSet<String> = numbered("G", 1, 6).into_iter().collect();
+        sample_genes.extend(genes(&["G21", "G30", "X1", "X2"])); // X1 and X2 are not in the universe
+        restrict_query_to_universe(&mut sample_genes, &universe);
+        assert_eq!(sample_genes.len(), 8);
+
+        let mut sa: HashSet<String> = numbered("G", 1, 8).into_iter().collect();
+        sa.extend(genes(&["Y1", "Y2"])); // Y1 and Y2 are not in the universe
+        let gene_sets = vec![
+            GeneSet {
+                id: "SA".to_string(),
+                genes: sa,
+            },
+            GeneSet {
+                id: "SB".to_string(),
+                genes: numbered("G", 5, 16).into_iter().collect(),
+            },
+            GeneSet {
+                id: "SC".to_string(),
+                genes: numbered("G", 20, 24).into_iter().collect(),
+            },
+            GeneSet {
+                id: "SD".to_string(),
+                genes: genes(&["G28", "G29", "G30", "Z1"]),
+            },
+            GeneSet {
+                id: "SE".to_string(), // No hit, not tested
+                genes: numbered("G", 25, 27).into_iter().collect(),
+            },
+        ];
+
+        let (tested, sizes) = restrict_to_universe(&gene_sets, &universe, &SizeFilter::default());
+        assert_eq!(sizes, vec![10, 12, 5, 4, 3]);
+        let effective_sizes: Vec<usize> = tested.iter().map(|gs| gs.genes.len()).collect();
+        assert_eq!(effective_sizes, vec![8, 12, 5, 3, 3]);
+
+        let results = adjust_p_values(test_gene_sets(&sample_genes, universe.len(), &tested, &sizes, None));
+        // (name, effective size, hits, pvalue, p.adjust)
+        let expected = [
+            ("SA", 8, 6, 0.0011353297710119299, 0.0045413190840477196),
+            ("SD", 3, 1, 0.6206896551724138, 0.9272763618190905),
+            ("SC", 5, 1, 0.8152077807250221, 0.9272763618190905),
+            ("SB", 12, 2, 0.9272763618190905, 0.9272763618190905),
+        ];
+        assert_eq!(results.len(), expected.len());
+        for (name, effective_size, hits, p_value, adjusted_p_value) in expected {
+            let result = results.iter().find(|r| r.pathway_name == name).unwrap();
+            assert_eq!(result.effective_gene_set_size, effective_size);
+            assert_eq!(result.gene_set_hits.split(",").count(), hits);
+            assert!(
+                (result.p_value_original - p_value).abs() < P_VALUE_CUTOFF,
+                "p-value of {}: {} != {}",
+                name,
+                result.p_value_original,
+                p_value
+            );
+            assert!(
+                (result.p_value_adjusted.unwrap() - adjusted_p_value).abs() < P_VALUE_CUTOFF,
+                "adjusted p-value of {}: {} != {}",
+                name,
+                result.p_value_adjusted.unwrap(),
+                adjusted_p_value
+            );
+        }
+    }
+
+    #[test]
+    fn non_coding_query_genes_stay_in_the_default_universe() {
+        let coding = || genes(&["TP53", "KRAS", "EGFR"]);
+        let query = ["TP53", "MALAT1", "KRAS"];
+        // Without the filter and without background genes, the non-coding query gene is added to the universe
+        let (query_genes, universe) = query_and_universe(&query, coding(), None, false).unwrap();
+        assert_eq!(query_genes, genes(&["TP53", "MALAT1", "KRAS"]));
+        assert_eq!(universe, genes(&["TP53", "KRAS", "EGFR", "MALAT1"]));
+        // With the filter it is removed from the query and the universe is the coding genes
+        let (query_genes, universe) = query_and_universe(&query, coding(), None, true).unwrap();
+        assert_eq!(query_genes, genes(&["TP53", "KRAS"]));
+        assert_eq!(universe, coding());
+        // Given background genes are the universe either way
+        let (query_genes, universe) = query_and_universe(&query, coding(), Some("MALAT1,EGFR,TP53"), false).unwrap();
+        assert_eq!(query_genes, genes(&["TP53", "MALAT1"]));
+        assert_eq!(universe.len(), 3);
+        assert!(query_and_universe(&["MALAT1"], coding(), None, true).is_err());
+        assert!(query_and_universe(&["MALAT1"], coding(), Some("EGFR"), false).is_err());
+    }
+
+    #[test]
+    fn size_filter_uses_universe_size() {
+        let universe: HashSet<String> = numbered("G", 1, 10).into_iter().collect();
+        let mut large: HashSet<String> = numbered("G", 1, 3).into_iter().collect();
+        large.extend(numbered("X", 1, 20)); // 23 genes, only 3 of them in the universe
+        let gene_sets = vec![GeneSet {
+            id: "LARGE".to_string(),
+            genes: large,
+        }];
+        let size_filter = SizeFilter {
+            min_size: Some(5),
+            max_size: None,
+        };
+        let (tested, _) = restrict_to_universe(&gene_sets, &universe, &size_filter);
+        assert!(tested.is_empty());
+    }
+}
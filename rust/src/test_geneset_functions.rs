@@ -0,0 +1,166 @@
+// For capturing output from a test, run: cd .. && cargo test -- --nocapture
+#[allow(dead_code)]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::geneset_functions::{
+        RedundancyOptions, SimilarityMetric, SizeFilter, cluster_redundant_terms, filter_query_genes, load_gene_sets,
+        normalize_gene_sets, parse_gmt, term_similarity,
+    };
+    use rusqlite::Connection;
+    use std::collections::HashSet;
+
+    // Creates an in-memory gene set database with the same tables as msigdb
+    fn test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "create table terms (id text, parent_id text);
+             create table term2genes (id text, genes text);",
+        )
+        .unwrap();
+        let terms = [
+            ("GO:0001", "BP: subset of GO"),
+            ("Fanconi's anemia pathway", "BP: subset of GO"),
+            ("GO:0002", "Hallmark's genes"),
+        ];
+        for (id, parent_id) in terms {
+            conn.execute("insert into terms (id, parent_id) values (?1, ?2)", [id, parent_id])
+                .unwrap();
+        }
+        let term2genes = [
+            ("GO:0001", r#"[{"symbol":"TP53"},{"symbol":"KRAS"}]"#),
+            ("Fanconi's anemia pathway", r#"[{"symbol":"FANCA"},{"symbol":"BRCA2"}]"#),
+            ("Fanconi's anemia pathway", r#"[{"symbol":"FANCD2"}]"#),
+            ("GO:0002", r#"[{"symbol":"MYC"}]"#),
+        ];
+        for (id, genes) in term2genes {
+            conn.execute("insert into term2genes (id, genes) values (?1, ?2)", [id, genes])
+                .unwrap();
+        }
+        conn
+    }
+
+    fn symbols(genes: &[&str]) -> HashSet<String> {
+        genes.iter().map(|g| g.to_string()).collect()
+    }
+
+    #[test]
+    fn term_id_with_quote() {
+        let conn = test_db();
+        let gene_sets = load_gene_sets(&conn, "BP: subset of GO").unwrap();
+        // Gene sets are sorted by term id
+        assert_eq!(gene_sets.len(), 2);
+        assert_eq!(gene_sets[0].id, "Fanconi's anemia pathway");
+        // Both term2genes rows of the term are merged
+        assert_eq!(gene_sets[0].genes, symbols(&["FANCA", "BRCA2", "FANCD2"]));
+        assert_eq!(gene_sets[1].id, "GO:0001");
+        assert_eq!(gene_sets[1].genes, symbols(&["TP53", "KRAS"]));
+    }
+
+    #[test]
+    fn group_name_with_quote() {
+        let conn = test_db();
+        let gene_sets = load_gene_sets(&conn, "Hallmark's genes").unwrap();
+        assert_eq!(gene_sets.len(), 1);
+        assert_eq!(gene_sets[0].id, "GO:0002");
+        assert_eq!(gene_sets[0].genes, symbols(&["MYC"]));
+    }
+
+    #[test]
+    fn group_name_is_not_interpreted_as_sql() {
+        let conn = test_db();
+        let gene_sets = load_gene_sets(&conn, "x' or '1'='1").unwrap();
+        assert!(gene_sets.is_empty());
+    }
+
+    #[test]
+    fn gmt_gene_sets_are_normalized() {
+        let gmt = "SET_A\tdescription\ttp53\tKRAS\tFOO1\r\n# comment\n\nSET_B\tna\tMYC\nSET_A\tna\tEGFR\n";
+        let mut gene_sets = parse_gmt(gmt).unwrap();
+        assert_eq!(gene_sets.len(), 2);
+        assert_eq!(gene_sets[0].id, "SET_A");
+        let coding_genes: Vec<String> = ["TP53", "KRAS", "MYC", "EGFR"].iter().map(|g| g.to_string()).collect();
+        let unmapped = normalize_gene_sets(&mut gene_sets, &coding_genes);
+        assert_eq!(unmapped, vec!["FOO1".to_string()]);
+        assert_eq!(gene_sets[0].genes, symbols(&["TP53", "KRAS", "EGFR"]));
+        assert_eq!(gene_sets[1].genes, symbols(&["MYC"]));
+        assert!(parse_gmt("SET_WITHOUT_DESCRIPTION\n").is_err());
+    }
+
+    // Regression test: with filter_non_coding_genes = false, the query genes were replaced by all the coding genes
+    #[test]
+    fn query_genes_with_and_without_non_coding_filter() {
+        let coding_genes = symbols(&["TP53", "KRAS", "MYC", "EGFR"]);
+        let query = vec!["KRAS", "MALAT1", "TP53", "XIST"];
+        assert_eq!(
+            filter_query_genes(query.clone(), &coding_genes, true),
+            vec!["KRAS", "TP53"]
+        );
+        assert_eq!(filter_query_genes(query.clone(), &coding_genes, false), query);
+    }
+
+    #[test]
+    fn jaccard_and_kappa_similarity() {
+        // 3 shared genes, 1 gene only in each term and 5 genes in neither of them out of 10
+        let genes1 = symbols(&["A", "B", "C", "D"]);
+        let genes2 = symbols(&["A", "B", "C", "E"]);
+        assert!((term_similarity(&genes1, &genes2, SimilarityMetric::Jaccard, 10) - 0.6).abs() < 1e-12);
+        // Observed agreement 0.8, expected agreement (4 * 4 + 6 * 6) / 100 = 0.52
+        let kappa = (0.8 - 0.52) / (1.0 - 0.52);
+        assert!((term_similarity(&genes1, &genes2, SimilarityMetric::Kappa, 10) - kappa).abs() < 1e-12);
+        // Identical terms covering the whole universe carry no information
+        assert_eq!(term_similarity(&genes1, &genes1, SimilarityMetric::Kappa, 4), 0.0);
+        assert_eq!(term_similarity(&genes1, &genes1, SimilarityMetric::Kappa, 10), 1.0);
+    }
+
+    #[test]
+    fn redundant_terms_join_the_most_similar_representative() {
+        let t1 = symbols(&["A", "B", "C", "D"]);
+        let t2 = symbols(&["A", "B", "C", "E"]);
+        let t3 = symbols(&["X", "Y", "Z"]);
+        // Jaccard 2/7 with T1 and 3/5 with T3
+        let t4 = symbols(&["A", "B", "X", "Y", "Z"]);
+        let terms = vec![("T1", &t1), ("T2", &t2), ("T3", &t3), ("T4", &t4)];
+        let options = RedundancyOptions {
+            metric: SimilarityMetric::Jaccard,
+            threshold: 0.5,
+            fdr_cutoff: 0.05,
+        };
+        let clusters = cluster_redundant_terms(&terms, &options, 20);
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].representative, "T1");
+        assert_eq!(clusters[0].members, vec!["T1", "T2"]);
+        assert_eq!(clusters[1].representative, "T3");
+        assert_eq!(clusters[1].members, vec!["T3", "T4"]);
+        // With a higher threshold every term is its own cluster
+        let options = RedundancyOptions {
+            threshold: 0.7,
+            ..options
+        };
+        assert_eq!(cluster_redundant_terms(&terms, &options, 20).len(), 4);
+    }
+
+    #[test]
+    fn size_filter_and_redundancy_options_from_json() {
+        let size_filter = SizeFilter::from_json(&json::parse(r#"{"min_gs_size":5}"#).unwrap()).unwrap();
+        assert!(!size_filter.accepts(4));
+        assert!(size_filter.accepts(5));
+        assert!(size_filter.accepts(5000));
+        assert!(SizeFilter::from_json(&json::parse(r#"{"min_gs_size":10,"max_gs_size":5}"#).unwrap()).is_err());
+        assert!(SizeFilter::from_json(&json::parse(r#"{"min_gs_size":-1}"#).unwrap()).is_err());
+
+        assert!(
+            RedundancyOptions::from_json(&json::parse("{}").unwrap())
+                .unwrap()
+                .is_none()
+        );
+        let options = RedundancyOptions::from_json(&json::parse(r#"{"redundancy":{"metric":"kappa"}}"#).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.metric, SimilarityMetric::Kappa);
+        assert_eq!(options.threshold, 0.35);
+        assert_eq!(options.fdr_cutoff, 0.05);
+        assert!(RedundancyOptions::from_json(&json::parse(r#"{"redundancy":{"metric":"cosine"}}"#).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+// For capturing output from a test, run: cd .. && cargo test -- --nocapture
+#[allow(dead_code)]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::geneset_functions::GeneSet;
+    use crate::go_dag::{
+        DagMethod, SemanticSimilarity, TermDag, dag_p_values, parse_edges, parse_go_ids, parse_obo, semantic_similarity,
+    };
+    use std::collections::HashMap;
+
+    const TOLERANCE: f64 = 1e-12;
+
+    // GO:3 is_a GO:2 is_a GO:1 (root), GO:4 part_of GO:1
+    const EDGES: &str = "# child\tparent\trelation\nGO:3\tGO:2\tis_a\nGO:2\tGO:1\tis_a\nGO:4\tGO:1\tpart_of\n";
+    const SIGNAL_GENES: [&str; 3] = ["S1", "S2", "S3"];
+
+    // Gene sets named as in msigdb, following the true path rule: every gene set has the genes of its children
+    fn gene_sets() -> Vec<GeneSet> {
+        let gene_set = |id: &str, genes: &[&str]| GeneSet {
+            id: id.to_string(),
+            genes: genes.iter().map(|g| g.to_string()).collect(),
+        };
+        vec![
+            gene_set("ROOT", &["S1", "S2", "S3", "X1", "X2", "Y"]),
+            gene_set("MIDDLE", &["S1", "S2", "S3", "X1"]),
+            gene_set("LEAF", &["S1", "S2", "S3"]),
+            gene_set("OTHER_LEAF", &["X2", "Y"]),
+        ]
+    }
+
+    fn placed_dag() -> TermDag {
+        let mut dag = parse_edges(EDGES).unwrap();
+        let go_ids = parse_go_ids("ROOT\tGO:1\nMIDDLE\tGO:2\nLEAF\tGO:3\nOTHER_LEAF\tGO:4\n").unwrap();
+        dag.place_terms(&["ROOT", "MIDDLE", "LEAF", "OTHER_LEAF"], &go_ids)
+            .unwrap();
+        dag
+    }
+
+    // P-value 0.5^(weight of the signal genes - weight of the other genes / 2), at most 1: gene sets are more
+    // significant with more signal genes and fewer other genes
+    fn score(weights: &HashMap<&str, f64>) -> f64 {
+        0.5f64.powf(exponent(weights)).min(1.0)
+    }
+
+    fn exponent(weights: &HashMap<&str, f64>) -> f64 {
+        weights
+            .iter()
+            .map(|(gene, w)| if SIGNAL_GENES.contains(gene) { *w } else { -0.5 * w })
+            .sum()
+    }
+
+    #[test]
+    fn classic_scores_every_gene_set_once() {
+        let p_values = dag_p_values(&placed_dag(), &gene_sets(), DagMethod::Classic, 0.2, &score);
+        let expected = [0.5f64.powf(1.5), 0.5f64.powf(2.5), 0.125, 1.0];
+        for (p, e) in p_values.iter().zip(expected) {
+            assert!((p - e).abs() < TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn elim_removes_genes_of_significant_terms_from_ancestors() {
+        let p_values = dag_p_values(&placed_dag(), &gene_sets(), DagMethod::Elim, 0.2, &score);
+        // LEAF is significant: its genes are removed from MIDDLE and ROOT, which are left with no signal gene
+        assert!((p_values[2] - 0.125).abs() < TOLERANCE);
+        assert_eq!(p_values[1], 1.0);
+        assert_eq!(p_values[0], 1.0);
+        assert_eq!(p_values[3], 1.0);
+        // With a lower cutoff no term is significant and elim is the classic test
+        let classic = dag_p_values(&placed_dag(), &gene_sets(), DagMethod::Classic, 0.2, &score);
+        assert_eq!(
+            dag_p_values(&placed_dag(), &gene_sets(), DagMethod::Elim, 0.1, &score),
+            classic
+        );
+    }
+
+    #[test]
+    fn weight_down_weights_the_less_significant_term() {
+        let p_values = dag_p_values(&placed_dag(), &gene_sets(), DagMethod::Weight, 0.2, &score);
+        // LEAF (0.125) is more significant than MIDDLE (0.5^2.5): the signal genes of MIDDLE are divided by the ratio
+        let leaf = 0.125;
+        let middle_ratio = 0.5f64.powf(2.5) / leaf;
+        let middle = 0.5f64.powf(3.0 / middle_ratio - 0.5);
+        // MIDDLE is then more significant than ROOT: the genes of MIDDLE are down-weighted in ROOT. OTHER_LEAF is less
+        // significant than ROOT and stays at 1.
+        let root_ratio = 0.5f64.powf(1.5) / middle;
+        let root = 0.5f64.powf((3.0 - 0.5) / root_ratio - 1.0);
+        let expected = [root, middle, leaf, 1.0];
+        for (p, e) in p_values.iter().zip(expected) {
+            assert!((p - e).abs() < TOLERANCE, "{:?} != {:?}", p_values, expected);
+        }
+        assert!(middle > 0.5f64.powf(2.5) && root > 0.5f64.powf(1.5));
+    }
+
+    #[test]
+    fn wang_similarity_from_the_dag() {
+        let dag = placed_dag();
+        let matrix = semantic_similarity(
+            &dag,
+            &["LEAF", "OTHER_LEAF", "GO:3"],
+            &gene_sets(),
+            SemanticSimilarity::Wang,
+        );
+        // S-values LEAF: {GO:3: 1, GO:2: 0.8, GO:1: 0.64}, OTHER_LEAF: {GO:4: 1, GO:1: 0.6}
+        let expected = (0.64 + 0.6) / (2.44 + 1.6);
+        assert!((matrix[0][1] - expected).abs() < TOLERANCE);
+        assert!((matrix[1][0] - expected).abs() < TOLERANCE);
+        assert_eq!(matrix[0][0], 1.0);
+        // A gene set and its GO id are the same node
+        assert!((matrix[0][2] - 1.0).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn gene_sets_must_be_placed() {
+        let mut dag = parse_edges(EDGES).unwrap();
+        let error = dag.place_terms(&["GOBP_UNKNOWN"], &HashMap::new()).unwrap_err();
+        assert_eq!(
+            error,
+            "Gene set GOBP_UNKNOWN has no GO id to place it in the GO hierarchy"
+        );
+        let go_ids = parse_go_ids("GOBP_UNKNOWN\tGO:9\n").unwrap();
+        let error = dag.place_terms(&["GOBP_UNKNOWN"], &go_ids).unwrap_err();
+        assert_eq!(error, "GO id GO:9 of gene set GOBP_UNKNOWN is not in the GO hierarchy");
+        let go_ids = parse_go_ids("LEAF\tGO:3\n").unwrap();
+        let error = dag.place_terms(&["GO:3", "LEAF"], &go_ids).unwrap_err();
+        assert_eq!(error, "Gene sets GO:3 and LEAF are both placed at GO:3");
+        // Gene sets named by a GO id of the DAG need no mapping
+        assert!(dag.place_terms(&["GO:1", "GO:4"], &HashMap::new()).is_ok());
+    }
+
+    #[test]
+    fn edges_and_obo_give_the_same_dag() {
+        let obo = "format-version: 1.2\n\n[Term]\nid: GO:2\nis_a: GO:1 ! root\n\n[Term]\nid: GO:3\nis_a: GO:2\n\n\
+                   [Term]\nid: GO:4\nrelationship: part_of GO:1 ! root\n\n[Term]\nid: GO:5\nis_a: GO:1\n\
+                   is_obsolete: true\n\n[Typedef]\nid: part_of\nis_a: GO:1\n";
+        let from_obo = parse_obo(obo).unwrap();
+        let from_edges = parse_edges(EDGES).unwrap();
+        let terms = ["GO:1", "GO:2", "GO:3", "GO:4"];
+        for method in [SemanticSimilarity::Wang, SemanticSimilarity::Resnik] {
+            assert_eq!(
+                semantic_similarity(&from_obo, &terms, &[], method),
+                semantic_similarity(&from_edges, &terms, &[], method)
+            );
+        }
+        assert!(!from_obo.contains("GO:5"));
+        assert!(parse_edges("GO:2\tGO:1\tregulates\n").is_err());
+        assert!(parse_edges("GO:2\tGO:1\tis_a\nGO:1\tGO:2\tis_a\n").is_err());
+    }
+}
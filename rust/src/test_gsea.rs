@@ -0,0 +1,122 @@
+// For capturing output from a test, run: cd .. && cargo test -- --nocapture
+#[allow(dead_code)]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::gsea::{RankedList, gsea};
+    use std::collections::HashSet;
+
+    // 200 genes g0..g199 with statistics decreasing linearly from 99.5 to -99.5
+    fn ranked_list() -> RankedList {
+        let genes: Vec<String> = (0..200).map(|i| format!("g{}", i)).collect();
+        let statistics: Vec<f64> = (0..200).map(|i| 99.5 - i as f64).collect();
+        RankedList::new(genes, &statistics, 1.0)
+    }
+
+    fn gene_set(positions: impl Iterator<Item = usize>) -> HashSet<String> {
+        positions.map(|i| format!("g{}", i)).collect()
+    }
+
+    // Running sum after each gene of the list, walked one gene at a time
+    fn walk(statistics: &[f64], hits: &[usize]) -> Vec<f64> {
+        let nr: f64 = hits.iter().map(|&h| statistics[h].abs()).sum();
+        let miss = 1.0 / (statistics.len() - hits.len()) as f64;
+        let mut sum = 0.0;
+        let mut values = vec![0.0];
+        for (i, s) in statistics.iter().enumerate() {
+            sum += if hits.contains(&i) { s.abs() / nr } else { -miss };
+            values.push(sum);
+        }
+        values
+    }
+
+    #[test]
+    fn running_sum_and_enrichment_score() {
+        let genes: Vec<String> = (0..10).map(|i| format!("g{}", i)).collect();
+        let statistics = [5.0, 4.0, 3.0, 2.0, 1.0, -1.0, -2.0, -3.0, -4.0, -5.0];
+        let ranked = RankedList::new(genes, &statistics, 1.0);
+        let set = gene_set(vec![1, 4, 7].into_iter());
+        assert_eq!(ranked.hit_positions(&set), vec![1, 4, 7]);
+        let expected = walk(&statistics, &[1, 4, 7]);
+        let curve = ranked.running_sum(&set);
+        assert_eq!(curve.first(), Some(&(0, 0.0)));
+        assert_eq!(curve.last().unwrap().0, 10);
+        for &(x, y) in &curve {
+            assert!((expected[x] - y).abs() < 1e-12, "running sum after {} genes", x);
+        }
+        // The ES is the largest deviation of the walk from zero
+        let es = expected
+            .iter()
+            .cloned()
+            .fold(0.0, |m: f64, v| if v.abs() > m.abs() { v } else { m });
+        let result = gsea(&ranked, &[&set], 100, 1, 1)[0].clone().unwrap();
+        assert!((result.es - es).abs() < 1e-12);
+        assert_eq!(result.gene_set_size, 3);
+    }
+
+    #[test]
+    fn gsea_of_top_bottom_and_scattered_gene_sets() {
+        let ranked = ranked_list();
+        let top = gene_set(0..10);
+        let bottom = gene_set(190..200);
+        let scattered = gene_set((0..10).map(|i| i * 20 + 3));
+        let all = gene_set(0..200);
+        let unknown: HashSet<String> = vec!["TP53".to_string()].into_iter().collect();
+        let results = gsea(&ranked, &[&top, &bottom, &scattered, &all, &unknown], 1000, 1, 3);
+
+        let top = results[0].as_ref().unwrap();
+        assert!((top.es - 1.0).abs() < 1e-12);
+        assert!(top.nes > 1.0);
+        assert!(top.p_value < 1e-4);
+        let expected_edge: Vec<String> = (0..10).map(|i| format!("g{}", i)).collect();
+        assert_eq!(top.leading_edge, expected_edge);
+
+        let bottom = results[1].as_ref().unwrap();
+        assert!((bottom.es + 1.0).abs() < 1e-12);
+        assert!(bottom.nes < -1.0);
+        assert!(bottom.p_value < 1e-4);
+        // Most extreme gene first
+        assert_eq!(bottom.leading_edge[0], "g199");
+        assert_eq!(bottom.leading_edge.len(), 10);
+
+        assert!(results[2].as_ref().unwrap().p_value > 0.05);
+        // Gene sets with all or none of the genes cannot be tested
+        assert!(results[3].is_none());
+        assert!(results[4].is_none());
+    }
+
+    #[test]
+    fn results_do_not_depend_on_threads() {
+        let ranked = ranked_list();
+        let sets: Vec<HashSet<String>> = (1..8).map(|step| gene_set((0..12).map(move |i| i * step))).collect();
+        let set_refs: Vec<&HashSet<String>> = sets.iter().collect();
+        let p_values = |threads: usize| -> Vec<f64> {
+            gsea(&ranked, &set_refs, 500, 7, threads)
+                .into_iter()
+                .map(|r| r.unwrap().p_value)
+                .collect()
+        };
+        assert_eq!(p_values(1), p_values(3));
+    }
+
+    #[test]
+    fn multilevel_agrees_with_permutations() {
+        // Gene set at every 6th position: about 3e-4 of random gene sets of 10 genes score as high
+        let ranked = ranked_list();
+        let set = gene_set((0..10).map(|i| i * 6));
+        // With 200000 permutations enough random gene sets exceed the ES for a plain permutation p-value
+        let permutation_p = gsea(&ranked, &[&set], 200000, 1, 3)[0].as_ref().unwrap().p_value;
+        // With 100 permutations none exceed it and the multilevel procedure is used
+        let log_mean = (1..11)
+            .map(|seed| gsea(&ranked, &[&set], 100, seed, 3)[0].as_ref().unwrap().p_value.ln())
+            .sum::<f64>()
+            / 10.0;
+        let ratio = log_mean.exp() / permutation_p;
+        assert!(
+            ratio > 0.5 && ratio < 2.0,
+            "multilevel / permutation p-value: {}",
+            ratio
+        );
+    }
+}
@@ -0,0 +1,245 @@
+// For capturing output from a test, run: cd .. && cargo test -- --nocapture
+#[allow(dead_code)]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Kernel, gsva, integer_ranks, kernel_cdf, plage, ssgsea, zscore};
+    use nalgebra::DMatrix;
+
+    const SCORE_CUTOFF: f64 = 1e-10; // Threshold difference between calculated and expected score for the test to pass
+
+    // 8 genes × 5 samples with a tie in the first sample
+    fn test_matrix() -> DMatrix<f64> {
+        DMatrix::from_row_slice(
+            8,
+            5,
+            &[
+                5.1, 2.0, 3.3, 7.2, 1.1, //
+                2.4, 6.1, 0.2, 1.9, 4.4, //
+                3.3, 3.3, 5.5, 0.7, 2.2, //
+                0.9, 4.8, 2.6, 3.1, 6.6, //
+                5.1, 1.2, 4.1, 2.2, 0.3, //
+                1.7, 0.4, 6.9, 5.0, 3.8, //
+                4.2, 5.6, 1.4, 4.4, 5.2, //
+                2.8, 2.9, 3.7, 6.3, 2.5, //
+            ],
+        )
+    }
+
+    fn gene_sets() -> Vec<Vec<usize>> {
+        vec![vec![0, 4, 6], vec![1, 3], vec![2, 5, 7, 3]]
+    }
+
+    // Reference values for test_matrix() and gene_sets() from a line by line transcription of the GSVA package
+    // (gsva.R and kernel_estimation.c, including its precomputed normal CDF table): gsva(method = "gsva",
+    // kcdf = "Gaussian") with mx.diff = TRUE and FALSE, and gsva(method = "ssgsea") with ssgsea.norm = FALSE and TRUE
+    const GSVA_SCORES: [[f64; 5]; 3] = [
+        [
+            1.0,
+            -5.551115123125783e-17,
+            -0.6000000000000001,
+            0.19999999999999996,
+            -0.45,
+        ],
+        [
+            -0.7499999999999998,
+            0.8333333333333333,
+            -0.6666666666666665,
+            -0.6666666666666665,
+            0.8333333333333333,
+        ],
+        [-0.75, -0.5, 0.7777777777777777, 0.11111111111111105, 0.6666666666666665],
+    ];
+    const GSVA_MAX_DEVIATION_SCORES: [[f64; 5]; 3] = [
+        [
+            1.0,
+            -0.30000000000000004,
+            -0.6000000000000001,
+            0.39999999999999997,
+            -0.625,
+        ],
+        [
+            -0.7499999999999999,
+            0.8333333333333333,
+            -0.8333333333333333,
+            -0.8333333333333333,
+            0.8333333333333333,
+        ],
+        [-0.75, -0.5, 0.7777777777777777, 0.4444444444444444, 0.6666666666666666],
+    ];
+    const SSGSEA_SCORES: [[f64; 5]; 3] = [
+        [
+            4.012762338724017,
+            -0.5131456281508666,
+            -0.6196583946253279,
+            1.537083164306978,
+            -1.33068994134995,
+        ],
+        [
+            -3.196863595671726,
+            3.3692780998507943,
+            -3.196863595671726,
+            -1.9135727662741102,
+            3.3692780998507943,
+        ],
+        [
+            -2.761569135516999,
+            -0.7030825980136779,
+            2.675911112083965,
+            0.39368726731197556,
+            1.1691119557443854,
+        ],
+    ];
+    const SSGSEA_NORMALIZED_SCORES: [[f64; 5]; 3] = [
+        [
+            0.5565839858042977,
+            -0.07117506966661712,
+            -0.08594875798882387,
+            0.21319873989215568,
+            -0.18457128753400134,
+        ],
+        [
+            -0.44341601419570226,
+            0.4673305009871059,
+            -0.44341601419570226,
+            -0.2654191470801309,
+            0.4673305009871059,
+        ],
+        [
+            -0.3830391702213123,
+            -0.09751998292441298,
+            0.37115810673584404,
+            0.05460578272636435,
+            0.16215986326929616,
+        ],
+    ];
+    // Kernel CDF log odds of the genes of test_matrix() (.compute_gene_cdf in GSVA)
+    const GENE_DENSITY: [[f64; 5]; 8] = [
+        [
+            0.8460145030748228,
+            -0.8992699956668534,
+            -0.012345668503466717,
+            2.1965491535581165,
+            -2.048379560615646,
+        ],
+        [
+            -0.1532220588655869,
+            2.1939093031376298,
+            -2.1937740742062326,
+            -0.67235908958368,
+            0.8484873935567075,
+        ],
+        [
+            0.4003368939073629,
+            0.4003368939073629,
+            2.1972233423866134,
+            -2.196510863320322,
+            -0.8359015854132138,
+        ],
+        [
+            -2.195163987632357,
+            0.8468663281476845,
+            -0.6825741717394415,
+            -0.1429912078252028,
+            2.1961697290302324,
+        ],
+        [
+            2.148234632476834,
+            -0.8597631293265364,
+            0.8687349892788967,
+            -0.017985701230665122,
+            -2.1204882217938703,
+        ],
+        [
+            -0.8679391700391209,
+            -2.148916023500807,
+            2.1935695365933157,
+            0.8188109728139216,
+            0.024920056700821543,
+        ],
+        [
+            -0.5568645634691498,
+            1.870133677959978,
+            -2.1972245773247296,
+            -0.22961401395789394,
+            0.9764619569894603,
+        ],
+        [
+            -0.6730906298585695,
+            -0.43074345939637526,
+            0.818140976733627,
+            2.197224577313375,
+            -1.5547278790140924,
+        ],
+    ];
+
+    fn assert_scores(scores: &[Vec<f64>], expected: &[[f64; 5]]) {
+        assert_eq!(scores.len(), expected.len());
+        for (row, expected_row) in scores.iter().zip(expected) {
+            for (score, e) in row.iter().zip(expected_row) {
+                assert!((score - e).abs() < SCORE_CUTOFF, "{:?} != {:?}", scores, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn ssgsea_matches_gsva_package() {
+        let x = test_matrix();
+        assert_scores(&ssgsea(&x, &gene_sets(), 0.25, false), &SSGSEA_SCORES);
+        // Normalized scores are divided by the range of all the scores
+        assert_scores(&ssgsea(&x, &gene_sets(), 0.25, true), &SSGSEA_NORMALIZED_SCORES);
+    }
+
+    #[test]
+    fn gsva_matches_gsva_package() {
+        let density = kernel_cdf(&test_matrix(), Kernel::Gaussian);
+        for row in 0..density.nrows() {
+            for col in 0..density.ncols() {
+                assert!((density[(row, col)] - GENE_DENSITY[row][col]).abs() < SCORE_CUTOFF);
+            }
+        }
+        assert_scores(&gsva(&density, &gene_sets(), 1.0, true), &GSVA_SCORES);
+        assert_scores(&gsva(&density, &gene_sets(), 1.0, false), &GSVA_MAX_DEVIATION_SCORES);
+    }
+
+    #[test]
+    fn gaussian_kernel_cdf() {
+        // Gene with values 0, 1, 2: bandwidth sd / 4 = 0.25, so the kernel CDF at 2 is (Φ(8) + Φ(4) + Φ(0)) / 3
+        let x = DMatrix::from_row_slice(1, 3, &[0.0, 1.0, 2.0]);
+        let density = kernel_cdf(&x, Kernel::Gaussian);
+        let left_tail: f64 = (0.9999999999999993 + 0.9999683287581669 + 0.5) / 3.0;
+        assert!((density[(0, 2)] - (left_tail / (1.0 - left_tail)).ln()).abs() < 1e-9);
+        assert!(density[(0, 1)].abs() < SCORE_CUTOFF); // Symmetric around the middle value
+    }
+
+    #[test]
+    fn zscore_and_plage() {
+        let x = test_matrix();
+        let scores = zscore(&x, &gene_sets());
+        // Gene set 1 (genes 1 and 3) in sample 0: (z(2.4) + z(0.9)) / sqrt(2)
+        let z = |row: usize, col: usize| {
+            let values: Vec<f64> = x.row(row).iter().copied().collect();
+            let mean = values.iter().sum::<f64>() / 5.0;
+            let sd = (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / 4.0).sqrt();
+            (x[(row, col)] - mean) / sd
+        };
+        assert!((scores[1][0] - (z(1, 0) + z(3, 0)) / 2f64.sqrt()).abs() < SCORE_CUTOFF);
+
+        // PLAGE scores are a unit vector over the samples, oriented with the mean standardized expression
+        let scores = plage(&x, &gene_sets());
+        for (i, genes) in gene_sets().iter().enumerate() {
+            let norm: f64 = scores[i].iter().map(|v| v * v).sum::<f64>();
+            assert!((norm - 1.0).abs() < 1e-9);
+            let direction: f64 = (0..5)
+                .map(|col| scores[i][col] * genes.iter().map(|&g| z(g, col)).sum::<f64>())
+                .sum();
+            assert!(direction >= 0.0);
+        }
+    }
+
+    #[test]
+    fn ties_share_truncated_average_rank() {
+        assert_eq!(integer_ranks(&[5.1, 2.4, 3.3, 5.1, 1.0]), vec![4.0, 2.0, 3.0, 4.0, 1.0]);
+    }
+}
@@ -4,6 +4,7 @@ import { run_R } from '@sjcrh/proteinpaint-r'
 import { invalidcoord } from '#shared/common.js'
 import { mayLog } from '#src/helpers.ts'
 import serverconfig from '#src/serverconfig.js'
+import path from 'path'
 import { formatElapsedTime } from '#shared'
 
 export const payload: RoutePayload = {
@@ -73,7 +74,32 @@ function init({ genomes }) {
 				blockWidth: q.blockWidth,
 				devicePixelRatio: q.devicePixelRatio,
 				maxLoessRegion: q.maxLoessRegion,
-				colors: q.colors
+				colors: q.colors,
+				trackHeight: q.trackHeight,
+				tracks: q.tracks,
+				group1Name: q.group1Name,
+				group2Name: q.group2Name,
+				// model, DMR caller and preprocessing options of the Rust backend; the R backend ignores them.
+				// scope and export_prefix are not forwarded: this route is a region query, and it must not write files
+				// at paths given by the client.
+				test: q.test,
+				method: q.method,
+				min_delta_beta: q.min_delta_beta,
+				min_delta_sd: q.min_delta_sd,
+				covariates: q.covariates,
+				pairs: q.pairs,
+				design: q.design,
+				contrast: q.contrast,
+				bump_cutoff: q.bump_cutoff,
+				num_permutations: q.num_permutations,
+				seed: q.seed,
+				combp_step: q.combp_step,
+				normalization: q.normalization,
+				remove_sex_chromosomes: q.remove_sex_chromosomes,
+				detection_p_cutoff: q.detection_p_cutoff,
+				include_sample_betas: q.include_sample_betas,
+				// annotates DMRs with overlapping/nearest genes and promoters
+				genedb: genome.genedb?.dbfile ? path.join(serverconfig.tpmasterdir, genome.genedb.dbfile) : undefined
 			}
 
 			const time1 = Date.now()
@@ -103,8 +103,12 @@ type blitzgseaResult = {
 	pathway: blitzgsea_json | blitzgsea_image_name
 }
 
+/** rust/src/cerno.rs output, optional fields such as unmapped_genes sit next to pathways */
 type cernoResult = {
-	data: cerno_map[]
+	/** cerno results keyed by gene set name */
+	pathways: cerno_map
+	/** symbols of custom gene sets not found in genedb */
+	unmapped_genes?: string[]
 }
 
 export type GenesetEnrichmentResponse = {
@@ -24,10 +24,43 @@ export type TermdbDmrRequest = {
 	blockWidth?: number
 	/** Device pixel ratio for server-side track rendering (default 1) */
 	devicePixelRatio?: number
+	/** Height in CSS pixels of the plot area of the server-rendered tracks (default 150) */
+	trackHeight?: number
+	/** Separate tracks to render in addition to track_png, each as PNG and SVG with a legend */
+	tracks?: ('means' | 'smoothed_fdr' | 'dmrs' | 'heatmap')[]
 	/** Maximum region size (bp) to show LOESS curves (default 50000) */
 	maxLoessRegion?: number
 	/** Group/DMR colors for server-side track rendering */
 	colors?: { group1: string; group2: string; hyper: string; hypo: string }
+	/** The options below are only used by the Rust backend */
+	/** diffmean (differential methylation, default) or diffvar (differential variability) */
+	test?: 'diffmean' | 'diffvar'
+	/** DMR caller: DMRCate kernel smoothing (default), bumphunter or comb-p */
+	method?: 'dmrcate' | 'bumphunter' | 'combp'
+	/** Cutoff on the difference of the group mean betas of the proximity fallback (default 0.05) */
+	min_delta_beta?: number
+	/** diffvar cutoff on the difference of the group standard deviations (default 0, no cutoff) */
+	min_delta_sd?: number
+	/** Covariates added to the case vs control model, by sample name; strings are factors */
+	covariates?: { [name: string]: { [sample: string]: number | string } }
+	/** Pair (e.g. patient) of each sample for a paired design */
+	pairs?: { [sample: string]: string }
+	/** Explicit design matrix, rows in the order of samples, tested with contrast */
+	design?: { samples: string[]; columns: string[]; matrix: number[][] }
+	contrast?: number[]
+	/** bumphunter: cutoff on the smoothed estimates, number of permutations (default 100) and their seed (default 1) */
+	bump_cutoff?: number
+	num_permutations?: number
+	seed?: number
+	/** comb-p: bin size in bp of the autocorrelation (default 50) */
+	combp_step?: number
+	/** Type I/II probe bias correction with BMIQ (default none) */
+	normalization?: 'none' | 'bmiq'
+	remove_sex_chromosomes?: boolean
+	/** Detection p-value above which a sample fails at a probe in sample_qc (default 0.01) */
+	detection_p_cutoff?: number
+	/** Adds the per-sample beta values of the region probes to the diagnostic block */
+	include_sample_betas?: boolean
 	/** Backend engine: 'rust' (genome-wide eBayes, default) or 'r' (DMRCate via cached limma) */
 	backend?: 'rust' | 'r'
 	filter?: Filter
@@ -63,8 +96,40 @@ export type DmrDiagnostic = {
 		mean_group2: (number | null)[]
 		fdr: number[]
 		logFC: (number | null)[]
+		/** Per-group standard deviation of the beta values */
+		sd_group1?: (number | null)[]
+		sd_group2?: (number | null)[]
 	}
 	probe_spacings: number[]
+	/** diffmean (differential methylation) or diffvar (differential variability) */
+	test?: 'diffmean' | 'diffvar'
+	/** DMR caller: DMRCate kernel smoothing, bumphunter or comb-p */
+	method?: 'dmrcate' | 'bumphunter' | 'combp'
+	/** eBayes prior variance and degrees of freedom of the genome-wide fit */
+	prior_var?: number
+	prior_df?: number | null
+	/** True when the genome-wide statistics were read from the cache */
+	from_cache?: boolean
+	/** Preprocessing of the beta values before the genome-wide fit */
+	preprocessing?: {
+		normalization: 'none' | 'bmiq'
+		/** Number of samples whose type II probes were corrected */
+		corrected_samples: number
+		removed_probes: { cross_reactive: number; snp: number; sex_chromosomes: number }
+	}
+	/** Per-sample QC of the case and control samples */
+	sample_qc?: { sample: string; median_beta: number | null; detection_failed_fraction: number }[]
+	/** Columns, contrast and residual degrees of freedom of the per-probe linear model */
+	design?: { columns: string[]; contrast: number[]; df_residual: number }
+	/** Per-sample beta values of the region probes, only when include_sample_betas is set; samples are the controls
+	 * followed by the cases, groups gives 1 for a control and 2 for a case */
+	sample_betas?: {
+		samples: string[]
+		groups: number[]
+		probe_ids: string[] | null
+		/** beta[probe][sample], null where the value is missing */
+		beta: (number | null)[][]
+	} | null
 	/** LOESS smoothed curves with 95% CI for both groups */
 	loess?: DmrLoessCurves
 	/** Total probes analyzed genome-wide for eBayes */
@@ -77,6 +142,22 @@ export type DmrDiagnostic = {
 	elapsed_ms?: number
 	/** Server-rendered track PNG as data URI (Rust backend only) */
 	track_png?: string | null
+	/** Tracks requested with `tracks`, in that order (Rust backend only) */
+	tracks?: DmrTrack[] | null
+}
+
+export type DmrTrack = {
+	name: 'means' | 'smoothed_fdr' | 'dmrs' | 'heatmap'
+	/** PNG data URI without text, and standalone SVG including the text */
+	png: string
+	svg: string
+	/** Size in CSS pixels: legend row above the trackHeight plot area */
+	width: number
+	height: number
+	devicePixelRatio: number
+	/** Text of the track, in CSS pixels, for the client to draw over the PNG */
+	labels: { text: string; x: number; y: number; anchor: 'start' | 'middle' | 'end'; size: number; vertical: boolean }[]
+	legend: { label: string; color: string }[]
 }
 
 export type TermdbDmrSuccessResponse = {
@@ -95,10 +176,25 @@ export type TermdbDmrSuccessResponse = {
 		maxdiff: number
 		/** Mean methylation difference across the DMR */
 		meandiff: number
-		/** hyper = case hypermethylated relative to control; hypo = opposite */
-		direction: 'hyper' | 'hypo'
+		/** hyper = case hypermethylated relative to control; hypo = opposite. For diffvar, hypervariable = case more variable
+		 * than control and maxdiff/meandiff are differences of the group standard deviations */
+		direction: 'hyper' | 'hypo' | 'hypervariable' | 'hypovariable'
+		/** bumphunter: sum of |smoothed estimate| over the bump, and the fractions of the permutation null bumps (p_value)
+		 * and of the permutations (fwer) reaching that area */
+		area?: number
+		fwer?: number
+		/** bumphunter and comb-p: p-value of the region; comb-p also gives its Šidák correction for the region length */
+		p_value?: number
+		p_sidak?: number
 		/** Comma-separated gene symbols overlapping the DMR */
 		overlapping_genes?: string | null
+		/** Gene(s) with the TSS closest to the DMR; distance_to_tss is negative upstream of the TSS and 0 when the TSS is inside the DMR */
+		nearest_genes?: { gene: string; strand: string; distance_to_tss: number }[]
+		/** Genes whose promoter overlaps the DMR */
+		promoter_genes?: string[]
+		promoter_overlap?: boolean
+		/** CpG island context, only when a CpG island file is given */
+		cpg_context?: 'island' | 'shore' | 'shelf' | 'open_sea'
 	}[]
 	/** Diagnostic data: per-CpG probe means and statistics */
 	diagnostic?: DmrDiagnostic